@@ -35,26 +35,71 @@ pub struct StateInfo {
 }
 
 impl StateInfo {
-    fn new(
-        name: &str,
-        enter_fn: Option<EnterFn>,
-        process_fn: StateFn,
-        exit_fn: Option<ExitFn>,
-        parent_hdl: Option<usize>,
-    ) -> Self {
+    fn new(name: &str, process_fn: StateFn) -> Self {
         StateInfo {
             name: name.to_owned(),
-            parent: parent_hdl,
-            enter: enter_fn,
+            parent: None,
+            enter: None,
             process: process_fn,
-            exit: exit_fn,
+            exit: None,
             active: false,
             enter_cnt: 0,
             process_cnt: 0,
             exit_cnt: 0,
         }
     }
+
+    fn enter_fn(mut self, enter_fn: EnterFn) -> Self {
+        self.enter = Some(enter_fn);
+
+        self
+    }
+
+    fn exit_fn(mut self, exit_fn: ExitFn) -> Self {
+        self.exit = Some(exit_fn);
+
+        self
+    }
+
+    fn parent_idx(mut self, parent_hdl: usize) -> Self {
+        self.parent = Some(parent_hdl);
+
+        self
+    }
 }
+
+// The reasons StateMachineInfo::build can fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    CycleDetected { states: Vec<String> },
+    InvalidInitialState { idx: usize, valid: Vec<usize> },
+    InvalidParent { state: String, parent_idx: usize },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::CycleDetected { states } => {
+                write!(f, "Cycle detected between states {states:?}")
+            }
+            BuildError::InvalidInitialState { idx, valid } => {
+                write!(
+                    f,
+                    "{idx} is not a valid initial state, only {valid:?} are allowed"
+                )
+            }
+            BuildError::InvalidParent { state, parent_idx } => {
+                write!(
+                    f,
+                    "State {state} has parent {parent_idx} which is not a state"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
 impl StateMachine {
     fn state_name(&self, hdl: usize) -> &str {
         &self.smi.state_fns[hdl].name
@@ -64,25 +109,6 @@ impl StateMachine {
         self.state_name(self.smi.current_state_fns_hdl)
     }
 
-    // When the state machine starts there will be no fn's to
-    // exit so we initialize only the enter_fns_hdls.
-    fn initial_enter_fns_hdls(&mut self) {
-        let mut enter_hdl = self.smi.current_state_fns_hdl;
-        loop {
-            log::trace!(
-                "initial_enter_fns_hdls: push enter_hdl={} {}",
-                enter_hdl,
-                self.state_name(enter_hdl)
-            );
-            self.smi.enter_fns_hdls.push(enter_hdl);
-            enter_hdl = if let Some(hdl) = self.smi.state_fns[enter_hdl].parent {
-                hdl
-            } else {
-                break;
-            };
-        }
-    }
-
     fn setup_exit_enter_fns_hdls(&mut self, next_state_hdl: usize) {
         let mut cur_hdl = next_state_hdl;
 
@@ -278,20 +304,98 @@ pub struct StateMachineInfo {
 }
 
 impl StateMachineInfo {
-    fn new(max_fns: usize, initial_hdl: usize) -> Self {
+    fn new(max_fns: usize) -> Self {
         StateMachineInfo {
             state_fns: Vec::<StateInfo>::with_capacity(max_fns),
             enter_fns_hdls: Vec::<usize>::with_capacity(max_fns),
             exit_fns_hdls: VecDeque::<usize>::with_capacity(max_fns),
-            current_state_fns_hdl: initial_hdl,
-            previous_state_fns_hdl: initial_hdl,
+            current_state_fns_hdl: 0,
+            previous_state_fns_hdl: 0,
             current_state_changed: true,
             //transition_dest_hdl: Option<usize>,
         }
     }
 
-    fn add_state(&mut self, state_info: StateInfo) {
+    // Add a state, its hdl is the number of states added before it
+    fn state(mut self, state_info: StateInfo) -> Self {
         self.state_fns.push(state_info);
+
+        self
+    }
+
+    // Validate the states and return the info ready to dispatch messages
+    // starting in initial_hdl, which must be a leaf.
+    fn build(mut self, initial_hdl: usize) -> Result<Self, BuildError> {
+        for state in self.state_fns.iter() {
+            if let Some(parent_idx) = state.parent {
+                if parent_idx >= self.state_fns.len() {
+                    return Err(BuildError::InvalidParent {
+                        state: state.name.clone(),
+                        parent_idx,
+                    });
+                }
+            }
+        }
+
+        let hdls_cycle = self.cycle_detector();
+        if !hdls_cycle.is_empty() {
+            return Err(BuildError::CycleDetected {
+                states: hdls_cycle
+                    .iter()
+                    .map(|hdl| self.state_fns[*hdl].name.clone())
+                    .collect(),
+            });
+        }
+
+        let is_leaf = |hdl: usize| !self.state_fns.iter().any(|s| s.parent == Some(hdl));
+        if initial_hdl >= self.state_fns.len() || !is_leaf(initial_hdl) {
+            return Err(BuildError::InvalidInitialState {
+                idx: initial_hdl,
+                valid: (0..self.state_fns.len())
+                    .filter(|hdl| is_leaf(*hdl))
+                    .collect(),
+            });
+        }
+
+        self.current_state_fns_hdl = initial_hdl;
+        self.previous_state_fns_hdl = initial_hdl;
+
+        // When the state machine starts there will be no fn's to
+        // exit so we initialize only the enter_fns_hdls.
+        let mut enter_hdl = initial_hdl;
+        loop {
+            log::trace!(
+                "build: push enter_hdl={} {}",
+                enter_hdl,
+                self.state_fns[enter_hdl].name
+            );
+            self.enter_fns_hdls.push(enter_hdl);
+            enter_hdl = if let Some(hdl) = self.state_fns[enter_hdl].parent {
+                hdl
+            } else {
+                break;
+            };
+        }
+
+        Ok(self)
+    }
+
+    // The hdls of the states in the first cycle of parents found,
+    // empty if there are none
+    fn cycle_detector(&self) -> Vec<usize> {
+        for start_hdl in 0..self.state_fns.len() {
+            let mut path = Vec::<usize>::new();
+            let mut hdl = Some(start_hdl);
+            while let Some(cur_hdl) = hdl {
+                if let Some(pos) = path.iter().position(|h| *h == cur_hdl) {
+                    return path[pos..].to_vec();
+                }
+                path.push(cur_hdl);
+                hdl = self.state_fns[cur_hdl].parent;
+            }
+        }
+
+        Vec::new()
     }
 }
 
@@ -321,39 +425,28 @@ const OTHER_HDL: usize = 2;
 
 impl StateMachine {
     pub fn new() -> Self {
-        let mut sm = StateMachine {
-            smi: StateMachineInfo::new(MAX_STATE_FNS, INITIAL_HDL),
-        };
-
-        let base_si = StateInfo::new(
-            "base",
-            Some(Self::base_enter),
-            Self::base,
-            Some(Self::base_exit),
-            None,
-        );
-        sm.smi.add_state(base_si);
-
-        let initial_si = StateInfo::new(
-            "initial",
-            Some(Self::initial_enter),
-            Self::initial,
-            Some(Self::initial_exit),
-            Some(BASE_HDL),
-        );
-        sm.smi.add_state(initial_si);
-
-        let other_si = StateInfo::new(
-            "other",
-            Some(Self::other_enter),
-            Self::other,
-            Some(Self::other_exit),
-            Some(BASE_HDL),
-        );
-        sm.smi.add_state(other_si);
-
-        // Initialize so transition to initial state works
-        sm.initial_enter_fns_hdls();
+        let smi = StateMachineInfo::new(MAX_STATE_FNS)
+            .state(
+                StateInfo::new("base", Self::base)
+                    .enter_fn(Self::base_enter)
+                    .exit_fn(Self::base_exit),
+            )
+            .state(
+                StateInfo::new("initial", Self::initial)
+                    .enter_fn(Self::initial_enter)
+                    .exit_fn(Self::initial_exit)
+                    .parent_idx(BASE_HDL),
+            )
+            .state(
+                StateInfo::new("other", Self::other)
+                    .enter_fn(Self::other_enter)
+                    .exit_fn(Self::other_exit)
+                    .parent_idx(BASE_HDL),
+            )
+            .build(INITIAL_HDL)
+            .expect("Unexpected error initializing");
+
+        let sm = StateMachine { smi };
 
         log::trace!(
             "new: inital state={} enter_fnss_hdls={:?}",
@@ -479,4 +572,45 @@ mod test {
     fn test_leaf_transitions_in_a_tree() {
         test_transition_between_leafs_in_a_tree();
     }
+
+    #[test]
+    fn test_build_errors() {
+        let smi = || {
+            StateMachineInfo::new(MAX_STATE_FNS)
+                .state(StateInfo::new("base", StateMachine::base))
+                .state(StateInfo::new("initial", StateMachine::initial).parent_idx(BASE_HDL))
+        };
+
+        // The initial state must be a leaf
+        assert_eq!(
+            smi().build(BASE_HDL).err(),
+            Some(BuildError::InvalidInitialState {
+                idx: BASE_HDL,
+                valid: vec![INITIAL_HDL],
+            })
+        );
+        assert!(smi().build(OTHER_HDL).is_err());
+
+        assert_eq!(
+            smi()
+                .state(StateInfo::new("other", StateMachine::other).parent_idx(3))
+                .build(INITIAL_HDL)
+                .err(),
+            Some(BuildError::InvalidParent {
+                state: "other".to_owned(),
+                parent_idx: 3,
+            })
+        );
+
+        let cycle = StateMachineInfo::new(MAX_STATE_FNS)
+            .state(StateInfo::new("base", StateMachine::base).parent_idx(OTHER_HDL))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(BASE_HDL))
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(BASE_HDL));
+        assert_eq!(
+            cycle.build(INITIAL_HDL).err(),
+            Some(BuildError::CycleDetected {
+                states: vec!["base".to_owned(), "other".to_owned()],
+            })
+        );
+    }
 }
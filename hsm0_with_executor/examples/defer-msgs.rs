@@ -5,7 +5,9 @@ use std::{
 
 use custom_logger::env_logger_init;
 
-use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{
+    handled, transition, DynError, Executor, ExecutorBuilder, StateInfo, StateResult,
+};
 
 #[derive(Debug, Clone)]
 enum Messages {
@@ -19,23 +21,22 @@ struct DeferMsgsSm {
     val: i32,
 }
 
-const MAX_STATES: usize = 2;
 const IDX_DEFERRING: usize = 0;
 const IDX_DO_DEFERRED_WORK: usize = 1;
 
 impl DeferMsgsSm {
     pub fn new() -> Result<Executor<Self, Messages>, DynError> {
         let sm = RefCell::new(DeferMsgsSm { val: 0 });
-        let sme = Executor::new(sm, MAX_STATES)
+        let sme = ExecutorBuilder::new(sm)
             .state(StateInfo::new("starting", Self::deferring))
             .state(StateInfo::new("deferring", Self::do_deferred_work))
-            .build(IDX_DEFERRING)
+            .build_idx(IDX_DEFERRING)
             .expect("Unexpected error initializing");
 
         log::info!(
-            "new: inital state={} idxs_enter_fns={:?}",
+            "new: inital state={} pending_enter_path={:?}",
             sme.get_current_state_name(),
-            sme.idxs_enter_fns
+            sme.pending_enter_path()
         );
 
         Ok(sme)
@@ -45,22 +46,22 @@ impl DeferMsgsSm {
         match msg {
             Messages::DeferredValue { val } => {
                 log::info!("deferring: Messages::DeferredValue:+ val={}", val);
-                e.defer_send(msg.clone()).unwrap();
-                (Handled::Yes, None)
+                e.defer_current().unwrap();
+                handled()
             }
             Messages::Complete { tx: _ } => {
                 log::info!("deferring: Messages::Complete, transition to do_deferred_work");
-                e.defer_send(msg.clone()).unwrap();
-                (Handled::Yes, Some(IDX_DO_DEFERRED_WORK))
+                e.defer_current().unwrap();
+                transition(e.state_id(IDX_DO_DEFERRED_WORK).unwrap())
             }
             Messages::Done { val: _ } => {
                 log::info!("deferring: Messages::Done, Unexpected Dropping");
-                (Handled::Yes, None)
+                handled()
             }
         }
     }
 
-    fn do_deferred_work(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn do_deferred_work(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
         match msg {
             Messages::DeferredValue { val } => {
                 self.val += val;
@@ -70,17 +71,17 @@ impl DeferMsgsSm {
                     self.val
                 );
 
-                (Handled::Yes, None)
+                handled()
             }
             Messages::Complete { tx } => {
                 let response = Messages::Done { val: self.val };
                 log::info!("do_deferred_work: Messages::Complete, sending {response:?}, transition to deferring");
                 tx.send(response).unwrap();
-                (Handled::Yes, Some(IDX_DEFERRING))
+                transition(e.state_id(IDX_DEFERRING).unwrap())
             }
             Messages::Done { val: _ } => {
                 log::info!("deferring: defer Messages::Done, Unexpected Dropping");
-                (Handled::Yes, None)
+                handled()
             }
         }
     }
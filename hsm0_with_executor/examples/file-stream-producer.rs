@@ -11,7 +11,10 @@ use std::{
 
 use custom_logger::env_logger_init;
 
-use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{
+    handled, not_handled, not_handled_transition, transition, DynError, Executor, ExecutorBuilder,
+    StateInfo, StateResult,
+};
 
 #[derive(Debug, Clone)]
 pub enum Messages {
@@ -63,7 +66,6 @@ pub struct FileStreamProducer {
     buffers: Vec<Box<Vec<u8>>>,
 }
 
-const MAX_STATES: usize = 5;
 const IDX_BASE: usize = 0;
 const IDX_OPEN: usize = 1;
 const IDX_WAIT_FOR_START: usize = 2;
@@ -82,7 +84,7 @@ impl FileStreamProducer {
             buffers: Vec::new(),
         });
 
-        let sme = Executor::new(fsp, MAX_STATES)
+        let sme = ExecutorBuilder::new(fsp)
             // IDX_BASE
             .state(StateInfo::new("base", Self::base))
             // IDX_OPEN
@@ -93,9 +95,9 @@ impl FileStreamProducer {
             .state(StateInfo::new("read", Self::read).parent_idx(IDX_BASE))
             // IDX_WAIT_FOR_EMPTY
             .state(StateInfo::new("wait_for_empty", Self::wait_for_empty).parent_idx(IDX_BASE))
-            .build(IDX_OPEN);
+            .build_idx(IDX_OPEN)?;
 
-        sme
+        Ok(sme)
     }
 
     // This is the parent of all states and handles all
@@ -137,7 +139,7 @@ impl FileStreamProducer {
             ),
         }
 
-        (Handled::Yes, None)
+        handled()
     }
 
     fn open(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
@@ -166,7 +168,7 @@ impl FileStreamProducer {
                         } else {
                             println!("open: couldn't send err: '{why}' because partner_tx is None");
                         }
-                        return (Handled::Yes, None);
+                        return handled();
                     }
                 };
 
@@ -190,9 +192,9 @@ impl FileStreamProducer {
                     "open: Handled Messages::Open transition to '{}'",
                     e.get_state_name(IDX_WAIT_FOR_START)
                 );
-                (Handled::Yes, Some(IDX_WAIT_FOR_START))
+                transition(e.state_id(IDX_WAIT_FOR_START).unwrap())
             }
-            _ => (Handled::No, None),
+            _ => not_handled(),
         }
     }
 
@@ -204,9 +206,9 @@ impl FileStreamProducer {
                     "wait_for_start: Got Start, tranistion to '{}'",
                     e.get_state_name(IDX_READ)
                 );
-                (Handled::Yes, Some(IDX_READ))
+                transition(e.state_id(IDX_READ).unwrap())
             }
-            _ => (Handled::No, None),
+            _ => not_handled(),
         }
     }
 
@@ -247,7 +249,7 @@ impl FileStreamProducer {
 
                             // Read all data back to open
                             println!("read: EOF transitition to '{}'", e.get_state_name(IDX_OPEN));
-                            (Handled::Yes, Some(IDX_OPEN))
+                            transition(e.state_id(IDX_OPEN).unwrap())
                         } else {
                             if let Some(partner_tx) = &self.partner_tx {
                                 println!("read: Send Data {} to partner", buf.len());
@@ -258,7 +260,7 @@ impl FileStreamProducer {
 
                             // Send message to ourselves so we continue processing
                             e.send(Messages::Read).expect("SNH");
-                            (Handled::Yes, None)
+                            handled()
                         }
                     } else {
                         // No file so we're done, back to IDX_OPEN
@@ -266,7 +268,7 @@ impl FileStreamProducer {
                             "read: SNH, self.file is NONE, transition to '{}'",
                             e.get_state_name(IDX_OPEN)
                         );
-                        (Handled::Yes, Some(IDX_OPEN))
+                        transition(e.state_id(IDX_OPEN).unwrap())
                     }
                 } else {
                     // There are no buffers, wait for an empty one
@@ -274,12 +276,12 @@ impl FileStreamProducer {
                         "read: no buffers, transition to '{}'",
                         e.get_state_name(IDX_WAIT_FOR_EMPTY)
                     );
-                    (Handled::Yes, Some(IDX_WAIT_FOR_EMPTY))
+                    transition(e.state_id(IDX_WAIT_FOR_EMPTY).unwrap())
                 }
             }
             _ => {
                 println!("read: unhandled {:0X?}", msg);
-                (Handled::No, None)
+                not_handled()
             }
         }
     }
@@ -289,15 +291,15 @@ impl FileStreamProducer {
             Messages::Empty { .. } => {
                 // Would be "faster" if we handled Empty here but DRY so let base do it.
                 e.send(Messages::Read).expect("SNH");
-                (Handled::No, Some(IDX_READ))
+                not_handled_transition(e.state_id(IDX_READ).unwrap())
             }
             //Messages::Read => {
             //    // SNH ???
             //    println!("wait_for_empty: Read received, defer");
             //    e.defer_send(msg.clone()).expect("SNH");
-            //    (Handled::Yes, None)
+            //    handled()
             //}
-            _ => (Handled::No, None),
+            _ => not_handled(),
         }
     }
 }
@@ -2,7 +2,9 @@ use std::cell::RefCell;
 
 use custom_logger::env_logger_init;
 
-use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{
+    handled, transition, DynError, Executor, ExecutorBuilder, StateInfo, StateResult,
+};
 
 // StateMachine simply transitions back and forth
 // between initial and other.
@@ -20,7 +22,6 @@ pub struct StateMachine;
 #[derive(Debug)]
 pub struct NoMessages;
 
-const MAX_STATES: usize = 3;
 const IDX_BASE: usize = 0;
 const IDX_INITIAL: usize = 1;
 const IDX_OTHER: usize = 2;
@@ -28,7 +29,7 @@ const IDX_OTHER: usize = 2;
 impl StateMachine {
     pub fn new() -> Result<Executor<Self, NoMessages>, DynError> {
         let sm = RefCell::new(StateMachine::default());
-        let sme = Executor::new(sm, MAX_STATES)
+        let sme = ExecutorBuilder::new(sm)
             .state(
                 StateInfo::new("base", Self::base)
                     .enter_fn(Self::base_enter)
@@ -46,13 +47,13 @@ impl StateMachine {
                     .exit_fn(Self::other_exit)
                     .parent_idx(IDX_BASE),
             )
-            .build(IDX_INITIAL)
+            .build_idx(IDX_INITIAL)
             .expect("Unexpected error initializing");
 
         log::trace!(
-            "new: inital state={} idxs_enter_fns={:?}",
+            "new: inital state={} pending_enter_path={:?}",
             sme.get_current_state_name(),
-            sme.idxs_enter_fns
+            sme.pending_enter_path()
         );
 
         Ok(sme)
@@ -62,7 +63,7 @@ impl StateMachine {
 
     // This state has hdl 0
     fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-        (Handled::Yes, None)
+        handled()
     }
 
     fn base_exit(&mut self, _msg: &NoMessages) {}
@@ -70,8 +71,8 @@ impl StateMachine {
     fn initial_enter(&mut self, _msg: &NoMessages) {}
 
     // This state has hdl 0
-    fn initial(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-        (Handled::Yes, Some(IDX_OTHER))
+    fn initial(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+        transition(e.state_id(IDX_OTHER).unwrap())
     }
 
     fn initial_exit(&mut self, _msg: &NoMessages) {}
@@ -79,8 +80,8 @@ impl StateMachine {
     fn other_enter(&mut self, _msg: &NoMessages) {}
 
     // This state has hdl 0
-    fn other(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-        (Handled::Yes, Some(IDX_INITIAL))
+    fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+        transition(e.state_id(IDX_INITIAL).unwrap())
     }
 
     fn other_exit(&mut self, _msg: &NoMessages) {}
@@ -2,7 +2,9 @@ use std::cell::RefCell;
 
 use custom_logger::env_logger_init;
 
-use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{
+    handled, transition, DynError, Executor, ExecutorBuilder, StateId, StateInfo, StateResult,
+};
 
 // StateMachine simply transitions back and forth
 // between initial and other.
@@ -13,50 +15,57 @@ use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
 //     other=3              initial=1
 
 #[derive(Default, Debug)]
-pub struct StateMachine;
+pub struct StateMachine {
+    initial_base: StateId,
+    initial: StateId,
+    other_base: StateId,
+    other: StateId,
+}
 
 // Create a Protocol with no messages
 #[derive(Debug)]
 pub struct NoMessages;
 
-const MAX_STATES: usize = 4;
-const IDX_INITIAL_BASE: usize = 0;
-const IDX_INITIAL: usize = 1;
-const IDX_OTHER_BASE: usize = 2;
-const IDX_OTHER: usize = 3;
-
 impl StateMachine {
     pub fn new() -> Result<Executor<Self, NoMessages>, DynError> {
         let sm = RefCell::new(StateMachine::default());
-        let sme = Executor::new(sm, MAX_STATES)
-            .state(
-                StateInfo::new("initial_base", Self::initial_base)
-                    .enter_fn(Self::initial_base_enter)
-                    .exit_fn(Self::initial_base_exit),
-            )
-            .state(
-                StateInfo::new("initial", Self::initial)
-                    .enter_fn(Self::initial_enter)
-                    .exit_fn(Self::initial_exit)
-                    .parent_idx(IDX_INITIAL_BASE),
-            )
-            .state(
-                StateInfo::new("other_base", Self::other_base)
-                    .enter_fn(Self::other_base_enter)
-                    .exit_fn(Self::other_base_exit),
-            )
-            .state(
-                StateInfo::new("other", Self::other)
-                    .enter_fn(Self::other_enter)
-                    .exit_fn(Self::other_exit)
-                    .parent_idx(IDX_OTHER_BASE),
-            )
-            .build(IDX_INITIAL)?;
+        let mut builder = ExecutorBuilder::new(sm);
+        let initial_base = builder.add_state(
+            StateInfo::new("initial_base", Self::initial_base)
+                .enter_fn(Self::initial_base_enter)
+                .exit_fn(Self::initial_base_exit),
+        );
+        let initial = builder.add_state(
+            StateInfo::new("initial", Self::initial)
+                .enter_fn(Self::initial_enter)
+                .exit_fn(Self::initial_exit)
+                .parent(initial_base),
+        );
+        let other_base = builder.add_state(
+            StateInfo::new("other_base", Self::other_base)
+                .enter_fn(Self::other_base_enter)
+                .exit_fn(Self::other_base_exit),
+        );
+        let other = builder.add_state(
+            StateInfo::new("other", Self::other)
+                .enter_fn(Self::other_enter)
+                .exit_fn(Self::other_exit)
+                .parent(other_base),
+        );
+        let sme = builder.build(initial)?;
+
+        // The process fns transition using the handles saved in the sm
+        *sme.get_sm().borrow_mut() = StateMachine {
+            initial_base,
+            initial,
+            other_base,
+            other,
+        };
 
         log::trace!(
-            "new: inital state={} idxs_enter_fns={:?}",
+            "new: inital state={} pending_enter_path={:?}",
             sme.get_current_state_name(),
-            sme.idxs_enter_fns
+            sme.pending_enter_path()
         );
 
         Ok(sme)
@@ -66,7 +75,7 @@ impl StateMachine {
 
     // This state has hdl 0
     fn initial_base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-        (Handled::Yes, None)
+        handled()
     }
 
     fn initial_base_exit(&mut self, _msg: &NoMessages) {}
@@ -75,7 +84,7 @@ impl StateMachine {
 
     // This state has hdl 0
     fn initial(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-        (Handled::Yes, Some(IDX_OTHER))
+        transition(self.other)
     }
 
     fn initial_exit(&mut self, _msg: &NoMessages) {}
@@ -84,7 +93,7 @@ impl StateMachine {
 
     // This state has hdl 0
     fn other_base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-        (Handled::Yes, None)
+        handled()
     }
 
     fn other_base_exit(&mut self, _msg: &NoMessages) {}
@@ -93,7 +102,7 @@ impl StateMachine {
 
     // This state has hdl 0
     fn other(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-        (Handled::Yes, Some(IDX_INITIAL))
+        transition(self.initial)
     }
 
     fn other_exit(&mut self, _msg: &NoMessages) {}
@@ -102,88 +111,97 @@ impl StateMachine {
 fn test_transition_between_leafs_across_trees() {
     // Create a sme and validate it's in the expected state
     let mut sme = StateMachine::new().unwrap();
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 0);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 0);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 0);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
+    let (idx_initial_base, idx_initial, idx_other_base, idx_other) = {
+        let sm = sme.get_sm().borrow();
+        (
+            sm.initial_base.raw(),
+            sm.initial.raw(),
+            sm.other_base.raw(),
+            sm.other.raw(),
+        )
+    };
+    assert_eq!(sme.get_state_enter_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_process_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial), 0);
+    assert_eq!(sme.get_state_process_cnt(idx_initial), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial), 0);
+    assert_eq!(sme.get_state_enter_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_process_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_enter_cnt(idx_other), 0);
+    assert_eq!(sme.get_state_process_cnt(idx_other), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other), 0);
 
     sme.dispatch(&NoMessages);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 1);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 1);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 1);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 0);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial_base), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial_base), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_initial), 1);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_process_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_enter_cnt(idx_other), 0);
+    assert_eq!(sme.get_state_process_cnt(idx_other), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other), 0);
 
     sme.dispatch(&NoMessages);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 1);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 1);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 1);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 1);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial_base), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial_base), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_initial), 1);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_other_base), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other_base), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_other), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_other), 1);
+    assert_eq!(sme.get_state_exit_cnt(idx_other), 1);
 
     sme.dispatch(&NoMessages);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 2);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 2);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 1);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 1);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial_base), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial_base), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_initial), 2);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_other_base), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other_base), 1);
+    assert_eq!(sme.get_state_enter_cnt(idx_other), 1);
+    assert_eq!(sme.get_state_process_cnt(idx_other), 1);
+    assert_eq!(sme.get_state_exit_cnt(idx_other), 1);
 
     sme.dispatch(&NoMessages);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 2);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 2);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 2);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial_base), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial_base), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_initial), 2);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_other_base), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other_base), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_other), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_other), 2);
+    assert_eq!(sme.get_state_exit_cnt(idx_other), 2);
 
     sme.dispatch(&NoMessages);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 3);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 3);
-    assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 3);
-    assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 3);
-    assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 3);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 2);
-    assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 2);
-    assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
-    assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial_base), 3);
+    assert_eq!(sme.get_state_process_cnt(idx_initial_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial_base), 3);
+    assert_eq!(sme.get_state_enter_cnt(idx_initial), 3);
+    assert_eq!(sme.get_state_process_cnt(idx_initial), 3);
+    assert_eq!(sme.get_state_exit_cnt(idx_initial), 3);
+    assert_eq!(sme.get_state_enter_cnt(idx_other_base), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_other_base), 0);
+    assert_eq!(sme.get_state_exit_cnt(idx_other_base), 2);
+    assert_eq!(sme.get_state_enter_cnt(idx_other), 2);
+    assert_eq!(sme.get_state_process_cnt(idx_other), 2);
+    assert_eq!(sme.get_state_exit_cnt(idx_other), 2);
 }
 
 fn main() {
@@ -1,84 +1,69 @@
-use std::{cell::RefCell, sync::mpsc::Sender};
+use std::cell::RefCell;
 
 use custom_logger::env_logger_init;
 
-use hsm0_with_executor::{DynError, Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{
+    handled, transition, DynError, Executor, ExecutorBuilder, RunOutcome, StateInfo, StateResult,
+};
 
 #[derive(Debug, Clone)]
 enum Messages {
     Value { val: i32 },
-    Done { val: i32 },
 }
 
 #[derive(Debug)]
 struct SendMsgToSelfSm {
-    self_tx: Sender<Messages>,
     val: i32,
 }
 
-const MAX_STATES: usize = 2;
 const IDX_BASE: usize = 0;
-const IDX_DONE: usize = 0;
+const IDX_DONE: usize = 1;
 
 impl SendMsgToSelfSm {
-    pub fn new(sender: Sender<Messages>) -> Result<Executor<Self, Messages>, DynError> {
-        let sm = RefCell::new(SendMsgToSelfSm {
-            self_tx: sender,
-            val: 0,
-        });
-        let sme = Executor::new(sm, MAX_STATES)
+    pub fn new() -> Result<Executor<Self, Messages>, DynError> {
+        let sm = RefCell::new(SendMsgToSelfSm { val: 0 });
+        let sme = ExecutorBuilder::new(sm)
             .state(StateInfo::new("base", Self::base))
             .state(StateInfo::new("done", Self::done))
-            .build(IDX_BASE)
+            .build_idx(IDX_BASE)
             .expect("Unexpected error initializing");
 
         log::info!(
-            "new: inital state={} idxs_enter_fns={:?}",
+            "new: inital state={} pending_enter_path={:?}",
             sme.get_current_state_name(),
-            sme.idxs_enter_fns
+            sme.pending_enter_path()
         );
 
         Ok(sme)
     }
 
-    fn base(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn base(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
         match msg {
             Messages::Value { val } => {
                 log::info!("base Messages::Value:+ val={}", val);
                 if self.val < 10 {
                     // Doing work
                     self.val += val;
-                    if self.self_tx.send(msg.clone()).is_ok() {
+                    if e.send(msg.clone()).is_ok() {
                         log::info!("base Messages::Value:- self.val={}", self.val);
-                        (Handled::Yes, None)
+                        handled()
                     } else {
                         log::info!("base Messages::Value:- ERR so DONE self.val={}", self.val);
-                        (Handled::Yes, Some(IDX_DONE))
+                        transition(e.state_id(IDX_DONE).unwrap())
                     }
                 } else {
                     // We're done
-                    self.send_done();
-
                     log::info!("base Messages::Value:- Done self.val={}", self.val);
-                    (Handled::Yes, Some(IDX_DONE))
+                    transition(e.state_id(IDX_DONE).unwrap())
                 }
             }
-            Messages::Done { val: _ } => {
-                self.send_done();
-                (Handled::Yes, Some(IDX_DONE))
-            }
         }
     }
 
     fn done(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
-        // Responsed with Done for any messages
-        self.send_done();
-        log::info!("base:+- self.val={}", self.val);
-        (Handled::Yes, None)
-    }
-
-    fn send_done(&mut self) {
-        self.self_tx.send(Messages::Done { val: self.val }).ok();
+        // Ignore any messages
+        log::info!("done:+- self.val={}", self.val);
+        handled()
     }
 }
 
@@ -86,25 +71,15 @@ fn main() {
     env_logger_init("info");
     log::info!("main:+");
 
-    let (tx, rx) = std::sync::mpsc::channel::<Messages>();
-    let mut sme = SendMsgToSelfSm::new(tx).unwrap();
+    let mut sme = SendMsgToSelfSm::new().unwrap();
 
-    // Dispatch the first message
-    let msg = Messages::Value { val: 1 };
-    sme.dispatch(&msg);
+    // Send the first message
+    sme.send(Messages::Value { val: 1 }).unwrap();
 
-    // Receive messages until SendMsgToSelfSm reports Done or rx is closed
-    while let Ok(m) = rx.recv() {
-        match m {
-            Messages::Value { val: _ } => {
-                // Dispatch the message received
-                sme.dispatch(&m);
-            }
-            Messages::Done { val } => {
-                println!("main: Done val={val}");
-                break;
-            }
-        }
+    // Receive messages until SendMsgToSelfSm transitions to done
+    match sme.run_until(|e| e.get_current_state_name() == "done") {
+        RunOutcome::Done => println!("main: Done val={}", sme.get_sm().borrow().val),
+        outcome => println!("main: Unexpected {outcome:?}"),
     }
 
     log::info!("main:-");
@@ -0,0 +1,224 @@
+// The primary and priority queues of an Executor. They're behind one
+// Mutex so recv blocks on a Condvar until either has a message, rather
+// than polling two channels.
+use super::*;
+
+use std::sync::Condvar;
+
+struct Queues<P> {
+    primary: VecDeque<P>,
+    priority: VecDeque<P>,
+
+    // The executor was dropped, nothing will receive
+    closed: bool,
+
+    // The InboxSenders, once they're all dropped an empty inbox is
+    // disconnected. The executor's own send isn't counted.
+    senders: usize,
+}
+
+struct Inbox<P> {
+    queues: Mutex<Queues<P>>,
+
+    // Signalled when a message is queued or the last sender is dropped
+    not_empty: Condvar,
+
+    // Signalled when a message is taken from the primary queue or the
+    // executor is dropped, for senders blocked on a full primary queue
+    not_full: Condvar,
+
+    // The capacity of the primary queue, the priority queue is unbounded
+    capacity: Option<usize>,
+}
+
+impl<P> Inbox<P> {
+    fn lock(&self) -> MutexGuard<'_, Queues<P>> {
+        // Nothing panics while it's locked
+        self.queues.lock().expect("SNH")
+    }
+
+    fn send(&self, m: P, priority: bool) -> Result<(), SendError<P>> {
+        let mut queues = self.lock();
+        if !priority {
+            while matches!(self.capacity, Some(capacity) if queues.primary.len() >= capacity)
+                && !queues.closed
+            {
+                queues = self.not_full.wait(queues).expect("SNH");
+            }
+        }
+        if queues.closed {
+            return Err(SendError(m));
+        }
+        if priority {
+            queues.priority.push_back(m);
+        } else {
+            queues.primary.push_back(m);
+        }
+        self.not_empty.notify_one();
+
+        Ok(())
+    }
+
+    fn try_send(&self, m: P, priority: bool) -> Result<(), TrySendError<P>> {
+        let mut queues = self.lock();
+        if queues.closed {
+            return Err(TrySendError::Disconnected(m));
+        }
+        if priority {
+            queues.priority.push_back(m);
+        } else if matches!(self.capacity, Some(capacity) if queues.primary.len() >= capacity) {
+            return Err(TrySendError::Full(m));
+        } else {
+            queues.primary.push_back(m);
+        }
+        self.not_empty.notify_one();
+
+        Ok(())
+    }
+
+    // Priority messages first, each queue in the order they were sent
+    fn pop(&self, queues: &mut Queues<P>) -> Option<P> {
+        if let Some(m) = queues.priority.pop_front() {
+            return Some(m);
+        }
+        let m = queues.primary.pop_front()?;
+        self.not_full.notify_one();
+
+        Some(m)
+    }
+}
+
+// The executor's end of its Inbox, dropping it disconnects the senders
+pub(crate) struct InboxReceiver<P> {
+    inbox: Arc<Inbox<P>>,
+}
+
+impl<P> InboxReceiver<P> {
+    pub(crate) fn new(capacity: Option<usize>) -> Self {
+        InboxReceiver {
+            inbox: Arc::new(Inbox {
+                queues: Mutex::new(Queues {
+                    primary: VecDeque::new(),
+                    priority: VecDeque::new(),
+                    closed: false,
+                    senders: 0,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+                capacity,
+            }),
+        }
+    }
+
+    pub(crate) fn sender(&self, priority: bool) -> InboxSender<P> {
+        InboxSender::new(self.inbox.clone(), priority)
+    }
+
+    pub(crate) fn send(&self, m: P, priority: bool) -> Result<(), SendError<P>> {
+        self.inbox.send(m, priority)
+    }
+
+    pub(crate) fn try_send(&self, m: P, priority: bool) -> Result<(), TrySendError<P>> {
+        self.inbox.try_send(m, priority)
+    }
+
+    pub(crate) fn try_recv(&self) -> Result<P, TryRecvError> {
+        let mut queues = self.inbox.lock();
+        match self.inbox.pop(&mut queues) {
+            Some(m) => Ok(m),
+            None if queues.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub(crate) fn recv(&self) -> Result<P, RecvError> {
+        let mut queues = self.inbox.lock();
+        loop {
+            if let Some(m) = self.inbox.pop(&mut queues) {
+                return Ok(m);
+            }
+            if queues.senders == 0 {
+                return Err(RecvError);
+            }
+            queues = self.inbox.not_empty.wait(queues).expect("SNH");
+        }
+    }
+
+    pub(crate) fn recv_timeout(&self, timeout: Duration) -> Result<P, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut queues = self.inbox.lock();
+        loop {
+            if let Some(m) = self.inbox.pop(&mut queues) {
+                return Ok(m);
+            }
+            if queues.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queues = self
+                .inbox
+                .not_empty
+                .wait_timeout(queues, remaining)
+                .expect("SNH")
+                .0;
+        }
+    }
+}
+
+impl<P> Drop for InboxReceiver<P> {
+    fn drop(&mut self) {
+        self.inbox.lock().closed = true;
+        self.inbox.not_full.notify_all();
+    }
+}
+
+// Sends to the primary or priority queue of an executor, see
+// Executor::clone_sender and Executor::clone_priority_sender
+pub struct InboxSender<P> {
+    inbox: Arc<Inbox<P>>,
+    priority: bool,
+}
+
+impl<P> InboxSender<P> {
+    fn new(inbox: Arc<Inbox<P>>, priority: bool) -> Self {
+        inbox.lock().senders += 1;
+        InboxSender { inbox, priority }
+    }
+
+    // Blocks while the primary queue is full
+    pub fn send(&self, m: P) -> Result<(), SendError<P>> {
+        self.inbox.send(m, self.priority)
+    }
+
+    pub fn try_send(&self, m: P) -> Result<(), TrySendError<P>> {
+        self.inbox.try_send(m, self.priority)
+    }
+
+    // The capacity of the queue it sends to, None if it's unbounded
+    pub fn capacity(&self) -> Option<usize> {
+        if self.priority {
+            None
+        } else {
+            self.inbox.capacity
+        }
+    }
+}
+
+impl<P> Clone for InboxSender<P> {
+    fn clone(&self) -> Self {
+        InboxSender::new(self.inbox.clone(), self.priority)
+    }
+}
+
+impl<P> Drop for InboxSender<P> {
+    fn drop(&mut self) {
+        let mut queues = self.inbox.lock();
+        queues.senders -= 1;
+        if queues.senders == 0 {
+            self.inbox.not_empty.notify_all();
+        }
+    }
+}
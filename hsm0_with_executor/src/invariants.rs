@@ -0,0 +1,243 @@
+// Structural invariants of an Executor that must hold between
+// dispatches, see Executor::check_invariants and drive.
+use super::*;
+
+// The first invariant check_invariants found broken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantViolation {
+    // The current state of a region isn't one of its leaf states
+    NotALeaf {
+        region: usize,
+        state: String,
+    },
+
+    // A state's active flag doesn't match it being on the path from the
+    // current state of its region to the root, minus the states whose
+    // enter fns are still pending
+    ActiveMismatch {
+        state: String,
+        active: bool,
+    },
+
+    // Exit fns are pending between dispatches
+    PendingExits {
+        region: usize,
+        states: Vec<String>,
+    },
+
+    // Enter fns are pending without a transition to enter them for
+    PendingEnters {
+        region: usize,
+        states: Vec<String>,
+    },
+
+    // A state with an enter and exit fn was exited more often than entered
+    #[cfg(feature = "counters")]
+    ExitsExceedEnters {
+        state: String,
+        enter_cnt: usize,
+        exit_cnt: usize,
+    },
+
+    // A counter of state went down between two dispatches, see drive
+    #[cfg(feature = "counters")]
+    CountDecreased {
+        state: String,
+        earlier: StateCounts,
+        now: StateCounts,
+    },
+}
+
+impl Display for InvariantViolation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InvariantViolation::NotALeaf { region, state } => {
+                write!(
+                    f,
+                    "The current state {state} of region {region} is not a leaf"
+                )
+            }
+            InvariantViolation::ActiveMismatch { state, active } => {
+                write!(
+                    f,
+                    "State {state} is active={active} but should be active={}",
+                    !active
+                )
+            }
+            InvariantViolation::PendingExits { region, states } => {
+                write!(f, "Region {region} has pending exits {states:?}")
+            }
+            InvariantViolation::PendingEnters { region, states } => {
+                write!(
+                    f,
+                    "Region {region} has pending enters {states:?} but didn't transition"
+                )
+            }
+            #[cfg(feature = "counters")]
+            InvariantViolation::ExitsExceedEnters {
+                state,
+                enter_cnt,
+                exit_cnt,
+            } => {
+                write!(
+                    f,
+                    "State {state} was exited {exit_cnt} times but only entered {enter_cnt} times"
+                )
+            }
+            #[cfg(feature = "counters")]
+            InvariantViolation::CountDecreased {
+                state,
+                earlier,
+                now,
+            } => {
+                write!(
+                    f,
+                    "The (enter, process, exit) counts of state {state} went from {earlier:?} to {now:?}"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvariantViolation {}
+
+impl<SM, P, I> Executor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    // Check the invariants that hold between dispatches:
+    //   - the current state of each region is one of its leaves
+    //   - the active states are the path from the current state to the
+    //     root, minus the states whose enter fns are still pending
+    //   - no exit fns are pending and enter fns are only pending after a
+    //     transition
+    //   - states with an enter and exit fn weren't exited more often
+    //     than entered
+    // Once stopped no state may be active.
+    pub fn check_invariants(&self) -> Result<(), InvariantViolation> {
+        for region in 0..self.regions.len() {
+            // Region 0 is selected between dispatches, the others are parked
+            let (idx_leaf, changed, idxs_enter, idxs_exit) = if region == self.idx_region {
+                (
+                    self.idx_current_state,
+                    self.current_state_changed,
+                    &self.idxs_enter_fns,
+                    &self.idxs_exit_fns,
+                )
+            } else {
+                let parked = &self.regions[region];
+                (
+                    parked.idx_current_state,
+                    parked.current_state_changed,
+                    &parked.idxs_enter_fns,
+                    &parked.idxs_exit_fns,
+                )
+            };
+            let names = |idxs: &mut dyn Iterator<Item = &I>| -> Vec<String> {
+                idxs.map(|&idx| self.states[idx.into()].name.to_string())
+                    .collect()
+            };
+
+            if !idxs_exit.is_empty() {
+                return Err(InvariantViolation::PendingExits {
+                    region,
+                    states: names(&mut idxs_exit.iter()),
+                });
+            }
+            if !changed && !idxs_enter.is_empty() {
+                return Err(InvariantViolation::PendingEnters {
+                    region,
+                    states: names(&mut idxs_enter.iter()),
+                });
+            }
+            if !self.transition_targets_set[idx_leaf] || self.states[idx_leaf].region != region {
+                return Err(InvariantViolation::NotALeaf {
+                    region,
+                    state: self.states[idx_leaf].name.to_string(),
+                });
+            }
+
+            let path: Vec<usize> = core::iter::once(idx_leaf)
+                .chain(Ancestors::new(&self.states, idx_leaf))
+                .collect();
+            for (idx, state) in self.states.iter().enumerate() {
+                if state.region != region {
+                    continue;
+                }
+                let should_be_active = !self.stopped
+                    && path.contains(&idx)
+                    && !idxs_enter.iter().any(|&idx_enter| idx_enter.into() == idx);
+                if state.active != should_be_active {
+                    return Err(InvariantViolation::ActiveMismatch {
+                        state: state.name.to_string(),
+                        active: state.active,
+                    });
+                }
+            }
+        }
+
+        #[cfg(feature = "counters")]
+        for state in self.states.iter() {
+            if state.enter.is_some() && state.exit.is_some() && state.exit_cnt > state.enter_cnt {
+                return Err(InvariantViolation::ExitsExceedEnters {
+                    state: state.name.to_string(),
+                    enter_cnt: state.enter_cnt,
+                    exit_cnt: state.exit_cnt,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Dispatch each of msgs, with dispatcher if there's messaging, and check
+// the invariants after each one as well as that no counter went down.
+// Panics with the message that broke an invariant, e.g. to fuzz a
+// machine with random message sequences.
+#[cfg(any(test, feature = "test-support"))]
+pub fn drive<SM, P, I>(sme: &mut Executor<SM, P, I>, msgs: impl Iterator<Item = P>)
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    if let Err(violation) = sme.check_invariants() {
+        panic!("drive: before the first message: {violation}");
+    }
+    for (n, msg) in msgs.enumerate() {
+        #[cfg(feature = "counters")]
+        let earlier = sme.counts();
+
+        #[cfg(feature = "messaging")]
+        sme.dispatcher(&msg);
+        #[cfg(not(feature = "messaging"))]
+        sme.dispatch(&msg);
+
+        let result = sme.check_invariants();
+        #[cfg(feature = "counters")]
+        let result = result.and_then(|()| check_counts(&earlier, &sme.counts()));
+        if let Err(violation) = result {
+            panic!("drive: after message {n} {msg:?}: {violation}");
+        }
+    }
+}
+
+// Counters only go up
+#[cfg(all(any(test, feature = "test-support"), feature = "counters"))]
+fn check_counts(earlier: &Counts, now: &Counts) -> Result<(), InvariantViolation> {
+    for ((name, e, p, x), (_, enter, process, exit)) in earlier.0.iter().zip(now.0.iter()) {
+        if enter < e || process < p || exit < x {
+            return Err(InvariantViolation::CountDecreased {
+                state: name.clone(),
+                earlier: (*e, *p, *x),
+                now: (*enter, *process, *exit),
+            });
+        }
+    }
+
+    Ok(())
+}
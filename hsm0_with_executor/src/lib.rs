@@ -1,712 +1,8945 @@
 #![feature(no_coverage)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
-use std::{
-    cell::RefCell,
+extern crate alloc;
+
+use alloc::{
+    borrow::{Cow, ToOwned},
+    boxed::Box,
     collections::VecDeque,
-    fmt::Debug,
-    sync::mpsc::{Receiver, RecvError, SendError, Sender, TryRecvError},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    cell::{Cell, RefCell},
+    fmt::{Debug, Display},
+};
+
+#[cfg(feature = "counters")]
+use alloc::collections::BTreeMap;
+
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[cfg(feature = "messaging")]
+use std::sync::mpsc::{
+    Receiver, RecvError, RecvTimeoutError, SendError, Sender, SyncSender, TryRecvError,
+    TrySendError,
 };
 
+#[cfg(feature = "messaging")]
+mod inbox;
+#[cfg(feature = "messaging")]
+use inbox::InboxReceiver;
+#[cfg(feature = "messaging")]
+pub use inbox::InboxSender;
+mod invariants;
+#[cfg(any(test, feature = "test-support"))]
+pub use invariants::drive;
+pub use invariants::InvariantViolation;
+
+// For user code, the executor itself returns BuildError
+#[cfg(feature = "std")]
 pub type DynError = Box<dyn std::error::Error>;
-type ProcessFn<SM, P> = fn(&mut SM, &Executor<SM, P>, &P) -> StateResult;
-type EnterFn<SM, P> = fn(&mut SM, &P);
-type ExitFn<SM, P> = fn(&mut SM, &P);
 
-pub enum Handled {
-    Yes,
-    No,
+// The reasons Executor::build can fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    CycleDetected {
+        states: Vec<String>,
+    },
+    InvalidInitialState {
+        idx: usize,
+        valid: Vec<usize>,
+    },
+    InvalidParent {
+        state: String,
+        parent_idx: usize,
+    },
+    UnknownParent {
+        state: String,
+        parent_name: String,
+    },
+    DuplicateName(String),
+    InvalidRecoveryState {
+        idx: usize,
+        valid: Vec<usize>,
+    },
+    InvalidRegion {
+        state: String,
+        region: usize,
+        regions: usize,
+    },
+    ConflictingRegion {
+        state: String,
+        region: usize,
+        root: String,
+        root_region: usize,
+    },
+    NoRegionInitialState {
+        region: usize,
+    },
+    IdxOverflow {
+        declared: usize,
+    },
+    ParentIsCurrentState {
+        state: String,
+        parent: String,
+    },
+    InvalidTimeoutTarget {
+        state: String,
+        idx: usize,
+        valid: Vec<usize>,
+    },
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BuildError::CycleDetected { states } => {
+                write!(f, "Cycle detected between states {states:?}")
+            }
+            BuildError::InvalidInitialState { idx, valid } => {
+                write!(
+                    f,
+                    "{idx} is not a valid initial state, only {valid:?} are allowed"
+                )
+            }
+            BuildError::InvalidParent { state, parent_idx } => {
+                write!(
+                    f,
+                    "State {state} has parent {parent_idx} which is not a state"
+                )
+            }
+            BuildError::UnknownParent { state, parent_name } => {
+                write!(
+                    f,
+                    "State {state} has parent {parent_name} which is not a state"
+                )
+            }
+            BuildError::DuplicateName(name) => {
+                write!(f, "State name {name} is used more than once")
+            }
+            BuildError::InvalidRecoveryState { idx, valid } => {
+                write!(
+                    f,
+                    "{idx} is not a valid recovery state, only {valid:?} are allowed"
+                )
+            }
+            BuildError::InvalidRegion {
+                state,
+                region,
+                regions,
+            } => write!(
+                f,
+                "State {state} is in region {region} but there are only {regions} regions"
+            ),
+            BuildError::ConflictingRegion {
+                state,
+                region,
+                root,
+                root_region,
+            } => write!(
+                f,
+                "State {state} is in region {region} but its root {root} is in region {root_region}"
+            ),
+            BuildError::NoRegionInitialState { region } => {
+                write!(f, "Region {region} has no initial state")
+            }
+            BuildError::IdxOverflow { declared } => {
+                write!(f, "{declared} states don't fit in the state index type")
+            }
+            BuildError::ParentIsCurrentState { state, parent } => write!(
+                f,
+                "State {state} can't be added as a child of {parent}, it is a current state"
+            ),
+            BuildError::InvalidTimeoutTarget { state, idx, valid } => write!(
+                f,
+                "State {state} times out to {idx} which is not a valid target, only {valid:?} are allowed"
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BuildError {}
+
+// The type of the state indices held by the executor, usize by default. A
+// smaller type such as u8 shrinks StateInfo and the transition bookkeeping.
+pub trait StateIdx: Copy + PartialEq + Debug + Into<usize> + TryFrom<usize> {}
+
+impl<T> StateIdx for T where T: Copy + PartialEq + Debug + Into<usize> + TryFrom<usize> {}
+
+// Convert an index to I, build has checked every state index fits.
+fn to_idx<I: StateIdx>(idx: usize) -> I {
+    match I::try_from(idx) {
+        Ok(idx) => idx,
+        Err(_) => panic!("{idx} doesn't fit in the state index type"),
+    }
+}
+
+pub type ProcessFn<SM, P, I = usize> = fn(&mut SM, &Executor<SM, P, I>, &P) -> StateResult<I>;
+pub type EnterFn<SM, P> = fn(&mut SM, &P);
+pub type ExitFn<SM, P> = fn(&mut SM, &P);
+pub type ActionFn<SM, P> = fn(&mut SM, &P);
+pub type EnterRedirectFn<SM, P, I = usize> =
+    fn(&mut SM, &Executor<SM, P, I>, &P) -> Option<Transition<I>>;
+pub type DefersFn<P> = fn(&P) -> bool;
+
+// Closures are Send + Sync so an Executor using them can still be
+// moved to another thread.
+pub type ProcessClosure<SM, P, I = usize> =
+    Arc<dyn Fn(&mut SM, &Executor<SM, P, I>, &P) -> StateResult<I> + Send + Sync>;
+pub type EnterExitClosure<SM, P> = Arc<dyn Fn(&mut SM, &P) + Send + Sync>;
+
+// A state's process function, a plain fn pointer or a closure which
+// can capture configuration that doesn't belong in SM.
+pub enum StateProcess<SM, P, I = usize> {
+    Fn(ProcessFn<SM, P, I>),
+    Closure(ProcessClosure<SM, P, I>),
+}
+
+impl<SM, P, I> StateProcess<SM, P, I> {
+    fn call(&self, sm: &mut SM, executor: &Executor<SM, P, I>, msg: &P) -> StateResult<I> {
+        match self {
+            StateProcess::Fn(f) => f(sm, executor, msg),
+            StateProcess::Closure(f) => f(sm, executor, msg),
+        }
+    }
+}
+
+impl<SM, P, I> Clone for StateProcess<SM, P, I> {
+    fn clone(&self) -> Self {
+        match self {
+            StateProcess::Fn(f) => StateProcess::Fn(*f),
+            StateProcess::Closure(f) => StateProcess::Closure(f.clone()),
+        }
+    }
+}
+
+// A state's enter or exit function, a plain fn pointer or a closure.
+pub enum StateEnterExit<SM, P> {
+    Fn(EnterFn<SM, P>),
+    Closure(EnterExitClosure<SM, P>),
+}
+
+impl<SM, P> StateEnterExit<SM, P> {
+    fn call(&self, sm: &mut SM, msg: &P) {
+        match self {
+            StateEnterExit::Fn(f) => f(sm, msg),
+            StateEnterExit::Closure(f) => f(sm, msg),
+        }
+    }
+}
+
+impl<SM, P> Clone for StateEnterExit<SM, P> {
+    fn clone(&self) -> Self {
+        match self {
+            StateEnterExit::Fn(f) => StateEnterExit::Fn(*f),
+            StateEnterExit::Closure(f) => StateEnterExit::Closure(f.clone()),
+        }
+    }
+}
+
+// Handled and StateResult are shared with the hsm1 proc-macro
+pub use state_result::Handled;
+
+pub type Transition<I = usize> = StateId<I>;
+
+pub type StateResult<I = usize> = state_result::StateResult<Transition<I>>;
+
+// Constructors for the StateResult a process fn returns, prefer these to
+// building the tuple. A message that isn't handled is passed to the
+// parent state. The transition target id, from ExecutorBuilder::add_state
+// or Executor::state_id, must be a leaf in the region of the state,
+// dispatch panics if it isn't.
+pub fn handled<I>() -> StateResult<I> {
+    (Handled::Yes, None)
+}
+
+pub fn not_handled<I>() -> StateResult<I> {
+    (Handled::No, None)
+}
+
+pub fn transition<I>(id: StateId<I>) -> StateResult<I> {
+    (Handled::Yes, Some(id))
+}
+
+pub fn not_handled_transition<I>(id: StateId<I>) -> StateResult<I> {
+    (Handled::No, Some(id))
+}
+
+// The index of a state, it's only created by ExecutorBuilder::add_state,
+// Executor::state_id and Executor::get_state_idx, which check it's in
+// range.
+// The default is the first state which every executor has.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StateId<I = usize>(I);
+
+impl<I: StateIdx> StateId<I> {
+    pub fn raw(self) -> usize {
+        self.0.into()
+    }
 }
 
-pub type Transition = usize;
+impl<I: StateIdx> From<StateId<I>> for usize {
+    fn from(id: StateId<I>) -> Self {
+        id.raw()
+    }
+}
 
-pub type StateResult = (Handled, Option<Transition>);
+// How a state refers to its parent, names are resolved by build so a
+// child may be declared before its parent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParentRef {
+    Idx(usize),
+    Name(String),
+}
 
-//#[derive(Clone)]
-pub struct StateInfo<SM, P> {
-    pub name: String,
-    pub parent: Option<usize>,
-    pub enter: Option<EnterFn<SM, P>>,
-    pub process: ProcessFn<SM, P>,
-    pub exit: Option<ExitFn<SM, P>>,
+pub struct StateInfo<SM, P, I = usize> {
+    pub name: Cow<'static, str>,
+    pub parent_ref: Option<ParentRef>,
+    pub parent: Option<I>,
+    pub enter: Option<StateEnterExit<SM, P>>,
+    pub enter_redirect: Option<EnterRedirectFn<SM, P, I>>,
+    pub process: StateProcess<SM, P, I>,
+    pub exit: Option<StateEnterExit<SM, P>>,
+    // Whether the state defers a message and how it's cloned
+    #[cfg(feature = "messaging")]
+    pub defers: Option<(DefersFn<P>, CloneFn<P>)>,
+    // Entering the state drops the deferred messages
+    #[cfg(feature = "messaging")]
+    pub clears_deferred: bool,
+    #[cfg(feature = "std")]
+    pub timeout: Option<(Duration, usize)>,
     pub active: bool,
-    pub children_for_cycle_detector: Vec<usize>,
+    pub region: usize,
+    pub children: Vec<I>,
+    #[cfg(feature = "counters")]
     pub enter_cnt: usize,
+    #[cfg(feature = "counters")]
     pub process_cnt: usize,
+    #[cfg(feature = "counters")]
+    pub handled_cnt: usize,
+    #[cfg(feature = "counters")]
     pub exit_cnt: usize,
+    #[cfg(feature = "counters")]
+    pub defer_cnt: usize,
+    #[cfg(feature = "timing")]
+    pub enter_time: Duration,
+    #[cfg(feature = "timing")]
+    pub process_time: Duration,
+    #[cfg(feature = "timing")]
+    pub exit_time: Duration,
 }
 
-impl<SM, P> StateInfo<SM, P> {
-    pub fn new(name: &str, process_fn: ProcessFn<SM, P>) -> Self {
+// Implemented by hand as derive would require SM and P to be Clone
+impl<SM, P, I: Clone> Clone for StateInfo<SM, P, I> {
+    fn clone(&self) -> Self {
         StateInfo {
-            name: name.to_owned(),
+            name: self.name.clone(),
+            parent_ref: self.parent_ref.clone(),
+            parent: self.parent.clone(),
+            enter: self.enter.clone(),
+            enter_redirect: self.enter_redirect,
+            process: self.process.clone(),
+            exit: self.exit.clone(),
+            #[cfg(feature = "messaging")]
+            defers: self.defers,
+            #[cfg(feature = "messaging")]
+            clears_deferred: self.clears_deferred,
+            #[cfg(feature = "std")]
+            timeout: self.timeout,
+            active: self.active,
+            region: self.region,
+            children: self.children.clone(),
+            #[cfg(feature = "counters")]
+            enter_cnt: self.enter_cnt,
+            #[cfg(feature = "counters")]
+            process_cnt: self.process_cnt,
+            #[cfg(feature = "counters")]
+            handled_cnt: self.handled_cnt,
+            #[cfg(feature = "counters")]
+            exit_cnt: self.exit_cnt,
+            #[cfg(feature = "counters")]
+            defer_cnt: self.defer_cnt,
+            #[cfg(feature = "timing")]
+            enter_time: self.enter_time,
+            #[cfg(feature = "timing")]
+            process_time: self.process_time,
+            #[cfg(feature = "timing")]
+            exit_time: self.exit_time,
+        }
+    }
+}
+
+impl<SM, P, I> StateInfo<SM, P, I>
+where
+    I: StateIdx,
+{
+    // A literal name is borrowed, only a String name is allocated
+    pub fn new(name: impl Into<Cow<'static, str>>, process_fn: ProcessFn<SM, P, I>) -> Self {
+        Self::new_with_process(name.into(), StateProcess::Fn(process_fn))
+    }
+
+    pub fn new_closure<F>(name: impl Into<Cow<'static, str>>, process_fn: F) -> Self
+    where
+        F: Fn(&mut SM, &Executor<SM, P, I>, &P) -> StateResult<I> + Send + Sync + 'static,
+    {
+        Self::new_with_process(name.into(), StateProcess::Closure(Arc::new(process_fn)))
+    }
+
+    fn new_with_process(name: Cow<'static, str>, process: StateProcess<SM, P, I>) -> Self {
+        StateInfo {
+            name,
+            parent_ref: None,
             parent: None,
             enter: None,
-            process: process_fn,
+            enter_redirect: None,
+            process,
             exit: None,
+            #[cfg(feature = "messaging")]
+            defers: None,
+            #[cfg(feature = "messaging")]
+            clears_deferred: false,
+            #[cfg(feature = "std")]
+            timeout: None,
             active: false,
-            children_for_cycle_detector: Vec::<usize>::new(),
+            region: 0,
+            children: Vec::<I>::new(),
+            #[cfg(feature = "counters")]
             enter_cnt: 0,
+            #[cfg(feature = "counters")]
             process_cnt: 0,
+            #[cfg(feature = "counters")]
+            handled_cnt: 0,
+            #[cfg(feature = "counters")]
             exit_cnt: 0,
+            #[cfg(feature = "counters")]
+            defer_cnt: 0,
+            #[cfg(feature = "timing")]
+            enter_time: Duration::ZERO,
+            #[cfg(feature = "timing")]
+            process_time: Duration::ZERO,
+            #[cfg(feature = "timing")]
+            exit_time: Duration::ZERO,
         }
     }
 
     pub fn enter_fn(mut self, enter_fn: EnterFn<SM, P>) -> Self {
-        self.enter = Some(enter_fn);
+        self.enter = Some(StateEnterExit::Fn(enter_fn));
 
         self
     }
 
-    pub fn exit_fn(mut self, exit_fn: EnterFn<SM, P>) -> Self {
-        self.exit = Some(exit_fn);
+    pub fn enter_closure<F>(mut self, enter_fn: F) -> Self
+    where
+        F: Fn(&mut SM, &P) + Send + Sync + 'static,
+    {
+        self.enter = Some(StateEnterExit::Closure(Arc::new(enter_fn)));
 
         self
     }
 
-    pub fn parent_idx(mut self, idx_parent: usize) -> Self {
-        self.parent = Some(idx_parent);
+    // Called after the enter fn, if any, and may redirect the transition
+    // that entered this state. Once the enter path has finished the
+    // executor transitions to the returned state within the same
+    // dispatch, see ExecutorBuilder::max_redirects.
+    pub fn enter_redirect_fn(mut self, enter_redirect_fn: EnterRedirectFn<SM, P, I>) -> Self {
+        self.enter_redirect = Some(enter_redirect_fn);
 
         self
     }
-}
 
-pub struct Executor<SM, P> {
-    //pub name: String, // TODO: add StateMachineInfo::name
+    pub fn exit_fn(mut self, exit_fn: ExitFn<SM, P>) -> Self {
+        self.exit = Some(StateEnterExit::Fn(exit_fn));
 
-    // Field `sm` needs "interior mutability" because we pass &mut sm and &Self
-    // to process in dispatch_idx. If we don't have `sm` as a RefCell
-    // we get the following error at the call site in dispatch_idx:
-    //     (self.states[idx].process)(&mut self.sm, self, msg);
-    //     -------------------------- ------------  ^^^^ immutable borrow occurs here
-    //     |                          |
-    //     |                          mutable borrow occurs here
-    //     mutable borrow later used by call
-    pub sm: RefCell<SM>,
+        self
+    }
 
-    pub states: Vec<StateInfo<SM, P>>,
-    pub current_state_changed: bool,
-    pub idx_transition_dest: Option<usize>,
-    pub idx_current_state: usize,
-    pub idx_previous_state: usize,
-    pub idxs_enter_fns: Vec<usize>,
-    pub idxs_exit_fns: std::collections::VecDeque<usize>,
+    pub fn exit_closure<F>(mut self, exit_fn: F) -> Self
+    where
+        F: Fn(&mut SM, &P) + Send + Sync + 'static,
+    {
+        self.exit = Some(StateEnterExit::Closure(Arc::new(exit_fn)));
 
-    // These are leaf states, i.e. states with no children
-    pub transition_targets: Vec<usize>,
+        self
+    }
 
-    // Returns `true` if array idx is in transition_targets
-    pub transition_targets_set: Vec<bool>,
+    // Transition to target once this state has been the current state of
+    // its region for duration, see Executor::poll_timeouts. Only the
+    // timeouts of leaf states are used. target must be a leaf in the same
+    // region, build returns InvalidTimeoutTarget if it isn't.
+    #[cfg(feature = "std")]
+    pub fn timeout(self, duration: Duration, target: StateId<I>) -> Self {
+        self.timeout_idx(duration, target.raw())
+    }
 
-    // Defer support
-    primary_tx: Sender<P>,
-    primary_rx: Receiver<P>,
-    defer_tx: [Sender<P>; 2],
-    defer_rx: [Receiver<P>; 2],
-    current_defer_idx: usize,
-}
+    // Like timeout for a target that hasn't been added yet, build returns
+    // InvalidTimeoutTarget if there's no state at idx_target
+    #[cfg(feature = "std")]
+    pub fn timeout_idx(mut self, duration: Duration, idx_target: usize) -> Self {
+        self.timeout = Some((duration, idx_target));
 
-impl<SM, P> Executor<SM, P>
-where
-    SM: Debug,
-    P: Debug,
-{
-    // Begin building an executor.
-    //
-    // You must call add_state to add one or more states
-    pub fn new(sm: RefCell<SM>, max_states: usize) -> Self {
-        let (primary_tx, primary_rx) = std::sync::mpsc::channel::<P>();
-        let (defer0_tx, defer0_rx) = std::sync::mpsc::channel::<P>();
-        let (defer1_tx, defer1_rx) = std::sync::mpsc::channel::<P>();
+        self
+    }
 
-        Executor {
-            sm,
-            states: Vec::<StateInfo<SM, P>>::with_capacity(max_states),
-            current_state_changed: true,
-            idx_transition_dest: None,
-            idx_current_state: 0,
-            idx_previous_state: 0,
-            idxs_enter_fns: Vec::<usize>::with_capacity(max_states),
-            idxs_exit_fns: VecDeque::<usize>::with_capacity(max_states),
-            transition_targets: Vec::<usize>::with_capacity(max_states),
-            transition_targets_set: Vec::<bool>::with_capacity(max_states),
-            primary_tx,
-            primary_rx,
-            defer_tx: [defer0_tx, defer1_tx],
-            defer_rx: [defer0_rx, defer1_rx],
-            current_defer_idx: 0,
-        }
+    pub fn parent_idx(mut self, idx_parent: I) -> Self {
+        self.parent_ref = Some(ParentRef::Idx(idx_parent.into()));
+
+        self
     }
 
-    // Add a state to the the executor
-    pub fn state(mut self, state_info: StateInfo<SM, P>) -> Self {
-        self.states.push(state_info);
+    pub fn parent(mut self, parent: StateId<I>) -> Self {
+        self.parent_ref = Some(ParentRef::Idx(parent.raw()));
 
         self
     }
 
-    // Initialize and make the executor ready to dispatch messages.
-    //
-    // The first state will be the state at idx_initial_state
-    pub fn build(mut self, idx_initial_state: usize) -> Result<Self, DynError> {
-        // Initialize StateInfo.children_for_cycle_dector for each state
-        self.initialize_children();
+    pub fn parent_name(mut self, parent_name: &str) -> Self {
+        self.parent_ref = Some(ParentRef::Name(parent_name.to_owned()));
 
-        // Initialize transition_targets_set to false
-        for _ in 0..self.states.len() {
-            self.transition_targets_set.push(false);
-        }
+        self
+    }
 
-        // Initialize transition_targets and transition_targets_set
-        for idx in 0..self.states.len() {
-            let cur_state = &mut self.states[idx];
+    // The orthogonal region this state belongs to, see ExecutorBuilder::with_regions.
+    // Only needs to be set on root states, children are in the region of
+    // their root.
+    pub fn region(mut self, region: usize) -> Self {
+        self.region = region;
 
-            if cur_state.children_for_cycle_detector.is_empty() {
-                self.transition_targets.push(idx);
-                self.transition_targets_set[idx] = true;
-            }
-        }
-        //println!("transition_targets: {:?}", self.transition_targets);
-        //println!("transition_targets_set: {:?}", self.transition_targets_set);
+        self
+    }
+}
 
-        if self.cycle_detector() {
-            return Err("Cycle detected".into());
-        }
+#[cfg(feature = "messaging")]
+impl<SM, P: Clone, I> StateInfo<SM, P, I> {
+    // The messages defers returns true for are deferred, as if the
+    // process fn called Executor::defer_current and returned handled,
+    // without calling the process fn.
+    pub fn defers(mut self, defers: DefersFn<P>) -> Self {
+        self.defers = Some((defers, P::clone));
 
-        // Validate idx_initial_state is valid.
-        if idx_initial_state >= self.states.len() || !self.transition_targets_set[idx_initial_state]
-        {
-            panic!(
-                "{idx_initial_state} is not a valid initial state, only {:?} are allowed",
-                self.transition_targets
-            );
-        }
+        self
+    }
+}
 
-        // Initialize current and previuos state to initial state
-        self.idx_current_state = idx_initial_state;
-        self.idx_previous_state = idx_initial_state;
+#[cfg(feature = "messaging")]
+impl<SM, P, I> StateInfo<SM, P, I> {
+    // Entering the state drops the deferred messages so they're never
+    // reprocessed, see Executor::get_deferred_dropped_cnt and
+    // ExecutorBuilder::on_deferred_dropped.
+    pub fn clears_deferred(mut self) -> Self {
+        self.clears_deferred = true;
 
-        // Initialize the idx_enter_fns array, start by
-        // always pushing the destination
-        let mut idx_enter = self.idx_current_state;
-        //log::trace!("initialialize: push idx_enter={} {}", idx_enter, self.state_name(idx_enter));
-        self.idxs_enter_fns.push(idx_enter);
+        self
+    }
+}
 
-        // Then push parents of the destination state so they are also entered.
-        while let Some(idx) = self.states[idx_enter].parent {
-            idx_enter = idx;
+// Iterator over the parent, grandparent, ... root of a state. A
+// malformed parent chain is truncated after states.len() steps.
+struct Ancestors<'a, SM, P, I> {
+    states: &'a [StateInfo<SM, P, I>],
+    idx: usize,
+    remaining: usize,
+}
 
-            //log::trace!("initialialize: push idx_enter={} {}", idx_enter, self.state_name(idx_enter));
-            self.idxs_enter_fns.push(idx_enter);
+impl<'a, SM, P, I> Ancestors<'a, SM, P, I> {
+    fn new(states: &'a [StateInfo<SM, P, I>], idx: usize) -> Self {
+        Ancestors {
+            states,
+            idx,
+            remaining: states.len(),
         }
-
-        Ok(self)
     }
+}
 
-    // Kahns algorithm for detecting cycles using a Breath First Search
-    //   https://www.geeksforgeeks.org/detect-cycle-in-a-directed-graph-using-bfs/
-    fn cycle_detector(&mut self) -> bool {
-        let mut leafs = self.transition_targets.to_vec();
-        //println!("cycle_dector: leafs: {leafs:?}");
-
-        let mut visited_cnt = 0usize;
-        while let Some(leaf_idx) = leafs.pop() {
-            visited_cnt += 1;
-            //println!("cycle_dector: leaf_idx={leaf_idx} visited_cnt={visited_cnt}");
+impl<'a, SM, P, I: StateIdx> Iterator for Ancestors<'a, SM, P, I> {
+    type Item = usize;
 
-            // Check if we have an "edge"
-            if let Some(parent_idx) = self.states[leaf_idx].parent {
-                // Yes, reference to that parent
-                let parent_state = &mut self.states[parent_idx];
+    fn next(&mut self) -> Option<usize> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.idx = self.states[self.idx].parent?.into();
 
-                // We need to remove the edge from leaf to parent, we'll do
-                // that by creating other_children which will be children_for_cycle_dector
-                // but with the "leaf_idx" removed.
-                let mut other_children = Vec::<usize>::new();
-                for child_idx in 0..parent_state.children_for_cycle_detector.len() {
-                    if parent_state.children_for_cycle_detector[child_idx] != leaf_idx {
-                        // This isn't the leaf index so save it in other_children
-                        other_children.push(parent_state.children_for_cycle_detector[child_idx]);
-                    }
-                }
+        Some(self.idx)
+    }
+}
 
-                if other_children.is_empty() {
-                    // There are NO other_children so the parent_idx is now a leaf
-                    leafs.push(parent_idx);
-                    //println!("cycle_dector: add new leaf {parent_idx} leafs: {leafs:?}");
-                } else {
-                    // Thre are other_children so copy it to children_for_cycle_dector
-                    //println!("cycle_dector: states[{parent_idx}] other_children: {other_children:?}");
-                    parent_state.children_for_cycle_detector = other_children.to_vec();
-                }
-            }
-        }
-        //println!("cycle_dector: visited_cnt: {visited_cnt} state.len()={}", self.states.len());
+// A read-only view of a state, see Executor::state and iter_states
+pub struct StateView<'a, SM, P, I = usize> {
+    idx: usize,
+    info: &'a StateInfo<SM, P, I>,
+    is_leaf: bool,
+}
 
-        visited_cnt != self.states.len()
+impl<'a, SM, P, I: StateIdx> StateView<'a, SM, P, I> {
+    pub fn idx(&self) -> StateId<I> {
+        StateId(to_idx(self.idx))
     }
 
-    // Determine Transition targets, (states with no children aka leafs)
-    fn initialize_children(&mut self) {
-        for idx in 0..self.states.len() {
-            self.initialize_states_children(idx);
-            //println!( "{idx:3}: {} {:?}", self.states[idx].children_for_cycle_detector.len(), self.states[idx].children_for_cycle_detector);
-        }
+    pub fn name(&self) -> &'a str {
+        &self.info.name
     }
 
-    fn initialize_states_children(&mut self, cur_state_idx: usize) {
-        // Itereate over all of the states looking for nodes that point to cur_state_idx
-        for idx in 0..self.states.len() {
-            if self.states[idx].parent == Some(cur_state_idx) {
-                // Add a child state
-                self.states[cur_state_idx]
-                    .children_for_cycle_detector
-                    .push(idx);
-            }
-        }
+    pub fn parent(&self) -> Option<StateId<I>> {
+        self.info.parent.map(StateId)
     }
 
-    pub fn get_state_name(&self, idx: usize) -> &str {
-        &self.states[idx].name
+    pub fn region(&self) -> usize {
+        self.info.region
     }
 
-    pub fn get_current_state_name(&self) -> &str {
-        self.get_state_name(self.idx_current_state)
+    // True if the state has been entered and not yet exited
+    pub fn is_active(&self) -> bool {
+        self.info.active
     }
 
-    pub fn get_sm(&self) -> &RefCell<SM> {
-        &self.sm
+    pub fn is_leaf(&self) -> bool {
+        self.is_leaf
     }
 
-    pub fn get_state_enter_cnt(&self, idx: usize) -> usize {
-        self.states[idx].enter_cnt
+    pub fn has_enter(&self) -> bool {
+        self.info.enter.is_some() || self.info.enter_redirect.is_some()
     }
-    pub fn get_state_process_cnt(&self, idx: usize) -> usize {
-        self.states[idx].process_cnt
+
+    pub fn has_exit(&self) -> bool {
+        self.info.exit.is_some()
     }
 
-    pub fn get_state_exit_cnt(&self, idx: usize) -> usize {
-        self.states[idx].exit_cnt
+    #[cfg(feature = "counters")]
+    pub fn counters(&self) -> StateCounters {
+        StateCounters {
+            enter_cnt: self.info.enter_cnt,
+            process_cnt: self.info.process_cnt,
+            handled_cnt: self.info.handled_cnt,
+            exit_cnt: self.info.exit_cnt,
+        }
     }
+}
 
-    fn setup_exit_enter_fns_idxs(&mut self, idx_next_state: usize) {
-        let mut cur_idx = idx_next_state;
+// The counters of a state, see StateView::counters. handled_cnt is the
+// number of times the process fn returned Handled::Yes.
+#[cfg(feature = "counters")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StateCounters {
+    pub enter_cnt: usize,
+    pub process_cnt: usize,
+    pub handled_cnt: usize,
+    pub exit_cnt: usize,
+}
 
-        // Setup the enter vector
-        let exit_sentinel = loop {
-            //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {}, TOL", cur_idx, self.state_name(cur_idx));
-            self.idxs_enter_fns.push(cur_idx);
+// The bookkeeping for one orthogonal region. The region being dispatched
+// is swapped into the Executor's own fields by select_region so the rest
+// of the executor only ever deals with a single region.
+#[derive(Clone)]
+struct RegionInfo<I> {
+    current_state_changed: bool,
+    idx_current_state: usize,
+    idx_previous_state: usize,
+    idxs_enter_fns: Vec<I>,
+    idxs_exit_fns: VecDeque<I>,
+}
 
-            cur_idx = if let Some(idx) = self.states[cur_idx].parent {
-                idx
-            } else {
-                // Exit state_infos[self.current_state_infos_idx] and all its parents
-                //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {} has no parent exit_sentinel=None", cur_dx, self.state_name(cur_idx));
-                break None;
-            };
+impl<I> Default for RegionInfo<I> {
+    fn default() -> Self {
+        RegionInfo {
+            current_state_changed: false,
+            idx_current_state: 0,
+            idx_previous_state: 0,
+            idxs_enter_fns: Vec::new(),
+            idxs_exit_fns: VecDeque::new(),
+        }
+    }
+}
 
-            if self.states[cur_idx].active {
-                // Exit state_infos[self.current_state_infos_idx] and
-                // parents upto but excluding state_infos[cur_idx]
-                //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {} is active so it's exit_sentinel", cur_idx, self.state_name(cur_idx));
-                break Some(cur_idx);
-            }
-        };
+// What an EventRecord records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Enter,
+    Process,
+    Exit,
+    Transition,
+    Deferred,
+}
 
-        // Starting at self.idx_current_state generate the
-        // list of StateFns that we're going to exit. If exit_sentinel is None
-        // then exit from idx_current_state and all of its parents.
-        // If exit_sentinel is Some then exit from the idx_current_state
-        // up to but not including the exit_sentinel.
-        let mut idx_exit = self.idx_current_state;
+// An entry in the event log, see ExecutorBuilder::event_log. For Transition
+// state_idx is the destination, for Deferred it's the state that deferred
+// a message while processing msg.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRecord {
+    pub kind: EventKind,
+    pub state_idx: usize,
+    pub msg_debug: Option<String>,
+}
 
-        // Always exit the first state, this handles the special case
-        // where Some(idx_exit) == exit_sentinel and we need to exit anyway.
-        //log::trace!("setup_exit_enter_fns_idxs: push_back(idx_exit={} {})", idx_exit, self.state_name(idx_exit));
-        self.idxs_exit_fns.push_back(idx_exit);
+// An event passed to a Tracer, the same events as the event log but
+// with the name of the state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    Enter(Cow<'static, str>),
+    Process(Cow<'static, str>),
+    Exit(Cow<'static, str>),
+    Transition(Cow<'static, str>),
+    Deferred(Cow<'static, str>),
+}
 
-        while let Some(idx) = self.states[idx_exit].parent {
-            idx_exit = idx;
+// Called with every TraceEvent as it happens, see ExecutorBuilder::tracer
+pub trait Tracer {
+    fn trace(&mut self, event: TraceEvent);
+}
 
-            if Some(idx_exit) == exit_sentinel {
-                // Reached the exit sentinel so we're done
-                //log::trace!("setup_exit_enter_fns_idxs: idx_exit={} {} == exit_sentinel={} {}, reached exit_sentinel return", idx_exit, self.state_name(idx_exit), exit_sentinel.unwrap(), self.state_name(exit_sentinel.unwrap()));
-                return;
-            }
+// A Tracer that collects the events for tests. Pass a clone to
+// ExecutorBuilder::tracer and take the events from the original.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Debug, Default, Clone)]
+pub struct VecTracer {
+    events: Arc<std::sync::Mutex<Vec<TraceEvent>>>,
+}
 
-            //log::trace!( "setup_exit_enter_fns_idxs: push_back(idx_exit={} {})", idx_exit, self.state_name(idx_exit));
-            self.idxs_exit_fns.push_back(idx_exit);
-        }
+#[cfg(any(test, feature = "test-support"))]
+impl VecTracer {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn dispatch_idx(&mut self, msg: &P, idx: usize) {
-        //log::trace!("dispatch_idx:+ idx={} {}", idx, self.state_name(idx));
+    // Remove and return the events traced so far
+    pub fn take(&self) -> Vec<TraceEvent> {
+        core::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
 
-        if self.current_state_changed {
-            // Execute the enter functions
-            while let Some(idx_enter) = self.idxs_enter_fns.pop() {
-                if let Some(state_enter) = self.states[idx_enter].enter {
-                    //log::trace!("dispatch_idx: entering idx={} {}", idx_enter, self.state_name(idx_enter));
-                    self.states[idx_enter].enter_cnt += 1;
-                    (state_enter)(&mut self.sm.borrow_mut(), msg);
-                    self.states[idx_enter].active = true;
-                }
-            }
-            self.current_state_changed = false;
-        }
+#[cfg(any(test, feature = "test-support"))]
+impl Tracer for VecTracer {
+    fn trace(&mut self, event: TraceEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+}
 
-        // Invoke the current state funtion processing the result
-        //log::trace!("dispatch_idx: processing idx={} {}", idx, self.state_name(idx));
+// Which states and transitions have been exercised, see Executor::coverage.
+// A state is visited if it has processed a message or been entered.
+#[cfg(feature = "counters")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub unvisited_states: Vec<String>,
+    pub visited_states: Vec<String>,
+    pub exercised_transitions: Vec<(String, String)>,
+}
 
-        self.states[idx].process_cnt += 1;
-        let (handled, transition) =
-            (self.states[idx].process)(&mut self.sm.borrow_mut(), self, msg);
-        if let Some(idx_next_state) = transition {
-            if self.idx_transition_dest.is_none() {
-                // First Transition it will be the idx_transition_dest
-                self.idx_transition_dest = Some(idx_next_state);
-            }
+#[cfg(feature = "counters")]
+impl Display for CoverageReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let state_cnt = self.visited_states.len() + self.unvisited_states.len();
+        writeln!(
+            f,
+            "visited {}/{} states",
+            self.visited_states.len(),
+            state_cnt
+        )?;
+        for name in self.unvisited_states.iter() {
+            writeln!(f, "  unvisited {name}")?;
         }
-        match handled {
-            Handled::No => {
-                if let Some(idx_parent) = self.states[idx].parent {
-                    //log::trace!("dispatch_idx: idx={} {} NotHandled, recurse into dispatch_idx", idx, self.state_name(idx));
-                    self.dispatch_idx(msg, idx_parent);
-                }
-                //} else {
-                //    log::trace!("dispatch_idx: idx={} {}, NotHandled, no parent, ignoring messages", idx, self.state_name(idx));
-                //}
-            }
-            Handled::Yes => {
-                // Nothing to do
-                //log::trace!("dispatch_idx: idx={} {} Handled", idx, self.state_name(idx));
-            }
+        writeln!(
+            f,
+            "exercised {} transitions",
+            self.exercised_transitions.len()
+        )?;
+        for (from, to) in self.exercised_transitions.iter() {
+            writeln!(f, "  {from} -> {to}")?;
         }
 
-        if let Some(idx_next_state) = self.idx_transition_dest {
-            self.idx_transition_dest = None;
-            if idx_next_state < self.states.len() && self.transition_targets_set[idx_next_state] {
-                //log::trace!("dispatch_idx: transition_to idx={} {}", idx_next_state, self.state_name(idx_next_state));
-                self.setup_exit_enter_fns_idxs(idx_next_state);
+        Ok(())
+    }
+}
 
-                self.idx_previous_state = self.idx_current_state;
-                self.idx_current_state = idx_next_state;
-                self.current_state_changed = true;
-            } else {
-                panic!(
-                    "{idx_next_state} is not a valid transition target, only {:?} are allowed",
-                    self.transition_targets
-                );
-            }
+// A snapshot of the counters of an executor, see Executor::metrics. It
+// can be mapped onto any metrics library or exported as Prometheus text.
+// current_states has the current state of each region and deferred is
+// always 0 without messaging.
+#[cfg(feature = "counters")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    pub current_states: Vec<Cow<'static, str>>,
+    pub dispatches: u64,
+    pub transitions: u64,
+    pub unhandled: u64,
+    pub timeouts: u64,
+    pub deferred: u64,
+    pub process_cnts: Vec<(Cow<'static, str>, u64)>,
+}
+
+#[cfg(feature = "counters")]
+impl Metrics {
+    // The metrics in the Prometheus text exposition format with each
+    // name prefixed by prefix, e.g. `prefix_dispatches_total 3`.
+    pub fn to_prometheus_text(&self, prefix: &str) -> String {
+        use core::fmt::Write;
+
+        let mut text = String::new();
+        for (name, kind, value) in [
+            ("dispatches_total", "counter", self.dispatches),
+            ("transitions_total", "counter", self.transitions),
+            ("unhandled_total", "counter", self.unhandled),
+            ("timeouts_total", "counter", self.timeouts),
+            ("deferred", "gauge", self.deferred),
+        ] {
+            let _ = writeln!(text, "# TYPE {prefix}_{name} {kind}");
+            let _ = writeln!(text, "{prefix}_{name} {value}");
+        }
+        let _ = writeln!(text, "# TYPE {prefix}_current_state gauge");
+        for (region, state) in self.current_states.iter().enumerate() {
+            let state = escape_label(state);
+            let _ = writeln!(
+                text,
+                "{prefix}_current_state{{region=\"{region}\",state=\"{state}\"}} 1"
+            );
+        }
+        let _ = writeln!(text, "# TYPE {prefix}_process_total counter");
+        for (state, cnt) in self.process_cnts.iter() {
+            let state = escape_label(state);
+            let _ = writeln!(text, "{prefix}_process_total{{state=\"{state}\"}} {cnt}");
         }
 
-        if self.current_state_changed {
-            while let Some(idx_exit) = self.idxs_exit_fns.pop_front() {
-                if let Some(state_exit) = self.states[idx_exit].exit {
-                    //log::trace!("dispatch_idx: exiting idx={} {}", idx_exit, self.state_name(idx_exit));
-                    self.states[idx_exit].exit_cnt += 1;
-                    (state_exit)(&mut self.sm.borrow_mut(), msg);
-                    self.states[idx_exit].active = false;
-                }
-            }
+        text
+    }
+}
+
+// Escape a Prometheus label value
+#[cfg(feature = "counters")]
+fn escape_label(value: &str) -> Cow<'_, str> {
+    if value.contains(['\\', '"', '\n']) {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+            .into()
+    } else {
+        value.into()
+    }
+}
+
+// The (name, enter, process, exit) counts of each state in index order,
+// see Executor::counts. Debug shows one state per line so a failed
+// assert_eq is easy to read, expected_counts! builds one for tests.
+#[cfg(feature = "counters")]
+#[derive(Clone, PartialEq, Eq)]
+pub struct Counts(pub Vec<(String, usize, usize, usize)>);
+
+#[cfg(feature = "counters")]
+impl Counts {
+    // The states whose counts changed since earlier
+    pub fn diff(&self, earlier: &Counts) -> CountsDiff {
+        CountsDiff(
+            self.0
+                .iter()
+                .zip(earlier.0.iter())
+                .filter(|(now, then)| now != then)
+                .map(|((name, enter, process, exit), (_, e, p, x))| {
+                    (name.clone(), (*e, *p, *x), (*enter, *process, *exit))
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(feature = "counters")]
+impl<const N: usize> From<[(&str, usize, usize, usize); N]> for Counts {
+    fn from(counts: [(&str, usize, usize, usize); N]) -> Self {
+        Counts(
+            counts
+                .into_iter()
+                .map(|(name, enter, process, exit)| (name.to_string(), enter, process, exit))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(feature = "counters")]
+impl Debug for Counts {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "Counts [")?;
+        for (name, enter, process, exit) in self.0.iter() {
+            writeln!(f, "  {name}: enter={enter} process={process} exit={exit}")?;
         }
+        write!(f, "]")
+    }
+}
 
-        //log::trace!("dispatch_idx:- idx={} {}", idx, self.state_name(idx));
+// The (enter, process, exit) counts of a state
+#[cfg(feature = "counters")]
+pub type StateCounts = (usize, usize, usize);
+
+// The states whose counts changed as (name, earlier, now), see
+// Counts::diff
+#[cfg(feature = "counters")]
+#[derive(Clone, PartialEq, Eq)]
+pub struct CountsDiff(pub Vec<(String, StateCounts, StateCounts)>);
+
+#[cfg(feature = "counters")]
+impl CountsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
+}
 
-    pub fn dispatch(&mut self, msg: &P) -> bool {
-        //log::trace!( "dispatch:+ current_state_infos_idx={} {}", self.idx_current_state, self.current_state_name());
-        self.dispatch_idx(msg, self.idx_current_state);
-        //log::trace!( "dispatch:- current_state_infos_idx={} {}", self.idx_current_state, self.current_state_name());
+#[cfg(feature = "counters")]
+impl Debug for CountsDiff {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "CountsDiff [")?;
+        for (name, (e, p, x), (enter, process, exit)) in self.0.iter() {
+            writeln!(
+                f,
+                "  {name}: enter={e}->{enter} process={p}->{process} exit={x}->{exit}"
+            )?;
+        }
+        write!(f, "]")
+    }
+}
 
-        self.current_state_changed
+// Counts for assert_eq!(sme.counts(), expected_counts![...]), each
+// state is `name => (enter, process, exit)` in index order.
+#[cfg(feature = "counters")]
+#[macro_export]
+macro_rules! expected_counts {
+    ($($name:expr => ($enter:expr, $process:expr, $exit:expr)),* $(,)?) => {
+        $crate::Counts::from([$(($name, $enter, $process, $exit)),*])
+    };
+}
+
+// What a region did with a dispatched message, see record_to and replay.
+// handled_by is the state that returned Handled::Yes, if any.
+#[cfg(feature = "serde")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionOutcome {
+    pub handled_by: Option<usize>,
+    pub transition_to: Option<usize>,
+}
+
+// A line written by record_to, outcomes has one entry per region
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recorded<M> {
+    pub msg: M,
+    pub outcomes: Vec<RegionOutcome>,
+}
+
+// Writes a Recorded line, set by record_to where P: Serialize is known
+#[cfg(feature = "serde")]
+type RecordFn<P> = fn(&mut dyn std::io::Write, &P, &[RegionOutcome]) -> std::io::Result<()>;
+
+// A recorded message whose replay had a different outcome, line is 1 based
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub line: usize,
+    pub recorded: Vec<RegionOutcome>,
+    pub replayed: Vec<RegionOutcome>,
+}
+
+// The result of replay, lines that couldn't be read or parsed are in
+// errors and aren't dispatched.
+#[cfg(feature = "serde")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub dispatched: usize,
+    pub divergences: Vec<Divergence>,
+    pub errors: Vec<(usize, String)>,
+}
+
+#[cfg(feature = "serde")]
+impl ReplayReport {
+    // True if every line was dispatched with its recorded outcome
+    pub fn is_clean(&self) -> bool {
+        self.divergences.is_empty() && self.errors.is_empty()
     }
+}
 
-    // TODO: More testing at warnings are needed that defering messages
-    // is "dangerous" and processing time increases for new messages. There
-    // maybe other dangers too!
-    pub fn dispatcher(&mut self, msg: &P) {
-        //log::trace!("dispatcher:+ msg={msg:?} sm={:?}", self.get_sm());
-        let mut transitioned = self.dispatch(msg);
-        //log::trace!("dispatcher:  msg={msg:?} sm={:?} ret={transitioned}", self.get_sm());
+// The source of time for the timing feature, the watchdog and the
+// deadline of Executor::run_for, tests can supply a fake.
+#[cfg(feature = "std")]
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
 
-        // Process all deferred messages we if we've transitioned
-        // above or within the loop below.
-        while transitioned {
-            //log::trace!("dispatcher:  TOL transitioned");
-            transitioned = false;
+// The default Clock, Instant::now()
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
 
-            // Switch to next set of deferred messages
-            self.next_defer();
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
 
-            // And process all of them before we do another next_defer().
-            // If we didn't do this we could process newly deferred message
-            // before we process previously deferred messages. In other words,
-            // we guarantee that previously sent messages are always processed
-            // before newly sent messages! TODO: add a messge counter or
-            // timestamp so we can guarantee this when testing!
-            while let Ok(m) = self.defer_try_recv() {
-                //log::trace!("dispatcher:  deferred msg={m:?} sm={:?}", self.get_sm());
-                transitioned |= self.dispatch(&m);
-                //log::trace!("dispatcher:  deferred msg={m:?} sm={:?} ret={transitioned}", self.get_sm());
+// A Clock that only advances when told to, so tests of the time based
+// features don't need to sleep. Clones share the same time.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+#[cfg(feature = "std")]
+impl ManualClock {
+    // Starts at the current time
+    pub fn new() -> Self {
+        ManualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+// Sent by Executor::check_watchdog, since is when the machine last
+// dispatched a message or the watchdog was set.
+#[cfg(feature = "messaging")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchdogEvent {
+    Stalled { machine: String, since: Instant },
+}
+
+#[cfg(feature = "messaging")]
+struct Watchdog {
+    timeout: Duration,
+    tx: Sender<WatchdogEvent>,
+    last_activity: Instant,
+    stalled: bool,
+}
+
+// A channel added by Executor::add_channel
+#[cfg(feature = "messaging")]
+struct NamedChannel<P> {
+    name: String,
+    tx: Sender<P>,
+    rx: Receiver<P>,
+}
+
+// Which named channel pump_once receives from next
+#[cfg(feature = "messaging")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PumpOrder {
+    // Each channel in turn, the one after the last one received from
+    // is tried first
+    RoundRobin,
+
+    // The first channel added that has a message, so a busy channel
+    // can starve the channels added after it
+    Priority,
+}
+
+// When dispatcher reprocesses the deferred messages
+#[cfg(feature = "messaging")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferPolicy {
+    // After the incoming message causes a transition
+    OnTransition,
+
+    // After every incoming message, so a message deferred until
+    // something other than a transition happens is retried
+    Always,
+
+    // Never, they're only reprocessed by Executor::process_deferred
+    Manual,
+}
+
+// What dispatcher did. messages_processed counts the incoming message
+// and each deferred message reprocessed, deferred_remaining is the
+// number still deferred so a caller knows there's work left that only
+// a transition, or process_deferred, will get to.
+#[cfg(feature = "messaging")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DispatcherOutcome {
+    pub transitioned: bool,
+    pub deferred_remaining: usize,
+    pub messages_processed: usize,
+
+    // The deferred messages were still causing transitions after
+    // max_defer_passes passes, the remaining ones are left deferred.
+    pub truncated: bool,
+}
+
+// What dispatch_reentrant does when called while a state is processing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReentrantDispatch {
+    // Panic naming the state and the message
+    Panic,
+
+    // Send the message to the primary channel so it's received after
+    // the current dispatch completes.
+    #[cfg(feature = "messaging")]
+    Queue,
+}
+
+// Why run_until returned
+#[cfg(feature = "messaging")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    // The predicate returned true
+    Done,
+
+    // No message is waiting and every sender from clone_sender and
+    // clone_priority_sender was dropped
+    Disconnected,
+
+    // The maximum number of messages were processed
+    BudgetExhausted,
+}
+
+// Where the message being processed came from, see
+// Executor::current_message_origin
+#[cfg(feature = "messaging")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageOrigin {
+    // Passed to dispatcher, or dispatch, by the caller
+    Fresh,
+
+    // Reprocessed from the defer queue, attempts is 1 the first time
+    // and one more each time it's deferred again
+    Deferred { attempts: u32 },
+}
+
+// Why defer_current failed
+#[cfg(feature = "messaging")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferError {
+    // No state is processing a message
+    NotDispatching,
+
+    // The message being processed was already deferred
+    AlreadyDeferred,
+}
+
+#[cfg(feature = "messaging")]
+impl Display for DeferError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DeferError::NotDispatching => {
+                write!(f, "defer_current called while no message is dispatched")
+            }
+            DeferError::AlreadyDeferred => {
+                write!(f, "the current message is already deferred")
             }
         }
+    }
+}
 
-        // At this point we've processed the incoming message and let
-        // the SM reprocessed all deferred messages at least one more
-        // time after each subsequent transition.
-        //
-        // There may still have deferred messages but the SM didn't
-        // transition so those will be processed after this fn is
-        // called with a new message which causes a transition.
+#[cfg(feature = "messaging")]
+impl std::error::Error for DeferError {}
 
-        //log::trace!("dispatcher:- msg={msg:?} sm={:?}", self.get_sm());
+// Sends to a channel or, with Executor, to the primary or priority
+// queue of an executor, see Executor::clone_sender.
+#[cfg(feature = "messaging")]
+pub enum MsgSender<P> {
+    Unbounded(Sender<P>),
+    Bounded(SyncSender<P>),
+    Executor(InboxSender<P>),
+}
+
+#[cfg(feature = "messaging")]
+impl<P> MsgSender<P> {
+    // Blocks while a Bounded channel is full
+    pub fn send(&self, m: P) -> Result<(), SendError<P>> {
+        match self {
+            MsgSender::Unbounded(tx) => tx.send(m),
+            MsgSender::Bounded(tx) => tx.send(m),
+            MsgSender::Executor(tx) => tx.send(m),
+        }
     }
 
-    // Defer support
-    pub fn recv(&self) -> Result<P, RecvError> {
-        self.primary_rx.recv()
+    // Fails with TrySendError::Full rather than blocking, an Unbounded
+    // channel is never full.
+    pub fn try_send(&self, m: P) -> Result<(), TrySendError<P>> {
+        match self {
+            MsgSender::Unbounded(tx) => tx
+                .send(m)
+                .map_err(|SendError(m)| TrySendError::Disconnected(m)),
+            MsgSender::Bounded(tx) => tx.try_send(m),
+            MsgSender::Executor(tx) => tx.try_send(m),
+        }
     }
+}
 
-    pub fn try_recv(&self) -> Result<P, TryRecvError> {
-        self.primary_rx.try_recv()
+#[cfg(feature = "messaging")]
+impl<P> From<Sender<P>> for MsgSender<P> {
+    fn from(tx: Sender<P>) -> Self {
+        MsgSender::Unbounded(tx)
     }
+}
 
-    pub fn send(&self, m: P) -> Result<(), SendError<P>> {
-        self.primary_tx.send(m)
+#[cfg(feature = "messaging")]
+impl<P> From<SyncSender<P>> for MsgSender<P> {
+    fn from(tx: SyncSender<P>) -> Self {
+        MsgSender::Bounded(tx)
+    }
+}
+
+// A deferred message, the sequence number it was given when it entered
+// the executor and the attempts it'll be reprocessed with. deferred_at
+// is the sequence number of the message being processed when it was
+// first deferred, used to check deferred messages stay in order. region
+// is the region that deferred it, it's only reprocessed by that region
+// as the others have already processed it. It's None if deferred
+// outside of dispatch.
+#[cfg(feature = "messaging")]
+struct Envelope<P> {
+    seq: u64,
+    attempts: u32,
+    deferred_at: u64,
+    region: Option<usize>,
+    msg: P,
+}
+
+// Maps and sends an emitted message downstream, see Executor::pipe_to
+#[cfg(feature = "messaging")]
+type OutputFn<P> = Box<dyn Fn(P) -> bool + Send>;
+
+// Clones the message being processed, see Executor::defer_current
+#[cfg(feature = "messaging")]
+type CloneFn<P> = fn(&P) -> P;
+
+#[cfg(feature = "messaging")]
+impl<P> Clone for MsgSender<P> {
+    fn clone(&self) -> Self {
+        match self {
+            MsgSender::Unbounded(tx) => MsgSender::Unbounded(tx.clone()),
+            MsgSender::Bounded(tx) => MsgSender::Bounded(tx.clone()),
+            MsgSender::Executor(tx) => MsgSender::Executor(tx.clone()),
+        }
     }
+}
 
-    pub fn clone_sender(&self) -> Sender<P> {
-        self.primary_tx.clone()
+#[cfg(feature = "messaging")]
+impl<P> Debug for MsgSender<P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MsgSender::Unbounded(_) => write!(f, "MsgSender::Unbounded"),
+            MsgSender::Bounded(_) => write!(f, "MsgSender::Bounded"),
+            MsgSender::Executor(_) => write!(f, "MsgSender::Executor"),
+        }
     }
+}
 
-    pub fn defer_try_recv(&self) -> Result<P, TryRecvError> {
-        self.defer_rx[self.other_defer()].try_recv()
+// Collects the states and options of an Executor, build validates them
+// and returns the Executor ready to dispatch messages. build consumes the
+// builder so it can't be called twice, Executor::extend adds states after
+// build.
+pub struct ExecutorBuilder<SM, P, I = usize> {
+    name: String,
+    sm: RefCell<SM>,
+    states: Vec<StateInfo<SM, P, I>>,
+    idxs_region_initial_state: Vec<Option<usize>>,
+    // The first (region, idx_initial_state) passed to
+    // region_initial_state with an invalid region, build reports it
+    invalid_region_initial_state: Option<(usize, usize)>,
+    idx_recovery_state: Option<usize>,
+    reentrant_dispatch: ReentrantDispatch,
+    event_log_capacity: usize,
+    event_log_msgs: bool,
+    tracer: Option<Box<dyn Tracer + Send>>,
+    #[cfg(feature = "std")]
+    clock: Arc<dyn Clock + Send + Sync>,
+    max_redirects: usize,
+    #[cfg(feature = "messaging")]
+    max_defer_passes: usize,
+    #[cfg(feature = "messaging")]
+    max_defer_attempts: Option<u32>,
+    #[cfg(feature = "messaging")]
+    on_deferred_dropped: Option<ActionFn<SM, P>>,
+    #[cfg(feature = "messaging")]
+    primary_capacity: Option<usize>,
+    #[cfg(feature = "messaging")]
+    pump_order: PumpOrder,
+    #[cfg(feature = "messaging")]
+    defer_policy: DeferPolicy,
+}
+
+pub struct Executor<SM, P, I = usize> {
+    name: String,
+
+    // Field `sm` needs "interior mutability" because we pass &mut sm and &Self
+    // to process in dispatch_idx. If we don't have `sm` as a RefCell
+    // we get the following error at the call site in dispatch_idx:
+    //     (self.states[idx].process)(&mut self.sm, self, msg);
+    //     -------------------------- ------------  ^^^^ immutable borrow occurs here
+    //     |                          |
+    //     |                          mutable borrow occurs here
+    //     mutable borrow later used by call
+    sm: RefCell<SM>,
+
+    states: Vec<StateInfo<SM, P, I>>,
+    current_state_changed: bool,
+    idx_transition_dest: Option<usize>,
+    idx_current_state: usize,
+    idx_previous_state: usize,
+
+    // Enter fns are popped from the back, exit fns from the front
+    idxs_enter_fns: Vec<I>,
+    idxs_exit_fns: VecDeque<I>,
+
+    // These are leaf states, i.e. states with no children
+    transition_targets: Vec<I>,
+
+    // Returns `true` if array idx is in transition_targets
+    transition_targets_set: Vec<bool>,
+
+    // Orthogonal regions, regions[idx_region] is stale as it's
+    // the region currently held in the fields above.
+    regions: Vec<RegionInfo<I>>,
+    idx_region: usize,
+
+    // Panic recovery, see catch_panics
+    idx_recovery_state: Option<usize>,
+    panic_cnt: usize,
+
+    // The state whose process fn is running and the Debug text of the
+    // message it's processing, see dispatch_reentrant
+    in_dispatch: Cell<Option<usize>>,
+    in_dispatch_msg: RefCell<String>,
+    reentrant_dispatch: ReentrantDispatch,
+
+    // Set by shutdown
+    stopped: bool,
+
+    // The action of the transition being made, see transition_with
+    pending_action: Cell<Option<ActionFn<SM, P>>>,
+    transition_action: Option<ActionFn<SM, P>>,
+
+    // The first redirect returned by an enter_redirect fn, see
+    // StateInfo::enter_redirect_fn
+    idx_redirect: Option<usize>,
+    max_redirects: usize,
+    redirect_cnt: usize,
+
+    // The state that handled the message being dispatched in each
+    // region, see DispatchResult
+    idxs_handled_by: Vec<Option<usize>>,
+
+    // The number of transitions from each state to each state
+    #[cfg(feature = "counters")]
+    transition_cnts: BTreeMap<(usize, usize), u64>,
+
+    // Messages dispatched and messages no state in a region handled
+    #[cfg(feature = "counters")]
+    dispatch_cnt: u64,
+    #[cfg(feature = "counters")]
+    unhandled_cnt: u64,
+
+    // Transitions made by poll_timeouts
+    #[cfg(feature = "counters")]
+    timeout_cnt: u64,
+
+    // The most recent events, see event_log
+    event_log: VecDeque<EventRecord>,
+    event_log_capacity: usize,
+    event_log_msgs: bool,
+
+    // See ExecutorBuilder::tracer
+    tracer: Option<Box<dyn Tracer + Send>>,
+
+    // Times the enter, process and exit functions of each state and
+    // the watchdog
+    #[cfg(feature = "std")]
+    clock: Arc<dyn Clock + Send + Sync>,
+
+    // When the current state of each region became current, see
+    // StateInfo::timeout
+    #[cfg(feature = "std")]
+    entered_at: Vec<Instant>,
+
+    // Defer support, the primary and priority queues. Messages sent with
+    // send_priority are received before the primary ones.
+    #[cfg(feature = "messaging")]
+    inbox: InboxReceiver<P>,
+    #[cfg(feature = "messaging")]
+    primary_capacity: Option<usize>,
+
+    // The channels added by add_channel, see pump_once
+    #[cfg(feature = "messaging")]
+    channels: Vec<NamedChannel<P>>,
+    #[cfg(feature = "messaging")]
+    idx_next_channel: usize,
+    #[cfg(feature = "messaging")]
+    pump_order: PumpOrder,
+
+    #[cfg(feature = "messaging")]
+    defer_tx: [Sender<Envelope<P>>; 2],
+    #[cfg(feature = "messaging")]
+    defer_rx: [Receiver<Envelope<P>>; 2],
+    #[cfg(feature = "messaging")]
+    current_defer_idx: usize,
+    #[cfg(feature = "messaging")]
+    deferred_cnt: Cell<usize>,
+    #[cfg(feature = "messaging")]
+    max_defer_passes: usize,
+    #[cfg(feature = "messaging")]
+    defer_policy: DeferPolicy,
+    #[cfg(feature = "messaging")]
+    defer_overflow_cnt: usize,
+    #[cfg(feature = "messaging")]
+    max_defer_attempts: Option<u32>,
+    #[cfg(feature = "messaging")]
+    dead_letter_tx: Sender<P>,
+    #[cfg(feature = "messaging")]
+    dead_letter_rx: Receiver<P>,
+    #[cfg(feature = "messaging")]
+    on_deferred_dropped: Option<ActionFn<SM, P>>,
+    #[cfg(feature = "messaging")]
+    deferred_dropped_cnt: usize,
+
+    // Sequence numbers of the messages passed to dispatcher
+    #[cfg(feature = "messaging")]
+    next_seq: Cell<u64>,
+    #[cfg(feature = "messaging")]
+    current_seq: Cell<Option<u64>>,
+    #[cfg(feature = "messaging")]
+    current_origin: Cell<MessageOrigin>,
+    #[cfg(feature = "messaging")]
+    current_deferred_at: Cell<Option<u64>>,
+
+    // Clones the message being processed into the defer channel once
+    // its process fn returns, see defer_current.
+    #[cfg(feature = "messaging")]
+    defer_current: Cell<Option<CloneFn<P>>>,
+
+    // See watchdog
+    #[cfg(feature = "messaging")]
+    watchdog: Option<Watchdog>,
+
+    // Output port, see pipe_to and emit
+    #[cfg(feature = "messaging")]
+    output: RefCell<Option<OutputFn<P>>>,
+    #[cfg(feature = "messaging")]
+    emitted_cnt: Cell<usize>,
+
+    // Record support, see record_to
+    #[cfg(feature = "serde")]
+    outcomes: Vec<RegionOutcome>,
+    #[cfg(feature = "serde")]
+    recorder: Option<(Box<dyn std::io::Write + Send>, RecordFn<P>)>,
+}
+
+// Check the states are well formed without building an Executor, build
+// calls this first. The states are not modified.
+pub fn validate<SM, P, I: StateIdx>(
+    states: &[StateInfo<SM, P, I>],
+    idx_initial_state: usize,
+) -> Result<(), BuildError> {
+    if !states.is_empty() && I::try_from(states.len() - 1).is_err() {
+        return Err(BuildError::IdxOverflow {
+            declared: states.len(),
+        });
     }
 
-    pub fn defer_send(&self, m: P) -> Result<(), SendError<P>> {
-        self.defer_tx[self.current_defer()].send(m)
+    let parents = resolve_parents(states)?;
+
+    let idxs_cycle = cycle_detector(&parents);
+    if !idxs_cycle.is_empty() {
+        return Err(BuildError::CycleDetected {
+            states: idxs_cycle
+                .iter()
+                .map(|idx| states[*idx].name.to_string())
+                .collect(),
+        });
     }
 
-    pub fn next_defer(&mut self) {
-        self.current_defer_idx = (self.current_defer_idx + 1) % self.defer_tx.len();
+    // The initial state must be a leaf in region 0, regions are
+    // inherited from the root.
+    let is_leaf = |idx: usize| !parents.contains(&Some(idx));
+    let root_region = |idx: usize| {
+        let mut idx_root = idx;
+        while let Some(idx_parent) = parents[idx_root] {
+            idx_root = idx_parent;
+        }
+        states[idx_root].region
+    };
+    if idx_initial_state >= states.len()
+        || !is_leaf(idx_initial_state)
+        || root_region(idx_initial_state) != 0
+    {
+        return Err(BuildError::InvalidInitialState {
+            idx: idx_initial_state,
+            valid: (0..states.len())
+                .filter(|idx| is_leaf(*idx) && root_region(*idx) == 0)
+                .collect(),
+        });
     }
 
-    pub fn current_defer(&self) -> usize {
-        self.current_defer_idx
+    #[cfg(feature = "std")]
+    validate_timeouts(states, &parents)?;
+
+    Ok(())
+}
+
+// A timeout must transition to a leaf in the region of its state
+#[cfg(feature = "std")]
+fn validate_timeouts<SM, P, I: StateIdx>(
+    states: &[StateInfo<SM, P, I>],
+    parents: &[Option<usize>],
+) -> Result<(), BuildError> {
+    let is_leaf = |idx: usize| !parents.contains(&Some(idx));
+    let root_region = |idx: usize| {
+        let mut idx_root = idx;
+        while let Some(idx_parent) = parents[idx_root] {
+            idx_root = idx_parent;
+        }
+        states[idx_root].region
+    };
+    for (idx, state) in states.iter().enumerate() {
+        let idx_target = match state.timeout {
+            Some((_, idx_target)) => idx_target,
+            None => continue,
+        };
+        let region = root_region(idx);
+        if idx_target >= states.len() || !is_leaf(idx_target) || root_region(idx_target) != region {
+            return Err(BuildError::InvalidTimeoutTarget {
+                state: state.name.to_string(),
+                idx: idx_target,
+                valid: (0..states.len())
+                    .filter(|idx| is_leaf(*idx) && root_region(*idx) == region)
+                    .collect(),
+            });
+        }
     }
 
-    pub fn other_defer(&self) -> usize {
-        (self.current_defer_idx + 1) % self.defer_tx.len()
+    Ok(())
+}
+
+// Returns the parent of each state after checking the names are unique
+// and the parent references are valid.
+fn resolve_parents<SM, P, I: StateIdx>(
+    states: &[StateInfo<SM, P, I>],
+) -> Result<Vec<Option<usize>>, BuildError> {
+    for (idx, state) in states.iter().enumerate() {
+        if states[..idx].iter().any(|s| s.name == state.name) {
+            return Err(BuildError::DuplicateName(state.name.to_string()));
+        }
+    }
+
+    let mut parents = Vec::<Option<usize>>::with_capacity(states.len());
+    for state in states.iter() {
+        let parent = match &state.parent_ref {
+            Some(ParentRef::Idx(idx_parent)) => Some(*idx_parent),
+            Some(ParentRef::Name(parent_name)) => {
+                match states.iter().position(|s| s.name == parent_name.as_str()) {
+                    Some(idx_parent) => Some(idx_parent),
+                    None => {
+                        return Err(BuildError::UnknownParent {
+                            state: state.name.to_string(),
+                            parent_name: parent_name.clone(),
+                        })
+                    }
+                }
+            }
+            None => state.parent.map(Into::into),
+        };
+        if let Some(parent_idx) = parent {
+            if parent_idx >= states.len() {
+                return Err(BuildError::InvalidParent {
+                    state: state.name.to_string(),
+                    parent_idx,
+                });
+            }
+        }
+        parents.push(parent);
+    }
+
+    Ok(parents)
+}
+
+// Kahns algorithm for detecting cycles using a Breath First Search
+//   https://www.geeksforgeeks.org/detect-cycle-in-a-directed-graph-using-bfs/
+//
+// Returns the states in a cycle, empty if there are none. The edges
+// are removed from a scratch list of children built from parents.
+fn cycle_detector(parents: &[Option<usize>]) -> Vec<usize> {
+    let mut children_for_cycle_detector = vec![Vec::<usize>::new(); parents.len()];
+    for (idx, parent) in parents.iter().enumerate() {
+        if let Some(parent_idx) = parent {
+            children_for_cycle_detector[*parent_idx].push(idx);
+        }
+    }
+
+    let mut leafs: Vec<usize> = (0..parents.len())
+        .filter(|idx| children_for_cycle_detector[*idx].is_empty())
+        .collect();
+    //println!("cycle_dector: leafs: {leafs:?}");
+
+    let mut visited = vec![false; parents.len()];
+    while let Some(leaf_idx) = leafs.pop() {
+        visited[leaf_idx] = true;
+        //println!("cycle_dector: leaf_idx={leaf_idx}");
+
+        // Check if we have an "edge"
+        if let Some(parent_idx) = parents[leaf_idx] {
+            // Yes, reference to that parent's children
+            let parent_children = &mut children_for_cycle_detector[parent_idx];
+
+            // We need to remove the edge from leaf to parent, we'll do
+            // that by creating other_children which will be children_for_cycle_dector
+            // but with the "leaf_idx" removed.
+            let mut other_children = Vec::<usize>::new();
+            for child_idx in parent_children.iter() {
+                if *child_idx != leaf_idx {
+                    // This isn't the leaf index so save it in other_children
+                    other_children.push(*child_idx);
+                }
+            }
+
+            if other_children.is_empty() {
+                // There are NO other_children so the parent_idx is now a leaf
+                leafs.push(parent_idx);
+                //println!("cycle_dector: add new leaf {parent_idx} leafs: {leafs:?}");
+            } else {
+                // Thre are other_children so copy it to children_for_cycle_dector
+                //println!("cycle_dector: states[{parent_idx}] other_children: {other_children:?}");
+                *parent_children = other_children.to_vec();
+            }
+        }
     }
+    //println!("cycle_dector: visited: {visited:?}");
+
+    // Each state has one parent so only states in a cycle are
+    // never visited.
+    (0..parents.len()).filter(|idx| !visited[*idx]).collect()
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+impl<SM, P, I> ExecutorBuilder<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    // Begin building an executor.
+    //
+    // You must call state to add one or more states
+    pub fn new(sm: RefCell<SM>) -> Self {
+        ExecutorBuilder {
+            name: String::new(),
+            sm,
+            states: Vec::<StateInfo<SM, P, I>>::new(),
+            idxs_region_initial_state: vec![None],
+            invalid_region_initial_state: None,
+            idx_recovery_state: None,
+            reentrant_dispatch: ReentrantDispatch::Panic,
+            event_log_capacity: 0,
+            event_log_msgs: false,
+            tracer: None,
+            #[cfg(feature = "std")]
+            clock: Arc::new(SystemClock),
+            max_redirects: 8,
+            #[cfg(feature = "messaging")]
+            max_defer_passes: 64,
+            #[cfg(feature = "messaging")]
+            max_defer_attempts: None,
+            #[cfg(feature = "messaging")]
+            on_deferred_dropped: None,
+            #[cfg(feature = "messaging")]
+            primary_capacity: None,
+            #[cfg(feature = "messaging")]
+            pump_order: PumpOrder::RoundRobin,
+            #[cfg(feature = "messaging")]
+            defer_policy: DeferPolicy::OnTransition,
+        }
+    }
+
+    // Name of the executor, it defaults to empty
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_owned();
+
+        self
+    }
+
+    // Add a state to the the executor
+    pub fn state(mut self, state_info: StateInfo<SM, P, I>) -> Self {
+        self.states.push(state_info);
+
+        self
+    }
+
+    // Add a state to the executor returning its id
+    pub fn add_state(&mut self, state_info: StateInfo<SM, P, I>) -> StateId<I> {
+        self.states.push(state_info);
+
+        StateId(to_idx(self.states.len() - 1))
+    }
+
+    // Catch panics in process functions and transition to the leaf
+    // recovery_state instead of unwinding out of dispatch. The pending
+    // enter and exit functions are discarded and panic_cnt is incremented.
+    //
+    // SM is not required to be UnwindSafe, so the recovery state must
+    // assume SM was left in whatever state the panicking handler got it
+    // to. Only panics in the region of recovery_state are caught.
+    #[cfg(feature = "std")]
+    pub fn catch_panics(self, recovery_state: StateId<I>) -> Self {
+        self.catch_panics_idx(recovery_state.raw())
+    }
+
+    // Like catch_panics with the state at idx_recovery_state, build
+    // returns InvalidRecoveryState if it isn't a leaf
+    #[cfg(feature = "std")]
+    pub fn catch_panics_idx(mut self, idx_recovery_state: usize) -> Self {
+        self.idx_recovery_state = Some(idx_recovery_state);
+
+        self
+    }
+
+    // Record the last `capacity` enter, process, exit, transition and
+    // deferred events in a ring buffer, see Executor::recent_events.
+    pub fn event_log(mut self, capacity: usize) -> Self {
+        self.event_log_capacity = capacity;
+
+        self
+    }
+
+    // Also record the Debug formatted message of each event, this
+    // allocates a String per event so it's off by default.
+    pub fn event_log_msgs(mut self) -> Self {
+        self.event_log_msgs = true;
+
+        self
+    }
+
+    // Pass every event to tracer as it happens, see VecTracer for tests
+    pub fn tracer(mut self, tracer: impl Tracer + Send + 'static) -> Self {
+        self.tracer = Some(Box::new(tracer));
+
+        self
+    }
+
+    // Use clock rather than SystemClock to time the states and for the
+    // watchdog and run_for
+    #[cfg(feature = "std")]
+    pub fn clock<C: Clock + Send + Sync + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+
+        self
+    }
+
+    // The maximum number of enter redirects in one dispatch, default 8.
+    // More means the redirects are looping and dispatch panics.
+    pub fn max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+
+        self
+    }
+
+    // The maximum number of times dispatcher reprocesses the deferred
+    // messages for one incoming message, default 64. This stops two
+    // states that keep deferring and transitioning from spinning forever.
+    #[cfg(feature = "messaging")]
+    pub fn max_defer_passes(mut self, max_defer_passes: usize) -> Self {
+        self.max_defer_passes = max_defer_passes;
+
+        self
+    }
+
+    // The maximum number of times a message may be deferred, deferring
+    // it again sends it to Executor::dead_letters instead. The default
+    // is no maximum.
+    #[cfg(feature = "messaging")]
+    pub fn max_defer_attempts(mut self, max_defer_attempts: u32) -> Self {
+        self.max_defer_attempts = Some(max_defer_attempts);
+
+        self
+    }
+
+    // Called with each deferred message dropped by entering a state
+    // with StateInfo::clears_deferred, e.g. to log them
+    #[cfg(feature = "messaging")]
+    pub fn on_deferred_dropped(mut self, on_deferred_dropped: ActionFn<SM, P>) -> Self {
+        self.on_deferred_dropped = Some(on_deferred_dropped);
+
+        self
+    }
+
+    // When dispatcher reprocesses the deferred messages, default
+    // DeferPolicy::OnTransition
+    #[cfg(feature = "messaging")]
+    pub fn defer_policy(mut self, defer_policy: DeferPolicy) -> Self {
+        self.defer_policy = defer_policy;
+
+        self
+    }
+
+    // What dispatch_reentrant does, default ReentrantDispatch::Panic
+    pub fn reentrant_dispatch(mut self, reentrant_dispatch: ReentrantDispatch) -> Self {
+        self.reentrant_dispatch = reentrant_dispatch;
+
+        self
+    }
+
+    // Limit the primary channel to capacity messages so send blocks, and
+    // try_send fails, when it's full. Beware a state sending to itself
+    // with a full channel blocks forever, use try_send there.
+    #[cfg(feature = "messaging")]
+    pub fn primary_capacity(mut self, capacity: usize) -> Self {
+        self.primary_capacity = Some(capacity);
+
+        self
+    }
+
+    // How pump_once chooses between the named channels, default
+    // PumpOrder::RoundRobin
+    #[cfg(feature = "messaging")]
+    pub fn pump_order(mut self, pump_order: PumpOrder) -> Self {
+        self.pump_order = pump_order;
+
+        self
+    }
+
+    // Split the machine into `count` orthogonal regions. Each region is
+    // one or more trees of states, see StateInfo::region, with its own
+    // current leaf. Every dispatch offers the message to each region in
+    // region order and a transition within one region never exits or
+    // enters states in another.
+    //
+    // Region 0 is initialized by build, the others by region_initial_state.
+    pub fn with_regions(mut self, count: usize) -> Self {
+        self.idxs_region_initial_state.resize(count.max(1), None);
+
+        self
+    }
+
+    // Set the initial state of region, region 0 may also be set by build.
+    // build returns BuildError::InvalidRegion if region isn't one of the
+    // with_regions regions.
+    pub fn region_initial_state(self, region: usize, initial_state: StateId<I>) -> Self {
+        self.region_initial_state_idx(region, initial_state.raw())
+    }
+
+    // Like region_initial_state with the state at idx_initial_state
+    pub fn region_initial_state_idx(mut self, region: usize, idx_initial_state: usize) -> Self {
+        if region < self.idxs_region_initial_state.len() {
+            self.idxs_region_initial_state[region] = Some(idx_initial_state);
+        } else if self.invalid_region_initial_state.is_none() {
+            self.invalid_region_initial_state = Some((region, idx_initial_state));
+        }
+
+        self
+    }
+
+    // Validate the states and return an Executor ready to dispatch messages.
+    //
+    // The first state will be initial_state
+    pub fn build(self, initial_state: StateId<I>) -> Result<Executor<SM, P, I>, BuildError> {
+        self.build_idx(initial_state.raw())
+    }
+
+    // Like build with the state at idx_initial_state, for states added
+    // with ExecutorBuilder::state. build_idx returns InvalidInitialState
+    // if there's no such state.
+    pub fn build_idx(mut self, idx_initial_state: usize) -> Result<Executor<SM, P, I>, BuildError> {
+        validate(&self.states, idx_initial_state)?;
+        if let Some((region, idx)) = self.invalid_region_initial_state {
+            return Err(BuildError::InvalidRegion {
+                state: match self.states.get(idx) {
+                    Some(state) => state.name.to_string(),
+                    None => idx.to_string(),
+                },
+                region,
+                regions: self.idxs_region_initial_state.len(),
+            });
+        }
+
+        // Resolve parent references now that all states are known
+        let parents = resolve_parents(&self.states)?;
+        for (state, parent) in self.states.iter_mut().zip(parents) {
+            state.parent = parent.map(to_idx);
+        }
+
+        // Region 0 is always initialized with idx_initial_state
+        self.idxs_region_initial_state[0] = Some(idx_initial_state);
+
+        #[cfg(feature = "messaging")]
+        let inbox = InboxReceiver::new(self.primary_capacity);
+        #[cfg(feature = "messaging")]
+        let (defer0_tx, defer0_rx) = std::sync::mpsc::channel::<Envelope<P>>();
+        #[cfg(feature = "messaging")]
+        let (defer1_tx, defer1_rx) = std::sync::mpsc::channel::<Envelope<P>>();
+        #[cfg(feature = "messaging")]
+        let (dead_letter_tx, dead_letter_rx) = std::sync::mpsc::channel::<P>();
+
+        let state_cnt = self.states.len();
+        let regions_cnt = self.idxs_region_initial_state.len();
+        let mut regions = Vec::<RegionInfo<I>>::new();
+        regions.resize_with(regions_cnt, RegionInfo::default);
+        let mut executor = Executor {
+            name: self.name,
+            sm: self.sm,
+            states: self.states,
+            current_state_changed: true,
+            idx_transition_dest: None,
+            idx_current_state: 0,
+            idx_previous_state: 0,
+            idxs_enter_fns: Vec::<I>::with_capacity(state_cnt),
+            idxs_exit_fns: VecDeque::<I>::with_capacity(state_cnt),
+            transition_targets: Vec::<I>::with_capacity(state_cnt),
+            transition_targets_set: Vec::<bool>::with_capacity(state_cnt),
+            regions,
+            idx_region: 0,
+            idx_recovery_state: self.idx_recovery_state,
+            panic_cnt: 0,
+            in_dispatch: Cell::new(None),
+            in_dispatch_msg: RefCell::new(String::new()),
+            reentrant_dispatch: self.reentrant_dispatch,
+            stopped: false,
+            pending_action: Cell::new(None),
+            transition_action: None,
+            idx_redirect: None,
+            max_redirects: self.max_redirects,
+            redirect_cnt: 0,
+            idxs_handled_by: vec![None; regions_cnt],
+            #[cfg(feature = "counters")]
+            transition_cnts: BTreeMap::new(),
+            #[cfg(feature = "counters")]
+            dispatch_cnt: 0,
+            #[cfg(feature = "counters")]
+            unhandled_cnt: 0,
+            #[cfg(feature = "counters")]
+            timeout_cnt: 0,
+            event_log: VecDeque::with_capacity(self.event_log_capacity),
+            event_log_capacity: self.event_log_capacity,
+            event_log_msgs: self.event_log_msgs,
+            tracer: self.tracer,
+            #[cfg(feature = "std")]
+            entered_at: vec![self.clock.now(); regions_cnt],
+            #[cfg(feature = "std")]
+            clock: self.clock,
+            #[cfg(feature = "messaging")]
+            inbox,
+            #[cfg(feature = "messaging")]
+            primary_capacity: self.primary_capacity,
+            #[cfg(feature = "messaging")]
+            channels: Vec::new(),
+            #[cfg(feature = "messaging")]
+            idx_next_channel: 0,
+            #[cfg(feature = "messaging")]
+            pump_order: self.pump_order,
+            #[cfg(feature = "messaging")]
+            defer_tx: [defer0_tx, defer1_tx],
+            #[cfg(feature = "messaging")]
+            defer_rx: [defer0_rx, defer1_rx],
+            #[cfg(feature = "messaging")]
+            current_defer_idx: 0,
+            #[cfg(feature = "messaging")]
+            deferred_cnt: Cell::new(0),
+            #[cfg(feature = "messaging")]
+            max_defer_passes: self.max_defer_passes,
+            #[cfg(feature = "messaging")]
+            defer_policy: self.defer_policy,
+            #[cfg(feature = "messaging")]
+            defer_overflow_cnt: 0,
+            #[cfg(feature = "messaging")]
+            max_defer_attempts: self.max_defer_attempts,
+            #[cfg(feature = "messaging")]
+            dead_letter_tx,
+            #[cfg(feature = "messaging")]
+            dead_letter_rx,
+            #[cfg(feature = "messaging")]
+            on_deferred_dropped: self.on_deferred_dropped,
+            #[cfg(feature = "messaging")]
+            deferred_dropped_cnt: 0,
+            #[cfg(feature = "messaging")]
+            next_seq: Cell::new(0),
+            #[cfg(feature = "messaging")]
+            current_seq: Cell::new(None),
+            #[cfg(feature = "messaging")]
+            current_origin: Cell::new(MessageOrigin::Fresh),
+            #[cfg(feature = "messaging")]
+            current_deferred_at: Cell::new(None),
+            #[cfg(feature = "messaging")]
+            defer_current: Cell::new(None),
+            #[cfg(feature = "messaging")]
+            watchdog: None,
+            #[cfg(feature = "messaging")]
+            output: RefCell::new(None),
+            #[cfg(feature = "messaging")]
+            emitted_cnt: Cell::new(0),
+            #[cfg(feature = "serde")]
+            outcomes: Vec::new(),
+            #[cfg(feature = "serde")]
+            recorder: None,
+        };
+        executor.initialize(&self.idxs_region_initial_state)?;
+
+        Ok(executor)
+    }
+}
+
+impl<SM, P, I> Executor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    // Begin building an executor, see ExecutorBuilder
+    #[deprecated(note = "use ExecutorBuilder::new")]
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(sm: RefCell<SM>) -> ExecutorBuilder<SM, P, I> {
+        ExecutorBuilder::new(sm)
+    }
+
+    // Initialize the states and enter each region's initial state,
+    // called by ExecutorBuilder::build.
+    fn initialize(
+        &mut self,
+        idxs_region_initial_state: &[Option<usize>],
+    ) -> Result<(), BuildError> {
+        // Initialize StateInfo.children for each state
+        self.initialize_children();
+
+        // Initialize transition_targets_set to false
+        for _ in 0..self.states.len() {
+            self.transition_targets_set.push(false);
+        }
+
+        // Initialize transition_targets and transition_targets_set
+        for idx in 0..self.states.len() {
+            let cur_state = &mut self.states[idx];
+
+            if cur_state.children.is_empty() {
+                self.transition_targets.push(to_idx(idx));
+                self.transition_targets_set[idx] = true;
+            }
+        }
+        //println!("transition_targets: {:?}", self.transition_targets);
+        //println!("transition_targets_set: {:?}", self.transition_targets_set);
+
+        if let Some(idx_recovery_state) = self.idx_recovery_state {
+            if idx_recovery_state >= self.states.len()
+                || !self.transition_targets_set[idx_recovery_state]
+            {
+                return Err(BuildError::InvalidRecoveryState {
+                    idx: idx_recovery_state,
+                    valid: self.leaf_states_idxs(),
+                });
+            }
+        }
+
+        // Validate found no cycles so each state can inherit
+        // the region of its root.
+        self.initialize_regions(0..self.states.len())?;
+
+        // Initialize each region, the last one initialized is region 0
+        // so it ends up being the region held in the Executor fields.
+        for region in (0..self.regions.len()).rev() {
+            let idx_initial_state = match idxs_region_initial_state[region] {
+                Some(idx) => idx,
+                None => return Err(BuildError::NoRegionInitialState { region }),
+            };
+
+            // Validate idx_initial_state is valid.
+            if idx_initial_state >= self.states.len()
+                || !self.transition_targets_set[idx_initial_state]
+                || self.states[idx_initial_state].region != region
+            {
+                return Err(BuildError::InvalidInitialState {
+                    idx: idx_initial_state,
+                    valid: self.region_transition_targets(region),
+                });
+            }
+
+            self.idx_region = region;
+            self.initialize_region(idx_initial_state);
+            if region != 0 {
+                core::mem::swap(
+                    &mut self.idxs_enter_fns,
+                    &mut self.regions[region].idxs_enter_fns,
+                );
+                self.regions[region].idx_current_state = self.idx_current_state;
+                self.regions[region].idx_previous_state = self.idx_previous_state;
+                self.regions[region].current_state_changed = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn initialize_region(&mut self, idx_initial_state: usize) {
+        // Initialize current and previuos state to initial state
+        self.idx_current_state = idx_initial_state;
+        self.idx_previous_state = idx_initial_state;
+
+        // Initialize the idx_enter_fns array, start by
+        // always pushing the destination
+        //log::trace!("initialialize: push idx_enter={} {}", idx_initial_state, self.state_name(idx_initial_state));
+        self.idxs_enter_fns.push(to_idx(idx_initial_state));
+
+        // Then push parents of the destination state so they are also entered.
+        self.idxs_enter_fns
+            .extend(Ancestors::new(&self.states, idx_initial_state).map(to_idx::<I>));
+    }
+
+    // Set the region of the states in idxs to the region of their root.
+    fn initialize_regions(&mut self, idxs: core::ops::Range<usize>) -> Result<(), BuildError> {
+        for idx in idxs {
+            let idx_root = self.ancestors(idx).last().unwrap_or(idx);
+
+            let region = self.states[idx_root].region;
+            if region >= self.regions.len() {
+                return Err(BuildError::InvalidRegion {
+                    state: self.states[idx_root].name.to_string(),
+                    region,
+                    regions: self.regions.len(),
+                });
+            }
+            if self.states[idx].region != 0 && self.states[idx].region != region {
+                return Err(BuildError::ConflictingRegion {
+                    state: self.states[idx].name.to_string(),
+                    region: self.states[idx].region,
+                    root: self.states[idx_root].name.to_string(),
+                    root_region: region,
+                });
+            }
+            self.states[idx].region = region;
+        }
+
+        Ok(())
+    }
+
+    fn region_transition_targets(&self, region: usize) -> Vec<usize> {
+        self.leaf_states_idxs()
+            .into_iter()
+            .filter(|idx| self.states[*idx].region == region)
+            .collect()
+    }
+
+    fn leaf_states_idxs(&self) -> Vec<usize> {
+        self.transition_targets
+            .iter()
+            .map(|idx| (*idx).into())
+            .collect()
+    }
+
+    // Add new_states to a built executor and return their indices.
+    //
+    // The new states may have existing or new states as parents and
+    // inherit the region of their root. An existing leaf that gains a
+    // child is no longer a transition target, so it's an error if it's
+    // the current state of a region or the recovery state. Nothing is
+    // changed if an error is returned.
+    pub fn extend(
+        &mut self,
+        new_states: Vec<StateInfo<SM, P, I>>,
+    ) -> Result<Vec<usize>, BuildError> {
+        let idx_first = self.states.len();
+        self.states.extend(new_states);
+        if let Err(e) = self.validate_extension(idx_first) {
+            self.states.truncate(idx_first);
+            return Err(e);
+        }
+
+        for idx in idx_first..self.states.len() {
+            if let Some(idx_parent) = self.states[idx].parent.map(Into::into) {
+                self.states[idx_parent].children.push(to_idx(idx));
+                if self.transition_targets_set[idx_parent] {
+                    // A former leaf, it's no longer a transition target
+                    self.transition_targets_set[idx_parent] = false;
+                    self.transition_targets
+                        .retain(|idx_target| (*idx_target).into() != idx_parent);
+                }
+            }
+        }
+        for idx in idx_first..self.states.len() {
+            let is_leaf = self.states[idx].children.is_empty();
+            self.transition_targets_set.push(is_leaf);
+            if is_leaf {
+                self.transition_targets.push(to_idx(idx));
+            }
+        }
+
+        Ok((idx_first..self.states.len()).collect())
+    }
+
+    // Validate the states from idx_first on, which extend appended, and
+    // resolve their parents and regions.
+    fn validate_extension(&mut self, idx_first: usize) -> Result<(), BuildError> {
+        if I::try_from(self.states.len() - 1).is_err() {
+            return Err(BuildError::IdxOverflow {
+                declared: self.states.len(),
+            });
+        }
+
+        let parents = resolve_parents(&self.states)?;
+        let idxs_cycle = cycle_detector(&parents);
+        if !idxs_cycle.is_empty() {
+            return Err(BuildError::CycleDetected {
+                states: idxs_cycle
+                    .iter()
+                    .map(|idx| self.states[*idx].name.to_string())
+                    .collect(),
+            });
+        }
+
+        for (idx, parent) in parents.iter().enumerate().skip(idx_first) {
+            let idx_parent = match *parent {
+                Some(idx_parent) if idx_parent < idx_first => idx_parent,
+                _ => continue,
+            };
+            if !self.transition_targets_set[idx_parent] {
+                continue;
+            }
+            if (0..self.regions.len())
+                .any(|region| self.get_region_current_state(region) == idx_parent)
+            {
+                return Err(BuildError::ParentIsCurrentState {
+                    state: self.states[idx].name.to_string(),
+                    parent: self.states[idx_parent].name.to_string(),
+                });
+            }
+            if self.idx_recovery_state == Some(idx_parent) {
+                return Err(BuildError::InvalidRecoveryState {
+                    idx: idx_parent,
+                    valid: self.leaf_states_idxs(),
+                });
+            }
+        }
+
+        #[cfg(feature = "std")]
+        validate_timeouts(&self.states, &parents)?;
+
+        for (state, parent) in self.states[idx_first..]
+            .iter_mut()
+            .zip(&parents[idx_first..])
+        {
+            state.parent = parent.map(to_idx);
+        }
+        self.initialize_regions(idx_first..self.states.len())
+    }
+
+    // Swap the bookkeeping of region into the Executor fields.
+    fn select_region(&mut self, region: usize) {
+        if region == self.idx_region {
+            return;
+        }
+
+        let parked = &mut self.regions[self.idx_region];
+        core::mem::swap(
+            &mut parked.current_state_changed,
+            &mut self.current_state_changed,
+        );
+        core::mem::swap(&mut parked.idx_current_state, &mut self.idx_current_state);
+        core::mem::swap(&mut parked.idx_previous_state, &mut self.idx_previous_state);
+        core::mem::swap(&mut parked.idxs_enter_fns, &mut self.idxs_enter_fns);
+        core::mem::swap(&mut parked.idxs_exit_fns, &mut self.idxs_exit_fns);
+
+        let selected = &mut self.regions[region];
+        core::mem::swap(
+            &mut selected.current_state_changed,
+            &mut self.current_state_changed,
+        );
+        core::mem::swap(&mut selected.idx_current_state, &mut self.idx_current_state);
+        core::mem::swap(
+            &mut selected.idx_previous_state,
+            &mut self.idx_previous_state,
+        );
+        core::mem::swap(&mut selected.idxs_enter_fns, &mut self.idxs_enter_fns);
+        core::mem::swap(&mut selected.idxs_exit_fns, &mut self.idxs_exit_fns);
+
+        self.idx_region = region;
+    }
+
+    // Determine Transition targets, (states with no children aka leafs)
+    fn initialize_children(&mut self) {
+        for idx in 0..self.states.len() {
+            self.initialize_states_children(idx);
+            //println!( "{idx:3}: {} {:?}", self.states[idx].children.len(), self.states[idx].children);
+        }
+    }
+
+    fn initialize_states_children(&mut self, cur_state_idx: usize) {
+        // Itereate over all of the states looking for nodes that point to cur_state_idx
+        for idx in 0..self.states.len() {
+            if self.states[idx].parent.map(Into::into) == Some(cur_state_idx) {
+                // Add a child state
+                self.states[cur_state_idx].children.push(to_idx(idx));
+            }
+        }
+    }
+
+    pub fn get_state_name(&self, idx: usize) -> &str {
+        &self.states[idx].name
+    }
+
+    pub fn get_current_state_name(&self) -> &str {
+        self.get_state_name(self.idx_current_state)
+    }
+
+    // True if a transition happened and the enter fns of the new
+    // states run on the next dispatch.
+    pub fn current_state_changed(&self) -> bool {
+        self.current_state_changed
+    }
+
+    // The states still to be entered, in the order they'll be entered
+    pub fn pending_enter_path(&self) -> Vec<usize> {
+        self.idxs_enter_fns
+            .iter()
+            .rev()
+            .map(|&idx| idx.into())
+            .collect()
+    }
+
+    // The states still to be exited, in the order they'll be exited
+    pub fn pending_exit_path(&self) -> Vec<usize> {
+        self.idxs_exit_fns.iter().map(|&idx| idx.into()).collect()
+    }
+
+    pub fn state_count(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn get_region_count(&self) -> usize {
+        self.regions.len()
+    }
+
+    // The current state of region
+    pub fn get_region_current_state(&self, region: usize) -> usize {
+        if region == self.idx_region {
+            self.idx_current_state
+        } else {
+            self.regions[region].idx_current_state
+        }
+    }
+
+    pub fn get_region_current_state_name(&self, region: usize) -> &str {
+        self.get_state_name(self.get_region_current_state(region))
+    }
+
+    pub fn get_sm(&self) -> &RefCell<SM> {
+        &self.sm
+    }
+
+    // The counters only exist with the "counters" feature, without it
+    // these accessors are compiled out so any use is a compile error.
+    #[cfg(feature = "counters")]
+    pub fn get_state_enter_cnt(&self, idx: usize) -> usize {
+        self.states[idx].enter_cnt
+    }
+
+    #[cfg(feature = "counters")]
+    pub fn get_state_process_cnt(&self, idx: usize) -> usize {
+        self.states[idx].process_cnt
+    }
+
+    // The number of messages the state at idx handled, a message it
+    // processed and returned Handled::No for isn't counted
+    #[cfg(feature = "counters")]
+    pub fn get_state_handled_cnt(&self, idx: usize) -> usize {
+        self.states[idx].handled_cnt
+    }
+
+    // The number of messages the state at idx deferred with its
+    // StateInfo::defers, they're also counted as handled
+    #[cfg(feature = "counters")]
+    pub fn get_state_defer_cnt(&self, idx: usize) -> usize {
+        self.states[idx].defer_cnt
+    }
+
+    #[cfg(feature = "counters")]
+    pub fn get_state_exit_cnt(&self, idx: usize) -> usize {
+        self.states[idx].exit_cnt
+    }
+
+    // The number of transitions keyed by (idx_from, idx_to), only
+    // transitions that occurred are present.
+    #[cfg(feature = "counters")]
+    pub fn transition_counts(&self) -> &BTreeMap<(usize, usize), u64> {
+        &self.transition_cnts
+    }
+
+    // The total time spent in the enter, process and exit functions of
+    // the state at idx.
+    #[cfg(feature = "timing")]
+    pub fn get_state_enter_time(&self, idx: usize) -> Duration {
+        self.states[idx].enter_time
+    }
+
+    #[cfg(feature = "timing")]
+    pub fn get_state_process_time(&self, idx: usize) -> Duration {
+        self.states[idx].process_time
+    }
+
+    #[cfg(feature = "timing")]
+    pub fn get_state_exit_time(&self, idx: usize) -> Duration {
+        self.states[idx].exit_time
+    }
+
+    // The name and total enter, process and exit time of every state,
+    // slowest first.
+    #[cfg(feature = "timing")]
+    pub fn timing_report(&self) -> Vec<(&str, Duration)> {
+        let mut report: Vec<(&str, Duration)> = self
+            .states
+            .iter()
+            .map(|state| {
+                (
+                    state.name.as_ref(),
+                    state.enter_time + state.process_time + state.exit_time,
+                )
+            })
+            .collect();
+        report.sort_by_key(|(_, time)| core::cmp::Reverse(*time));
+
+        report
+    }
+
+    // The states and transitions exercised so far, states are in index
+    // order and transitions in transition_counts order.
+    #[cfg(feature = "counters")]
+    pub fn coverage(&self) -> CoverageReport {
+        let (visited, unvisited): (Vec<_>, Vec<_>) = self
+            .states
+            .iter()
+            .partition(|state| state.process_cnt > 0 || state.enter_cnt > 0);
+        CoverageReport {
+            unvisited_states: unvisited
+                .iter()
+                .map(|state| state.name.to_string())
+                .collect(),
+            visited_states: visited.iter().map(|state| state.name.to_string()).collect(),
+            exercised_transitions: self
+                .transition_counts_named()
+                .into_iter()
+                .map(|(from, to, _)| (from.to_owned(), to.to_owned()))
+                .collect(),
+        }
+    }
+
+    // A snapshot of the counters, see Metrics
+    #[cfg(feature = "counters")]
+    pub fn metrics(&self) -> Metrics {
+        #[cfg(feature = "messaging")]
+        let deferred = self.get_deferred_cnt() as u64;
+        #[cfg(not(feature = "messaging"))]
+        let deferred = 0;
+
+        Metrics {
+            current_states: (0..self.get_region_count())
+                .map(|region| {
+                    self.states[self.get_region_current_state(region)]
+                        .name
+                        .clone()
+                })
+                .collect(),
+            dispatches: self.dispatch_cnt,
+            transitions: self.transition_cnts.values().sum(),
+            unhandled: self.unhandled_cnt,
+            timeouts: self.timeout_cnt,
+            deferred,
+            process_cnts: self
+                .states
+                .iter()
+                .map(|state| (state.name.clone(), state.process_cnt as u64))
+                .collect(),
+        }
+    }
+
+    // A snapshot of the enter, process and exit counts, see Counts
+    #[cfg(feature = "counters")]
+    pub fn counts(&self) -> Counts {
+        Counts(
+            self.states
+                .iter()
+                .map(|state| {
+                    (
+                        state.name.to_string(),
+                        state.enter_cnt,
+                        state.process_cnt,
+                        state.exit_cnt,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    // The transition_counts as (from name, to name, count)
+    #[cfg(feature = "counters")]
+    pub fn transition_counts_named(&self) -> Vec<(&str, &str, u64)> {
+        self.transition_cnts
+            .iter()
+            .map(|((idx_from, idx_to), cnt)| {
+                (
+                    self.get_state_name(*idx_from),
+                    self.get_state_name(*idx_to),
+                    *cnt,
+                )
+            })
+            .collect()
+    }
+
+    // The parent, grandparent, ... and finally the root of the state at idx,
+    // the state itself is not included.
+    pub fn ancestors(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        Ancestors::new(&self.states, idx)
+    }
+
+    // The number of ancestors of the state at idx, roots have depth 0
+    pub fn depth(&self, idx: usize) -> usize {
+        self.ancestors(idx).count()
+    }
+
+    // The deepest state that is a or b or an ancestor of both, None if
+    // they are in different trees. A state is its own common ancestor.
+    pub fn common_ancestor(&self, a: usize, b: usize) -> Option<usize> {
+        let path_a: Vec<usize> = core::iter::once(a).chain(self.ancestors(a)).collect();
+
+        core::iter::once(b)
+            .chain(self.ancestors(b))
+            .find(|idx| path_a.contains(idx))
+    }
+
+    // The states without a parent in index order
+    pub fn roots(&self) -> Vec<usize> {
+        (0..self.states.len())
+            .filter(|idx| self.states[*idx].parent.is_none())
+            .collect()
+    }
+
+    // The names of the states from the root to the current state separated
+    // by `/`. It follows the parent chain so it's correct even before the
+    // enter fns of the current state have run.
+    pub fn state_path(&self) -> String {
+        self.path(self.idx_current_state)
+    }
+
+    fn path(&self, idx: usize) -> String {
+        let mut names: Vec<&str> = core::iter::once(idx)
+            .chain(self.ancestors(idx))
+            .map(|idx| self.get_state_name(idx))
+            .collect();
+        names.reverse();
+
+        names.join("/")
+    }
+
+    // The id of the state at idx for use as a transition, None if there
+    // is no such state
+    pub fn state_id(&self, idx: usize) -> Option<StateId<I>> {
+        (idx < self.states.len()).then(|| StateId(to_idx(idx)))
+    }
+
+    // A transition to target whose action is run once the states being
+    // left have been exited and before the states being entered are.
+    // If a state returns it but a child state's transition is taken,
+    // the action isn't run.
+    //
+    // The action isn't part of the returned Transition, the executor
+    // holds it until the process fn returns. So it's the action of
+    // whatever transition the process fn returns, the last transition_with
+    // called in a process fn wins and it's dropped if the process fn
+    // doesn't transition.
+    pub fn transition_with(&self, target: StateId<I>, action: ActionFn<SM, P>) -> Transition<I> {
+        self.pending_action.set(Some(action));
+
+        target
+    }
+
+    // The id of the state named name
+    pub fn get_state_idx(&self, name: &str) -> Option<StateId<I>> {
+        let idx = self.states.iter().position(|state| state.name == name)?;
+
+        self.state_id(idx)
+    }
+
+    // The state at idx, None if there is no such state
+    pub fn state(&self, idx: usize) -> Option<StateView<'_, SM, P, I>> {
+        Some(StateView {
+            idx,
+            info: self.states.get(idx)?,
+            is_leaf: self.is_leaf(idx),
+        })
+    }
+
+    // Visit every state parent before children, the roots and the
+    // children of each state are visited in index order.
+    pub fn iter_states(&self) -> impl Iterator<Item = StateView<'_, SM, P, I>> + '_ {
+        let mut stack = self.roots();
+        stack.reverse();
+
+        core::iter::from_fn(move || {
+            let idx = stack.pop()?;
+            stack.extend(
+                self.states[idx]
+                    .children
+                    .iter()
+                    .rev()
+                    .map(|idx| (*idx).into()),
+            );
+
+            self.state(idx)
+        })
+    }
+
+    // The leaf states, these are the only valid transition targets
+    pub fn leaf_states(&self) -> &[I] {
+        &self.transition_targets
+    }
+
+    pub fn leaf_state_names(&self) -> Vec<&str> {
+        self.transition_targets
+            .iter()
+            .map(|idx| self.states[(*idx).into()].name.as_ref())
+            .collect()
+    }
+
+    // Returns true if idx is a leaf state and so a valid transition target
+    pub fn is_leaf(&self, idx: usize) -> bool {
+        idx < self.transition_targets_set.len() && self.transition_targets_set[idx]
+    }
+
+    // The children of the state at idx in declaration order
+    pub fn children(&self, idx: usize) -> &[I] {
+        &self.states[idx].children
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_panic_cnt(&self) -> usize {
+        self.panic_cnt
+    }
+
+    // The number of enter redirects taken, see StateInfo::enter_redirect_fn
+    pub fn get_redirect_cnt(&self) -> usize {
+        self.redirect_cnt
+    }
+
+    // True while a state's process fn is running
+    pub fn is_dispatching(&self) -> bool {
+        self.in_dispatch.get().is_some()
+    }
+
+    // Dispatch msg from within a state's process fn, which only has &Self.
+    // The message can't be processed while another is, so depending on
+    // ReentrantDispatch this panics or queues msg on the primary channel.
+    // Outside of dispatch msg is always queued.
+    pub fn dispatch_reentrant(&self, msg: P) {
+        match (self.in_dispatch.get(), self.reentrant_dispatch) {
+            (Some(idx), ReentrantDispatch::Panic) => self.reentrant_panic(idx, &msg),
+            #[cfg(feature = "messaging")]
+            _ => {
+                // The receiver is owned by self so this can't fail
+                let _ = self.send(msg);
+            }
+            #[cfg(not(feature = "messaging"))]
+            _ => panic!(
+                "{}: dispatch_reentrant requires the messaging feature to queue {msg:?}",
+                self.name
+            ),
+        }
+    }
+
+    fn reentrant_panic(&self, idx: usize, msg: &P) -> ! {
+        panic!(
+            "{}: dispatch of {msg:?} called re-entrantly from state {} while processing {}",
+            self.name,
+            self.state_label(idx),
+            self.in_dispatch_msg.borrow()
+        );
+    }
+
+    // Mark the state at idx as processing msg, returns the state that was
+    // processing to restore afterwards. The Debug text of msg is only
+    // needed to panic, the buffer is reused so it isn't reallocated.
+    fn begin_processing(&self, idx: usize, msg: &P) -> Option<usize> {
+        if self.reentrant_dispatch == ReentrantDispatch::Panic {
+            use core::fmt::Write;
+
+            let mut text = self.in_dispatch_msg.borrow_mut();
+            text.clear();
+            let _ = write!(text, "{msg:?}");
+        }
+        self.in_dispatch.replace(Some(idx))
+    }
+
+    // The name and index of a state for panic messages, e.g. 'yellow' (idx 2)
+    fn state_label(&self, idx: usize) -> String {
+        format!("'{}' (idx {idx})", self.get_state_name(idx))
+    }
+
+    // The names of the states at idxs for panic messages, only the
+    // first few of a long list are named
+    fn state_names(&self, idxs: &[usize]) -> String {
+        const MAX_NAMES: usize = 8;
+
+        let mut names = idxs
+            .iter()
+            .take(MAX_NAMES)
+            .map(|idx| self.get_state_name(*idx))
+            .collect::<Vec<&str>>()
+            .join(", ");
+        if idxs.len() > MAX_NAMES {
+            names += &format!(", ... {} more", idxs.len() - MAX_NAMES);
+        }
+
+        names
+    }
+
+    // The recorded events oldest first, empty unless event_log was used
+    pub fn recent_events(&self) -> impl Iterator<Item = &EventRecord> + '_ {
+        self.event_log.iter()
+    }
+
+    fn record_event(&mut self, kind: EventKind, state_idx: usize, msg: &P) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            let name = self.states[state_idx].name.clone();
+            tracer.trace(match kind {
+                EventKind::Enter => TraceEvent::Enter(name),
+                EventKind::Process => TraceEvent::Process(name),
+                EventKind::Exit => TraceEvent::Exit(name),
+                EventKind::Transition => TraceEvent::Transition(name),
+                EventKind::Deferred => TraceEvent::Deferred(name),
+            });
+        }
+        if self.event_log_capacity == 0 {
+            return;
+        }
+        if self.event_log.len() == self.event_log_capacity {
+            self.event_log.pop_front();
+        }
+        let msg_debug = if self.event_log_msgs {
+            Some(format!("{msg:?}"))
+        } else {
+            None
+        };
+        self.event_log.push_back(EventRecord {
+            kind,
+            state_idx,
+            msg_debug,
+        });
+    }
+
+    fn setup_exit_enter_fns_idxs(&mut self, idx_next_state: usize) {
+        // Setup the enter vector
+        //log::trace!("setup_exit_enter_fns_idxs: idx_next_state={} {}", idx_next_state, self.state_name(idx_next_state));
+        self.idxs_enter_fns.push(to_idx(idx_next_state));
+
+        // If no ancestor is active exit state_infos[self.current_state_infos_idx]
+        // and all its parents.
+        let mut exit_sentinel = None;
+        for cur_idx in Ancestors::new(&self.states, idx_next_state) {
+            if self.states[cur_idx].active {
+                // Exit state_infos[self.current_state_infos_idx] and
+                // parents upto but excluding state_infos[cur_idx]
+                //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {} is active so it's exit_sentinel", cur_idx, self.state_name(cur_idx));
+                exit_sentinel = Some(cur_idx);
+                break;
+            }
+
+            //log::trace!("setup_exit_enter_fns_idxs: cur_idx={} {}", cur_idx, self.state_name(cur_idx));
+            self.idxs_enter_fns.push(to_idx(cur_idx));
+        }
+
+        // Starting at self.idx_current_state generate the
+        // list of StateFns that we're going to exit. If exit_sentinel is None
+        // then exit from idx_current_state and all of its parents.
+        // If exit_sentinel is Some then exit from the idx_current_state
+        // up to but not including the exit_sentinel.
+        //
+        // Always exit the first state, this handles the special case
+        // where Some(idx_exit) == exit_sentinel and we need to exit anyway.
+        //log::trace!("setup_exit_enter_fns_idxs: push_back(idx_exit={} {})", self.idx_current_state, self.state_name(self.idx_current_state));
+        self.idxs_exit_fns.push_back(to_idx(self.idx_current_state));
+
+        for idx_exit in Ancestors::new(&self.states, self.idx_current_state) {
+            if Some(idx_exit) == exit_sentinel {
+                // Reached the exit sentinel so we're done
+                //log::trace!("setup_exit_enter_fns_idxs: idx_exit={} {} == exit_sentinel={} {}, reached exit_sentinel return", idx_exit, self.state_name(idx_exit), exit_sentinel.unwrap(), self.state_name(exit_sentinel.unwrap()));
+                return;
+            }
+
+            //log::trace!( "setup_exit_enter_fns_idxs: push_back(idx_exit={} {})", idx_exit, self.state_name(idx_exit));
+            self.idxs_exit_fns.push_back(to_idx(idx_exit));
+        }
+    }
+
+    // Discard what the panicking handler's transition had pending and
+    // make the recovery state the transition destination.
+    #[cfg(feature = "std")]
+    fn recover(&mut self, idx_recovery_state: usize) {
+        //log::trace!("recover: transition_to idx={} {}", idx_recovery_state, self.state_name(idx_recovery_state));
+        self.panic_cnt += 1;
+        self.idxs_enter_fns.clear();
+        self.idxs_exit_fns.clear();
+        self.idx_transition_dest = Some(idx_recovery_state);
+    }
+
+    // Run the next pending enter fn, states without one are skipped
+    // but still become active. Returns the state entered or None if
+    // none are pending.
+    fn enter_next(&mut self, msg: &P) -> Option<usize> {
+        while let Some(idx_enter) = self.idxs_enter_fns.pop() {
+            let idx_enter: usize = idx_enter.into();
+            let state_enter = self.states[idx_enter].enter.clone();
+            let enter_redirect = self.states[idx_enter].enter_redirect;
+            if state_enter.is_none() && enter_redirect.is_none() {
+                self.states[idx_enter].active = true;
+                continue;
+            }
+
+            #[cfg(feature = "log")]
+            log::trace!("enter state={}", self.states[idx_enter].name);
+            #[cfg(feature = "tracing")]
+            tracing::trace!(state = %self.states[idx_enter].name, "enter");
+            #[cfg(feature = "counters")]
+            {
+                self.states[idx_enter].enter_cnt += 1;
+            }
+            self.record_event(EventKind::Enter, idx_enter, msg);
+            #[cfg(feature = "timing")]
+            let start = self.clock.now();
+            if let Some(state_enter) = state_enter {
+                state_enter.call(&mut self.sm.borrow_mut(), msg);
+            }
+            if let Some(enter_redirect) = enter_redirect {
+                let in_dispatch = self.begin_processing(idx_enter, msg);
+                let redirect = enter_redirect(&mut self.sm.borrow_mut(), self, msg);
+                self.in_dispatch.set(in_dispatch);
+                if self.idx_redirect.is_none() {
+                    self.idx_redirect = redirect.map(StateId::raw);
+                }
+            }
+            #[cfg(feature = "timing")]
+            {
+                self.states[idx_enter].enter_time += self.clock.now() - start;
+            }
+            self.states[idx_enter].active = true;
+
+            return Some(idx_enter);
+        }
+
+        None
+    }
+
+    // Make the pending enter redirect the transition destination,
+    // redirects is the number of them in this dispatch.
+    fn redirect(&mut self, idx_redirect: usize, redirects: usize, msg: &P) {
+        if redirects > self.max_redirects {
+            panic!(
+                "{}: {redirects} enter redirects in one dispatch exceeds max_redirects {}, the last was to {}",
+                self.name,
+                self.max_redirects,
+                self.state_label(idx_redirect)
+            );
+        }
+        self.redirect_cnt += 1;
+        self.idx_transition_dest = Some(idx_redirect);
+        self.transition(msg);
+    }
+
+    // Invoke the process fn of the state at idx, returns its parent if
+    // the message wasn't handled and so is to be processed by the parent.
+    fn process(&mut self, msg: &P, idx: usize) -> Option<usize> {
+        #[cfg(feature = "counters")]
+        {
+            self.states[idx].process_cnt += 1;
+        }
+        self.record_event(EventKind::Process, idx, msg);
+        #[cfg(feature = "messaging")]
+        let deferred_cnt = self.get_deferred_cnt();
+        #[cfg(feature = "timing")]
+        let start = self.clock.now();
+        let in_dispatch = self.begin_processing(idx, msg);
+        let (handled, transition) = if self.defers(idx, msg) {
+            handled()
+        } else {
+            match self.idx_recovery_state {
+                #[cfg(feature = "std")]
+                Some(idx_recovery_state)
+                    if self.states[idx_recovery_state].region == self.idx_region =>
+                {
+                    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        self.states[idx]
+                            .process
+                            .call(&mut self.sm.borrow_mut(), self, msg)
+                    })) {
+                        Ok(state_result) => state_result,
+                        Err(_) => {
+                            self.recover(idx_recovery_state);
+                            handled()
+                        }
+                    }
+                }
+                _ => self.states[idx]
+                    .process
+                    .call(&mut self.sm.borrow_mut(), self, msg),
+            }
+        };
+        self.in_dispatch.set(in_dispatch);
+        let action = self.pending_action.take();
+        #[cfg(feature = "messaging")]
+        if let Some(clone) = self.defer_current.take() {
+            // The executor holds both ends of the defer channels
+            self.defer_send_from(clone(msg), Some(self.idx_region), true)
+                .expect("SNH");
+        }
+        #[cfg(feature = "timing")]
+        {
+            self.states[idx].process_time += self.clock.now() - start;
+        }
+        #[cfg(feature = "messaging")]
+        for _ in deferred_cnt..self.get_deferred_cnt() {
+            self.record_event(EventKind::Deferred, idx, msg);
+        }
+        #[cfg(feature = "log")]
+        log::trace!(
+            "process state={} handled={}",
+            self.states[idx].name,
+            matches!(handled, Handled::Yes)
+        );
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            state = %self.states[idx].name,
+            handled = matches!(handled, Handled::Yes),
+            "process"
+        );
+        if let Some(next_state) = transition {
+            if self.idx_transition_dest.is_none() {
+                // First Transition it will be the idx_transition_dest
+                self.idx_transition_dest = Some(next_state.raw());
+                self.transition_action = action;
+            }
+        }
+        match handled {
+            Handled::No => {
+                let idx_parent = self.states[idx].parent.map(Into::into);
+                #[cfg(feature = "counters")]
+                if idx_parent.is_none() {
+                    self.unhandled_cnt += 1;
+                }
+                idx_parent
+            }
+            Handled::Yes => {
+                #[cfg(feature = "counters")]
+                {
+                    self.states[idx].handled_cnt += 1;
+                }
+                self.idxs_handled_by[self.idx_region] = Some(idx);
+                #[cfg(feature = "serde")]
+                {
+                    self.outcomes[self.idx_region].handled_by = Some(idx);
+                }
+                None
+            }
+        }
+    }
+
+    // Whether the state at idx defers msg, it's then deferred as if by
+    // defer_current
+    #[cfg(feature = "messaging")]
+    fn defers(&mut self, idx: usize, msg: &P) -> bool {
+        match self.states[idx].defers {
+            Some((defers, clone)) if defers(msg) => {
+                self.defer_current.set(Some(clone));
+                #[cfg(feature = "counters")]
+                {
+                    self.states[idx].defer_cnt += 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    #[cfg(not(feature = "messaging"))]
+    fn defers(&mut self, _idx: usize, _msg: &P) -> bool {
+        false
+    }
+
+    // Make idx_transition_dest, if any, the current state
+    fn transition(&mut self, msg: &P) {
+        if let Some(idx_next_state) = self.idx_transition_dest {
+            self.idx_transition_dest = None;
+            if idx_next_state >= self.states.len() {
+                panic!(
+                    "{}: transition from {} to idx {idx_next_state} rejected: there are only {} states",
+                    self.name,
+                    self.state_label(self.idx_current_state),
+                    self.states.len()
+                );
+            } else if !self.transition_targets_set[idx_next_state] {
+                panic!(
+                    "{}: transition from {} to {} rejected: target is not a leaf; valid targets: {}",
+                    self.name,
+                    self.state_label(self.idx_current_state),
+                    self.state_label(idx_next_state),
+                    self.state_names(&self.region_transition_targets(self.idx_region))
+                );
+            } else if self.states[idx_next_state].region != self.idx_region {
+                panic!(
+                    "{}: transition from {} to {} rejected: target is in region {} not region {}; valid targets: {}",
+                    self.name,
+                    self.state_label(self.idx_current_state),
+                    self.state_label(idx_next_state),
+                    self.states[idx_next_state].region,
+                    self.idx_region,
+                    self.state_names(&self.region_transition_targets(self.idx_region))
+                );
+            } else {
+                #[cfg(feature = "log")]
+                log::trace!(
+                    "transition from={} to={}",
+                    self.states[self.idx_current_state].name,
+                    self.states[idx_next_state].name
+                );
+                #[cfg(feature = "tracing")]
+                tracing::trace!(
+                    from = %self.states[self.idx_current_state].name,
+                    to = %self.states[idx_next_state].name,
+                    "transition"
+                );
+                self.setup_exit_enter_fns_idxs(idx_next_state);
+
+                // The enter fns run later so drop the deferred messages
+                // now, before dispatcher replays them
+                #[cfg(feature = "messaging")]
+                if self
+                    .idxs_enter_fns
+                    .iter()
+                    .any(|&idx| self.states[idx.into()].clears_deferred)
+                {
+                    self.drop_deferred();
+                }
+
+                self.idx_previous_state = self.idx_current_state;
+                self.idx_current_state = idx_next_state;
+                self.current_state_changed = true;
+                #[cfg(feature = "std")]
+                {
+                    self.entered_at[self.idx_region] = self.clock.now();
+                }
+                #[cfg(feature = "counters")]
+                {
+                    *self
+                        .transition_cnts
+                        .entry((self.idx_previous_state, idx_next_state))
+                        .or_insert(0) += 1;
+                }
+                self.record_event(EventKind::Transition, idx_next_state, msg);
+                #[cfg(feature = "serde")]
+                {
+                    self.outcomes[self.idx_region].transition_to = Some(idx_next_state);
+                }
+            }
+        }
+    }
+
+    // Run the next pending exit fn, states without one are skipped.
+    // Returns the state exited or None if none are pending.
+    fn exit_next(&mut self, msg: &P) -> Option<usize> {
+        while let Some(idx_exit) = self.idxs_exit_fns.pop_front() {
+            let idx_exit: usize = idx_exit.into();
+            if let Some(state_exit) = self.states[idx_exit].exit.clone() {
+                #[cfg(feature = "log")]
+                log::trace!("exit state={}", self.states[idx_exit].name);
+                #[cfg(feature = "tracing")]
+                tracing::trace!(state = %self.states[idx_exit].name, "exit");
+                #[cfg(feature = "counters")]
+                {
+                    self.states[idx_exit].exit_cnt += 1;
+                }
+                self.record_event(EventKind::Exit, idx_exit, msg);
+                #[cfg(feature = "timing")]
+                let start = self.clock.now();
+                state_exit.call(&mut self.sm.borrow_mut(), msg);
+                #[cfg(feature = "timing")]
+                {
+                    self.states[idx_exit].exit_time += self.clock.now() - start;
+                }
+                self.states[idx_exit].active = false;
+
+                return Some(idx_exit);
+            }
+            self.states[idx_exit].active = false;
+        }
+
+        None
+    }
+
+    // Execute the exit functions of idxs_exit_fns
+    fn exit_states(&mut self, msg: &P) {
+        while self.exit_next(msg).is_some() {}
+    }
+
+    // Wind down the machine by executing the exit functions of the
+    // entered states of every region, leaf first and root last. States
+    // whose enter functions are still pending aren't exited. Afterwards
+    // all states are inactive and dispatch does nothing.
+    pub fn shutdown(&mut self, msg: &P) {
+        if self.stopped {
+            return;
+        }
+
+        for region in 0..self.regions.len() {
+            self.select_region(region);
+
+            let idx_leaf = self.idx_current_state;
+            let idxs_exit: Vec<I> = core::iter::once(idx_leaf)
+                .chain(Ancestors::new(&self.states, idx_leaf))
+                .map(to_idx::<I>)
+                .filter(|idx| !self.idxs_enter_fns.contains(idx))
+                .collect();
+            self.idxs_enter_fns.clear();
+            self.idxs_exit_fns.clear();
+            self.idxs_exit_fns.extend(idxs_exit);
+            self.exit_states(msg);
+            self.current_state_changed = false;
+        }
+        self.select_region(0);
+
+        for state in self.states.iter_mut() {
+            state.active = false;
+        }
+        self.stopped = true;
+    }
+
+    // True once shutdown has been called
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // Dispatch msg to the current state of each region, returns true
+    // if any region transitioned.
+    pub fn dispatch(&mut self, msg: &P) -> bool {
+        self.dispatch_result(msg).transitioned
+    }
+
+    // Dispatch msg like dispatch, returns which state handled it in
+    // each region as well as whether any region transitioned
+    pub fn dispatch_result(&mut self, msg: &P) -> DispatchResult {
+        let mut session = self.begin_dispatch(msg);
+        while session.step().is_some() {}
+
+        session.result()
+    }
+
+    // Begin dispatching msg one step at a time, see StepSession
+    pub fn begin_dispatch<'a>(&'a mut self, msg: &'a P) -> StepSession<'a, SM, P, I> {
+        if let Some(idx) = self.in_dispatch.get() {
+            self.reentrant_panic(idx, msg);
+        }
+        #[cfg(feature = "counters")]
+        if !self.stopped {
+            self.dispatch_cnt += 1;
+        }
+        #[cfg(feature = "messaging")]
+        if let Some(watchdog) = self.watchdog.as_mut() {
+            watchdog.last_activity = self.clock.now();
+            watchdog.stalled = false;
+        }
+
+        self.session(msg)
+    }
+
+    // Make the transitions of the current states whose timeout has
+    // elapsed, see StateInfo::timeout. msg is passed to the enter and
+    // exit fns as if it had been dispatched but no process fns are
+    // called. Returns true if any region transitioned.
+    #[cfg(feature = "std")]
+    pub fn poll_timeouts(&mut self, msg: &P) -> bool {
+        if let Some(idx) = self.in_dispatch.get() {
+            self.reentrant_panic(idx, msg);
+        }
+        let now = self.clock.now();
+        let mut session = self.session(msg);
+        session.timeouts_at = Some(now);
+        while session.step().is_some() {}
+
+        session.transitioned()
+    }
+
+    // Transition if the timeout of the current state has elapsed at now
+    #[cfg(feature = "std")]
+    fn timeout(&mut self, msg: &P, now: Instant) {
+        if let Some((duration, idx_target)) = self.states[self.idx_current_state].timeout {
+            if now.saturating_duration_since(self.entered_at[self.idx_region]) >= duration {
+                #[cfg(feature = "counters")]
+                {
+                    self.timeout_cnt += 1;
+                }
+                self.idx_transition_dest = Some(idx_target);
+                self.transition(msg);
+            }
+        }
+    }
+
+    // A session that hasn't yet done anything
+    fn session<'a>(&'a mut self, msg: &'a P) -> StepSession<'a, SM, P, I> {
+        let phase = if self.stopped {
+            StepPhase::Done
+        } else {
+            StepPhase::Region(0)
+        };
+        #[cfg(feature = "log")]
+        log::trace!(
+            "dispatch name={} state={}",
+            self.name,
+            self.get_current_state_name()
+        );
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!(
+            "dispatch",
+            name = %self.name,
+            state = %self.get_current_state_name()
+        );
+        self.idxs_handled_by.fill(None);
+        #[cfg(feature = "serde")]
+        {
+            self.outcomes.clear();
+            self.outcomes
+                .resize(self.regions.len(), RegionOutcome::default());
+        }
+
+        StepSession {
+            executor: self,
+            msg,
+            phase,
+            transitioned: false,
+            redirects: 0,
+            #[cfg(feature = "std")]
+            timeouts_at: None,
+            #[cfg(feature = "messaging")]
+            region: None,
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+}
+
+// The channels, dispatcher and defer support
+#[cfg(feature = "messaging")]
+impl<SM, P, I> Executor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    // TODO: More testing at warnings are needed that defering messages
+    // is "dangerous" and processing time increases for new messages. There
+    // maybe other dangers too!
+    pub fn dispatcher(&mut self, msg: &P) -> DispatcherOutcome {
+        //log::trace!("dispatcher:+ msg={msg:?} sm={:?}", self.get_sm());
+        self.current_seq.set(Some(self.new_seq()));
+        let transitioned = self.dispatch(msg);
+        //log::trace!("dispatcher:  msg={msg:?} sm={:?} ret={transitioned}", self.get_sm());
+
+        let mut outcome = match self.defer_policy {
+            DeferPolicy::OnTransition => self.reprocess_deferred(transitioned),
+            DeferPolicy::Always => self.reprocess_deferred(true),
+            DeferPolicy::Manual => DispatcherOutcome {
+                deferred_remaining: self.get_deferred_cnt(),
+                ..DispatcherOutcome::default()
+            },
+        };
+        outcome.transitioned |= transitioned;
+        outcome.messages_processed += 1;
+
+        // At this point we've processed the incoming message and let
+        // the SM reprocessed all deferred messages at least one more
+        // time after each subsequent transition.
+        //
+        // There may still have deferred messages but the SM didn't
+        // transition so those will be processed after this fn is
+        // called with a new message which causes a transition.
+
+        //log::trace!("dispatcher:- msg={msg:?} sm={:?}", self.get_sm());
+        self.current_seq.set(None);
+        outcome
+    }
+
+    // Reprocess the deferred messages as dispatcher would after a
+    // transition, this is how they're processed with DeferPolicy::Manual.
+    // Returns the number of deferred messages dispatched.
+    pub fn process_deferred(&mut self) -> usize {
+        let outcome = self.reprocess_deferred(true);
+        self.current_seq.set(None);
+
+        outcome.messages_processed
+    }
+
+    // Process all deferred messages if transitioned, and again after
+    // each pass in which they cause a transition. The outcome only
+    // counts the deferred messages.
+    fn reprocess_deferred(&mut self, mut transitioned: bool) -> DispatcherOutcome {
+        let mut outcome = DispatcherOutcome::default();
+        let mut passes = 0;
+        while transitioned {
+            //log::trace!("dispatcher:  TOL transitioned");
+            if passes == self.max_defer_passes {
+                self.defer_overflow_cnt += 1;
+                outcome.truncated = true;
+                break;
+            }
+            passes += 1;
+            transitioned = false;
+
+            // Switch to next set of deferred messages
+            self.next_defer();
+
+            // And process all of them before we do another next_defer().
+            // If we didn't do this we could process newly deferred message
+            // before we process previously deferred messages. In other words,
+            // we guarantee that previously sent messages are always processed
+            // before newly sent messages! The sequence numbers of the
+            // messages they were deferred at check this.
+            let mut last_deferred_at = 0;
+            while let Ok(envelope) = self.defer_try_recv_envelope() {
+                debug_assert!(
+                    envelope.deferred_at >= last_deferred_at,
+                    "message deferred at seq {} processed after one deferred at seq {last_deferred_at}",
+                    envelope.deferred_at
+                );
+                last_deferred_at = envelope.deferred_at;
+                self.current_seq.set(Some(envelope.seq));
+                self.current_origin.set(MessageOrigin::Deferred {
+                    attempts: envelope.attempts,
+                });
+                self.current_deferred_at.set(Some(envelope.deferred_at));
+                let m = envelope.msg;
+                //log::trace!("dispatcher:  deferred msg={m:?} sm={:?}", self.get_sm());
+                transitioned |= self.dispatch_deferred(&m, envelope.region);
+                outcome.transitioned |= transitioned;
+                outcome.messages_processed += 1;
+                //log::trace!("dispatcher:  deferred msg={m:?} sm={:?} ret={transitioned}", self.get_sm());
+            }
+        }
+
+        self.current_origin.set(MessageOrigin::Fresh);
+        self.current_deferred_at.set(None);
+        outcome.deferred_remaining = self.get_deferred_cnt();
+
+        outcome
+    }
+
+    // Dispatch a deferred message to the region that deferred it, or to
+    // every region if it was deferred outside of dispatch
+    fn dispatch_deferred(&mut self, msg: &P, region: Option<usize>) -> bool {
+        let mut session = self.begin_dispatch(msg);
+        session.region = region;
+        while session.step().is_some() {}
+
+        session.transitioned()
+    }
+
+    fn new_seq(&self) -> u64 {
+        let seq = self.next_seq.get();
+        self.next_seq.set(seq + 1);
+
+        seq
+    }
+
+    // The sequence number of the message dispatcher is processing, a
+    // deferred message keeps the number it was first given.
+    pub fn current_seq(&self) -> Option<u64> {
+        self.current_seq.get()
+    }
+
+    // Whether the message being processed was deferred and how many
+    // times, it's Fresh outside of dispatcher and process_deferred.
+    pub fn current_message_origin(&self) -> MessageOrigin {
+        self.current_origin.get()
+    }
+
+    // Pass each message to dispatcher returning their results. If the
+    // executor is stopped by shutdown the remaining messages are not
+    // dispatched, so the length of the result is the position reached.
+    pub fn dispatch_iter<'a>(
+        &mut self,
+        msgs: impl IntoIterator<Item = &'a P>,
+    ) -> Vec<DispatcherOutcome>
+    where
+        P: 'a,
+    {
+        let mut results = Vec::new();
+        for msg in msgs {
+            if self.stopped {
+                break;
+            }
+            results.push(self.dispatcher(msg));
+        }
+
+        results
+    }
+
+    // Connect the output port to a downstream channel, typically another
+    // executor's clone_sender. Messages passed to emit are converted by
+    // map and those it returns Some for are sent downstream. Replaces
+    // any previous pipe.
+    pub fn pipe_to<Q>(&self, other_sender: impl Into<MsgSender<Q>>, map: fn(&P) -> Option<Q>)
+    where
+        P: 'static,
+        Q: Send + 'static,
+    {
+        let tx = other_sender.into();
+        *self.output.borrow_mut() = Some(Box::new(move |m: P| match map(&m) {
+            Some(q) => tx.send(q).is_ok(),
+            None => false,
+        }));
+    }
+
+    // Send m out of the output port, unlike send it doesn't come back to
+    // this executor. Returns true if m was sent downstream.
+    pub fn emit(&self, m: P) -> bool {
+        let emitted = match self.output.borrow().as_ref() {
+            Some(output) => output(m),
+            None => false,
+        };
+        if emitted {
+            self.emitted_cnt.set(self.emitted_cnt.get() + 1);
+        }
+
+        emitted
+    }
+
+    // The number of messages emit sent downstream
+    pub fn get_emitted_cnt(&self) -> usize {
+        self.emitted_cnt.get()
+    }
+
+    // The number of times dispatcher returned a truncated outcome
+    pub fn get_defer_overflow_cnt(&self) -> usize {
+        self.defer_overflow_cnt
+    }
+
+    // Receive the next message, those sent with send_priority come
+    // before those sent with send. Each are received in the order they
+    // were sent. Fails once none are waiting and every sender from
+    // clone_sender and clone_priority_sender was dropped, the executor's
+    // own send doesn't keep it connected.
+    pub fn recv(&self) -> Result<P, RecvError> {
+        self.inbox.recv()
+    }
+
+    pub fn try_recv(&self) -> Result<P, TryRecvError> {
+        self.inbox.try_recv()
+    }
+
+    // Waits at most timeout of real time, unlike run_for it doesn't use
+    // the executor's Clock as a Condvar can only wait in real time.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<P, RecvTimeoutError> {
+        self.inbox.recv_timeout(timeout)
+    }
+
+    // Send a WatchdogEvent to tx when no message has been dispatched
+    // for timeout, see check_watchdog. Replaces any previous watchdog.
+    pub fn watchdog(&mut self, timeout: Duration, tx: Sender<WatchdogEvent>) {
+        self.watchdog = Some(Watchdog {
+            timeout,
+            tx,
+            last_activity: self.clock.now(),
+            stalled: false,
+        });
+    }
+
+    // Send WatchdogEvent::Stalled if the watchdog's timeout has elapsed
+    // since the last dispatch, it's sent once per stall. Returns true if
+    // it was sent. The run_* fns call this after each message and run_for
+    // also while waiting for one.
+    pub fn check_watchdog(&mut self) -> bool {
+        let now = self.clock.now();
+        match self.watchdog.as_mut() {
+            Some(watchdog)
+                if !watchdog.stalled
+                    && now.saturating_duration_since(watchdog.last_activity)
+                        >= watchdog.timeout =>
+            {
+                watchdog.stalled = true;
+                // A disconnected receiver isn't interested
+                let _ = watchdog.tx.send(WatchdogEvent::Stalled {
+                    machine: self.name.clone(),
+                    since: watchdog.last_activity,
+                });
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Receive messages and pass them to dispatcher until duration has elapsed,
+    // returns the number of messages processed. The deadline is measured
+    // with the executor's Clock, with a ManualClock it's reached when the
+    // clock is advanced past it while waiting for a message in real time.
+    pub fn run_for(&mut self, duration: Duration) -> usize {
+        let deadline = self.clock.now() + duration;
+        let mut msg_cnt = 0;
+        loop {
+            let remaining = deadline.saturating_duration_since(self.clock.now());
+            if remaining.is_zero() {
+                break;
+            }
+            let wait = match self.watchdog.as_ref() {
+                Some(watchdog) => remaining.min(watchdog.timeout),
+                None => remaining,
+            };
+            match self.recv_timeout(wait) {
+                Ok(m) => {
+                    self.dispatcher(&m);
+                    msg_cnt += 1;
+                }
+                Err(RecvTimeoutError::Timeout) if wait < remaining => {}
+                Err(_) => break,
+            }
+            self.check_watchdog();
+        }
+
+        msg_cnt
+    }
+
+    // Receive messages and pass them to dispatcher until pred returns
+    // true, pred is called before the first message and after each one.
+    pub fn run_until<F>(&mut self, pred: F) -> RunOutcome
+    where
+        F: FnMut(&Executor<SM, P, I>) -> bool,
+    {
+        self.run_until_budget(usize::MAX, pred)
+    }
+
+    // Like run_until but processes at most max_msgs messages
+    pub fn run_until_budget<F>(&mut self, max_msgs: usize, mut pred: F) -> RunOutcome
+    where
+        F: FnMut(&Executor<SM, P, I>) -> bool,
+    {
+        if pred(self) {
+            return RunOutcome::Done;
+        }
+        for _ in 0..max_msgs {
+            match self.recv() {
+                Ok(m) => {
+                    self.dispatcher(&m);
+                    self.check_watchdog();
+                    if pred(self) {
+                        return RunOutcome::Done;
+                    }
+                }
+                Err(_) => return RunOutcome::Disconnected,
+            }
+        }
+
+        RunOutcome::BudgetExhausted
+    }
+
+    pub fn send(&self, m: P) -> Result<(), SendError<P>> {
+        self.inbox.send(m, false)
+    }
+
+    pub fn try_send(&self, m: P) -> Result<(), TrySendError<P>> {
+        self.inbox.try_send(m, false)
+    }
+
+    pub fn clone_sender(&self) -> MsgSender<P> {
+        MsgSender::Executor(self.inbox.sender(false))
+    }
+
+    // Send m ahead of the messages waiting in the primary channel, e.g.
+    // a shutdown. The priority channel is unbounded.
+    pub fn send_priority(&self, m: P) -> Result<(), SendError<P>> {
+        self.inbox.send(m, true)
+    }
+
+    pub fn clone_priority_sender(&self) -> MsgSender<P> {
+        MsgSender::Executor(self.inbox.sender(true))
+    }
+
+    // Add a channel named name and return a sender for it, the messages
+    // sent to it are dispatched by pump_once. If there's already a
+    // channel named name another sender for it is returned.
+    pub fn add_channel(&mut self, name: &str) -> Sender<P> {
+        if let Some(channel) = self.channels.iter().find(|c| c.name == name) {
+            return channel.tx.clone();
+        }
+        let (tx, rx) = std::sync::mpsc::channel::<P>();
+        self.channels.push(NamedChannel {
+            name: name.to_owned(),
+            tx: tx.clone(),
+            rx,
+        });
+
+        tx
+    }
+
+    // Pass one message from the named channels to dispatcher, the channel
+    // is chosen as set by ExecutorBuilder::pump_order. Returns the name
+    // of the channel and what dispatcher did, None if every channel is
+    // empty.
+    pub fn pump_once(&mut self) -> Option<(String, DispatcherOutcome)> {
+        let channel_cnt = self.channels.len();
+        let idx_first = match self.pump_order {
+            PumpOrder::RoundRobin => self.idx_next_channel,
+            PumpOrder::Priority => 0,
+        };
+        for i in 0..channel_cnt {
+            let idx = (idx_first + i) % channel_cnt;
+            if let Ok(m) = self.channels[idx].rx.try_recv() {
+                self.idx_next_channel = (idx + 1) % channel_cnt;
+                let name = self.channels[idx].name.clone();
+                #[cfg(feature = "log")]
+                log::trace!("pump channel={name}");
+                #[cfg(feature = "tracing")]
+                let span = tracing::trace_span!("pump", channel = %name);
+                #[cfg(feature = "tracing")]
+                let _entered = span.enter();
+                let result = self.dispatcher(&m);
+
+                return Some((name, result));
+            }
+        }
+
+        None
+    }
+
+    // Pass every message waiting in the primary channel to dispatcher,
+    // returns the number of messages processed.
+    pub fn dispatch_all(&mut self) -> usize {
+        self.process_pending(usize::MAX)
+    }
+
+    // Like dispatch_all but processes at most max messages, so a shared
+    // event loop can yield between calls. Returns the number of messages
+    // processed, 0 if none were waiting.
+    pub fn process_pending(&mut self, max: usize) -> usize {
+        let mut msg_cnt = 0;
+        while msg_cnt < max {
+            match self.try_recv() {
+                Ok(m) => {
+                    self.dispatcher(&m);
+                    msg_cnt += 1;
+                }
+                Err(_) => break,
+            }
+        }
+
+        msg_cnt
+    }
+
+    pub fn defer_try_recv(&self) -> Result<P, TryRecvError> {
+        Ok(self.defer_try_recv_envelope()?.msg)
+    }
+
+    fn defer_try_recv_envelope(&self) -> Result<Envelope<P>, TryRecvError> {
+        let envelope = self.defer_rx[self.other_defer()].try_recv()?;
+        self.deferred_cnt.set(self.deferred_cnt.get() - 1);
+
+        Ok(envelope)
+    }
+
+    // Defer m, a new message with its own sequence number and its first
+    // attempt. It's only reprocessed by the region deferring it, use
+    // defer_current to defer the message being processed.
+    pub fn defer_send(&self, m: P) -> Result<(), SendError<P>> {
+        let region = self.is_dispatching().then_some(self.idx_region);
+        self.defer_send_from(m, region, false)
+    }
+
+    // Defer m, with carry_origin it's the message being processed and
+    // keeps its sequence number and adds an attempt, once there are more
+    // than max_defer_attempts it's sent to dead_letters instead.
+    fn defer_send_from(
+        &self,
+        m: P,
+        region: Option<usize>,
+        carry_origin: bool,
+    ) -> Result<(), SendError<P>> {
+        let (seq, attempts) = match (carry_origin, self.current_seq.get()) {
+            (true, Some(seq)) => match self.current_origin.get() {
+                MessageOrigin::Fresh => (seq, 1),
+                MessageOrigin::Deferred { attempts } => (seq, attempts + 1),
+            },
+            _ => (self.new_seq(), 1),
+        };
+        let deferred_at = self
+            .current_deferred_at
+            .get()
+            .or(self.current_seq.get())
+            .unwrap_or(seq);
+        if matches!(self.max_defer_attempts, Some(max) if attempts > max) {
+            // The executor holds both ends of the dead letter channel
+            self.dead_letter_tx.send(m).expect("SNH");
+            return Ok(());
+        }
+        self.defer_tx[self.current_defer()]
+            .send(Envelope {
+                seq,
+                attempts,
+                deferred_at,
+                region,
+                msg: m,
+            })
+            .map_err(|SendError(envelope)| SendError(envelope.msg))?;
+        self.deferred_cnt.set(self.deferred_cnt.get() + 1);
+
+        Ok(())
+    }
+
+    // The number of deferred messages waiting to be processed
+    pub fn get_deferred_cnt(&self) -> usize {
+        self.deferred_cnt.get()
+    }
+
+    // Drop the messages in both defer channels, called when entering a
+    // state with StateInfo::clears_deferred
+    fn drop_deferred(&mut self) {
+        for defer_rx in self.defer_rx.iter() {
+            while let Ok(envelope) = defer_rx.try_recv() {
+                self.deferred_cnt.set(self.deferred_cnt.get() - 1);
+                self.deferred_dropped_cnt += 1;
+                if let Some(on_deferred_dropped) = self.on_deferred_dropped {
+                    on_deferred_dropped(&mut self.sm.borrow_mut(), &envelope.msg);
+                }
+            }
+        }
+    }
+
+    // The number of deferred messages dropped by entering states with
+    // StateInfo::clears_deferred
+    pub fn get_deferred_dropped_cnt(&self) -> usize {
+        self.deferred_dropped_cnt
+    }
+
+    // The messages dropped for being deferred more than
+    // max_defer_attempts times
+    pub fn dead_letters(&self) -> &Receiver<P> {
+        &self.dead_letter_rx
+    }
+
+    pub fn next_defer(&mut self) {
+        self.current_defer_idx = (self.current_defer_idx + 1) % self.defer_tx.len();
+    }
+
+    pub fn current_defer(&self) -> usize {
+        self.current_defer_idx
+    }
+
+    pub fn other_defer(&self) -> usize {
+        (self.current_defer_idx + 1) % self.defer_tx.len()
+    }
+}
+
+#[cfg(feature = "messaging")]
+impl<SM, P, I> Executor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug + Clone,
+    I: StateIdx,
+{
+    // Defer the message being processed, it's cloned into the defer
+    // channel when the process fn returns.
+    pub fn defer_current(&self) -> Result<(), DeferError> {
+        if !self.is_dispatching() {
+            return Err(DeferError::NotDispatching);
+        }
+        if self.defer_current.get().is_some() {
+            return Err(DeferError::AlreadyDeferred);
+        }
+        self.defer_current.set(Some(P::clone));
+
+        Ok(())
+    }
+}
+
+// Record and replay
+#[cfg(feature = "serde")]
+impl<SM, P, I> Executor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    // Append each message dispatched from now on, and what each region
+    // did with it, as a JSON line to writer. Recording stops if writing
+    // fails. Replay the lines with replay.
+    pub fn record_to(&mut self, writer: impl std::io::Write + Send + 'static)
+    where
+        P: Serialize,
+    {
+        fn record<P: Serialize>(
+            writer: &mut dyn std::io::Write,
+            msg: &P,
+            outcomes: &[RegionOutcome],
+        ) -> std::io::Result<()> {
+            let recorded = Recorded {
+                msg,
+                outcomes: outcomes.to_vec(),
+            };
+            serde_json::to_writer(&mut *writer, &recorded)?;
+            writer.write_all(b"\n")
+        }
+
+        self.recorder = Some((Box::new(writer), record::<P>));
+    }
+
+    // Stop recording, returns true if it was recording
+    pub fn stop_recording(&mut self) -> bool {
+        self.recorder.take().is_some()
+    }
+
+    // What each region did with the last message dispatched
+    pub fn last_outcomes(&self) -> &[RegionOutcome] {
+        &self.outcomes
+    }
+
+    fn record(&mut self, msg: &P) {
+        if let Some((writer, record)) = self.recorder.as_mut() {
+            if record(writer.as_mut(), msg, &self.outcomes).is_err() {
+                self.recorder = None;
+            }
+        }
+    }
+}
+
+// Dispatch each line written by record_to and report those whose
+// outcome differs from the recorded one. The executor should be in
+// the state the recording one was in when record_to was called.
+#[cfg(feature = "serde")]
+pub fn replay<SM, P, I>(
+    reader: impl std::io::BufRead,
+    executor: &mut Executor<SM, P, I>,
+) -> ReplayReport
+where
+    SM: Debug,
+    P: Debug + DeserializeOwned,
+    I: StateIdx,
+{
+    let mut report = ReplayReport::default();
+    for (idx, line) in reader.lines().enumerate() {
+        let line_nr = idx + 1;
+        let recorded: Recorded<P> = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => match serde_json::from_str(&line) {
+                Ok(recorded) => recorded,
+                Err(e) => {
+                    report.errors.push((line_nr, e.to_string()));
+                    continue;
+                }
+            },
+            Err(e) => {
+                report.errors.push((line_nr, e.to_string()));
+                break;
+            }
+        };
+
+        executor.dispatch(&recorded.msg);
+        report.dispatched += 1;
+        if executor.last_outcomes() != recorded.outcomes.as_slice() {
+            report.divergences.push(Divergence {
+                line: line_nr,
+                recorded: recorded.outcomes,
+                replayed: executor.last_outcomes().to_vec(),
+            });
+        }
+    }
+
+    report
+}
+
+// Prints the name, the state tree with `*` marking active states and `->`
+// the current leaf of each region, the counters of each state if enabled
+// and the number of deferred messages if messaging is enabled.
+impl<SM, P, I> Debug for Executor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Executor name={:?}", self.name)?;
+        #[cfg(feature = "messaging")]
+        write!(f, " deferred_cnt={}", self.get_deferred_cnt())?;
+        writeln!(f)?;
+        match self.sm.try_borrow() {
+            Ok(sm) => writeln!(f, "  sm={sm:?}")?,
+            Err(_) => writeln!(f, "  sm=<borrowed>")?,
+        }
+        for state in self.iter_states() {
+            let active = if state.is_active() { "* " } else { "  " };
+            let current = if self.get_region_current_state(state.region()) == state.idx().raw() {
+                "-> "
+            } else {
+                ""
+            };
+            write!(
+                f,
+                "  {:indent$}{active}{current}{}",
+                "",
+                state.name(),
+                indent = self.depth(state.idx().raw()) * 2
+            )?;
+            #[cfg(feature = "counters")]
+            {
+                let counters = state.counters();
+                write!(
+                    f,
+                    " enter_cnt={} process_cnt={} exit_cnt={}",
+                    counters.enter_cnt, counters.process_cnt, counters.exit_cnt,
+                )?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Prints the name and the state path of each region, e.g.
+// `traffic_light[vehicle_base/green]`.
+impl<SM, P, I> Display for Executor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let paths: Vec<String> = (0..self.get_region_count())
+            .map(|region| self.path(self.get_region_current_state(region)))
+            .collect();
+
+        write!(f, "{}[{}]", self.name, paths.join(", "))
+    }
+}
+
+// A copy for speculative evaluation, it has its own SM and counters but
+// new empty channels and no named channels, so pending and deferred
+// messages aren't cloned and the output port isn't connected nor is it
+// tracing, recording or watched. Must not be called while dispatching.
+impl<SM: Clone, P, I: Clone> Clone for Executor<SM, P, I> {
+    fn clone(&self) -> Self {
+        #[cfg(feature = "messaging")]
+        let inbox = InboxReceiver::new(self.primary_capacity);
+        #[cfg(feature = "messaging")]
+        let (defer0_tx, defer0_rx) = std::sync::mpsc::channel::<Envelope<P>>();
+        #[cfg(feature = "messaging")]
+        let (defer1_tx, defer1_rx) = std::sync::mpsc::channel::<Envelope<P>>();
+        #[cfg(feature = "messaging")]
+        let (dead_letter_tx, dead_letter_rx) = std::sync::mpsc::channel::<P>();
+
+        Executor {
+            name: self.name.clone(),
+            sm: RefCell::new(self.sm.borrow().clone()),
+            states: self.states.clone(),
+            current_state_changed: self.current_state_changed,
+            idx_transition_dest: self.idx_transition_dest,
+            idx_current_state: self.idx_current_state,
+            idx_previous_state: self.idx_previous_state,
+            idxs_enter_fns: self.idxs_enter_fns.clone(),
+            idxs_exit_fns: self.idxs_exit_fns.clone(),
+            transition_targets: self.transition_targets.clone(),
+            transition_targets_set: self.transition_targets_set.clone(),
+            regions: self.regions.clone(),
+            idx_region: self.idx_region,
+            idx_recovery_state: self.idx_recovery_state,
+            panic_cnt: self.panic_cnt,
+            in_dispatch: Cell::new(None),
+            in_dispatch_msg: RefCell::new(String::new()),
+            reentrant_dispatch: self.reentrant_dispatch,
+            stopped: self.stopped,
+            pending_action: Cell::new(None),
+            transition_action: None,
+            idx_redirect: None,
+            max_redirects: self.max_redirects,
+            redirect_cnt: self.redirect_cnt,
+            idxs_handled_by: self.idxs_handled_by.clone(),
+            #[cfg(feature = "counters")]
+            transition_cnts: self.transition_cnts.clone(),
+            #[cfg(feature = "counters")]
+            dispatch_cnt: self.dispatch_cnt,
+            #[cfg(feature = "counters")]
+            unhandled_cnt: self.unhandled_cnt,
+            #[cfg(feature = "counters")]
+            timeout_cnt: self.timeout_cnt,
+            event_log: self.event_log.clone(),
+            event_log_capacity: self.event_log_capacity,
+            event_log_msgs: self.event_log_msgs,
+            tracer: None,
+            #[cfg(feature = "std")]
+            clock: Arc::clone(&self.clock),
+            #[cfg(feature = "std")]
+            entered_at: self.entered_at.clone(),
+            #[cfg(feature = "messaging")]
+            inbox,
+            #[cfg(feature = "messaging")]
+            primary_capacity: self.primary_capacity,
+            #[cfg(feature = "messaging")]
+            channels: Vec::new(),
+            #[cfg(feature = "messaging")]
+            idx_next_channel: 0,
+            #[cfg(feature = "messaging")]
+            pump_order: self.pump_order,
+            #[cfg(feature = "messaging")]
+            defer_tx: [defer0_tx, defer1_tx],
+            #[cfg(feature = "messaging")]
+            defer_rx: [defer0_rx, defer1_rx],
+            #[cfg(feature = "messaging")]
+            current_defer_idx: 0,
+            #[cfg(feature = "messaging")]
+            deferred_cnt: Cell::new(0),
+            #[cfg(feature = "messaging")]
+            max_defer_passes: self.max_defer_passes,
+            #[cfg(feature = "messaging")]
+            defer_policy: self.defer_policy,
+            #[cfg(feature = "messaging")]
+            defer_overflow_cnt: self.defer_overflow_cnt,
+            #[cfg(feature = "messaging")]
+            max_defer_attempts: self.max_defer_attempts,
+            #[cfg(feature = "messaging")]
+            dead_letter_tx,
+            #[cfg(feature = "messaging")]
+            dead_letter_rx,
+            #[cfg(feature = "messaging")]
+            on_deferred_dropped: self.on_deferred_dropped,
+            #[cfg(feature = "messaging")]
+            deferred_dropped_cnt: self.deferred_dropped_cnt,
+            #[cfg(feature = "messaging")]
+            next_seq: self.next_seq.clone(),
+            #[cfg(feature = "messaging")]
+            current_seq: Cell::new(None),
+            #[cfg(feature = "messaging")]
+            current_origin: Cell::new(MessageOrigin::Fresh),
+            #[cfg(feature = "messaging")]
+            current_deferred_at: Cell::new(None),
+            #[cfg(feature = "messaging")]
+            defer_current: Cell::new(None),
+            #[cfg(feature = "messaging")]
+            watchdog: None,
+            #[cfg(feature = "messaging")]
+            output: RefCell::new(None),
+            #[cfg(feature = "messaging")]
+            emitted_cnt: self.emitted_cnt.clone(),
+            #[cfg(feature = "serde")]
+            outcomes: self.outcomes.clone(),
+            #[cfg(feature = "serde")]
+            recorder: None,
+        }
+    }
+}
+
+// Where a StepSession is in the dispatch of its message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepPhase {
+    Region(usize),
+    Enter(usize),
+    Redirect(usize),
+    Process(usize, usize),
+    Exit(usize),
+    Done,
+
+    // While a step runs, it stays so if a user fn panics
+    Stepping,
+}
+
+// What a step did, see StepSession::step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepEvent {
+    Enter(usize),
+    Process(usize),
+    Exit(usize),
+
+    // The action of a transition to the state, see transition_with
+    Action(usize),
+}
+
+// What dispatching a message did, see Executor::dispatch_result.
+// handled_by has an entry per region, the state whose process fn
+// returned Handled::Yes, the current state or one of its ancestors, or
+// None if no state handled the message.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DispatchResult {
+    pub transitioned: bool,
+    pub handled_by: Vec<Option<usize>>,
+}
+
+impl DispatchResult {
+    // True if a state handled the message in any region
+    pub fn handled(&self) -> bool {
+        self.handled_by.iter().any(Option::is_some)
+    }
+}
+
+// Dispatches a message one enter, process or exit fn per step, this is
+// how dispatch itself runs. Dropping a session runs its remaining steps
+// so the executor is always left as dispatch would leave it.
+pub struct StepSession<'a, SM, P, I = usize>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    executor: &'a mut Executor<SM, P, I>,
+    msg: &'a P,
+    phase: StepPhase,
+    transitioned: bool,
+    redirects: usize,
+
+    // Set by poll_timeouts, the timeouts are checked rather than
+    // processing the message.
+    #[cfg(feature = "std")]
+    timeouts_at: Option<Instant>,
+
+    // Set when reprocessing a deferred message, only this region
+    // processes it.
+    #[cfg(feature = "messaging")]
+    region: Option<usize>,
+
+    // Entered by each step so its events are in the dispatch span
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl<'a, SM, P, I> StepSession<'a, SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    // Run the next enter, process or exit fn, None once the message has
+    // been dispatched to every region.
+    pub fn step(&mut self) -> Option<StepEvent> {
+        #[cfg(feature = "tracing")]
+        let _entered = self.span.enter();
+        let e = &mut *self.executor;
+        let msg = self.msg;
+        loop {
+            match core::mem::replace(&mut self.phase, StepPhase::Stepping) {
+                #[cfg(feature = "messaging")]
+                StepPhase::Region(region)
+                    if region < e.regions.len()
+                        && self.region.is_some_and(|only_region| only_region != region) =>
+                {
+                    self.phase = StepPhase::Region(region + 1);
+                }
+                StepPhase::Region(region) if region < e.regions.len() => {
+                    e.select_region(region);
+                    self.phase = if e.current_state_changed {
+                        StepPhase::Enter(region)
+                    } else {
+                        StepPhase::Process(region, e.idx_current_state)
+                    };
+                }
+                StepPhase::Region(_) => {
+                    e.select_region(0);
+                    #[cfg(feature = "serde")]
+                    if self.timeouts_at.is_none() {
+                        e.record(msg);
+                    }
+                    self.phase = StepPhase::Done;
+                }
+                StepPhase::Enter(region) => {
+                    if let Some(idx) = e.enter_next(msg) {
+                        self.phase = StepPhase::Enter(region);
+                        return Some(StepEvent::Enter(idx));
+                    }
+                    if let Some(idx_redirect) = e.idx_redirect.take() {
+                        self.redirects += 1;
+                        e.redirect(idx_redirect, self.redirects, msg);
+                        self.transitioned = true;
+                        self.phase = StepPhase::Redirect(region);
+                        continue;
+                    }
+                    e.current_state_changed = false;
+                    self.phase = StepPhase::Process(region, e.idx_current_state);
+                }
+                #[cfg(feature = "std")]
+                StepPhase::Process(region, _) if self.timeouts_at.is_some() => {
+                    e.timeout(msg, self.timeouts_at.unwrap());
+                    self.transitioned |= e.current_state_changed;
+                    self.phase = StepPhase::Exit(region);
+                }
+                StepPhase::Process(region, idx) => {
+                    match e.process(msg, idx) {
+                        Some(idx_parent) => self.phase = StepPhase::Process(region, idx_parent),
+                        None => {
+                            e.transition(msg);
+                            self.transitioned |= e.current_state_changed;
+                            self.phase = StepPhase::Exit(region);
+                        }
+                    }
+                    return Some(StepEvent::Process(idx));
+                }
+                StepPhase::Redirect(region) => {
+                    if let Some(idx) = e.exit_next(msg) {
+                        self.phase = StepPhase::Redirect(region);
+                        return Some(StepEvent::Exit(idx));
+                    }
+                    self.phase = StepPhase::Enter(region);
+                }
+                StepPhase::Exit(region) => {
+                    if e.current_state_changed {
+                        if let Some(idx) = e.exit_next(msg) {
+                            self.phase = StepPhase::Exit(region);
+                            return Some(StepEvent::Exit(idx));
+                        }
+                    }
+                    if let Some(action) = e.transition_action.take() {
+                        action(&mut e.sm.borrow_mut(), msg);
+                        self.phase = StepPhase::Exit(region);
+                        return Some(StepEvent::Action(e.idx_current_state));
+                    }
+                    self.phase = StepPhase::Region(region + 1);
+                }
+                StepPhase::Done | StepPhase::Stepping => {
+                    self.phase = StepPhase::Done;
+                    return None;
+                }
+            }
+        }
+    }
+
+    // True if any region has transitioned so far
+    pub fn transitioned(&self) -> bool {
+        self.transitioned
+    }
+
+    // What the session has done so far, the handled_by of a region is
+    // None until one of its states handles the message
+    pub fn result(&self) -> DispatchResult {
+        DispatchResult {
+            transitioned: self.transitioned,
+            handled_by: self.executor.idxs_handled_by.clone(),
+        }
+    }
+}
+
+impl<'a, SM, P, I> Drop for StepSession<'a, SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    fn drop(&mut self) {
+        // Don't run more user fns while unwinding from a panic in one
+        if self.phase == StepPhase::Stepping {
+            return;
+        }
+        while self.step().is_some() {}
+    }
+}
+
+// A set of executors, e.g. one per connection, that messages can be
+// broadcast to. Executors are kept, and iterated, in the order pushed.
+pub struct ExecutorGroup<SM, P, I = usize> {
+    executors: Vec<Executor<SM, P, I>>,
+}
+
+impl<SM, P, I> Default for ExecutorGroup<SM, P, I> {
+    fn default() -> Self {
+        ExecutorGroup {
+            executors: Vec::new(),
+        }
+    }
+}
+
+impl<SM, P, I> ExecutorGroup<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add executor to the group returning its index
+    pub fn push(&mut self, executor: Executor<SM, P, I>) -> usize {
+        self.executors.push(executor);
+
+        self.executors.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.executors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.executors.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> &Executor<SM, P, I> {
+        &self.executors[idx]
+    }
+
+    pub fn get_mut(&mut self, idx: usize) -> &mut Executor<SM, P, I> {
+        &mut self.executors[idx]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Executor<SM, P, I>> + '_ {
+        self.executors.iter()
+    }
+
+    // Dispatch msg to every executor, returns whether each transitioned
+    pub fn broadcast(&mut self, msg: &P) -> Vec<bool> {
+        self.executors
+            .iter_mut()
+            .map(|executor| executor.dispatch(msg))
+            .collect()
+    }
+
+    // Dispatch msg to the executor at idx, returns whether it transitioned
+    pub fn dispatch_to(&mut self, idx: usize, msg: &P) -> bool {
+        self.executors[idx].dispatch(msg)
+    }
+
+    // The current state name of each executor
+    pub fn states(&self) -> Vec<&str> {
+        self.executors
+            .iter()
+            .map(|executor| executor.get_current_state_name())
+            .collect()
+    }
+
+    // True if every executor's current state is name
+    pub fn all_in(&self, name: &str) -> bool {
+        self.executors
+            .iter()
+            .all(|executor| executor.get_current_state_name() == name)
+    }
+}
+
+// An executor that can be driven from several threads, e.g. a timer
+// thread and a network thread. It's Send and Sync when SM and P are Send.
+// dispatch and with lock the executor, send doesn't so it may be called
+// while another thread is dispatching.
+#[cfg(feature = "std")]
+pub struct SharedExecutor<SM, P, I = usize> {
+    executor: Mutex<Executor<SM, P, I>>,
+    #[cfg(feature = "messaging")]
+    tx: MsgSender<P>,
+    #[cfg(feature = "messaging")]
+    priority_tx: MsgSender<P>,
+}
+
+#[cfg(feature = "std")]
+impl<SM, P, I> SharedExecutor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    pub fn new(executor: Executor<SM, P, I>) -> Self {
+        SharedExecutor {
+            #[cfg(feature = "messaging")]
+            tx: executor.clone_sender(),
+            #[cfg(feature = "messaging")]
+            priority_tx: executor.clone_priority_sender(),
+            executor: Mutex::new(executor),
+        }
+    }
+
+    // A process fn that panicked while the executor was locked leaves it
+    // in an unknown state, so the panic is propagated to every user.
+    fn lock(&self) -> MutexGuard<'_, Executor<SM, P, I>> {
+        self.executor
+            .lock()
+            .expect("SharedExecutor poisoned by a panic while dispatching")
+    }
+
+    pub fn dispatch(&self, msg: &P) -> bool {
+        self.lock().dispatch(msg)
+    }
+
+    pub fn dispatch_result(&self, msg: &P) -> DispatchResult {
+        self.lock().dispatch_result(msg)
+    }
+
+    // Call f with the locked executor, e.g. to query its state
+    pub fn with<R>(&self, f: impl FnOnce(&mut Executor<SM, P, I>) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    // Lock the executor and take a snapshot of its counters
+    #[cfg(feature = "counters")]
+    pub fn metrics(&self) -> Metrics {
+        self.lock().metrics()
+    }
+
+    pub fn into_inner(self) -> Executor<SM, P, I> {
+        self.executor
+            .into_inner()
+            .expect("SharedExecutor poisoned by a panic while dispatching")
+    }
+}
+
+#[cfg(feature = "messaging")]
+impl<SM, P, I> SharedExecutor<SM, P, I>
+where
+    SM: Debug,
+    P: Debug,
+    I: StateIdx,
+{
+    // Send m to the primary channel without locking the executor
+    pub fn send(&self, m: P) -> Result<(), SendError<P>> {
+        self.tx.send(m)
+    }
+
+    // Send m to the priority channel without locking the executor
+    pub fn send_priority(&self, m: P) -> Result<(), SendError<P>> {
+        self.priority_tx.send(m)
+    }
+
+    // Lock the executor and process the messages waiting in the primary
+    // channel, see Executor::dispatch_all.
+    pub fn dispatch_all(&self) -> usize {
+        self.lock().dispatch_all()
+    }
+
+    // Lock the executor and process at most max of the messages waiting
+    // in the primary channel, see Executor::process_pending.
+    pub fn process_pending(&self, max: usize) -> usize {
+        self.lock().process_pending(max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Test SM with one state with one field and no enter or exit
+    #[cfg(all(feature = "counters", feature = "messaging"))]
+    #[test]
+    #[no_coverage]
+    fn test_sm_1s_no_enter_no_exit() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            state: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine { state: 0 });
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                println!("{}:+", e.get_state_name(IDX_STATE1));
+
+                self.state += 1;
+
+                println!("{}:-", e.get_state_name(IDX_STATE1));
+                handled()
+            }
+        }
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_sm().borrow().state, 0);
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatcher(&NoMessages);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_sm().borrow().state, 1);
+
+        sme.dispatcher(&NoMessages);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 2);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_sm().borrow().state, 2);
+    }
+
+    // Test SM with one state getting names
+    #[test]
+    #[no_coverage]
+    fn test_sm_1s_get_names() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            state: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine { state: 0 });
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                self.state += 1;
+
+                handled()
+            }
+        }
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(sme.get_sm().borrow().state, 0);
+        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
+        assert_eq!(sme.get_current_state_name(), "state1");
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_sm().borrow().state, 1);
+        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
+        assert_eq!(sme.get_current_state_name(), "state1");
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_sm().borrow().state, 2);
+        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
+        assert_eq!(sme.get_current_state_name(), "state1");
+    }
+
+    // Test SM with two states getting names
+    #[test]
+    #[no_coverage]
+    fn test_sm_2s_get_names() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            state: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine { state: 0 });
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .state(StateInfo::new("state2", Self::state2))
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                self.state += 1;
+
+                transition(e.state_id(IDX_STATE2).unwrap())
+            }
+
+            #[no_coverage]
+            fn state2(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                self.state -= 1;
+
+                transition(e.state_id(IDX_STATE1).unwrap())
+            }
+        }
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(sme.get_sm().borrow().state, 0);
+        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
+        assert_eq!(sme.get_current_state_name(), "state1");
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_sm().borrow().state, 1);
+        assert_eq!(sme.get_state_name(IDX_STATE2), "state2");
+        assert_eq!(sme.get_current_state_name(), "state2");
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_sm().borrow().state, 0);
+        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
+        assert_eq!(sme.get_current_state_name(), "state1");
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    #[should_panic]
+    fn test_sm_out_of_bounds_initial_transition() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const INVALID_STATE: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine);
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .build_idx(INVALID_STATE)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                // Invalid transition to a state that doesn't exist
+                transition(StateId(INVALID_STATE))
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 4);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+
+        // This will panic because state1 returns an invalid transition
+        sme.dispatch(&NoMessages);
+    }
+
+    #[test]
+    #[no_coverage]
+    #[should_panic]
+    fn test_sm_invalid_initial_state() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const _IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine);
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE1))
+                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state2(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                // Invalid transition IDX_STATE1 isn't a leaf
+                transition(e.state_id(IDX_STATE1).unwrap())
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create a sme and validate it's in the expected state
+        let _ = StateMachine::new();
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    #[should_panic(
+        expected = ": transition from 'state2' (idx 1) to 'state1' (idx 0) rejected: target is not a leaf; valid targets: state2"
+    )]
+    fn test_sm_2s_invalid_transition() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine);
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
+                    .build_idx(IDX_STATE2)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state2(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                // Invalid transition IDX_STATE1 isn't a leaf
+                transition(e.state_id(IDX_STATE1).unwrap())
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 8);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+
+        // This will panic because state2 returns an invalid transition
+        // to state1 which isn't a leaf
+        sme.dispatch(&NoMessages);
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_sm_out_of_bounds_invalid_transition() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine);
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                // A state that doesn't exist has no id to transition to
+                assert_eq!(e.state_id(1), None);
+                transition(e.state_id(IDX_STATE1).unwrap())
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 8);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
+        assert_eq!(sme.get_current_state_name(), "state1");
+        assert_eq!(sme.get_state_idx("state1"), sme.state_id(IDX_STATE1));
+        assert_eq!(sme.get_state_idx("state2"), None);
+    }
+
+    // Test SM with one state with one field
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_sm_1s_enter_no_exit() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            state: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Add { val: i32 },
+            Sub { val: i32 },
+        }
+
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, Messages> {
+                let sm = RefCell::new(StateMachine { state: 0 });
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1).enter_fn(Self::state1_enter))
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1_enter(&mut self, _msg: &Messages) {
+                self.state = 100;
+            }
+
+            #[no_coverage]
+            fn state1(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Add { val } => self.state += val,
+                    Messages::Sub { val } => self.state -= val,
+                }
+                handled()
+            }
+        }
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_sm().borrow().state, 0);
+
+        // For code coverage
+        println!("{:?}", Messages::Add { val: -1 });
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatch(&Messages::Add { val: 2 });
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_sm().borrow().state, 102);
+
+        sme.dispatch(&Messages::Sub { val: 1 });
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 2);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_sm().borrow().state, 101);
+    }
+
+    // Test SM with twos state with one field
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_sm_2s_no_enter_no_exit() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            state: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Message {
+            Add { val: i32 },
+        }
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, Message> {
+                let sm = RefCell::new(StateMachine { state: 0 });
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .state(StateInfo::new("state2", Self::state2))
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn state1(&mut self, e: &Executor<Self, Message>, msg: &Message) -> StateResult {
+                match msg {
+                    Message::Add { val } => self.state += val,
+                }
+                transition(e.state_id(IDX_STATE2).unwrap())
+            }
+
+            #[no_coverage]
+            fn state2(&mut self, e: &Executor<Self, Message>, msg: &Message) -> StateResult {
+                match msg {
+                    Message::Add { val } => self.state += 2 * val,
+                }
+                transition(e.state_id(IDX_STATE1).unwrap())
+            }
+        }
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_sm().borrow().state, 0);
+
+        // For code coverage
+        println!("{:?}", Message::Add { val: -2 });
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatch(&Message::Add { val: 2 });
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_sm().borrow().state, 2);
+
+        sme.dispatch(&Message::Add { val: -1 });
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        assert_eq!(sme.get_state_enter_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE2), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_STATE2), 0);
+        assert_eq!(sme.get_sm().borrow().state, 0);
+    }
+
+    // Test SM with twos state with one field
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_sm_1h_2s_not_handled_no_enter_no_exit() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            state: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Message {
+            Add { val: i32 },
+            Sub { val: i32 },
+        }
+
+        const IDX_PARENT: usize = 0;
+        const IDX_CHILD: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, Message> {
+                let sm = RefCell::new(StateMachine { state: 0 });
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("parent", Self::parent))
+                    .state(StateInfo::new("child", Self::child).parent_idx(IDX_PARENT))
+                    .build_idx(IDX_CHILD)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn parent(&mut self, _e: &Executor<Self, Message>, msg: &Message) -> StateResult {
+                match msg {
+                    Message::Add { val } => self.state += val,
+                    Message::Sub { val } => self.state -= val,
+                }
+                handled()
+            }
+
+            #[no_coverage]
+            fn child(&mut self, _e: &Executor<Self, Message>, _msg: &Message) -> StateResult {
+                not_handled()
+            }
+        }
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
+        assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
+        assert_eq!(sme.get_state_enter_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_sm().borrow().state, 0);
+
+        // For code coverage
+        println!("{:?}", Message::Add { val: -1 });
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatch(&Message::Add { val: 2 });
+        assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
+        assert_eq!(sme.get_state_enter_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_sm().borrow().state, 2);
+
+        // The child processes every message but the parent handles them
+        assert_eq!(sme.get_state_handled_cnt(IDX_PARENT), 1);
+        assert_eq!(sme.get_state_handled_cnt(IDX_CHILD), 0);
+
+        let result = sme.dispatch_result(&Message::Sub { val: 1 });
+        assert_eq!(
+            result,
+            DispatchResult {
+                transitioned: false,
+                handled_by: vec![Some(IDX_PARENT)],
+            }
+        );
+        assert!(result.handled());
+        assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 2);
+        assert_eq!(sme.get_state_handled_cnt(IDX_PARENT), 2);
+        assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
+        assert_eq!(sme.get_state_enter_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 2);
+        assert_eq!(sme.get_state_handled_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_CHILD), 0);
+        assert_eq!(sme.get_sm().borrow().state, 1);
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_leaf_transitions_in_a_tree() {
+        // StateMachine simply transitions back and forth
+        // between initial and other.
+        //
+        //                base=0
+        //        --------^  ^-------
+        //       /                   \
+        //      /                     \
+        //    other=2   <======>   initial=1
+
+        #[derive(Debug)]
+        struct StateMachine;
+
+        // Create a Protocol with no messages
+        #[derive(Debug)]
+        struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine);
+                let sme = ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("base", Self::base).enter_fn(Self::base_enter))
+                    .state(
+                        StateInfo::new("initial", Self::initial)
+                            .enter_fn(Self::initial_enter)
+                            .exit_fn(Self::initial_exit)
+                            .parent_idx(IDX_BASE),
+                    )
+                    .state(
+                        StateInfo::new("other", Self::other)
+                            .enter_fn(Self::other_enter)
+                            .exit_fn(Self::other_exit)
+                            .parent_idx(IDX_BASE),
+                    )
+                    .build_idx(IDX_INITIAL)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            fn base_enter(&mut self, _msg: &NoMessages) {}
+
+            // This state has idx 0
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn initial_enter(&mut self, _msg: &NoMessages) {}
+
+            // This state has idx 0
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn initial_exit(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn other_enter(&mut self, _msg: &NoMessages) {}
+
+            // This state has idx 0
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+
+            #[no_coverage]
+            fn other_exit(&mut self, _msg: &NoMessages) {}
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create a sme and validate it's in the expected state
+        let mut sme = StateMachine::new();
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 8);
+        assert_eq!(
+            sme.counts(),
+            expected_counts![
+                "base" => (0, 0, 0),
+                "initial" => (0, 0, 0),
+                "other" => (0, 0, 0),
+            ]
+        );
+
+        sme.dispatch(&NoMessages);
+        let counts = sme.counts();
+        assert_eq!(
+            counts,
+            expected_counts![
+                "base" => (1, 0, 0),
+                "initial" => (1, 1, 1),
+                "other" => (0, 0, 0),
+            ]
+        );
+
+        // Only other changes, it's entered, transitions and is exited
+        sme.dispatch(&NoMessages);
+        let diff = sme.counts().diff(&counts);
+        assert_eq!(diff.0, vec![("other".to_string(), (0, 0, 0), (1, 1, 1))]);
+        assert_eq!(
+            sme.counts(),
+            expected_counts![
+                "base" => (1, 0, 0),
+                "initial" => (1, 1, 1),
+                "other" => (1, 1, 1),
+            ]
+        );
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(
+            sme.counts(),
+            expected_counts![
+                "base" => (1, 0, 0),
+                "initial" => (2, 2, 2),
+                "other" => (1, 1, 1),
+            ]
+        );
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(
+            sme.counts(),
+            expected_counts![
+                "base" => (1, 0, 0),
+                "initial" => (2, 2, 2),
+                "other" => (2, 2, 2),
+            ]
+        );
+
+        let counts = sme.counts();
+        sme.dispatch(&NoMessages);
+        assert_eq!(
+            sme.counts(),
+            expected_counts![
+                "base" => (1, 0, 0),
+                "initial" => (3, 3, 3),
+                "other" => (2, 2, 2),
+            ]
+        );
+        assert!(sme.counts().diff(&sme.counts()).is_empty());
+        assert_eq!(
+            format!("{:?}", sme.counts().diff(&counts)),
+            "CountsDiff [\n  initial: enter=2->3 process=2->3 exit=2->3\n]"
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_drive_checks_invariants() {
+        //               base=0
+        //        -------^  ^-------
+        //       /                  \
+        //    left=1   <======>   right=2   <======>   other=3
+        //
+        // Only some of the states have enter or exit fns
+
+        #[derive(Debug, Default)]
+        struct StateMachine {
+            base_exits: usize,
+        }
+
+        #[derive(Debug, Clone, Copy)]
+        enum Messages {
+            Left,
+            Right,
+            Other,
+            Nop,
+        }
+
+        const IDX_BASE: usize = 0;
+        const IDX_LEFT: usize = 1;
+        const IDX_RIGHT: usize = 2;
+        const IDX_OTHER: usize = 3;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn base_exit(&mut self, _msg: &Messages) {
+                self.base_exits += 1;
+            }
+
+            #[no_coverage]
+            fn enter(&mut self, _msg: &Messages) {}
+
+            #[no_coverage]
+            fn exit(&mut self, _msg: &Messages) {}
+
+            #[no_coverage]
+            fn leaf(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Left => transition(e.state_id(IDX_LEFT).unwrap()),
+                    Messages::Right => transition(e.state_id(IDX_RIGHT).unwrap()),
+                    Messages::Other => transition(e.state_id(IDX_OTHER).unwrap()),
+                    Messages::Nop => not_handled(),
+                }
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("base", StateMachine::base).exit_fn(StateMachine::base_exit))
+            .state(
+                StateInfo::new("left", StateMachine::leaf)
+                    .enter_fn(StateMachine::enter)
+                    .exit_fn(StateMachine::exit)
+                    .parent_idx(IDX_BASE),
+            )
+            .state(
+                StateInfo::new("right", StateMachine::leaf)
+                    .exit_fn(StateMachine::exit)
+                    .parent_idx(IDX_BASE),
+            )
+            .state(StateInfo::new("other", StateMachine::leaf).enter_fn(StateMachine::enter))
+            .build_idx(IDX_LEFT)
+            .expect("Unexpected error initializing");
+
+        // A pseudo random sequence of messages
+        let mut seed: u32 = 0x1234_5678;
+        let msgs = core::iter::repeat_with(move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            [
+                Messages::Left,
+                Messages::Right,
+                Messages::Other,
+                Messages::Nop,
+            ][seed as usize % 4]
+        });
+        drive(&mut sme, msgs.take(200));
+        assert_eq!(sme.check_invariants(), Ok(()));
+
+        // base is only exited when transitioning from left or right to other
+        #[cfg(feature = "counters")]
+        assert_eq!(
+            sme.get_sm().borrow().base_exits,
+            sme.transition_counts()
+                .iter()
+                .filter(|((idx_from, idx_to), _)| *idx_from != IDX_OTHER && *idx_to == IDX_OTHER)
+                .map(|(_, cnt)| *cnt as usize)
+                .sum::<usize>()
+        );
+
+        // A corrupted active flag is caught
+        sme.states[IDX_OTHER].active = !sme.states[IDX_OTHER].active;
+        assert!(matches!(
+            sme.check_invariants(),
+            Err(InvariantViolation::ActiveMismatch { .. })
+        ));
+    }
+
+    #[test]
+    #[no_coverage]
+    //#[cfg(not(tarpaulin_include))]
+    fn test_leaf_transitions_between_trees() {
+        // StateMachine simply transitions back and forth
+        // between initial and other.
+        //
+        //  other_base=2          initial_base=0
+        //       ^                     ^
+        //       |                     |
+        //     other=3              initial=1
+
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol with no messages
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_INITIAL_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER_BASE: usize = 2;
+        const IDX_OTHER: usize = 3;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new(tracer: &VecTracer) -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine);
+                let sme = ExecutorBuilder::new(sm)
+                    .tracer(tracer.clone())
+                    .state(
+                        StateInfo::new("initial_base", Self::initial_base)
+                            .enter_fn(Self::initial_base_enter)
+                            .exit_fn(Self::initial_base_exit),
+                    )
+                    .state(
+                        StateInfo::new("initial", Self::initial)
+                            .enter_fn(Self::initial_enter)
+                            .exit_fn(Self::initial_exit)
+                            .parent_idx(IDX_INITIAL_BASE),
+                    )
+                    .state(
+                        StateInfo::new("other_base", Self::other_base)
+                            .enter_fn(Self::other_base_enter)
+                            .exit_fn(Self::other_base_exit),
+                    )
+                    .state(
+                        StateInfo::new("other", Self::other)
+                            .enter_fn(Self::other_enter)
+                            .exit_fn(Self::other_exit)
+                            .parent_idx(IDX_OTHER_BASE),
+                    )
+                    .build_idx(IDX_INITIAL)
+                    .expect("Unexpected error initializing");
+
+                sme
+            }
+
+            #[no_coverage]
+            fn initial_base_enter(&mut self, _msg: &NoMessages) {}
+
+            // This state has hdl 0
+            #[no_coverage]
+            fn initial_base(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn initial_base_exit(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn initial_enter(&mut self, _msg: &NoMessages) {}
+
+            // This state has hdl 0
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn initial_exit(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn other_base_enter(&mut self, _msg: &NoMessages) {}
+
+            // This state has hdl 0
+            #[no_coverage]
+            fn other_base(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn other_base_exit(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn other_enter(&mut self, _msg: &NoMessages) {}
+
+            // This state has hdl 0
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+
+            #[no_coverage]
+            fn other_exit(&mut self, _msg: &NoMessages) {}
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create a sme and validate nothing has run yet
+        let tracer = VecTracer::new();
+        let mut sme = StateMachine::new(&tracer);
+        assert_eq!(std::mem::size_of_val(sme.get_sm()), 8);
+        assert_eq!(tracer.take(), []);
+
+        use TraceEvent::*;
+        let to_other = [
+            Enter("initial_base".into()),
+            Enter("initial".into()),
+            Process("initial".into()),
+            Transition("other".into()),
+            Exit("initial".into()),
+            Exit("initial_base".into()),
+        ];
+        let to_initial = [
+            Enter("other_base".into()),
+            Enter("other".into()),
+            Process("other".into()),
+            Transition("initial".into()),
+            Exit("other".into()),
+            Exit("other_base".into()),
+        ];
+        for _ in 0..3 {
+            sme.dispatch(&NoMessages);
+            assert_eq!(tracer.take(), to_other);
+
+            sme.dispatch(&NoMessages);
+            assert_eq!(tracer.take(), to_initial);
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_1s_cycle() {
+        // StateMachine with one state and has itself as parent,
+        // this should fail to initialize!
+        //
+        //     ------
+        //     |    |
+        //     v    |
+        //  state1 --
+
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() {
+                let sm = RefCell::new(StateMachine);
+                match ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE1))
+                    .build_idx(IDX_STATE1)
+                {
+                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::CycleDetected {
+                            states: ["state1"].iter().map(|s| s.to_string()).collect()
+                        }
+                    ),
+                }
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        StateMachine::new();
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_2s_one_self_cycle() {
+        // StateMachine with one state and has itself as parent
+        // plus another state with no parent, this should fail to initialize!
+        //
+        //     ------
+        //     |    |
+        //     v    |
+        //  state1 --     state2
+
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const _IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() {
+                let sm = RefCell::new(StateMachine);
+                match ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE1))
+                    .state(StateInfo::new("state2", Self::state2))
+                    .build_idx(IDX_STATE1)
+                {
+                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::CycleDetected {
+                            states: ["state1"].iter().map(|s| s.to_string()).collect()
+                        }
+                    ),
+                }
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state2(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        StateMachine::new();
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_2s_cycle() {
+        // StateMachine with two states each has the other as parent,
+        // this should fail to build!
+        //
+        //  state2
+        //   |  ^
+        //   v  |
+        //  state1
+
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+        const _IDX_STATE3: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() {
+                let sm = RefCell::new(StateMachine);
+                match ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE2))
+                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
+                    .build_idx(IDX_STATE1)
+                {
+                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::CycleDetected {
+                            states: ["state1", "state2"].iter().map(|s| s.to_string()).collect()
+                        }
+                    ),
+                }
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state2(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        StateMachine::new();
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_3s_one_cycle() {
+        // StateMachine with three states two have other as parent third is standalone,
+        // this should fail to build!
+        //
+        //  state2   state3
+        //   |  ^
+        //   v  |
+        //  state1
+
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() {
+                let sm = RefCell::new(StateMachine);
+                match ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE2))
+                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
+                    .state(StateInfo::new("state3", Self::state3))
+                    .build_idx(IDX_STATE1)
+                {
+                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::CycleDetected {
+                            states: ["state1", "state2"].iter().map(|s| s.to_string()).collect()
+                        }
+                    ),
+                }
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state2(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state3(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        StateMachine::new();
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_5s_long_cycle() {
+        // StateMachine with 5 states twi leafs and a long cycle from state1 to state3
+        // this should fail to initialize!
+        //
+        //  --- state1
+        //  |      ^
+        //  |      |
+        //  |   state2
+        //  |      ^
+        //  |      |
+        //  --> state3 <-------
+        //         ^          |
+        //         |          |
+        //      state4     state5
+        //
+
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+        const IDX_STATE3: usize = 2;
+        const _IDX_STATE4: usize = 3;
+        const _IDX_STATE5: usize = 4;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() {
+                let sm = RefCell::new(StateMachine);
+                match ExecutorBuilder::new(sm)
+                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE3))
+                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
+                    .state(StateInfo::new("state3", Self::state3).parent_idx(IDX_STATE2))
+                    .state(StateInfo::new("state4", Self::state4).parent_idx(IDX_STATE3))
+                    .state(StateInfo::new("state5", Self::state5).parent_idx(IDX_STATE3))
+                    .build_idx(IDX_STATE1)
+                {
+                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
+                    Err(e) => assert_eq!(
+                        e,
+                        BuildError::CycleDetected {
+                            states: ["state1", "state2", "state3"]
+                                .iter()
+                                .map(|s| s.to_string())
+                                .collect()
+                        }
+                    ),
+                }
+            }
+
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state2(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state3(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state4(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn state5(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        StateMachine::new();
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_regions() {
+        // StateMachine with two orthogonal regions each of which
+        // toggles between two leafs on its own message.
+        //
+        //  region 0:       keyboard=0          region 1:      numlock=3
+        //                 ^          ^                       ^         ^
+        //                /            \                     /           \
+        //     caps_off=1 <=========> caps_on=2    num_off=4 <=========> num_on=5
+
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            CapsLock,
+            NumLock,
+        }
+
+        const IDX_KEYBOARD: usize = 0;
+        const IDX_CAPS_OFF: usize = 1;
+        const IDX_CAPS_ON: usize = 2;
+        const IDX_NUMLOCK: usize = 3;
+        const IDX_NUM_OFF: usize = 4;
+        const IDX_NUM_ON: usize = 5;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, Messages> {
+                let sm = RefCell::new(StateMachine);
+                ExecutorBuilder::new(sm)
+                    .with_regions(2)
+                    .state(StateInfo::new("keyboard", Self::base))
+                    .state(
+                        StateInfo::new("caps_off", Self::caps_off)
+                            .enter_fn(Self::leaf_enter)
+                            .exit_fn(Self::leaf_exit)
+                            .parent_idx(IDX_KEYBOARD),
+                    )
+                    .state(
+                        StateInfo::new("caps_on", Self::caps_on)
+                            .enter_fn(Self::leaf_enter)
+                            .exit_fn(Self::leaf_exit)
+                            .parent_idx(IDX_KEYBOARD),
+                    )
+                    .state(StateInfo::new("numlock", Self::base).region(1))
+                    .state(
+                        StateInfo::new("num_off", Self::num_off)
+                            .enter_fn(Self::leaf_enter)
+                            .exit_fn(Self::leaf_exit)
+                            .parent_idx(IDX_NUMLOCK),
+                    )
+                    .state(
+                        StateInfo::new("num_on", Self::num_on)
+                            .enter_fn(Self::leaf_enter)
+                            .exit_fn(Self::leaf_exit)
+                            .parent_idx(IDX_NUMLOCK),
+                    )
+                    .region_initial_state_idx(1, IDX_NUM_OFF)
+                    .build_idx(IDX_CAPS_OFF)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn leaf_enter(&mut self, _msg: &Messages) {}
+
+            #[no_coverage]
+            fn leaf_exit(&mut self, _msg: &Messages) {}
+
+            #[no_coverage]
+            fn caps_off(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::CapsLock => transition(e.state_id(IDX_CAPS_ON).unwrap()),
+                    Messages::NumLock => not_handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn caps_on(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::CapsLock => transition(e.state_id(IDX_CAPS_OFF).unwrap()),
+                    Messages::NumLock => not_handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn num_off(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::NumLock => transition(e.state_id(IDX_NUM_ON).unwrap()),
+                    Messages::CapsLock => not_handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn num_on(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::NumLock => transition(e.state_id(IDX_NUM_OFF).unwrap()),
+                    Messages::CapsLock => not_handled(),
+                }
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", Messages::CapsLock);
+        println!("{:?}", StateMachine);
+
+        let mut sme = StateMachine::new();
+        assert_eq!(sme.get_region_count(), 2);
+        assert_eq!(sme.get_region_current_state(0), IDX_CAPS_OFF);
+        assert_eq!(sme.get_region_current_state(1), IDX_NUM_OFF);
+        assert_eq!(sme.get_current_state_name(), "caps_off");
+
+        // CapsLock only transitions region 0, but both regions are
+        // entered as this is the first dispatch.
+        assert!(sme.dispatch(&Messages::CapsLock));
+        assert_eq!(sme.get_region_current_state_name(0), "caps_on");
+        assert_eq!(sme.get_region_current_state_name(1), "num_off");
+        assert_eq!(sme.get_state_enter_cnt(IDX_CAPS_OFF), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_CAPS_OFF), 1);
+        assert_eq!(sme.get_state_enter_cnt(IDX_NUM_OFF), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_NUM_OFF), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_NUMLOCK), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_NUM_OFF), 0);
+
+        // NumLock only transitions region 1
+        assert!(sme.dispatch(&Messages::NumLock));
+        assert_eq!(sme.get_region_current_state_name(0), "caps_on");
+        assert_eq!(sme.get_region_current_state_name(1), "num_on");
+        assert_eq!(sme.get_state_enter_cnt(IDX_CAPS_ON), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_CAPS_ON), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_NUM_OFF), 1);
+        assert_eq!(sme.get_state_enter_cnt(IDX_NUM_ON), 0);
+
+        // CapsLock again, region 1 enters num_on but stays there
+        assert!(sme.dispatch(&Messages::CapsLock));
+        assert_eq!(sme.get_region_current_state_name(0), "caps_off");
+        assert_eq!(sme.get_region_current_state_name(1), "num_on");
+        assert_eq!(sme.get_state_exit_cnt(IDX_CAPS_ON), 1);
+        assert_eq!(sme.get_state_enter_cnt(IDX_NUM_ON), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_NUM_ON), 0);
+
+        // Neither base state is ever exited
+        assert_eq!(sme.get_state_exit_cnt(IDX_KEYBOARD), 0);
+        assert_eq!(sme.get_state_exit_cnt(IDX_NUMLOCK), 0);
+    }
+
+    #[test]
+    #[no_coverage]
+    #[should_panic(
+        expected = ": transition from 'state1' (idx 0) to 'state2' (idx 1) rejected: target is in region 1 not region 0; valid targets: state1"
+    )]
+    fn test_regions_transition_to_other_region() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, NoMessages> {
+                let sm = RefCell::new(StateMachine);
+                ExecutorBuilder::new(sm)
+                    .with_regions(2)
+                    .state(StateInfo::new("state1", Self::state1))
+                    .state(StateInfo::new("state2", Self::state2).region(1))
+                    .region_initial_state_idx(1, IDX_STATE2)
+                    .build_idx(IDX_STATE1)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn state1(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                // Invalid transition, state2 is in another region
+                transition(e.state_id(IDX_STATE2).unwrap())
+            }
+
+            #[no_coverage]
+            fn state2(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // This will panic because state1 returns a transition
+        // to state2 which is in region 1
+        let mut sme = StateMachine::new();
+        sme.dispatch(&NoMessages);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_regions_invalid_region_initial_state() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        let sm = RefCell::new(StateMachine);
+        match ExecutorBuilder::new(sm)
+            .with_regions(2)
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(StateInfo::new("state2", StateMachine::state1).region(1))
+            .region_initial_state_idx(2, IDX_STATE2)
+            .build_idx(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected region 2 to be invalid"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::InvalidRegion {
+                    state: "state2".to_owned(),
+                    region: 2,
+                    regions: 2,
+                }
+            ),
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_regions_missing_initial_state() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        let sm = RefCell::new(StateMachine);
+        match ExecutorBuilder::new(sm)
+            .with_regions(2)
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(StateInfo::new("state2", StateMachine::state1).region(1))
+            .build_idx(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected region 1 to have no initial state"),
+            Err(e) => assert_eq!(e, BuildError::NoRegionInitialState { region: 1 }),
+        }
+    }
+
+    // Test closures capturing configuration and an observation log
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_closures() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            state: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Value { val: i32 },
+        }
+
+        const IDX_LOW: usize = 0;
+        const IDX_HIGH: usize = 1;
+
+        let threshold = 10;
+        let observed = Arc::new(std::sync::Mutex::new(Vec::<i32>::new()));
+        let entered = Arc::new(std::sync::Mutex::new(Vec::<&str>::new()));
+
+        let low_observed = observed.clone();
+        let high_observed = observed.clone();
+        let low_entered = entered.clone();
+        let low_exited = entered.clone();
+        let sm = RefCell::new(StateMachine { state: 0 });
+        let mut sme = ExecutorBuilder::new(sm)
+            .state(
+                StateInfo::new_closure(
+                    "low",
+                    move |sm: &mut StateMachine, e: &Executor<StateMachine, Messages>, msg| {
+                        let Messages::Value { val } = msg;
+                        low_observed.lock().unwrap().push(*val);
+                        sm.state = *val;
+                        if *val > threshold {
+                            transition(e.state_id(IDX_HIGH).unwrap())
+                        } else {
+                            handled()
+                        }
+                    },
+                )
+                .enter_closure(move |_sm, _msg| low_entered.lock().unwrap().push("low"))
+                .exit_closure(move |_sm, _msg| low_exited.lock().unwrap().push("~low")),
+            )
+            .state(StateInfo::new_closure(
+                "high",
+                move |sm: &mut StateMachine, e: &Executor<StateMachine, Messages>, msg| {
+                    let Messages::Value { val } = msg;
+                    high_observed.lock().unwrap().push(-val);
+                    sm.state = -val;
+                    if *val <= threshold {
+                        transition(e.state_id(IDX_LOW).unwrap())
+                    } else {
+                        handled()
+                    }
+                },
+            ))
+            .build_idx(IDX_LOW)
+            .expect("Unexpected error initializing");
+
+        // For code coverage
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatch(&Messages::Value { val: 1 });
+        assert_eq!(sme.get_current_state_name(), "low");
+        sme.dispatch(&Messages::Value { val: 11 });
+        assert_eq!(sme.get_current_state_name(), "high");
+        assert_eq!(sme.get_sm().borrow().state, 11);
+        sme.dispatch(&Messages::Value { val: 12 });
+        assert_eq!(sme.get_sm().borrow().state, -12);
+        sme.dispatch(&Messages::Value { val: 3 });
+        assert_eq!(sme.get_current_state_name(), "low");
+        sme.dispatch(&Messages::Value { val: 4 });
+
+        assert_eq!(*observed.lock().unwrap(), vec![1, 11, -12, -3, 4]);
+        assert_eq!(*entered.lock().unwrap(), vec!["low", "~low", "low"]);
+        assert_eq!(sme.get_state_process_cnt(IDX_LOW), 3);
+        assert_eq!(sme.get_state_process_cnt(IDX_HIGH), 2);
+    }
+
+    // Test a panicking process fn transitions to the recovery state
+    #[cfg(all(feature = "std", feature = "counters"))]
+    #[test]
+    #[no_coverage]
+    fn test_catch_panics() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            recovered_cnt: usize,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Work,
+            Boom,
+            Reset,
+        }
+
+        const IDX_BASE: usize = 0;
+        const IDX_WORKING: usize = 1;
+        const IDX_RECOVERY: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn new() -> Executor<Self, Messages> {
+                let sm = RefCell::new(StateMachine { recovered_cnt: 0 });
+                let mut builder = ExecutorBuilder::new(sm);
+                let base = builder.add_state(StateInfo::new("base", Self::base));
+                let working = builder.add_state(
+                    StateInfo::new("working", Self::working)
+                        .exit_fn(Self::working_exit)
+                        .parent(base),
+                );
+                let recovery = builder.add_state(
+                    StateInfo::new("recovery", Self::recovery)
+                        .enter_fn(Self::recovery_enter)
+                        .parent(base),
+                );
+                builder
+                    .catch_panics(recovery)
+                    .build(working)
+                    .expect("Unexpected error initializing")
+            }
+
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Boom => panic!("base can't handle Boom"),
+                    _ => handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn working(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Work => handled(),
+                    Messages::Boom => not_handled_transition(e.state_id(IDX_WORKING).unwrap()),
+                    Messages::Reset => not_handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn working_exit(&mut self, _msg: &Messages) {}
+
+            #[no_coverage]
+            fn recovery_enter(&mut self, _msg: &Messages) {
+                self.recovered_cnt += 1;
+            }
+
+            #[no_coverage]
+            fn recovery(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Reset => transition(e.state_id(IDX_WORKING).unwrap()),
+                    _ => not_handled(),
+                }
+            }
+        }
+
+        let mut sme = StateMachine::new();
+        sme.dispatch(&Messages::Work);
+        assert_eq!(sme.get_current_state_name(), "working");
+        assert_eq!(sme.get_panic_cnt(), 0);
+
+        // working defers Boom to base which panics, the transition
+        // working asked for is discarded in favor of recovery.
+        sme.dispatch(&Messages::Boom);
+        assert_eq!(sme.get_current_state_name(), "recovery");
+        assert_eq!(sme.get_panic_cnt(), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_WORKING), 1);
+        sme.dispatch(&Messages::Work);
+        assert_eq!(sme.get_sm().borrow().recovered_cnt, 1);
+        assert_eq!(sme.get_state_enter_cnt(IDX_RECOVERY), 1);
+
+        // Panicking while in the recovery state re-enters it
+        sme.dispatch(&Messages::Boom);
+        assert_eq!(sme.get_current_state_name(), "recovery");
+        assert_eq!(sme.get_panic_cnt(), 2);
+
+        // And the machine keeps dispatching
+        sme.dispatch(&Messages::Reset);
+        assert_eq!(sme.get_current_state_name(), "working");
+        sme.dispatch(&Messages::Work);
+        assert_eq!(sme.get_state_process_cnt(IDX_WORKING), 3);
+
+        // The recovery state must be a leaf
+        let sm = RefCell::new(StateMachine { recovered_cnt: 0 });
+        match ExecutorBuilder::new(sm)
+            .catch_panics_idx(IDX_BASE)
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(StateInfo::new("working", StateMachine::working).parent_idx(IDX_BASE))
+            .build_idx(IDX_WORKING)
+        {
+            Ok(_) => panic!("Expected base to be an invalid recovery state"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::InvalidRecoveryState {
+                    idx: 0,
+                    valid: vec![1]
+                }
+            ),
+        }
+    }
+
+    // Test the remaining BuildError variants
+    #[test]
+    #[no_coverage]
+    fn test_build_errors() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+        const IDX_STATE2: usize = 1;
+        const INVALID_STATE: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        match ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .build_idx(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected a duplicate name"),
+            Err(e) => assert_eq!(e, BuildError::DuplicateName("state1".to_owned())),
+        }
+
+        match ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(StateInfo::new("state2", StateMachine::state1).parent_idx(INVALID_STATE))
+            .build_idx(IDX_STATE2)
+        {
+            Ok(_) => panic!("Expected an invalid parent"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::InvalidParent {
+                    state: "state2".to_owned(),
+                    parent_idx: INVALID_STATE
+                }
+            ),
+        }
+
+        match ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .state(StateInfo::new("state2", StateMachine::state1).parent_idx(IDX_STATE1))
+            .build_idx(IDX_STATE1)
+        {
+            Ok(_) => panic!("Expected an invalid initial state"),
+            Err(e) => {
+                assert_eq!(
+                    e.to_string(),
+                    "0 is not a valid initial state, only [1] are allowed"
+                );
+                assert_eq!(
+                    e,
+                    BuildError::InvalidInitialState {
+                        idx: IDX_STATE1,
+                        valid: vec![IDX_STATE2]
+                    }
+                );
+            }
+        }
+    }
+
+    // Test the builder's name and the deprecated Executor::new
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_builder_name_and_deprecated_new() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_STATE1: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state1(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("sm")
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .build_idx(IDX_STATE1)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.get_name(), "sm");
+
+        #[allow(deprecated)]
+        let mut sme = Executor::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("state1", StateMachine::state1))
+            .build_idx(IDX_STATE1)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.get_name(), "");
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
+    }
+
+    // Test states registered with add_state using the returned handles
+    // for parents and transitions
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_add_state_handles() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            initial: StateId,
+            other: StateId,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                transition(self.other)
+            }
+
+            #[no_coverage]
+            fn other(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                not_handled_transition(self.initial)
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+
+        let mut builder = ExecutorBuilder::new(RefCell::new(StateMachine::default()));
+        let base = builder.add_state(StateInfo::new("base", StateMachine::base));
+        let initial =
+            builder.add_state(StateInfo::new("initial", StateMachine::initial).parent(base));
+        let other = builder.add_state(StateInfo::new("other", StateMachine::other).parent(base));
+        assert_eq!(
+            [base.raw(), initial.raw(), other.raw()],
+            [0usize, 1usize, 2usize]
+        );
+
+        let mut sme = builder
+            .build(initial)
+            .expect("Unexpected error initializing");
+        {
+            let mut sm = sme.get_sm().borrow_mut();
+            sm.initial = initial;
+            sm.other = other;
+        }
+        println!("{:?}", sme.get_sm());
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "other");
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "initial");
+        assert_eq!(sme.get_state_process_cnt(base.raw()), 1);
+        assert_eq!(sme.get_state_process_cnt(initial.raw()), 1);
+        assert_eq!(sme.get_state_process_cnt(other.raw()), 1);
+    }
+
+    // Test parent_name with a child declared before its parent and an
+    // unknown parent name
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_parent_name() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_INITIAL: usize = 0;
+        const IDX_BASE: usize = 1;
+        const IDX_OTHER: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                not_handled_transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_name("base"))
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(StateInfo::new("other", StateMachine::other).parent_name("base"))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.states[IDX_INITIAL].parent, Some(IDX_BASE));
+        assert_eq!(sme.states[IDX_BASE].parent, None);
+        assert_eq!(sme.states[IDX_OTHER].parent, Some(IDX_BASE));
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "other");
+        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 1);
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "initial");
+
+        match ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_name("bsae"))
+            .state(StateInfo::new("base", StateMachine::base))
+            .build_idx(IDX_INITIAL)
+        {
+            Ok(_) => panic!("Expected an unknown parent"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::UnknownParent {
+                    state: "initial".to_owned(),
+                    parent_name: "bsae".to_owned()
+                }
+            ),
+        }
+    }
+
+    // Test children are intact after build
+    #[test]
+    #[no_coverage]
+    fn test_children() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const MAX_STATES: usize = 5;
+        const IDX_BASE: usize = 0;
+        const IDX_LEAF1: usize = 1;
+        const IDX_LEAF2: usize = 2;
+        const IDX_OTHER: usize = 3;
+        const IDX_LEAF3: usize = 4;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("base", StateMachine::state))
+            .state(StateInfo::new("leaf1", StateMachine::state).parent_idx(IDX_BASE))
+            .state(StateInfo::new("leaf2", StateMachine::state).parent_idx(IDX_BASE))
+            .state(StateInfo::new("other", StateMachine::state))
+            .state(StateInfo::new("leaf3", StateMachine::state).parent_idx(IDX_BASE))
+            .build_idx(IDX_LEAF1)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.children(IDX_BASE), &[IDX_LEAF1, IDX_LEAF2, IDX_LEAF3]);
+        assert!(sme.children(IDX_LEAF1).is_empty());
+        assert!(sme.children(IDX_OTHER).is_empty());
+
+        assert_eq!(
+            sme.leaf_states(),
+            &[IDX_LEAF1, IDX_LEAF2, IDX_OTHER, IDX_LEAF3]
+        );
+        assert_eq!(
+            sme.leaf_state_names(),
+            vec!["leaf1", "leaf2", "other", "leaf3"]
+        );
+        assert!(!sme.is_leaf(IDX_BASE));
+        assert!(sme.is_leaf(IDX_LEAF2));
+        assert!(sme.is_leaf(IDX_OTHER));
+        assert!(!sme.is_leaf(MAX_STATES));
+    }
+
+    // Test ancestors and depth
+    #[test]
+    #[no_coverage]
+    fn test_ancestors_and_depth() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const MAX_STATES: usize = 4;
+        const IDX_ROOT: usize = 0;
+        const IDX_MIDDLE: usize = 1;
+        const IDX_LEAF: usize = 2;
+        const IDX_OTHER: usize = 3;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("root", StateMachine::state))
+            .state(StateInfo::new("middle", StateMachine::state).parent_idx(IDX_ROOT))
+            .state(StateInfo::new("leaf", StateMachine::state).parent_idx(IDX_MIDDLE))
+            .state(StateInfo::new("other", StateMachine::state))
+            .build_idx(IDX_LEAF)
+            .expect("Unexpected error initializing");
+        assert_eq!(
+            sme.ancestors(IDX_LEAF).collect::<Vec<usize>>(),
+            vec![IDX_MIDDLE, IDX_ROOT]
+        );
+        assert_eq!(sme.ancestors(IDX_ROOT).count(), 0);
+        assert_eq!(sme.depth(IDX_ROOT), 0);
+        assert_eq!(sme.depth(IDX_MIDDLE), 1);
+        assert_eq!(sme.depth(IDX_LEAF), 2);
+        assert_eq!(sme.depth(IDX_OTHER), 0);
+
+        // A malformed chain is truncated rather than looping forever
+        sme.states[IDX_ROOT].parent = Some(IDX_LEAF);
+        assert_eq!(sme.depth(IDX_LEAF), MAX_STATES);
+    }
+
+    // Test common_ancestor
+    #[test]
+    #[no_coverage]
+    fn test_common_ancestor() {
+        // Two trees
+        //
+        //          base=0            other_base=4
+        //         /      \                |
+        //   left=1       right=3       other=5
+        //      |
+        //   left_leaf=2
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_LEFT: usize = 1;
+        const IDX_LEFT_LEAF: usize = 2;
+        const IDX_RIGHT: usize = 3;
+        const IDX_OTHER_BASE: usize = 4;
+        const IDX_OTHER: usize = 5;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("base", StateMachine::state))
+            .state(StateInfo::new("left", StateMachine::state).parent_idx(IDX_BASE))
+            .state(StateInfo::new("left_leaf", StateMachine::state).parent_idx(IDX_LEFT))
+            .state(StateInfo::new("right", StateMachine::state).parent_idx(IDX_BASE))
+            .state(StateInfo::new("other_base", StateMachine::state))
+            .state(StateInfo::new("other", StateMachine::state).parent_idx(IDX_OTHER_BASE))
+            .build_idx(IDX_LEFT_LEAF)
+            .expect("Unexpected error initializing");
+
+        // Same tree
+        assert_eq!(
+            sme.common_ancestor(IDX_LEFT_LEAF, IDX_RIGHT),
+            Some(IDX_BASE)
+        );
+        assert_eq!(
+            sme.common_ancestor(IDX_RIGHT, IDX_LEFT_LEAF),
+            Some(IDX_BASE)
+        );
+
+        // Cross tree
+        assert_eq!(sme.common_ancestor(IDX_LEFT_LEAF, IDX_OTHER), None);
+        assert_eq!(sme.common_ancestor(IDX_BASE, IDX_OTHER_BASE), None);
+
+        // Identical
+        assert_eq!(
+            sme.common_ancestor(IDX_LEFT_LEAF, IDX_LEFT_LEAF),
+            Some(IDX_LEFT_LEAF)
+        );
+
+        // Parent and child
+        assert_eq!(sme.common_ancestor(IDX_LEFT, IDX_LEFT_LEAF), Some(IDX_LEFT));
+        assert_eq!(sme.common_ancestor(IDX_LEFT_LEAF, IDX_BASE), Some(IDX_BASE));
+    }
+
+    // Test validate with the cycle scenarios and that it doesn't modify
+    // the states
+    #[test]
+    #[no_coverage]
+    fn test_validate() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // Create states named state1.. with the given parents
+        #[no_coverage]
+        fn states(parents: &[Option<usize>]) -> Vec<StateInfo<StateMachine, NoMessages>> {
+            parents
+                .iter()
+                .enumerate()
+                .map(|(idx, parent)| {
+                    let state = StateInfo::new(format!("state{}", idx + 1), StateMachine::state);
+                    match parent {
+                        Some(idx_parent) => state.parent_idx(*idx_parent),
+                        None => state,
+                    }
+                })
+                .collect()
+        }
+
+        #[no_coverage]
+        fn cycle(names: &[&str]) -> Result<(), BuildError> {
+            Err(BuildError::CycleDetected {
+                states: names.iter().map(|s| s.to_string()).collect(),
+            })
+        }
+
+        // test_1s_cycle
+        assert_eq!(validate(&states(&[Some(0)]), 0), cycle(&["state1"]));
+
+        // test_2s_one_self_cycle
+        assert_eq!(validate(&states(&[Some(0), None]), 0), cycle(&["state1"]));
+
+        // test_2s_cycle
+        assert_eq!(
+            validate(&states(&[Some(1), Some(0)]), 0),
+            cycle(&["state1", "state2"])
+        );
+
+        // test_3s_one_cycle
+        assert_eq!(
+            validate(&states(&[Some(1), Some(0), None]), 0),
+            cycle(&["state1", "state2"])
+        );
+
+        // test_5s_long_cycle
+        assert_eq!(
+            validate(&states(&[Some(2), Some(0), Some(1), Some(2), Some(2)]), 3),
+            cycle(&["state1", "state2", "state3"])
+        );
+
+        // A valid tree isn't modified
+        let tree = states(&[None, Some(0), Some(0)]);
+        assert_eq!(validate(&tree, 1), Ok(()));
+        assert!(tree
+            .iter()
+            .all(|s| s.parent.is_none() && s.children.is_empty()));
+
+        // The initial state must be a leaf
+        assert_eq!(
+            validate(&tree, 0),
+            Err(BuildError::InvalidInitialState {
+                idx: 0,
+                valid: vec![1, 2]
+            })
+        );
+    }
+
+    // Test iter_states visits parents before children
+    #[test]
+    #[no_coverage]
+    fn test_iter_states() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_INITIAL_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER_BASE: usize = 2;
+        const IDX_OTHER: usize = 3;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        // The hsm-2h-2s example layout
+        //
+        //  initial_base=0         other_base=2
+        //       |                     |
+        //   initial=1              other=3
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("initial_base", StateMachine::state))
+            .state(StateInfo::new("initial", StateMachine::state).parent_idx(IDX_INITIAL_BASE))
+            .state(StateInfo::new("other_base", StateMachine::state))
+            .state(StateInfo::new("other", StateMachine::state).parent_idx(IDX_OTHER_BASE))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.roots(), vec![IDX_INITIAL_BASE, IDX_OTHER_BASE]);
+        assert_eq!(
+            sme.iter_states()
+                .map(|state| (state.idx().raw(), state.name()))
+                .collect::<Vec<_>>(),
+            vec![
+                (IDX_INITIAL_BASE, "initial_base"),
+                (IDX_INITIAL, "initial"),
+                (IDX_OTHER_BASE, "other_base"),
+                (IDX_OTHER, "other")
+            ]
+        );
+
+        // Children declared before their parents
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("other", StateMachine::state).parent_name("other_base"))
+            .state(StateInfo::new("initial", StateMachine::state).parent_name("initial_base"))
+            .state(StateInfo::new("initial_base", StateMachine::state))
+            .state(StateInfo::new("other_base", StateMachine::state))
+            .build_idx(1)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.roots(), vec![2, 3]);
+        assert_eq!(
+            sme.iter_states()
+                .map(|state| state.idx().raw())
+                .collect::<Vec<_>>(),
+            vec![2, 1, 3, 0]
+        );
+
+        // A flat machine is visited in index order
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("state1", StateMachine::state))
+            .state(StateInfo::new("state2", StateMachine::state))
+            .state(StateInfo::new("state3", StateMachine::state))
+            .build_idx(0)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.roots(), vec![0, 1, 2]);
+        assert_eq!(
+            sme.iter_states()
+                .map(|state| state.idx().raw())
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+
+    // Test the Debug output of an Executor
+    #[cfg(all(feature = "counters", feature = "messaging"))]
+    #[test]
+    #[no_coverage]
+    fn test_debug() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn base_enter(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn initial(&mut self, e: &Executor<Self, NoMessages>, msg: &NoMessages) -> StateResult {
+                e.defer_send(NoMessages).unwrap();
+                println!("{msg:?} {e:?}");
+                transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn other(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn other_enter(&mut self, _msg: &NoMessages) {}
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("debug")
+            .state(StateInfo::new("base", StateMachine::base).enter_fn(StateMachine::base_enter))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_BASE))
+            .state(
+                StateInfo::new("other", StateMachine::other)
+                    .enter_fn(StateMachine::other_enter)
+                    .parent_idx(IDX_BASE),
+            )
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages);
+        let debug = format!("{sme:?}");
+        println!("{debug}");
+        assert!(debug.contains("Executor name=\"debug\" deferred_cnt=1\n"));
+        assert!(debug.contains("  sm=StateMachine\n"));
+        assert!(debug.contains("\n  * base enter_cnt=1 process_cnt=0 exit_cnt=0\n"));
+        assert!(debug.contains("\n      initial enter_cnt=0 process_cnt=1 exit_cnt=0\n"));
+        assert!(debug.contains("\n    * -> other enter_cnt=1 process_cnt=1 exit_cnt=0\n"));
+    }
+
+    // Smoke test without the counters feature
+    #[cfg(not(feature = "counters"))]
+    #[test]
+    #[no_coverage]
+    fn test_without_counters() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn base_enter(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                not_handled_transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("base", StateMachine::base).enter_fn(StateMachine::base_enter))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_BASE))
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(IDX_BASE))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "other");
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "initial");
+
+        let debug = format!("{sme:?}");
+        println!("{debug}");
+        assert!(debug.contains("\n  * base\n"));
+        assert!(!debug.contains("enter_cnt"));
+        #[cfg(not(feature = "messaging"))]
+        assert!(!debug.contains("deferred_cnt"));
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_u8_state_idx() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_BASE: u8 = 0;
+        const IDX_INITIAL: u8 = 1;
+        const IDX_OTHER: u8 = 2;
+
+        type Sme = Executor<StateMachine, NoMessages, u8>;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Sme, _msg: &NoMessages) -> StateResult<u8> {
+                handled()
+            }
+
+            #[no_coverage]
+            fn initial(&mut self, e: &Sme, _msg: &NoMessages) -> StateResult<u8> {
+                not_handled_transition(e.state_id(IDX_OTHER.into()).unwrap())
+            }
+
+            #[no_coverage]
+            fn other(&mut self, e: &Sme, _msg: &NoMessages) -> StateResult<u8> {
+                transition(e.state_id(IDX_INITIAL.into()).unwrap())
+            }
+        }
+
+        assert!(
+            std::mem::size_of::<StateInfo<StateMachine, NoMessages, u8>>()
+                < std::mem::size_of::<StateInfo<StateMachine, NoMessages>>()
+        );
+
+        let mut sme: Sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_BASE))
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(IDX_BASE))
+            .build_idx(IDX_INITIAL.into())
+            .expect("Unexpected error initializing");
+
+        assert_eq!(sme.leaf_states(), &[IDX_INITIAL, IDX_OTHER]);
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "other");
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "initial");
+
+        // 257 states don't fit in a u8
+        let mut builder = ExecutorBuilder::<_, _, u8>::new(RefCell::new(StateMachine));
+        for idx in 0..257 {
+            builder = builder.state(StateInfo::new(format!("state{idx}"), StateMachine::base));
+        }
+        assert_eq!(
+            builder.build_idx(0).err(),
+            Some(BuildError::IdxOverflow { declared: 257 })
+        );
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_state_names() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn state(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
+
+        let base = StateInfo::new("base", StateMachine::state);
+        assert!(matches!(base.name, Cow::Borrowed("base")));
+        let name = String::from("initial");
+        let initial = StateInfo::new(name, StateMachine::state).parent_idx(IDX_BASE);
+        assert!(matches!(initial.name, Cow::Owned(_)));
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(base)
+            .state(initial)
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_state_name(IDX_BASE), "base");
+        assert_eq!(sme.get_current_state_name(), "initial");
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_event_log() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn base_enter(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                not_handled_transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+
+            #[no_coverage]
+            fn other_enter(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn other_exit(&mut self, _msg: &NoMessages) {}
+        }
+
+        // For code coverage
+        println!("{:?}", NoMessages);
+        println!("{:?}", StateMachine);
+
+        #[no_coverage]
+        fn builder(capacity: usize) -> ExecutorBuilder<StateMachine, NoMessages> {
+            ExecutorBuilder::new(RefCell::new(StateMachine))
+                .event_log(capacity)
+                .state(
+                    StateInfo::new("base", StateMachine::base).enter_fn(StateMachine::base_enter),
+                )
+                .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_BASE))
+                .state(
+                    StateInfo::new("other", StateMachine::other)
+                        .enter_fn(StateMachine::other_enter)
+                        .exit_fn(StateMachine::other_exit)
+                        .parent_idx(IDX_BASE),
+                )
+        }
+
+        #[no_coverage]
+        fn event(kind: EventKind, state_idx: usize) -> EventRecord {
+            EventRecord {
+                kind,
+                state_idx,
+                msg_debug: None,
+            }
+        }
+
+        let mut sme = builder(64)
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.recent_events().count(), 0);
+        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages);
+        let events: Vec<EventRecord> = sme.recent_events().cloned().collect();
+        assert_eq!(
+            events,
+            [
+                event(EventKind::Enter, IDX_BASE),
+                event(EventKind::Process, IDX_INITIAL),
+                event(EventKind::Process, IDX_BASE),
+                event(EventKind::Transition, IDX_OTHER),
+                event(EventKind::Enter, IDX_OTHER),
+                event(EventKind::Process, IDX_OTHER),
+                event(EventKind::Transition, IDX_INITIAL),
+                event(EventKind::Exit, IDX_OTHER),
+            ]
+        );
+
+        // Only the last 3 events are kept
+        let mut sme = builder(3)
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages);
+        let events: Vec<EventRecord> = sme.recent_events().cloned().collect();
+        assert_eq!(
+            events,
+            [
+                event(EventKind::Process, IDX_OTHER),
+                event(EventKind::Transition, IDX_INITIAL),
+                event(EventKind::Exit, IDX_OTHER),
+            ]
+        );
+
+        // Messages are recorded if asked for
+        let mut sme = builder(1)
+            .event_log_msgs()
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        sme.dispatch(&NoMessages);
+        let event = sme.recent_events().next().unwrap();
+        assert_eq!(event.kind, EventKind::Transition);
+        assert_eq!(event.msg_debug.as_deref(), Some("NoMessages"));
+
+        // And nothing is recorded by default
+        let mut sme = builder(0)
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.recent_events().count(), 0);
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_event_log_deferred() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug, Clone)]
+        pub struct NoMessages;
+
+        const IDX_DEFERRING: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn deferring(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                e.defer_current().unwrap();
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .event_log(4)
+            .event_log_msgs()
+            .state(StateInfo::new("deferring", StateMachine::deferring))
+            .build_idx(IDX_DEFERRING)
+            .expect("Unexpected error initializing");
+
+        sme.dispatcher(&NoMessages);
+        let events: Vec<EventRecord> = sme.recent_events().cloned().collect();
+        assert_eq!(
+            events,
+            [
+                EventRecord {
+                    kind: EventKind::Process,
+                    state_idx: IDX_DEFERRING,
+                    msg_debug: Some("NoMessages".to_owned()),
+                },
+                EventRecord {
+                    kind: EventKind::Deferred,
+                    state_idx: IDX_DEFERRING,
+                    msg_debug: Some("NoMessages".to_owned()),
+                },
+            ]
+        );
+    }
+
+    // Ping-pong between the leaves of the two trees of hsm-2h-2s
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_transition_counts() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_INITIAL_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER_BASE: usize = 2;
+        const IDX_OTHER: usize = 3;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("initial_base", StateMachine::base))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_INITIAL_BASE))
+            .state(StateInfo::new("other_base", StateMachine::base))
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(IDX_OTHER_BASE))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        assert!(sme.transition_counts().is_empty());
+        for _ in 0..5 {
+            sme.dispatch(&NoMessages);
+        }
+        assert_eq!(sme.transition_counts().len(), 2);
+        assert_eq!(sme.transition_counts()[&(IDX_INITIAL, IDX_OTHER)], 3);
+        assert_eq!(sme.transition_counts()[&(IDX_OTHER, IDX_INITIAL)], 2);
+        assert_eq!(
+            sme.transition_counts_named(),
+            [("initial", "other", 3), ("other", "initial", 2)]
+        );
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_coverage() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const MAX_STATES: usize = 5;
+        const IDX_INITIAL_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER_BASE: usize = 2;
+        const IDX_OTHER: usize = 3;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn base_enter(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                transition(e.state_id(IDX_OTHER).unwrap())
+            }
+
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("initial_base", StateMachine::base))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_INITIAL_BASE))
+            .state(
+                StateInfo::new("other_base", StateMachine::base).enter_fn(StateMachine::base_enter),
+            )
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(IDX_OTHER_BASE))
+            .state(StateInfo::new("unused", StateMachine::base).parent_idx(IDX_OTHER_BASE))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        let coverage = sme.coverage();
+        assert!(coverage.visited_states.is_empty());
+        assert_eq!(coverage.unvisited_states.len(), MAX_STATES);
+        assert!(coverage.exercised_transitions.is_empty());
+
+        sme.dispatch(&NoMessages);
+        sme.dispatch(&NoMessages);
+        let coverage = sme.coverage();
+        assert_eq!(coverage.visited_states, ["initial", "other_base", "other"]);
+        assert_eq!(coverage.unvisited_states, ["initial_base", "unused"]);
+        assert_eq!(
+            coverage.exercised_transitions,
+            [
+                ("initial".to_owned(), "other".to_owned()),
+                ("other".to_owned(), "initial".to_owned())
+            ]
+        );
+        assert_eq!(
+            format!("{coverage}"),
+            "visited 3/5 states\n  unvisited initial_base\n  unvisited unused\n\
+             exercised 2 transitions\n  initial -> other\n  other -> initial\n"
+        );
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    #[no_coverage]
+    fn test_timing() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            clock: ManualClock,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_FAST: usize = 1;
+        const IDX_SLOW: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn fast(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                self.clock.advance(Duration::from_millis(1));
+                transition(e.state_id(IDX_SLOW).unwrap())
+            }
+
+            #[no_coverage]
+            fn slow(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                self.clock.advance(Duration::from_millis(10));
+                transition(e.state_id(IDX_FAST).unwrap())
+            }
+
+            #[no_coverage]
+            fn slow_enter(&mut self, _msg: &NoMessages) {
+                self.clock.advance(Duration::from_millis(2));
+            }
+
+            #[no_coverage]
+            fn slow_exit(&mut self, _msg: &NoMessages) {
+                self.clock.advance(Duration::from_millis(3));
+            }
+        }
+
+        let clock = ManualClock::new();
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine {
+            clock: clock.clone(),
+        }))
+        .clock(clock)
+        .state(StateInfo::new("base", StateMachine::base))
+        .state(StateInfo::new("fast", StateMachine::fast).parent_idx(IDX_BASE))
+        .state(
+            StateInfo::new("slow", StateMachine::slow)
+                .enter_fn(StateMachine::slow_enter)
+                .exit_fn(StateMachine::slow_exit)
+                .parent_idx(IDX_BASE),
+        )
+        .build_idx(IDX_FAST)
+        .expect("Unexpected error initializing");
+
+        for _ in 0..4 {
+            sme.dispatch(&NoMessages);
+        }
+        assert_eq!(
+            sme.get_state_process_time(IDX_FAST),
+            Duration::from_millis(2)
+        );
+        assert_eq!(sme.get_state_enter_time(IDX_SLOW), Duration::from_millis(4));
+        assert_eq!(
+            sme.get_state_process_time(IDX_SLOW),
+            Duration::from_millis(20)
+        );
+        assert_eq!(sme.get_state_exit_time(IDX_SLOW), Duration::from_millis(6));
+        assert_eq!(sme.get_state_process_time(IDX_BASE), Duration::ZERO);
+        assert_eq!(
+            sme.timing_report(),
+            [
+                ("slow", Duration::from_millis(30)),
+                ("fast", Duration::from_millis(2)),
+                ("base", Duration::ZERO),
+            ]
+        );
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_max_defer_passes() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug, Clone)]
+        pub struct NoMessages;
+
+        const IDX_PING: usize = 0;
+        const IDX_PONG: usize = 1;
+        const IDX_DONE: usize = 2;
+
+        impl StateMachine {
+            // Ping and pong defer every message and transition to each other
+            #[no_coverage]
+            fn ping(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                e.defer_current().unwrap();
+                transition(e.state_id(IDX_PONG).unwrap())
+            }
+
+            #[no_coverage]
+            fn pong(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                e.defer_current().unwrap();
+                transition(e.state_id(IDX_PING).unwrap())
+            }
+
+            #[no_coverage]
+            fn done(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .max_defer_passes(5)
+            .state(StateInfo::new("ping", StateMachine::ping))
+            .state(StateInfo::new("pong", StateMachine::pong))
+            .state(StateInfo::new("done", StateMachine::done))
+            .build_idx(IDX_PING)
+            .expect("Unexpected error initializing");
+
+        assert_eq!(
+            sme.dispatcher(&NoMessages),
+            DispatcherOutcome {
+                transitioned: true,
+                deferred_remaining: 1,
+                messages_processed: 6,
+                truncated: true,
+            }
+        );
+        assert_eq!(sme.get_defer_overflow_cnt(), 1);
+        assert_eq!(sme.get_deferred_cnt(), 1);
+        assert_eq!(sme.get_current_state_name(), "ping");
+
+        // A machine that stops transitioning isn't truncated
+        #[no_coverage]
+        fn deferring(
+            _sm: &mut StateMachine,
+            e: &Executor<StateMachine, NoMessages>,
+            _msg: &NoMessages,
+        ) -> StateResult {
+            e.defer_current().unwrap();
+            transition(e.state_id(IDX_DONE).unwrap())
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("deferring", deferring))
+            .state(StateInfo::new("unused", StateMachine::pong))
+            .state(StateInfo::new("done", StateMachine::done))
+            .build_idx(0)
+            .expect("Unexpected error initializing");
+
+        assert_eq!(
+            sme.dispatcher(&NoMessages),
+            DispatcherOutcome {
+                transitioned: true,
+                deferred_remaining: 0,
+                messages_processed: 2,
+                truncated: false,
+            }
+        );
+        assert_eq!(sme.get_defer_overflow_cnt(), 0);
+        assert_eq!(sme.get_deferred_cnt(), 0);
+        assert_eq!(sme.get_current_state_name(), "done");
+    }
+
+    #[test]
+    #[no_coverage]
+    #[should_panic(
+        expected = ": dispatch of Value(1) called re-entrantly from state 'base' (idx 0) while processing Value(0)"
+    )]
+    fn test_reentrant_dispatch_panics() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Value(i32),
+        }
+
+        const IDX_BASE: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                assert!(e.is_dispatching());
+                let Messages::Value(val) = msg;
+                e.dispatch_reentrant(Messages::Value(val + 1));
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("base", StateMachine::base))
+            .build_idx(IDX_BASE)
+            .expect("Unexpected error initializing");
+
+        assert!(!sme.is_dispatching());
+        sme.dispatch(&Messages::Value(0));
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_reentrant_dispatch_queues() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            vals: Vec<i32>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Value(i32),
+        }
+
+        const IDX_BASE: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                let Messages::Value(val) = msg;
+                self.vals.push(*val);
+                if *val < 2 {
+                    e.dispatch_reentrant(Messages::Value(val + 1));
+                }
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine { vals: vec![] }))
+            .reentrant_dispatch(ReentrantDispatch::Queue)
+            .state(StateInfo::new("base", StateMachine::base))
+            .build_idx(IDX_BASE)
+            .expect("Unexpected error initializing");
+
+        // The nested messages are processed after the current one
+        sme.dispatch(&Messages::Value(0));
+        assert_eq!(sme.get_sm().borrow().vals, [0]);
+        while let Ok(msg) = sme.try_recv() {
+            sme.dispatch(&msg);
+        }
+        assert_eq!(sme.get_sm().borrow().vals, [0, 1, 2]);
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_shutdown() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            exited: Vec<&'static str>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_ROOT: usize = 0;
+        const IDX_MID: usize = 1;
+        const IDX_LEAF: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn process(
+                &mut self,
+                _e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn enter(&mut self, _msg: &NoMessages) {}
+
+            #[no_coverage]
+            fn root_exit(&mut self, _msg: &NoMessages) {
+                self.exited.push("root");
+            }
+
+            #[no_coverage]
+            fn mid_exit(&mut self, _msg: &NoMessages) {
+                self.exited.push("mid");
+            }
+
+            #[no_coverage]
+            fn leaf_exit(&mut self, _msg: &NoMessages) {
+                self.exited.push("leaf");
+            }
+        }
+
+        #[no_coverage]
+        fn build() -> Executor<StateMachine, NoMessages> {
+            ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+                .state(
+                    StateInfo::new("root", StateMachine::process)
+                        .enter_fn(StateMachine::enter)
+                        .exit_fn(StateMachine::root_exit),
+                )
+                .state(
+                    StateInfo::new("mid", StateMachine::process)
+                        .enter_fn(StateMachine::enter)
+                        .exit_fn(StateMachine::mid_exit)
+                        .parent_idx(IDX_ROOT),
+                )
+                .state(
+                    StateInfo::new("leaf", StateMachine::process)
+                        .enter_fn(StateMachine::enter)
+                        .exit_fn(StateMachine::leaf_exit)
+                        .parent_idx(IDX_MID),
+                )
+                .build_idx(IDX_LEAF)
+                .expect("Unexpected error initializing")
+        }
+
+        let mut sme = build();
+        sme.dispatch(&NoMessages);
+        assert!(sme.states.iter().all(|state| state.active));
+        assert!(!sme.is_stopped());
+
+        sme.shutdown(&NoMessages);
+        assert!(sme.is_stopped());
+        assert_eq!(sme.get_sm().borrow().exited, ["leaf", "mid", "root"]);
+        assert_eq!(sme.get_state_exit_cnt(IDX_ROOT), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_MID), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_LEAF), 1);
+        assert!(sme.states.iter().all(|state| !state.active));
+
+        // Further dispatches and shutdowns do nothing
+        assert!(!sme.dispatch(&NoMessages));
+        sme.shutdown(&NoMessages);
+        assert_eq!(sme.get_state_process_cnt(IDX_LEAF), 1);
+        assert_eq!(sme.get_state_exit_cnt(IDX_LEAF), 1);
+
+        // Nothing has been entered before the first dispatch
+        let mut sme = build();
+        sme.shutdown(&NoMessages);
+        assert!(sme.get_sm().borrow().exited.is_empty());
+        assert_eq!(sme.get_state_enter_cnt(IDX_ROOT), 0);
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_run_for() {
+        use std::{thread, time::Duration};
+
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            vals: Vec<i32>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Value(i32),
+        }
+
+        const IDX_BASE: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                let Messages::Value(val) = msg;
+                self.vals.push(*val);
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("base", StateMachine::base))
+            .build_idx(IDX_BASE)
+            .expect("Unexpected error initializing");
+
+        // Without a sender nothing can arrive
+        assert_eq!(
+            sme.recv_timeout(Duration::from_millis(1)).err(),
+            Some(RecvTimeoutError::Disconnected)
+        );
+        let tx = sme.clone_sender();
+        assert_eq!(
+            sme.recv_timeout(Duration::from_millis(1)).err(),
+            Some(RecvTimeoutError::Timeout)
+        );
+
+        // Messages arriving well within the duration are all processed
+        let thread_tx = tx.clone();
+        let sender = thread::spawn(move || {
+            for val in 0..3 {
+                thread::sleep(Duration::from_millis(10));
+                thread_tx.send(Messages::Value(val)).unwrap();
+            }
+        });
+        let start = Instant::now();
+        assert_eq!(sme.run_for(Duration::from_millis(500)), 3);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        sender.join().unwrap();
+        assert_eq!(sme.get_sm().borrow().vals, [0, 1, 2]);
+
+        // And with no messages it returns after the duration
+        let start = Instant::now();
+        assert_eq!(sme.run_for(Duration::from_millis(10)), 0);
+        assert!(start.elapsed() >= Duration::from_millis(10));
+
+        // Or once the last sender is dropped
+        drop(tx);
+        let start = Instant::now();
+        assert_eq!(sme.run_for(Duration::from_millis(500)), 0);
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_run_for_uses_clock() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            clock: ManualClock,
+            vals: Vec<i32>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Value(i32),
+        }
+
+        const IDX_BASE: usize = 0;
+
+        impl StateMachine {
+            // Each message takes an hour on the executor's clock
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                let Messages::Value(val) = msg;
+                self.vals.push(*val);
+                self.clock.advance(Duration::from_secs(3600));
+                handled()
+            }
+        }
+
+        let clock = ManualClock::new();
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine {
+            clock: clock.clone(),
+            vals: vec![],
+        }))
+        .clock(clock)
+        .state(StateInfo::new("base", StateMachine::base))
+        .build_idx(IDX_BASE)
+        .expect("Unexpected error initializing");
+
+        // The deadline passes after the second message although there's
+        // a sender and a message waiting
+        let tx = sme.clone_sender();
+        for val in 0..3 {
+            tx.send(Messages::Value(val)).unwrap();
+        }
+        let start = Instant::now();
+        assert_eq!(sme.run_for(Duration::from_secs(7200)), 2);
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(sme.get_sm().borrow().vals, [0, 1]);
+        assert_eq!(sme.try_recv().ok().map(|Messages::Value(val)| val), Some(2));
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_run_until() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            val: i32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct Increment;
+
+        const IDX_COUNTING: usize = 0;
+        const IDX_DONE: usize = 1;
+
+        impl StateMachine {
+            // Keep sending ourselves Increment until val is 3
+            #[no_coverage]
+            fn counting(&mut self, e: &Executor<Self, Increment>, _msg: &Increment) -> StateResult {
+                self.val += 1;
+                if self.val < 3 {
+                    e.send(Increment).unwrap();
+                    handled()
+                } else {
+                    transition(e.state_id(IDX_DONE).unwrap())
+                }
+            }
+
+            #[no_coverage]
+            fn done(&mut self, _e: &Executor<Self, Increment>, _msg: &Increment) -> StateResult {
+                handled()
+            }
+        }
+
+        #[no_coverage]
+        fn build() -> Executor<StateMachine, Increment> {
+            let sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+                .state(StateInfo::new("counting", StateMachine::counting))
+                .state(StateInfo::new("done", StateMachine::done))
+                .build_idx(IDX_COUNTING)
+                .expect("Unexpected error initializing");
+            sme.send(Increment).unwrap();
+            sme
+        }
+
+        let mut sme = build();
+        assert_eq!(
+            sme.run_until(|e| e.get_current_state_name() == "done"),
+            RunOutcome::Done
+        );
+        assert_eq!(sme.get_sm().borrow().val, 3);
+
+        let mut sme = build();
+        assert_eq!(
+            sme.run_until_budget(2, |e| e.get_current_state_name() == "done"),
+            RunOutcome::BudgetExhausted
+        );
+        assert_eq!(sme.get_sm().borrow().val, 2);
+
+        // pred is checked before waiting for a message
+        let tx = sme.clone_sender();
+        assert_eq!(sme.run_until(|_| true), RunOutcome::Done);
+        assert_eq!(sme.get_sm().borrow().val, 2);
+
+        // Once the pending messages are processed and the senders are
+        // dropped no more can arrive
+        let sender = std::thread::spawn(move || tx.send(Increment).unwrap());
+        sender.join().unwrap();
+        assert_eq!(sme.run_until(|_| false), RunOutcome::Disconnected);
+        assert_eq!(sme.get_current_state_name(), "done");
+    }
+
+    #[cfg(all(feature = "counters", feature = "messaging"))]
+    #[test]
+    #[no_coverage]
+    fn test_dispatch_iter() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            sum: u64,
+        }
+
+        // Create a Protocol
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Value(u64),
+            Toggle,
+            Stop,
+        }
+
+        const IDX_BASE: usize = 0;
+        const IDX_SUMMING: usize = 1;
+        const IDX_DEFERRING: usize = 2;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn summing(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Value(val) => {
+                        self.sum += val;
+                        handled()
+                    }
+                    Messages::Toggle => transition(e.state_id(IDX_DEFERRING).unwrap()),
+                    Messages::Stop => not_handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn deferring(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Value(_) => {
+                        e.defer_current().unwrap();
+                        handled()
+                    }
+                    Messages::Toggle => transition(e.state_id(IDX_SUMMING).unwrap()),
+                    Messages::Stop => not_handled(),
+                }
+            }
+        }
+
+        #[no_coverage]
+        fn build() -> Executor<StateMachine, Messages> {
+            ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+                .state(StateInfo::new("base", StateMachine::base))
+                .state(StateInfo::new("summing", StateMachine::summing).parent_idx(IDX_BASE))
+                .state(StateInfo::new("deferring", StateMachine::deferring).parent_idx(IDX_BASE))
+                .build_idx(IDX_SUMMING)
+                .expect("Unexpected error initializing")
+        }
+
+        // A recorded sequence of 1000 messages
+        let msgs: Vec<Messages> = (0..1000u64)
+            .map(|i| match i % 7 {
+                3 => Messages::Toggle,
+                6 if i % 5 == 0 => Messages::Stop,
+                _ => Messages::Value(i),
+            })
+            .collect();
+
+        let mut batched = build();
+        let results = batched.dispatch_iter(&msgs);
+        assert_eq!(results.len(), msgs.len());
+        assert!(results.iter().all(|r| !r.truncated));
+
+        let mut looped = build();
+        for msg in msgs.iter() {
+            looped.dispatcher(msg);
+        }
+
+        assert_eq!(batched.get_sm().borrow().sum, looped.get_sm().borrow().sum);
+        assert_eq!(batched.get_deferred_cnt(), looped.get_deferred_cnt());
+        for idx in [IDX_BASE, IDX_SUMMING, IDX_DEFERRING] {
+            assert_eq!(
+                batched.get_state_process_cnt(idx),
+                looped.get_state_process_cnt(idx)
+            );
+        }
+        assert_eq!(
+            batched.get_current_state_name(),
+            looped.get_current_state_name()
+        );
+
+        // After shutdown nothing more is dispatched
+        batched.shutdown(&Messages::Stop);
+        assert!(batched.dispatch_iter(&msgs).is_empty());
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_primary_capacity() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            vals: Vec<i32>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug, PartialEq)]
+        pub enum Messages {
+            Value(i32),
+        }
+
+        const IDX_BASE: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                let Messages::Value(val) = msg;
+                self.vals.push(*val);
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .primary_capacity(2)
+            .state(StateInfo::new("base", StateMachine::base))
+            .build_idx(IDX_BASE)
+            .expect("Unexpected error initializing");
+
+        let tx = sme.clone_sender();
+        assert!(matches!(&tx, MsgSender::Executor(tx) if tx.capacity() == Some(2)));
+        tx.try_send(Messages::Value(1)).unwrap();
+        sme.try_send(Messages::Value(2)).unwrap();
+        assert_eq!(
+            tx.try_send(Messages::Value(3)),
+            Err(TrySendError::Full(Messages::Value(3)))
+        );
+
+        assert_eq!(sme.dispatch_all(), 2);
+        tx.send(Messages::Value(3)).unwrap();
+        assert_eq!(sme.dispatch_all(), 1);
+        assert_eq!(sme.get_sm().borrow().vals, [1, 2, 3]);
+
+        // A sender blocked on the full queue continues once one is received
+        tx.send(Messages::Value(4)).unwrap();
+        tx.send(Messages::Value(5)).unwrap();
+        let sender = std::thread::spawn(move || tx.send(Messages::Value(6)));
+        assert_eq!(sme.recv(), Ok(Messages::Value(4)));
+        sender.join().unwrap().unwrap();
+        assert_eq!(sme.dispatch_all(), 2);
+        assert_eq!(sme.get_sm().borrow().vals, [1, 2, 3, 5, 6]);
+
+        // Unbounded by default
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("base", StateMachine::base))
+            .build_idx(IDX_BASE)
+            .expect("Unexpected error initializing");
+        assert!(matches!(sme.clone_sender(), MsgSender::Executor(tx) if tx.capacity().is_none()));
+        for val in 0..10 {
+            sme.try_send(Messages::Value(val)).unwrap();
+        }
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_executor_group() {
+        #[derive(Debug)]
+        pub struct StateMachine {
+            configurable: bool,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            ConfigChanged,
+            Reset,
+        }
+
+        const IDX_RUNNING: usize = 0;
+        const IDX_RECONFIGURED: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn running(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::ConfigChanged if self.configurable => {
+                        transition(e.state_id(IDX_RECONFIGURED).unwrap())
+                    }
+                    _ => handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn reconfigured(
+                &mut self,
+                e: &Executor<Self, Messages>,
+                msg: &Messages,
+            ) -> StateResult {
+                match msg {
+                    Messages::Reset => transition(e.state_id(IDX_RUNNING).unwrap()),
+                    _ => handled(),
+                }
+            }
+        }
+
+        let mut group = ExecutorGroup::new();
+        assert!(group.is_empty());
+        for configurable in [true, false, true] {
+            let idx = group.push(
+                ExecutorBuilder::new(RefCell::new(StateMachine { configurable }))
+                    .state(StateInfo::new("running", StateMachine::running))
+                    .state(StateInfo::new("reconfigured", StateMachine::reconfigured))
+                    .build_idx(IDX_RUNNING)
+                    .expect("Unexpected error initializing"),
+            );
+            assert_eq!(idx, group.len() - 1);
+        }
+
+        assert!(group.all_in("running"));
+        assert_eq!(
+            group.broadcast(&Messages::ConfigChanged),
+            [true, false, true]
+        );
+        assert_eq!(group.states(), ["reconfigured", "running", "reconfigured"]);
+        assert!(!group.all_in("reconfigured"));
+
+        assert!(group.dispatch_to(2, &Messages::Reset));
+        assert_eq!(group.states(), ["reconfigured", "running", "running"]);
+        assert!(!group.get(1).get_sm().borrow().configurable);
+        group.get_mut(0).dispatch(&Messages::Reset);
+        assert!(group
+            .iter()
+            .all(|e| e.get_current_state_name() == "running"));
+    }
+
+    #[cfg(all(feature = "counters", feature = "messaging"))]
+    #[test]
+    #[no_coverage]
+    fn test_pipe_to() {
+        // A protocol machine emitting events to a session machine
+        #[derive(Debug)]
+        pub struct Protocol;
+
+        #[derive(Debug)]
+        pub enum Bytes {
+            Frame(u8),
+            Noise,
+        }
+
+        #[derive(Debug)]
+        pub struct Session;
+
+        #[derive(Debug)]
+        pub enum Events {
+            Connected,
+            Data(u8),
+        }
+
+        const IDX_IDLE: usize = 0;
+        const IDX_ACTIVE: usize = 1;
+
+        impl Protocol {
+            #[no_coverage]
+            fn framing(&mut self, e: &Executor<Self, Bytes>, msg: &Bytes) -> StateResult {
+                if let Bytes::Frame(b) = msg {
+                    e.emit(Bytes::Frame(*b));
+                } else {
+                    assert!(!e.emit(Bytes::Noise));
+                }
+                handled()
+            }
+        }
+
+        impl Session {
+            #[no_coverage]
+            fn idle(&mut self, e: &Executor<Self, Events>, msg: &Events) -> StateResult {
+                match msg {
+                    Events::Connected => transition(e.state_id(IDX_ACTIVE).unwrap()),
+                    Events::Data(_) => handled(),
+                }
+            }
+
+            #[no_coverage]
+            fn active(&mut self, _e: &Executor<Self, Events>, msg: &Events) -> StateResult {
+                if let Events::Data(b) = msg {
+                    assert!(*b > 0);
+                }
+                handled()
+            }
+        }
+
+        #[no_coverage]
+        fn to_events(msg: &Bytes) -> Option<Events> {
+            match msg {
+                Bytes::Frame(0) => Some(Events::Connected),
+                Bytes::Frame(b) => Some(Events::Data(*b)),
+                Bytes::Noise => None,
+            }
+        }
+
+        let mut a = ExecutorBuilder::new(RefCell::new(Protocol))
+            .state(StateInfo::new("framing", Protocol::framing))
+            .build_idx(0)
+            .expect("Unexpected error initializing");
+        let mut b = ExecutorBuilder::new(RefCell::new(Session))
+            .state(StateInfo::new("idle", Session::idle))
+            .state(StateInfo::new("active", Session::active))
+            .build_idx(IDX_IDLE)
+            .expect("Unexpected error initializing");
+
+        // Nothing is emitted until piped
+        a.dispatch(&Bytes::Frame(0));
+        assert_eq!(a.get_emitted_cnt(), 0);
+
+        a.pipe_to(b.clone_sender(), to_events);
+        for msg in [
+            Bytes::Frame(0),
+            Bytes::Noise,
+            Bytes::Frame(1),
+            Bytes::Frame(2),
+        ] {
+            a.dispatch(&msg);
+        }
+        assert_eq!(a.get_emitted_cnt(), 3);
+
+        assert_eq!(b.dispatch_all(), 3);
+        assert_eq!(b.get_current_state_name(), "active");
+        assert_eq!(b.get_state_process_cnt(IDX_IDLE), 1);
+        assert_eq!(b.get_state_process_cnt(IDX_ACTIVE), 2);
+    }
+
+    // Interleave defers across many transitions, the debug_assert in
+    // dispatcher checks the sequence numbers never regress.
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_deferred_order() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            processed: Vec<(u64, u32)>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Value(u32),
+            Toggle,
+        }
+
+        const IDX_DEFERRING: usize = 0;
+        const IDX_PROCESSING: usize = 1;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn deferring(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Value(_) => {
+                        e.defer_current().unwrap();
+                        handled()
+                    }
+                    Messages::Toggle => transition(e.state_id(IDX_PROCESSING).unwrap()),
+                }
+            }
+
+            // Go back to deferring part way through the deferred messages
+            #[no_coverage]
+            fn processing(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Value(val) => {
+                        self.processed.push((e.current_seq().unwrap(), *val));
+                        if val % 3 == 0 {
+                            transition(e.state_id(IDX_DEFERRING).unwrap())
+                        } else {
+                            handled()
+                        }
+                    }
+                    Messages::Toggle => transition(e.state_id(IDX_DEFERRING).unwrap()),
+                }
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("deferring", StateMachine::deferring))
+            .state(StateInfo::new("processing", StateMachine::processing))
+            .build_idx(IDX_DEFERRING)
+            .expect("Unexpected error initializing");
+
+        const VALUE_CNT: u32 = 1000;
+        for val in 0..VALUE_CNT {
+            sme.dispatcher(&Messages::Value(val));
+            if val % 7 == 0 {
+                sme.dispatcher(&Messages::Toggle);
+            }
+        }
+        while sme.get_current_state_name() != "processing" || sme.get_deferred_cnt() != 0 {
+            sme.dispatcher(&Messages::Toggle);
+        }
+
+        // Every value was processed once and in the order sent
+        let processed = &sme.get_sm().borrow().processed;
+        assert_eq!(processed.len(), VALUE_CNT as usize);
+        assert!(processed.windows(2).all(|w| w[0] < w[1]));
+        assert!(processed.iter().map(|(_, val)| *val).eq(0..VALUE_CNT));
+        assert_eq!(sme.current_seq(), None);
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_current_message_origin() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            origins: Vec<MessageOrigin>,
+            handled: u32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Work,
+        }
+
+        const IDX_RETRYING: usize = 0;
+
+        impl StateMachine {
+            // Defer twice and handle it on the third attempt
+            #[no_coverage]
+            fn retrying(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                let origin = e.current_message_origin();
+                self.origins.push(origin);
+                match origin {
+                    MessageOrigin::Deferred { attempts } if attempts >= 2 => {
+                        self.handled += 1;
+                    }
+                    _ => e.defer_current().unwrap(),
+                }
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("retrying", StateMachine::retrying))
+            .build_idx(IDX_RETRYING)
+            .expect("Unexpected error initializing");
+
+        sme.dispatcher(&Messages::Work);
+        assert_eq!(sme.get_deferred_cnt(), 1);
+        assert_eq!(sme.process_deferred(), 1);
+        assert_eq!(sme.get_deferred_cnt(), 1);
+        assert_eq!(sme.process_deferred(), 1);
+        assert_eq!(sme.get_deferred_cnt(), 0);
+        assert_eq!(sme.current_message_origin(), MessageOrigin::Fresh);
+
+        let sm = sme.get_sm().borrow();
+        assert_eq!(sm.handled, 1);
+        assert_eq!(
+            sm.origins,
+            vec![
+                MessageOrigin::Fresh,
+                MessageOrigin::Deferred { attempts: 1 },
+                MessageOrigin::Deferred { attempts: 2 },
+            ]
+        );
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_max_defer_attempts() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            attempts: Vec<u32>,
+            values: Vec<i32>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Messages {
+            Value(i32),
+            Poison,
+        }
+
+        const IDX_WORKING: usize = 0;
+
+        impl StateMachine {
+            // Poison is always deferred
+            #[no_coverage]
+            fn working(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Value(val) => self.values.push(*val),
+                    Messages::Poison => {
+                        if let MessageOrigin::Deferred { attempts } = e.current_message_origin() {
+                            self.attempts.push(attempts);
+                        }
+                        e.defer_current().unwrap();
+                    }
+                }
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("working", StateMachine::working))
+            .max_defer_attempts(3)
+            .build_idx(IDX_WORKING)
+            .expect("Unexpected error initializing");
+
+        sme.dispatcher(&Messages::Poison);
+        sme.dispatcher(&Messages::Value(1));
+        for _ in 0..3 {
+            assert_eq!(sme.get_deferred_cnt(), 1);
+            assert!(sme.dead_letters().try_recv().is_err());
+            assert_eq!(sme.process_deferred(), 1);
+        }
+        assert_eq!(sme.get_deferred_cnt(), 0);
+        assert_eq!(sme.dead_letters().try_recv(), Ok(Messages::Poison));
+        assert_eq!(sme.process_deferred(), 0);
+
+        let sm = sme.get_sm().borrow();
+        assert_eq!(sm.attempts, vec![1, 2, 3]);
+        assert_eq!(sm.values, vec![1]);
+    }
+
+    // A message deferred with defer_send while replaying another is new,
+    // it doesn't inherit the replayed message's attempts or seq
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_defer_send_while_replaying() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            // (msg, seq, origin) of each message processed
+            processed: Vec<(&'static str, u64, MessageOrigin)>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Messages {
+            A,
+            B,
+        }
+
+        const IDX_WORKING: usize = 0;
+
+        impl StateMachine {
+            // A is deferred once, replaying it defers a new B
+            #[no_coverage]
+            fn working(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                let origin = e.current_message_origin();
+                let seq = e.current_seq().unwrap();
+                match msg {
+                    Messages::A => {
+                        self.processed.push(("a", seq, origin));
+                        match origin {
+                            MessageOrigin::Fresh => e.defer_current().unwrap(),
+                            MessageOrigin::Deferred { .. } => e.defer_send(Messages::B).unwrap(),
+                        }
+                    }
+                    Messages::B => self.processed.push(("b", seq, origin)),
+                }
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("working", StateMachine::working))
+            .max_defer_attempts(1)
+            .build_idx(IDX_WORKING)
+            .expect("Unexpected error initializing");
+
+        sme.dispatcher(&Messages::A);
+        assert_eq!(sme.get_deferred_cnt(), 1);
+        assert_eq!(sme.process_deferred(), 1);
+        assert_eq!(sme.get_deferred_cnt(), 1);
+        assert!(sme.dead_letters().try_recv().is_err());
+        assert_eq!(sme.process_deferred(), 1);
+        assert_eq!(sme.get_deferred_cnt(), 0);
+
+        let sm = sme.get_sm().borrow();
+        assert_eq!(
+            sm.processed,
+            vec![
+                ("a", 0, MessageOrigin::Fresh),
+                ("a", 0, MessageOrigin::Deferred { attempts: 1 }),
+                ("b", 1, MessageOrigin::Deferred { attempts: 1 }),
+            ]
+        );
+    }
 
-    // Test SM with one state with one field and no enter or exit
+    #[cfg(feature = "messaging")]
     #[test]
     #[no_coverage]
-    fn test_sm_1s_no_enter_no_exit() {
-        #[derive(Debug)]
+    fn test_state_info_defers() {
+        #[derive(Debug, Default)]
         pub struct StateMachine {
-            state: i32,
+            busy_requests: Vec<u32>,
+            idle_requests: Vec<u32>,
         }
 
         // Create a Protocol
-        #[derive(Debug)]
-        pub struct NoMessages;
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Request(u32),
+            Done,
+        }
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
+        const IDX_BUSY: usize = 0;
+        const IDX_IDLE: usize = 1;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine { state: 0 });
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1))
-                    .build(IDX_STATE1)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn is_request(msg: &Messages) -> bool {
+                matches!(msg, Messages::Request(_))
             }
 
+            // Requests are deferred before busy sees them
             #[no_coverage]
-            fn state1(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-                println!("{}:+", e.get_state_name(IDX_STATE1));
-
-                self.state += 1;
+            fn busy(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Request(id) => {
+                        self.busy_requests.push(*id);
+                        handled()
+                    }
+                    Messages::Done => transition(e.state_id(IDX_IDLE).unwrap()),
+                }
+            }
 
-                println!("{}:-", e.get_state_name(IDX_STATE1));
-                (Handled::Yes, None)
+            #[no_coverage]
+            fn idle(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                if let Messages::Request(id) = msg {
+                    self.idle_requests.push(*id);
+                }
+                handled()
             }
         }
 
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_sm().borrow().state, 0);
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("busy", StateMachine::busy).defers(StateMachine::is_request))
+            .state(StateInfo::new("idle", StateMachine::idle))
+            .build_idx(IDX_BUSY)
+            .expect("Unexpected error initializing");
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", sme.get_sm());
+        sme.dispatcher(&Messages::Request(1));
+        sme.dispatcher(&Messages::Request(2));
+        assert_eq!(sme.get_deferred_cnt(), 2);
+        #[cfg(feature = "counters")]
+        {
+            assert_eq!(sme.get_state_defer_cnt(IDX_BUSY), 2);
+            assert_eq!(sme.get_state_handled_cnt(IDX_BUSY), 2);
+        }
 
-        sme.dispatcher(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_sm().borrow().state, 1);
+        let outcome = sme.dispatcher(&Messages::Done);
+        assert!(outcome.transitioned);
+        assert_eq!(outcome.messages_processed, 3);
+        assert_eq!(sme.get_deferred_cnt(), 0);
+        assert_eq!(sme.get_current_state_name(), "idle");
 
-        sme.dispatcher(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_sm().borrow().state, 2);
+        let sm = sme.get_sm().borrow();
+        assert!(sm.busy_requests.is_empty());
+        assert_eq!(sm.idle_requests, vec![1, 2]);
     }
 
-    // Test SM with one state getting names
+    #[cfg(feature = "messaging")]
     #[test]
     #[no_coverage]
-    fn test_sm_1s_get_names() {
-        #[derive(Debug)]
+    fn test_regions_defer() {
+        // Region 0 defers Req with StateInfo::defers and Note with
+        // defer_send until Go, region 1 counts them as they arrive.
+        //
+        //  region 0:  waiting=0 =====> ready=1      region 1:  counting=2
+
+        #[derive(Debug, Default)]
         pub struct StateMachine {
-            state: i32,
+            ready_msgs: Vec<&'static str>,
+            counted_msgs: Vec<&'static str>,
         }
 
         // Create a Protocol
-        #[derive(Debug)]
-        pub struct NoMessages;
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Req,
+            Note,
+            Go,
+        }
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
+        const IDX_WAITING: usize = 0;
+        const IDX_READY: usize = 1;
+        const IDX_COUNTING: usize = 2;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine { state: 0 });
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1))
-                    .build(IDX_STATE1)
-                    .expect("Unexpected error initializing");
+            fn is_req(msg: &Messages) -> bool {
+                matches!(msg, Messages::Req)
+            }
 
-                sme
+            #[no_coverage]
+            fn waiting(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Go => transition(e.state_id(IDX_READY).unwrap()),
+                    _ => {
+                        e.defer_send(msg.clone()).unwrap();
+                        handled()
+                    }
+                }
             }
 
             #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                self.state += 1;
+            fn ready(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Req => self.ready_msgs.push("req"),
+                    Messages::Note => self.ready_msgs.push("note"),
+                    Messages::Go => (),
+                }
+                handled()
+            }
 
-                (Handled::Yes, None)
+            #[no_coverage]
+            fn counting(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Req => self.counted_msgs.push("req"),
+                    Messages::Note => self.counted_msgs.push("note"),
+                    Messages::Go => (),
+                }
+                handled()
             }
         }
 
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(sme.get_sm().borrow().state, 0);
-        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
-        assert_eq!(sme.get_current_state_name(), "state1");
-
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", sme.get_sm());
-
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_sm().borrow().state, 1);
-        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
-        assert_eq!(sme.get_current_state_name(), "state1");
-
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_sm().borrow().state, 2);
-        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
-        assert_eq!(sme.get_current_state_name(), "state1");
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .with_regions(2)
+            .state(StateInfo::new("waiting", StateMachine::waiting).defers(StateMachine::is_req))
+            .state(StateInfo::new("ready", StateMachine::ready))
+            .state(StateInfo::new("counting", StateMachine::counting).region(1))
+            .region_initial_state_idx(1, IDX_COUNTING)
+            .build_idx(IDX_WAITING)
+            .expect("Unexpected error initializing");
+
+        sme.dispatcher(&Messages::Req);
+        sme.dispatcher(&Messages::Note);
+        assert_eq!(sme.get_deferred_cnt(), 2);
+        assert_eq!(sme.get_sm().borrow().counted_msgs, vec!["req", "note"]);
+
+        // Only region 0 reprocesses them
+        let outcome = sme.dispatcher(&Messages::Go);
+        assert!(outcome.transitioned);
+        assert_eq!(outcome.messages_processed, 3);
+        assert_eq!(sme.get_deferred_cnt(), 0);
+        let sm = sme.get_sm().borrow();
+        assert_eq!(sm.ready_msgs, vec!["req", "note"]);
+        assert_eq!(sm.counted_msgs, vec!["req", "note"]);
     }
 
-    // Test SM with two states getting names
+    #[cfg(feature = "messaging")]
     #[test]
     #[no_coverage]
-    fn test_sm_2s_get_names() {
-        #[derive(Debug)]
+    fn test_clears_deferred() {
+        #[derive(Debug, Default)]
         pub struct StateMachine {
-            state: i32,
+            requests: Vec<u32>,
+            dropped: Vec<u32>,
         }
 
         // Create a Protocol
-        #[derive(Debug)]
-        pub struct NoMessages;
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Request(u32),
+            Reset,
+        }
 
-        const MAX_STATES: usize = 2;
-        const IDX_STATE1: usize = 0;
-        const IDX_STATE2: usize = 1;
+        const IDX_BUSY: usize = 0;
+        const IDX_IDLE: usize = 1;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine { state: 0 });
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1))
-                    .state(StateInfo::new("state2", Self::state2))
-                    .build(IDX_STATE1)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn busy(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Request(_) => {
+                        e.defer_send(msg.clone()).unwrap();
+                        handled()
+                    }
+                    Messages::Reset => transition(e.state_id(IDX_IDLE).unwrap()),
+                }
             }
 
             #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                self.state += 1;
-
-                (Handled::Yes, Some(IDX_STATE2))
+            fn idle(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                if let Messages::Request(id) = msg {
+                    self.requests.push(*id);
+                }
+                handled()
             }
 
             #[no_coverage]
-            fn state2(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                self.state -= 1;
-
-                (Handled::Yes, Some(IDX_STATE1))
+            fn deferred_dropped(&mut self, msg: &Messages) {
+                if let Messages::Request(id) = msg {
+                    self.dropped.push(*id);
+                }
             }
         }
 
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(sme.get_sm().borrow().state, 0);
-        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
-        assert_eq!(sme.get_current_state_name(), "state1");
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .on_deferred_dropped(StateMachine::deferred_dropped)
+            .state(StateInfo::new("busy", StateMachine::busy))
+            .state(StateInfo::new("idle", StateMachine::idle).clears_deferred())
+            .build_idx(IDX_BUSY)
+            .expect("Unexpected error initializing");
+
+        sme.dispatcher(&Messages::Request(1));
+        sme.dispatcher(&Messages::Request(2));
+        sme.dispatcher(&Messages::Request(3));
+        assert_eq!(sme.get_deferred_cnt(), 3);
+
+        // Entering idle drops the deferred requests before they're replayed
+        let outcome = sme.dispatcher(&Messages::Reset);
+        assert!(outcome.transitioned);
+        assert_eq!(outcome.messages_processed, 1);
+        assert_eq!(sme.get_deferred_cnt(), 0);
+        assert_eq!(sme.get_deferred_dropped_cnt(), 3);
+        assert_eq!(sme.get_current_state_name(), "idle");
+
+        let sm = sme.get_sm().borrow();
+        assert!(sm.requests.is_empty());
+        assert_eq!(sm.dropped, vec![1, 2, 3]);
+    }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", sme.get_sm());
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_defer_current() {
+        #[derive(Debug)]
+        pub struct StateMachine;
 
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_sm().borrow().state, 1);
-        assert_eq!(sme.get_state_name(IDX_STATE2), "state2");
-        assert_eq!(sme.get_current_state_name(), "state2");
+        // Create a Protocol
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Value { val: i32, name: String },
+        }
 
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_sm().borrow().state, 0);
-        assert_eq!(sme.get_state_name(IDX_STATE1), "state1");
-        assert_eq!(sme.get_current_state_name(), "state1");
+        const IDX_DEFERRING: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn deferring(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                e.defer_current().unwrap();
+                assert_eq!(e.defer_current(), Err(DeferError::AlreadyDeferred));
+                handled()
+            }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("deferring", StateMachine::deferring))
+            .build_idx(IDX_DEFERRING)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.defer_current(), Err(DeferError::NotDispatching));
+
+        let msg = Messages::Value {
+            val: 1,
+            name: "one".to_owned(),
+        };
+        sme.dispatch(&msg);
+        assert_eq!(sme.get_deferred_cnt(), 1);
+
+        // The deferred copy is identical to the dispatched message
+        sme.next_defer();
+        let deferred = sme.defer_try_recv().unwrap();
+        assert_eq!(format!("{deferred:?}"), format!("{msg:?}"));
+        let Messages::Value { val, name } = deferred;
+        assert_eq!((val, name.as_str()), (1, "one"));
+        assert_eq!(sme.get_deferred_cnt(), 0);
     }
 
     #[test]
     #[no_coverage]
-    #[should_panic]
-    fn test_sm_out_of_bounds_initial_transition() {
+    fn test_pending_paths() {
         #[derive(Debug)]
         pub struct StateMachine;
 
@@ -714,52 +8947,62 @@ mod test {
         #[derive(Debug)]
         pub struct NoMessages;
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
-        const INVALID_STATE: usize = 1;
+        const MAX_STATES: usize = 4;
+        const IDX_INITIAL_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER_BASE: usize = 2;
+        const IDX_OTHER: usize = 3;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine);
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1))
-                    .build(INVALID_STATE)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
             }
 
             #[no_coverage]
-            fn state1(
+            fn initial(
                 &mut self,
-                _e: &Executor<Self, NoMessages>,
+                e: &Executor<Self, NoMessages>,
                 _msg: &NoMessages,
             ) -> StateResult {
-                // Invalid transition that is not less than MAX_STATES
-                (Handled::Yes, Some(1))
+                transition(e.state_id(IDX_OTHER).unwrap())
             }
-        }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                assert!(!e.current_state_changed());
+                assert!(e.pending_enter_path().is_empty());
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
+        }
 
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 4);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("initial_base", StateMachine::base))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_INITIAL_BASE))
+            .state(StateInfo::new("other_base", StateMachine::base))
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(IDX_OTHER_BASE))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.state_count(), MAX_STATES);
+        assert!(sme.current_state_changed());
+        assert_eq!(sme.pending_enter_path(), [IDX_INITIAL_BASE, IDX_INITIAL]);
+        assert!(sme.pending_exit_path().is_empty());
+
+        // The exits run at the end of dispatch, the enters on the next one
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "other");
+        assert!(sme.current_state_changed());
+        assert_eq!(sme.pending_enter_path(), [IDX_OTHER_BASE, IDX_OTHER]);
+        assert!(sme.pending_exit_path().is_empty());
 
-        // This will panic because state1 returns an invalid transition
         sme.dispatch(&NoMessages);
+        assert_eq!(sme.pending_enter_path(), [IDX_INITIAL_BASE, IDX_INITIAL]);
     }
 
+    #[cfg(feature = "counters")]
     #[test]
     #[no_coverage]
-    #[should_panic]
-    fn test_sm_invalid_initial_state() {
+    fn test_state_view() {
         #[derive(Debug)]
         pub struct StateMachine;
 
@@ -767,55 +9010,75 @@ mod test {
         #[derive(Debug)]
         pub struct NoMessages;
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
-        const _IDX_STATE2: usize = 1;
+        const IDX_BASE: usize = 0;
+        const IDX_LEAF: usize = 1;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine);
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE1))
-                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
-                    .build(IDX_STATE1)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
             }
 
             #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            fn leaf(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                not_handled()
             }
 
             #[no_coverage]
-            fn state2(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                // Invalid transition IDX_STATE1 isn't a leaf
-                (Handled::Yes, Some(IDX_STATE1))
-            }
+            fn leaf_enter(&mut self, _msg: &NoMessages) {}
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(
+                StateInfo::new("leaf", StateMachine::leaf)
+                    .enter_fn(StateMachine::leaf_enter)
+                    .parent_idx(IDX_BASE),
+            )
+            .build_idx(IDX_LEAF)
+            .expect("Unexpected error initializing");
+        sme.dispatch(&NoMessages);
 
-        // Create a sme and validate it's in the expected state
-        let _ = StateMachine::new();
+        let base = sme.state(IDX_BASE).unwrap();
+        assert_eq!(base.idx(), sme.state_id(IDX_BASE).unwrap());
+        assert_eq!(base.name(), "base");
+        assert_eq!(base.parent(), None);
+        assert!(!base.is_leaf());
+        assert!(!base.has_enter());
+        assert!(!base.has_exit());
+        assert_eq!(
+            base.counters(),
+            StateCounters {
+                enter_cnt: 0,
+                process_cnt: 1,
+                handled_cnt: 1,
+                exit_cnt: 0
+            }
+        );
+
+        let leaf = sme.state(IDX_LEAF).unwrap();
+        assert_eq!(leaf.name(), "leaf");
+        assert_eq!(leaf.parent(), sme.state_id(IDX_BASE));
+        assert!(leaf.is_leaf());
+        assert!(leaf.is_active());
+        assert!(leaf.has_enter());
+        assert!(!leaf.has_exit());
+        assert_eq!(
+            leaf.counters(),
+            StateCounters {
+                enter_cnt: 1,
+                process_cnt: 1,
+                handled_cnt: 0,
+                exit_cnt: 0
+            }
+        );
+
+        assert!(sme.state(2).is_none());
     }
 
     #[test]
     #[no_coverage]
-    #[should_panic]
-    fn test_sm_2s_invalid_transition() {
+    fn test_display() {
         #[derive(Debug)]
         pub struct StateMachine;
 
@@ -823,516 +9086,658 @@ mod test {
         #[derive(Debug)]
         pub struct NoMessages;
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
-        const IDX_STATE2: usize = 1;
-
-        impl StateMachine {
-            #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine);
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1))
-                    .state(StateInfo::new("state1", Self::state2).parent_idx(IDX_STATE1))
-                    .build(IDX_STATE2)
-                    .expect("Unexpected error initializing");
+        const IDX_INITIAL_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER_BASE: usize = 2;
+        const IDX_OTHER: usize = 3;
 
-                sme
+        impl StateMachine {
+            #[no_coverage]
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
             }
 
             #[no_coverage]
-            fn state1(
+            fn initial(
                 &mut self,
-                _e: &Executor<Self, NoMessages>,
+                e: &Executor<Self, NoMessages>,
                 _msg: &NoMessages,
             ) -> StateResult {
-                (Handled::Yes, None)
+                transition(e.state_id(IDX_OTHER).unwrap())
             }
 
             #[no_coverage]
-            fn state2(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                // Invalid transition IDX_STATE1 isn't a leaf
-                (Handled::Yes, Some(IDX_STATE1))
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
             }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
-
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 4);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        // The hsm-2h-2s example layout
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("hsm-2h-2s")
+            .state(StateInfo::new("initial_base", StateMachine::base))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_INITIAL_BASE))
+            .state(StateInfo::new("other_base", StateMachine::base))
+            .state(StateInfo::new("other", StateMachine::other).parent_idx(IDX_OTHER_BASE))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.state_path(), "initial_base/initial");
+        assert_eq!(format!("{sme}"), "hsm-2h-2s[initial_base/initial]");
+
+        // The path is updated before the enter fns run
+        sme.dispatch(&NoMessages);
+        assert!(sme.current_state_changed());
+        assert_eq!(sme.state_path(), "other_base/other");
+        assert_eq!(format!("{sme}"), "hsm-2h-2s[other_base/other]");
 
-        // This will panic because state2 returns an invalid transition
-        // to state1 which isn't a leaf
         sme.dispatch(&NoMessages);
+        assert_eq!(format!("{sme}"), "hsm-2h-2s[initial_base/initial]");
     }
 
+    #[cfg(all(feature = "counters", feature = "messaging"))]
     #[test]
     #[no_coverage]
-    #[should_panic]
-    fn test_sm_out_of_bounds_invalid_transition() {
-        #[derive(Debug)]
-        pub struct StateMachine;
+    fn test_shared_executor() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            total: usize,
+        }
 
         // Create a Protocol
         #[derive(Debug)]
-        pub struct NoMessages;
+        pub struct Add(usize);
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
+        const IDX_BASE: usize = 0;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine);
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1))
-                    .build(IDX_STATE1)
-                    .expect("Unexpected error initializing");
-
-                sme
-            }
-
-            #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                // Invalid transition that is not less than MAX_STATES
-                (Handled::Yes, Some(1))
+            fn base(&mut self, _e: &Executor<Self, Add>, msg: &Add) -> StateResult {
+                self.total += msg.0;
+                handled()
             }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
+        let sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("base", StateMachine::base))
+            .build_idx(IDX_BASE)
+            .expect("Unexpected error initializing");
+        let shared = Arc::new(SharedExecutor::new(sme));
+
+        // One thread dispatches, the other sends and periodically
+        // processes what it sent.
+        const MSG_CNT: usize = 1000;
+        let dispatching = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || {
+                for _ in 0..MSG_CNT {
+                    shared.dispatch(&Add(1));
+                }
+            })
+        };
+        let sending = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || {
+                for i in 0..MSG_CNT {
+                    shared.send(Add(2)).unwrap();
+                    if i % 100 == 0 {
+                        shared.dispatch_all();
+                    }
+                }
+            })
+        };
+        dispatching.join().unwrap();
+        sending.join().unwrap();
+        shared.dispatch_all();
 
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 4);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
+        let (total, process_cnt) =
+            shared.with(|e| (e.get_sm().borrow().total, e.get_state_process_cnt(IDX_BASE)));
+        assert_eq!(total, MSG_CNT * 3);
+        assert_eq!(process_cnt, MSG_CNT * 2);
 
-        // This will panic because state1 returns an invalid transition
-        sme.dispatch(&NoMessages);
+        let sme = Arc::try_unwrap(shared).ok().unwrap().into_inner();
+        assert_eq!(sme.get_state_process_cnt(IDX_BASE), MSG_CNT * 2);
     }
 
-    // Test SM with one state with one field
+    #[cfg(feature = "counters")]
     #[test]
     #[no_coverage]
-    fn test_sm_1s_enter_no_exit() {
-        #[derive(Debug)]
+    fn test_clone() {
+        #[derive(Debug, Clone)]
         pub struct StateMachine {
-            state: i32,
+            processed: Vec<u32>,
         }
 
         // Create a Protocol
         #[derive(Debug)]
-        pub enum Messages {
-            Add { val: i32 },
-            Sub { val: i32 },
-        }
+        pub struct Value(u32);
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
+        const IDX_EVEN: usize = 0;
+        const IDX_ODD: usize = 1;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, Messages> {
-                let sm = RefCell::new(StateMachine { state: 0 });
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1).enter_fn(Self::state1_enter))
-                    .build(IDX_STATE1)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn even(&mut self, e: &Executor<Self, Value>, msg: &Value) -> StateResult {
+                self.processed.push(msg.0);
+                transition(e.state_id(IDX_ODD).unwrap())
             }
 
             #[no_coverage]
-            fn state1_enter(&mut self, _msg: &Messages) {
-                self.state = 100;
+            fn odd(&mut self, e: &Executor<Self, Value>, msg: &Value) -> StateResult {
+                self.processed.push(msg.0);
+                transition(e.state_id(IDX_EVEN).unwrap())
             }
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine {
+            processed: Vec::new(),
+        }))
+        .name("original")
+        .state(StateInfo::new("even", StateMachine::even))
+        .state(StateInfo::new("odd", StateMachine::odd))
+        .build_idx(IDX_EVEN)
+        .expect("Unexpected error initializing");
+        sme.dispatch(&Value(1));
+
+        // Drive the clone three steps ahead
+        let mut what_if = sme.clone();
+        for val in 2..5 {
+            what_if.dispatch(&Value(val));
+        }
+        assert_eq!(what_if.get_name(), "original");
+        assert_eq!(what_if.get_sm().borrow().processed, [1, 2, 3, 4]);
+        assert_eq!(what_if.get_current_state_name(), "even");
+        assert_eq!(what_if.get_state_process_cnt(IDX_EVEN), 2);
+        assert_eq!(what_if.get_state_process_cnt(IDX_ODD), 2);
+
+        // The original is untouched
+        assert_eq!(sme.get_sm().borrow().processed, [1]);
+        assert_eq!(sme.get_current_state_name(), "odd");
+        assert_eq!(sme.get_state_process_cnt(IDX_EVEN), 1);
+        assert_eq!(sme.get_state_process_cnt(IDX_ODD), 0);
+        assert_eq!(sme.transition_counts().len(), 1);
+        assert_eq!(what_if.transition_counts().len(), 2);
+    }
+
+    // Replay test_leaf_transitions_in_a_tree one step at a time
+    #[test]
+    #[no_coverage]
+    fn test_step_session() {
+        #[derive(Debug)]
+        struct StateMachine;
+
+        // Create a Protocol with no messages
+        #[derive(Debug)]
+        struct NoMessages;
+
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_OTHER: usize = 2;
 
+        impl StateMachine {
             #[no_coverage]
-            fn state1(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
-                match msg {
-                    Messages::Add { val } => self.state += val,
-                    Messages::Sub { val } => self.state -= val,
-                }
-                (Handled::Yes, None)
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
             }
-        }
 
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_sm().borrow().state, 0);
+            #[no_coverage]
+            fn initial(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> StateResult {
+                transition(e.state_id(IDX_OTHER).unwrap())
+            }
 
-        // For code coverage
-        println!("{:?}", Messages::Add { val: -1 });
-        println!("{:?}", sme.get_sm());
+            #[no_coverage]
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_INITIAL).unwrap())
+            }
 
-        sme.dispatch(&Messages::Add { val: 2 });
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_sm().borrow().state, 102);
+            #[no_coverage]
+            fn enter_exit(&mut self, _msg: &NoMessages) {}
+        }
 
-        sme.dispatch(&Messages::Sub { val: 1 });
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_sm().borrow().state, 101);
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("base", StateMachine::base).enter_fn(StateMachine::enter_exit))
+            .state(
+                StateInfo::new("initial", StateMachine::initial)
+                    .enter_fn(StateMachine::enter_exit)
+                    .exit_fn(StateMachine::enter_exit)
+                    .parent_idx(IDX_BASE),
+            )
+            .state(
+                StateInfo::new("other", StateMachine::other)
+                    .enter_fn(StateMachine::enter_exit)
+                    .exit_fn(StateMachine::enter_exit)
+                    .parent_idx(IDX_BASE),
+            )
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        // Each step is observable, initial is still active after it
+        // transitioned until its exit step.
+        let mut session = sme.begin_dispatch(&NoMessages);
+        assert_eq!(session.step(), Some(StepEvent::Enter(IDX_BASE)));
+        assert_eq!(session.step(), Some(StepEvent::Enter(IDX_INITIAL)));
+        assert_eq!(session.step(), Some(StepEvent::Process(IDX_INITIAL)));
+        assert!(session.transitioned());
+        assert!(session.executor.states[IDX_INITIAL].active);
+        assert_eq!(session.step(), Some(StepEvent::Exit(IDX_INITIAL)));
+        assert!(!session.executor.states[IDX_INITIAL].active);
+        assert_eq!(session.step(), None);
+        assert_eq!(session.step(), None);
+        drop(session);
+        assert_eq!(sme.get_current_state_name(), "other");
+
+        let mut session = sme.begin_dispatch(&NoMessages);
+        let events: Vec<StepEvent> = core::iter::from_fn(|| session.step()).collect();
+        assert_eq!(
+            events,
+            [
+                StepEvent::Enter(IDX_OTHER),
+                StepEvent::Process(IDX_OTHER),
+                StepEvent::Exit(IDX_OTHER)
+            ]
+        );
+        drop(session);
+
+        // Dropping a session part way finishes the dispatch
+        let mut session = sme.begin_dispatch(&NoMessages);
+        assert_eq!(session.step(), Some(StepEvent::Enter(IDX_INITIAL)));
+        drop(session);
+        assert_eq!(sme.get_current_state_name(), "other");
+        #[cfg(feature = "counters")]
+        {
+            assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
+            assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
+            assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 2);
+            assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
+            assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 1);
+            assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+        }
     }
 
-    // Test SM with twos state with one field
-    #[test]
-    #[no_coverage]
-    fn test_sm_2s_no_enter_no_exit() {
-        #[derive(Debug)]
-        pub struct StateMachine {
-            state: i32,
-        }
+    #[cfg(feature = "serde")]
+    mod record_replay {
+        use super::*;
 
-        // Create a Protocol
         #[derive(Debug)]
-        pub enum Message {
-            Add { val: i32 },
+        pub struct StateMachine;
+
+        // Create a Protocol that can be recorded
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum Messages {
+            Start,
+            Stop,
+            Ping { id: u32 },
         }
 
-        const MAX_STATES: usize = 2;
-        const IDX_STATE1: usize = 0;
-        const IDX_STATE2: usize = 1;
+        pub const IDX_BASE: usize = 0;
+        pub const IDX_IDLE: usize = 1;
+        pub const IDX_RUNNING: usize = 2;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, Message> {
-                let sm = RefCell::new(StateMachine { state: 0 });
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1))
-                    .state(StateInfo::new("state2", Self::state2))
-                    .build(IDX_STATE1)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn base(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                handled()
             }
 
             #[no_coverage]
-            fn state1(&mut self, _e: &Executor<Self, Message>, msg: &Message) -> StateResult {
+            fn idle(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
                 match msg {
-                    Message::Add { val } => self.state += val,
+                    Messages::Start => transition(e.state_id(IDX_RUNNING).unwrap()),
+                    _ => not_handled(),
                 }
-                (Handled::Yes, Some(IDX_STATE2))
             }
 
             #[no_coverage]
-            fn state2(&mut self, _e: &Executor<Self, Message>, msg: &Message) -> StateResult {
+            fn running(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
                 match msg {
-                    Message::Add { val } => self.state += 2 * val,
+                    Messages::Stop => transition(e.state_id(IDX_IDLE).unwrap()),
+                    _ => not_handled(),
                 }
-                (Handled::Yes, Some(IDX_STATE1))
             }
         }
 
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_sm().borrow().state, 0);
+        #[no_coverage]
+        pub fn build(idx_initial_state: usize) -> Executor<StateMachine, Messages> {
+            ExecutorBuilder::new(RefCell::new(StateMachine))
+                .state(StateInfo::new("base", StateMachine::base))
+                .state(StateInfo::new("idle", StateMachine::idle).parent_idx(IDX_BASE))
+                .state(StateInfo::new("running", StateMachine::running).parent_idx(IDX_BASE))
+                .build_idx(idx_initial_state)
+                .expect("Unexpected error initializing")
+        }
 
-        // For code coverage
-        println!("{:?}", Message::Add { val: -2 });
-        println!("{:?}", sme.get_sm());
+        // A writer the test can read back after the executor has it
+        #[derive(Clone, Default)]
+        pub struct SharedBuf(pub Arc<Mutex<Vec<u8>>>);
 
-        sme.dispatch(&Message::Add { val: 2 });
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_sm().borrow().state, 2);
+        impl std::io::Write for SharedBuf {
+            #[no_coverage]
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
 
-        sme.dispatch(&Message::Add { val: -1 });
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE1), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE1), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_STATE2), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_STATE2), 0);
-        assert_eq!(sme.get_sm().borrow().state, 0);
+            #[no_coverage]
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        #[no_coverage]
+        pub fn messages() -> Vec<Messages> {
+            vec![
+                Messages::Ping { id: 1 },
+                Messages::Start,
+                Messages::Ping { id: 2 },
+                Messages::Stop,
+            ]
+        }
     }
 
-    // Test SM with twos state with one field
+    #[cfg(feature = "serde")]
     #[test]
     #[no_coverage]
-    fn test_sm_1h_2s_not_handled_no_enter_no_exit() {
-        #[derive(Debug)]
-        pub struct StateMachine {
-            state: i32,
+    fn test_record_replay() {
+        use record_replay::*;
+
+        let buf = SharedBuf::default();
+        let mut sme = build(IDX_IDLE);
+        sme.record_to(buf.clone());
+        for msg in messages() {
+            sme.dispatch(&msg);
+        }
+        assert!(sme.stop_recording());
+        sme.dispatch(&Messages::Start);
+
+        let recording = buf.0.lock().unwrap().clone();
+        let lines: Vec<Recorded<Messages>> = recording
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0].msg, Messages::Ping { id: 1 });
+        assert_eq!(
+            lines[0].outcomes,
+            [RegionOutcome {
+                handled_by: Some(IDX_BASE),
+                transition_to: None,
+            }]
+        );
+        assert_eq!(
+            lines[1].outcomes,
+            [RegionOutcome {
+                handled_by: Some(IDX_IDLE),
+                transition_to: Some(IDX_RUNNING),
+            }]
+        );
+        assert_eq!(lines[3].msg, Messages::Stop);
+
+        // Replaying into an executor in the same state has no divergences
+        let mut replayer = build(IDX_IDLE);
+        let report = replay(recording.as_slice(), &mut replayer);
+        assert!(report.is_clean(), "{report:?}");
+        assert_eq!(report.dispatched, 4);
+        assert_eq!(replayer.get_current_state_name(), "idle");
+        assert_eq!(replayer.get_state_process_cnt(IDX_BASE), 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    #[no_coverage]
+    fn test_replay_divergences() {
+        use record_replay::*;
+
+        let buf = SharedBuf::default();
+        let mut sme = build(IDX_IDLE);
+        sme.record_to(buf.clone());
+        for msg in messages() {
+            sme.dispatch(&msg);
         }
+        let mut recording = buf.0.lock().unwrap().clone();
+        recording.extend_from_slice(b"\nnot json\n");
+
+        // Starting in running, Start is handled by base rather than idle
+        // so there's no transition, afterwards the two agree again.
+        let mut replayer = build(IDX_RUNNING);
+        let report = replay(recording.as_slice(), &mut replayer);
+        assert!(!report.is_clean());
+        assert_eq!(report.dispatched, 4);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, 6);
+        let lines: Vec<usize> = report.divergences.iter().map(|d| d.line).collect();
+        assert_eq!(lines, [2]);
+        assert_eq!(
+            report.divergences[0].replayed,
+            [RegionOutcome {
+                handled_by: Some(IDX_BASE),
+                transition_to: None,
+            }]
+        );
+    }
+
+    #[cfg(feature = "counters")]
+    #[test]
+    #[no_coverage]
+    fn test_metrics() {
+        #[derive(Debug)]
+        pub struct StateMachine;
 
         // Create a Protocol
         #[derive(Debug)]
-        pub enum Message {
-            Add { val: i32 },
-            Sub { val: i32 },
+        pub enum Messages {
+            Toggle,
+            Other,
         }
 
-        const MAX_STATES: usize = 2;
-        const IDX_PARENT: usize = 0;
-        const IDX_CHILD: usize = 1;
+        const IDX_OFF: usize = 0;
+        const IDX_ON: usize = 1;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, Message> {
-                let sm = RefCell::new(StateMachine { state: 0 });
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("parent", Self::parent))
-                    .state(StateInfo::new("child", Self::child).parent_idx(IDX_PARENT))
-                    .build(IDX_CHILD)
-                    .expect("Unexpected error initializing");
-
-                sme
-            }
-
-            #[no_coverage]
-            fn parent(&mut self, _e: &Executor<Self, Message>, msg: &Message) -> StateResult {
+            fn off(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
                 match msg {
-                    Message::Add { val } => self.state += val,
-                    Message::Sub { val } => self.state -= val,
+                    Messages::Toggle => transition(e.state_id(IDX_ON).unwrap()),
+                    Messages::Other => not_handled(),
                 }
-                (Handled::Yes, None)
-            }
-
-            #[no_coverage]
-            fn child(&mut self, _e: &Executor<Self, Message>, _msg: &Message) -> StateResult {
-                (Handled::No, None)
-            }
-        }
-
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 16);
-        assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_CHILD), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_CHILD), 0);
-        assert_eq!(sme.get_sm().borrow().state, 0);
-
-        // For code coverage
-        println!("{:?}", Message::Add { val: -1 });
-        println!("{:?}", sme.get_sm());
+            }
 
-        sme.dispatch(&Message::Add { val: 2 });
-        assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_CHILD), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_CHILD), 0);
-        assert_eq!(sme.get_sm().borrow().state, 2);
+            #[no_coverage]
+            fn on(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Toggle => transition(e.state_id(IDX_OFF).unwrap()),
+                    Messages::Other => not_handled(),
+                }
+            }
+        }
 
-        sme.dispatch(&Message::Sub { val: 1 });
-        assert_eq!(sme.get_state_enter_cnt(IDX_PARENT), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_PARENT), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_PARENT), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_CHILD), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_CHILD), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_CHILD), 0);
-        assert_eq!(sme.get_sm().borrow().state, 1);
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("off", StateMachine::off))
+            .state(StateInfo::new("on \"1\"", StateMachine::on))
+            .build_idx(IDX_OFF)
+            .expect("Unexpected error initializing");
+        sme.dispatch(&Messages::Toggle);
+        sme.dispatch(&Messages::Other);
+        sme.dispatch(&Messages::Toggle);
+        sme.dispatch(&Messages::Toggle);
+
+        let metrics = sme.metrics();
+        assert_eq!(metrics.current_states, ["on \"1\""]);
+        assert_eq!(metrics.dispatches, 4);
+        assert_eq!(metrics.transitions, 3);
+        assert_eq!(metrics.unhandled, 1);
+        assert_eq!(metrics.timeouts, 0);
+        assert_eq!(metrics.deferred, 0);
+        assert_eq!(
+            metrics.process_cnts,
+            [("off".into(), 2), ("on \"1\"".into(), 2)]
+        );
+
+        let text = metrics.to_prometheus_text("sm");
+        assert_eq!(
+            text,
+            concat!(
+                "# TYPE sm_dispatches_total counter\n",
+                "sm_dispatches_total 4\n",
+                "# TYPE sm_transitions_total counter\n",
+                "sm_transitions_total 3\n",
+                "# TYPE sm_unhandled_total counter\n",
+                "sm_unhandled_total 1\n",
+                "# TYPE sm_timeouts_total counter\n",
+                "sm_timeouts_total 0\n",
+                "# TYPE sm_deferred gauge\n",
+                "sm_deferred 0\n",
+                "# TYPE sm_current_state gauge\n",
+                "sm_current_state{region=\"0\",state=\"on \\\"1\\\"\"} 1\n",
+                "# TYPE sm_process_total counter\n",
+                "sm_process_total{state=\"off\"} 2\n",
+                "sm_process_total{state=\"on \\\"1\\\"\"} 2\n",
+            )
+        );
+
+        // Stopped executors don't count dispatches
+        sme.shutdown(&Messages::Other);
+        sme.dispatch(&Messages::Toggle);
+        assert_eq!(sme.metrics().dispatches, 4);
     }
 
     #[test]
     #[no_coverage]
-    fn test_leaf_transitions_in_a_tree() {
-        // StateMachine simply transitions back and forth
-        // between initial and other.
-        //
-        //                base=0
-        //        --------^  ^-------
-        //       /                   \
-        //      /                     \
-        //    other=2   <======>   initial=1
+    fn test_transition_with() {
+        //  base=0
+        //    ^
+        //    |
+        //  initial=1    other=2
 
-        #[derive(Debug)]
-        struct StateMachine;
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            log: Vec<&'static str>,
+        }
 
-        // Create a Protocol with no messages
+        // Create a Protocol
         #[derive(Debug)]
-        struct NoMessages;
+        pub struct NoMessages;
 
-        const MAX_STATES: usize = 3;
         const IDX_BASE: usize = 0;
         const IDX_INITIAL: usize = 1;
         const IDX_OTHER: usize = 2;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine);
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("base", Self::base).enter_fn(Self::base_enter))
-                    .state(
-                        StateInfo::new("initial", Self::initial)
-                            .enter_fn(Self::initial_enter)
-                            .exit_fn(Self::initial_exit)
-                            .parent_idx(IDX_BASE),
-                    )
-                    .state(
-                        StateInfo::new("other", Self::other)
-                            .enter_fn(Self::other_enter)
-                            .exit_fn(Self::other_exit)
-                            .parent_idx(IDX_BASE),
-                    )
-                    .build(IDX_INITIAL)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn base(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                self.log.push("base");
+                (
+                    Handled::Yes,
+                    Some(e.transition_with(e.state_id(IDX_OTHER).unwrap(), Self::base_action)),
+                )
             }
 
-            fn base_enter(&mut self, _msg: &NoMessages) {}
-
-            // This state has idx 0
             #[no_coverage]
-            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-                (Handled::Yes, None)
+            fn base_exit(&mut self, _msg: &NoMessages) {
+                self.log.push("base_exit");
             }
 
             #[no_coverage]
-            fn initial_enter(&mut self, _msg: &NoMessages) {}
+            fn base_action(&mut self, _msg: &NoMessages) {
+                self.log.push("base_action");
+            }
 
-            // This state has idx 0
             #[no_coverage]
             fn initial(
                 &mut self,
-                _e: &Executor<Self, NoMessages>,
+                e: &Executor<Self, NoMessages>,
                 _msg: &NoMessages,
             ) -> StateResult {
-                (Handled::Yes, Some(IDX_OTHER))
+                self.log.push("initial");
+                (
+                    Handled::No,
+                    Some(e.transition_with(e.state_id(IDX_OTHER).unwrap(), Self::initial_action)),
+                )
             }
 
             #[no_coverage]
-            fn initial_exit(&mut self, _msg: &NoMessages) {}
+            fn initial_exit(&mut self, _msg: &NoMessages) {
+                self.log.push("initial_exit");
+            }
 
             #[no_coverage]
-            fn other_enter(&mut self, _msg: &NoMessages) {}
+            fn initial_action(&mut self, _msg: &NoMessages) {
+                self.log.push("initial_action");
+            }
 
-            // This state has idx 0
             #[no_coverage]
-            fn other(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-                (Handled::Yes, Some(IDX_INITIAL))
+            fn other_enter(&mut self, _msg: &NoMessages) {
+                self.log.push("other_enter");
             }
 
             #[no_coverage]
-            fn other_exit(&mut self, _msg: &NoMessages) {}
-        }
-
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
-
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 8);
-        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
+            fn other(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                self.log.push("other");
 
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
-
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+                // Dropped as other doesn't transition
+                e.transition_with(e.state_id(IDX_BASE).unwrap(), Self::other_action);
+                handled()
+            }
 
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+            #[no_coverage]
+            fn other_action(&mut self, _msg: &NoMessages) {
+                self.log.push("other_action");
+            }
+        }
 
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("base", StateMachine::base).exit_fn(StateMachine::base_exit))
+            .state(
+                StateInfo::new("initial", StateMachine::initial)
+                    .exit_fn(StateMachine::initial_exit)
+                    .parent_idx(IDX_BASE),
+            )
+            .state(StateInfo::new("other", StateMachine::other).enter_fn(StateMachine::other_enter))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        // The first transition, initial's, is taken so only its action
+        // runs and it runs after the exits.
+        let mut session = sme.begin_dispatch(&NoMessages);
+        assert_eq!(session.step(), Some(StepEvent::Process(IDX_INITIAL)));
+        assert_eq!(session.step(), Some(StepEvent::Process(IDX_BASE)));
+        assert_eq!(session.step(), Some(StepEvent::Exit(IDX_INITIAL)));
+        assert_eq!(session.step(), Some(StepEvent::Exit(IDX_BASE)));
+        assert_eq!(session.step(), Some(StepEvent::Action(IDX_OTHER)));
+        assert_eq!(session.step(), None);
+        drop(session);
+
+        // The action runs once, before the enter, and other's is dropped
         sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
-
         sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 3);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 3);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 3);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
+        assert_eq!(
+            sme.get_sm().borrow().log,
+            [
+                "initial",
+                "base",
+                "initial_exit",
+                "base_exit",
+                "initial_action",
+                "other_enter",
+                "other",
+                "other",
+            ]
+        );
     }
 
     #[test]
     #[no_coverage]
-    //#[cfg(not(tarpaulin_include))]
-    fn test_leaf_transitions_between_trees() {
-        // StateMachine simply transitions back and forth
-        // between initial and other.
+    fn test_enter_redirect() {
+        //                 parent=1
+        //                ^        ^
+        //                |        |
+        //  idle=0   connecting=2  connected=3   offline=4
         //
-        //  other_base=2          initial_base=0
-        //       ^                     ^
-        //       |                     |
-        //     other=3              initial=1
+        // idle transitions to connecting which finds it's connected, but
+        // connected finds the connection has gone so ends up offline.
 
         #[derive(Debug)]
         pub struct StateMachine;
@@ -1341,320 +9746,481 @@ mod test {
         #[derive(Debug)]
         pub struct NoMessages;
 
-        const MAX_STATES: usize = 4;
-        const IDX_INITIAL_BASE: usize = 0;
-        const IDX_INITIAL: usize = 1;
-        const IDX_OTHER_BASE: usize = 2;
-        const IDX_OTHER: usize = 3;
+        const IDX_IDLE: usize = 0;
+        const IDX_PARENT: usize = 1;
+        const IDX_CONNECTING: usize = 2;
+        const IDX_CONNECTED: usize = 3;
+        const IDX_OFFLINE: usize = 4;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() -> Executor<Self, NoMessages> {
-                let sm = RefCell::new(StateMachine);
-                let sme = Executor::new(sm, MAX_STATES)
-                    .state(
-                        StateInfo::new("initial_base", Self::initial_base)
-                            .enter_fn(Self::initial_base_enter)
-                            .exit_fn(Self::initial_base_exit),
-                    )
-                    .state(
-                        StateInfo::new("initial", Self::initial)
-                            .enter_fn(Self::initial_enter)
-                            .exit_fn(Self::initial_exit)
-                            .parent_idx(IDX_INITIAL_BASE),
-                    )
-                    .state(
-                        StateInfo::new("other_base", Self::other_base)
-                            .enter_fn(Self::other_base_enter)
-                            .exit_fn(Self::other_base_exit),
-                    )
-                    .state(
-                        StateInfo::new("other", Self::other)
-                            .enter_fn(Self::other_enter)
-                            .exit_fn(Self::other_exit)
-                            .parent_idx(IDX_OTHER_BASE),
-                    )
-                    .build(IDX_INITIAL)
-                    .expect("Unexpected error initializing");
-
-                sme
+            fn idle(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                transition(e.state_id(IDX_CONNECTING).unwrap())
             }
 
             #[no_coverage]
-            fn initial_base_enter(&mut self, _msg: &NoMessages) {}
-
-            // This state has hdl 0
-            #[no_coverage]
-            fn initial_base(
+            fn handled(
                 &mut self,
                 _e: &Executor<Self, NoMessages>,
                 _msg: &NoMessages,
             ) -> StateResult {
-                (Handled::Yes, None)
+                handled()
             }
 
             #[no_coverage]
-            fn initial_base_exit(&mut self, _msg: &NoMessages) {}
+            fn enter_or_exit(&mut self, _msg: &NoMessages) {}
 
             #[no_coverage]
-            fn initial_enter(&mut self, _msg: &NoMessages) {}
+            fn connecting_redirect(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> Option<Transition> {
+                e.state_id(IDX_CONNECTED)
+            }
 
-            // This state has hdl 0
             #[no_coverage]
-            fn initial(
+            fn connected_redirect(
                 &mut self,
-                _e: &Executor<Self, NoMessages>,
+                e: &Executor<Self, NoMessages>,
                 _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, Some(IDX_OTHER))
+            ) -> Option<Transition> {
+                e.state_id(IDX_OFFLINE)
             }
+        }
 
-            #[no_coverage]
-            fn initial_exit(&mut self, _msg: &NoMessages) {}
+        let tracer = VecTracer::new();
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .tracer(tracer.clone())
+            .state(StateInfo::new("idle", StateMachine::idle).exit_fn(StateMachine::enter_or_exit))
+            .state(
+                StateInfo::new("parent", StateMachine::handled)
+                    .enter_fn(StateMachine::enter_or_exit)
+                    .exit_fn(StateMachine::enter_or_exit),
+            )
+            .state(
+                StateInfo::new("connecting", StateMachine::handled)
+                    .enter_redirect_fn(StateMachine::connecting_redirect)
+                    .exit_fn(StateMachine::enter_or_exit)
+                    .parent_idx(IDX_PARENT),
+            )
+            .state(
+                StateInfo::new("connected", StateMachine::handled)
+                    .enter_fn(StateMachine::enter_or_exit)
+                    .enter_redirect_fn(StateMachine::connected_redirect)
+                    .exit_fn(StateMachine::enter_or_exit)
+                    .parent_idx(IDX_PARENT),
+            )
+            .state(
+                StateInfo::new("offline", StateMachine::handled)
+                    .enter_fn(StateMachine::enter_or_exit),
+            )
+            .build_idx(IDX_IDLE)
+            .expect("Unexpected error initializing");
+        assert!(sme.state(IDX_CONNECTING).unwrap().has_enter());
+
+        assert!(sme.dispatch(&NoMessages));
+        assert_eq!(sme.get_current_state_name(), "connecting");
+        tracer.take();
+
+        // Both redirects happen in the next dispatch, parent is only
+        // exited when leaving for offline.
+        assert!(sme.dispatch(&NoMessages));
+        use TraceEvent::{Enter, Exit, Process};
+        assert_eq!(
+            tracer.take(),
+            [
+                Enter("parent".into()),
+                Enter("connecting".into()),
+                TraceEvent::Transition("connected".into()),
+                Exit("connecting".into()),
+                Enter("connected".into()),
+                TraceEvent::Transition("offline".into()),
+                Exit("connected".into()),
+                Exit("parent".into()),
+                Enter("offline".into()),
+                Process("offline".into()),
+            ]
+        );
+        assert_eq!(sme.get_current_state_name(), "offline");
+        assert_eq!(sme.get_redirect_cnt(), 2);
+
+        assert!(!sme.dispatch(&NoMessages));
+        assert_eq!(tracer.take(), [Process("offline".into())]);
+    }
 
-            #[no_coverage]
-            fn other_base_enter(&mut self, _msg: &NoMessages) {}
+    #[test]
+    #[no_coverage]
+    #[should_panic(
+        expected = "ping_pong: 4 enter redirects in one dispatch exceeds max_redirects 3"
+    )]
+    fn test_enter_redirect_loop() {
+        #[derive(Debug)]
+        pub struct StateMachine;
 
-            // This state has hdl 0
+        // Create a Protocol with no messages
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_PING: usize = 0;
+        const IDX_PONG: usize = 1;
+
+        impl StateMachine {
             #[no_coverage]
-            fn other_base(
+            fn handled(
                 &mut self,
                 _e: &Executor<Self, NoMessages>,
                 _msg: &NoMessages,
             ) -> StateResult {
-                (Handled::Yes, None)
+                handled()
             }
 
             #[no_coverage]
-            fn other_base_exit(&mut self, _msg: &NoMessages) {}
-
-            #[no_coverage]
-            fn other_enter(&mut self, _msg: &NoMessages) {}
-
-            // This state has hdl 0
-            #[no_coverage]
-            fn other(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
-                (Handled::Yes, Some(IDX_INITIAL))
+            fn ping_redirect(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> Option<Transition> {
+                e.state_id(IDX_PONG)
             }
 
             #[no_coverage]
-            fn other_exit(&mut self, _msg: &NoMessages) {}
+            fn pong_redirect(
+                &mut self,
+                e: &Executor<Self, NoMessages>,
+                _msg: &NoMessages,
+            ) -> Option<Transition> {
+                e.state_id(IDX_PING)
+            }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
-
-        // Create a sme and validate it's in the expected state
-        let mut sme = StateMachine::new();
-        assert_eq!(std::mem::size_of_val(sme.get_sm()), 8);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("ping_pong")
+            .max_redirects(3)
+            .state(
+                StateInfo::new("ping", StateMachine::handled)
+                    .enter_redirect_fn(StateMachine::ping_redirect),
+            )
+            .state(
+                StateInfo::new("pong", StateMachine::handled)
+                    .enter_redirect_fn(StateMachine::pong_redirect),
+            )
+            .build_idx(IDX_PING)
+            .expect("Unexpected error initializing");
 
         sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 0);
+    }
 
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_watchdog() {
+        use std::sync::mpsc::channel;
 
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 1);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 1);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 1);
+        #[derive(Debug)]
+        pub struct StateMachine;
 
-        sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
+        // Create a Protocol with no messages
+        #[derive(Debug)]
+        pub struct NoMessages;
+
+        const IDX_IDLE: usize = 0;
+
+        impl StateMachine {
+            #[no_coverage]
+            fn idle(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
+            }
+        }
 
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("quiet")
+            .clock(clock.clone())
+            .state(StateInfo::new("idle", StateMachine::idle))
+            .build_idx(IDX_IDLE)
+            .expect("Unexpected error initializing");
+        let (tx, rx) = channel();
+        sme.watchdog(Duration::from_millis(10), tx);
+
+        clock.advance(Duration::from_millis(9));
+        assert!(!sme.check_watchdog());
+
+        // A stall is only reported once
+        clock.advance(Duration::from_millis(1));
+        assert!(sme.check_watchdog());
+        clock.advance(Duration::from_millis(100));
+        assert!(!sme.check_watchdog());
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            [WatchdogEvent::Stalled {
+                machine: "quiet".to_owned(),
+                since: start,
+            }]
+        );
+
+        // Dispatching resets the timer
         sme.dispatch(&NoMessages);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL_BASE), 3);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL_BASE), 3);
-        assert_eq!(sme.get_state_enter_cnt(IDX_INITIAL), 3);
-        assert_eq!(sme.get_state_process_cnt(IDX_INITIAL), 3);
-        assert_eq!(sme.get_state_exit_cnt(IDX_INITIAL), 3);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER_BASE), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER_BASE), 0);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER_BASE), 2);
-        assert_eq!(sme.get_state_enter_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_process_cnt(IDX_OTHER), 2);
-        assert_eq!(sme.get_state_exit_cnt(IDX_OTHER), 2);
+        let dispatched = clock.now();
+        clock.advance(Duration::from_millis(9));
+        assert!(!sme.check_watchdog());
+        clock.advance(Duration::from_millis(1));
+        assert!(sme.check_watchdog());
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            [WatchdogEvent::Stalled {
+                machine: "quiet".to_owned(),
+                since: dispatched,
+            }]
+        );
+
+        // The run_* fns check the watchdog after each message
+        sme.send(NoMessages).unwrap();
+        clock.advance(Duration::from_millis(10));
+        sme.run_until_budget(1, |_| false);
+        assert_eq!(rx.try_iter().count(), 0);
     }
 
+    #[cfg(all(feature = "std", feature = "counters"))]
     #[test]
     #[no_coverage]
-    fn test_1s_cycle() {
-        // StateMachine with one state and has itself as parent,
-        // this should fail to initialize!
-        //
-        //     ------
-        //     |    |
-        //     v    |
-        //  state1 --
-
-        #[derive(Debug)]
-        pub struct StateMachine;
+    fn test_state_timeout() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            exited_by: Vec<Messages>,
+        }
 
         // Create a Protocol
-        #[derive(Debug)]
-        pub struct NoMessages;
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Messages {
+            Start,
+            Timeout,
+        }
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
+        const IDX_IDLE: usize = 0;
+        const IDX_RETRY: usize = 1;
+        const IDX_FAILED: usize = 2;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() {
-                let sm = RefCell::new(StateMachine);
-                match Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE1))
-                    .build(IDX_STATE1)
-                {
-                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
-                }
+            fn idle(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                transition(e.state_id(IDX_RETRY).unwrap())
             }
 
             #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            fn retry(&mut self, _e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                handled()
+            }
+
+            #[no_coverage]
+            fn retry_exit(&mut self, msg: &Messages) {
+                self.exited_by.push(msg.clone());
+            }
+
+            #[no_coverage]
+            fn failed(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
+                transition(e.state_id(IDX_RETRY).unwrap())
             }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
+        let clock = ManualClock::new();
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .clock(clock.clone())
+            .state(StateInfo::new("idle", StateMachine::idle))
+            .state(
+                StateInfo::new("retry", StateMachine::retry)
+                    .exit_fn(StateMachine::retry_exit)
+                    .timeout_idx(Duration::from_millis(30), IDX_FAILED),
+            )
+            .state(StateInfo::new("failed", StateMachine::failed))
+            .build_idx(IDX_IDLE)
+            .expect("Unexpected error initializing");
+
+        // idle has no timeout
+        clock.advance(Duration::from_millis(100));
+        assert!(!sme.poll_timeouts(&Messages::Timeout));
+        assert_eq!(sme.get_current_state_name(), "idle");
+
+        // The timeout starts when retry becomes the current state
+        sme.dispatch(&Messages::Start);
+        assert_eq!(sme.get_current_state_name(), "retry");
+        clock.advance(Duration::from_millis(29));
+        assert!(!sme.poll_timeouts(&Messages::Timeout));
+        sme.dispatch(&Messages::Start);
+        assert_eq!(sme.get_state_process_cnt(IDX_RETRY), 1);
+        clock.advance(Duration::from_millis(1));
+        assert!(sme.poll_timeouts(&Messages::Timeout));
+        assert_eq!(sme.get_current_state_name(), "failed");
+        assert_eq!(sme.get_sm().borrow().exited_by, [Messages::Timeout]);
+        assert_eq!(sme.get_state_process_cnt(IDX_RETRY), 1);
+        assert_eq!(sme.metrics().timeouts, 1);
+        assert_eq!(sme.metrics().dispatches, 2);
+
+        // Re-entering retry restarts its timeout
+        sme.dispatch(&Messages::Start);
+        clock.advance(Duration::from_millis(20));
+        assert!(!sme.poll_timeouts(&Messages::Timeout));
+        sme.dispatch(&Messages::Start);
+        sme.dispatch(&Messages::Start);
+        assert_eq!(sme.get_current_state_name(), "retry");
+        clock.advance(Duration::from_millis(30));
+        assert!(sme.poll_timeouts(&Messages::Timeout));
+        assert_eq!(sme.get_current_state_name(), "failed");
+        assert_eq!(sme.transition_counts()[&(IDX_RETRY, IDX_FAILED)], 2);
+        assert_eq!(sme.metrics().timeouts, 2);
+
+        // The target must be a leaf
+        match ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("idle", StateMachine::idle))
+            .state(
+                StateInfo::new("retry", StateMachine::retry)
+                    .timeout_idx(Duration::from_millis(30), IDX_FAILED),
+            )
+            .state(StateInfo::new("failed", StateMachine::failed))
+            .state(StateInfo::new("failed_child", StateMachine::failed).parent_idx(IDX_FAILED))
+            .build_idx(IDX_IDLE)
+        {
+            Ok(_) => panic!("Expected failed to be an invalid timeout target"),
+            Err(e) => {
+                assert_eq!(
+                    e.to_string(),
+                    "State retry times out to 2 which is not a valid target, only [0, 1, 3] are allowed"
+                );
+                assert_eq!(
+                    e,
+                    BuildError::InvalidTimeoutTarget {
+                        state: "retry".to_owned(),
+                        idx: IDX_FAILED,
+                        valid: vec![IDX_IDLE, IDX_RETRY, 3]
+                    }
+                );
+            }
+        }
 
-        StateMachine::new();
+        // In the same region
+        match ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .with_regions(2)
+            .state(StateInfo::new("idle", StateMachine::idle))
+            .state(
+                StateInfo::new("retry", StateMachine::retry)
+                    .timeout_idx(Duration::from_millis(30), IDX_FAILED),
+            )
+            .state(StateInfo::new("failed", StateMachine::failed).region(1))
+            .region_initial_state_idx(1, IDX_FAILED)
+            .build_idx(IDX_IDLE)
+        {
+            Ok(_) => panic!("Expected failed to be an invalid timeout target"),
+            Err(e) => assert_eq!(
+                e,
+                BuildError::InvalidTimeoutTarget {
+                    state: "retry".to_owned(),
+                    idx: IDX_FAILED,
+                    valid: vec![IDX_IDLE, IDX_RETRY]
+                }
+            ),
+        }
+
+        // With the ids add_state returns a timeout targets a state added
+        // before it
+        let clock = ManualClock::new();
+        let mut builder =
+            ExecutorBuilder::new(RefCell::new(StateMachine::default())).clock(clock.clone());
+        let idle = builder.add_state(StateInfo::new("idle", StateMachine::idle));
+        builder.add_state(
+            StateInfo::new("retry", StateMachine::retry).timeout(Duration::from_millis(30), idle),
+        );
+        let mut sme = builder.build(idle).expect("Unexpected error initializing");
+        sme.dispatch(&Messages::Start);
+        assert_eq!(sme.get_current_state_name(), "retry");
+        clock.advance(Duration::from_millis(30));
+        assert!(sme.poll_timeouts(&Messages::Timeout));
+        assert_eq!(sme.get_current_state_name(), "idle");
     }
 
     #[test]
     #[no_coverage]
-    fn test_2s_one_self_cycle() {
-        // StateMachine with one state and has itself as parent
-        // plus another state with no parent, this should fail to initialize!
-        //
-        //     ------
-        //     |    |
-        //     v    |
-        //  state1 --     state2
+    fn test_extend_sibling_leaf() {
+        //  base=0
+        //    ^
+        //    |
+        //  initial=1    added=2 (extended)
 
-        #[derive(Debug)]
-        pub struct StateMachine;
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            added_cnt: usize,
+        }
 
         // Create a Protocol
         #[derive(Debug)]
         pub struct NoMessages;
 
-        const MAX_STATES: usize = 1;
-        const IDX_STATE1: usize = 0;
-        const _IDX_STATE2: usize = 1;
+        const IDX_BASE: usize = 0;
+        const IDX_INITIAL: usize = 1;
+        const IDX_ADDED: usize = 2;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() {
-                let sm = RefCell::new(StateMachine);
-                match Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE1))
-                    .state(StateInfo::new("state2", Self::state2))
-                    .build(IDX_STATE1)
-                {
-                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
-                }
+            fn base(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
             }
 
             #[no_coverage]
-            fn state1(
+            fn initial(
                 &mut self,
-                _e: &Executor<Self, NoMessages>,
+                e: &Executor<Self, NoMessages>,
                 _msg: &NoMessages,
             ) -> StateResult {
-                (Handled::Yes, None)
+                transition(e.state_id(IDX_ADDED).unwrap())
             }
 
             #[no_coverage]
-            fn state2(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            fn added(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                self.added_cnt += 1;
+                handled()
             }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
-
-        StateMachine::new();
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("base", StateMachine::base))
+            .state(StateInfo::new("initial", StateMachine::initial).parent_idx(IDX_BASE))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+        assert_eq!(sme.leaf_states(), &[IDX_INITIAL]);
+
+        let idxs = sme
+            .extend(vec![
+                StateInfo::new("added", StateMachine::added).parent_name("base")
+            ])
+            .expect("Unexpected error extending");
+        assert_eq!(idxs, vec![IDX_ADDED]);
+        assert_eq!(sme.get_state_name(IDX_ADDED), "added");
+        assert_eq!(sme.children(IDX_BASE), &[IDX_INITIAL, IDX_ADDED]);
+        assert_eq!(sme.leaf_states(), &[IDX_INITIAL, IDX_ADDED]);
+        assert!(sme.is_leaf(IDX_ADDED));
+        assert_eq!(sme.depth(IDX_ADDED), 1);
+
+        // The added state is a transition target
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_current_state_name(), "added");
+        sme.dispatch(&NoMessages);
+        assert_eq!(sme.get_sm().borrow().added_cnt, 1);
+
+        // Duplicate names are still rejected and nothing is added
+        assert_eq!(
+            sme.extend(vec![StateInfo::new("added", StateMachine::added)]),
+            Err(BuildError::DuplicateName("added".to_owned()))
+        );
+        assert_eq!(sme.state_count(), 3);
     }
 
     #[test]
     #[no_coverage]
-    fn test_2s_cycle() {
-        // StateMachine with two states each has the other as parent,
-        // this should fail to build!
-        //
-        //  state2
-        //   |  ^
-        //   v  |
-        //  state1
+    fn test_extend_child_of_current_state() {
+        //  initial=0    other=1
+        //    ^            ^
+        //    |            |
+        //  (rejected)   child=2 (extended)
 
         #[derive(Debug)]
         pub struct StateMachine;
@@ -1663,222 +10229,511 @@ mod test {
         #[derive(Debug)]
         pub struct NoMessages;
 
-        const MAX_STATES: usize = 2;
-        const IDX_STATE1: usize = 0;
-        const IDX_STATE2: usize = 1;
-        const _IDX_STATE3: usize = 2;
+        const IDX_INITIAL: usize = 0;
+        const IDX_OTHER: usize = 1;
+        const IDX_CHILD: usize = 2;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() {
-                let sm = RefCell::new(StateMachine);
-                match Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE2))
-                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
-                    .build(IDX_STATE1)
-                {
-                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
-                }
+            fn state(&mut self, _e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                handled()
             }
+        }
 
-            #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
-            }
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .state(StateInfo::new("initial", StateMachine::state))
+            .state(StateInfo::new("other", StateMachine::state))
+            .build_idx(IDX_INITIAL)
+            .expect("Unexpected error initializing");
+
+        // The current state can't stop being a leaf
+        assert_eq!(
+            sme.extend(vec![
+                StateInfo::new("child", StateMachine::state).parent_idx(IDX_INITIAL)
+            ]),
+            Err(BuildError::ParentIsCurrentState {
+                state: "child".to_owned(),
+                parent: "initial".to_owned(),
+            })
+        );
+        assert_eq!(sme.state_count(), 2);
+        assert!(sme.children(IDX_INITIAL).is_empty());
+        assert_eq!(sme.leaf_states(), &[IDX_INITIAL, IDX_OTHER]);
+
+        // Any other leaf can, it's no longer a transition target
+        let idxs = sme
+            .extend(vec![
+                StateInfo::new("child", StateMachine::state).parent_idx(IDX_OTHER)
+            ])
+            .expect("Unexpected error extending");
+        assert_eq!(idxs, vec![IDX_CHILD]);
+        assert_eq!(sme.children(IDX_OTHER), &[IDX_CHILD]);
+        assert!(!sme.is_leaf(IDX_OTHER));
+        assert_eq!(sme.leaf_states(), &[IDX_INITIAL, IDX_CHILD]);
+        assert_eq!(sme.get_current_state_name(), "initial");
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_send_priority() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            data: u32,
+            control: u32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub enum Messages {
+            Data(u32),
+            Control(u32),
+        }
 
+        const IDX_RUNNING: usize = 0;
+
+        impl StateMachine {
             #[no_coverage]
-            fn state2(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            fn running(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Data(n) => self.data += n,
+                    Messages::Control(n) => self.control = *n,
+                }
+                handled()
             }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
+        #[no_coverage]
+        fn processed(sme: &Executor<StateMachine, Messages>) -> Vec<String> {
+            sme.recent_events()
+                .filter(|event| event.kind == EventKind::Process)
+                .filter_map(|event| event.msg_debug.clone())
+                .collect()
+        }
 
-        StateMachine::new();
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .event_log(16)
+            .event_log_msgs()
+            .state(StateInfo::new("running", StateMachine::running))
+            .build_idx(IDX_RUNNING)
+            .expect("Unexpected error initializing");
+
+        // Control messages jump ahead of data, each stays in order
+        let priority_tx = sme.clone_priority_sender();
+        sme.send(Messages::Data(1)).unwrap();
+        sme.send_priority(Messages::Control(1)).unwrap();
+        sme.send(Messages::Data(2)).unwrap();
+        priority_tx.send(Messages::Control(2)).unwrap();
+        sme.clone_sender().send(Messages::Data(3)).unwrap();
+        assert_eq!(sme.dispatch_all(), 5);
+        assert_eq!(
+            processed(&sme),
+            ["Control(1)", "Control(2)", "Data(1)", "Data(2)", "Data(3)"]
+        );
+        assert_eq!(sme.get_sm().borrow().data, 6);
+        assert_eq!(sme.get_sm().borrow().control, 2);
+
+        // The same for the run_* fns
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .event_log(16)
+            .event_log_msgs()
+            .state(StateInfo::new("running", StateMachine::running))
+            .build_idx(IDX_RUNNING)
+            .expect("Unexpected error initializing");
+        sme.send(Messages::Data(1)).unwrap();
+        sme.send(Messages::Data(2)).unwrap();
+        sme.send_priority(Messages::Control(1)).unwrap();
+        assert_eq!(
+            sme.run_until_budget(2, |_| false),
+            RunOutcome::BudgetExhausted
+        );
+        assert_eq!(processed(&sme), ["Control(1)", "Data(1)"]);
+
+        // A priority message sent while waiting is received
+        let priority_tx = sme.clone_priority_sender();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            priority_tx.send(Messages::Control(2)).unwrap();
+        });
+        sme.recv().unwrap();
+        assert!(matches!(sme.recv().unwrap(), Messages::Control(n) if n == 2));
+        sender.join().unwrap();
+        assert!(sme.try_recv().is_err());
     }
 
+    #[cfg(feature = "messaging")]
     #[test]
     #[no_coverage]
-    fn test_3s_one_cycle() {
-        // StateMachine with three states two have other as parent third is standalone,
-        // this should fail to build!
-        //
-        //  state2   state3
-        //   |  ^
-        //   v  |
-        //  state1
-
-        #[derive(Debug)]
-        pub struct StateMachine;
+    fn test_pump_once() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            received: Vec<u32>,
+        }
 
         // Create a Protocol
         #[derive(Debug)]
-        pub struct NoMessages;
+        pub struct Value(u32);
 
-        const MAX_STATES: usize = 2;
-        const IDX_STATE1: usize = 0;
-        const IDX_STATE2: usize = 1;
+        const IDX_RUNNING: usize = 0;
 
         impl StateMachine {
             #[no_coverage]
-            fn new() {
-                let sm = RefCell::new(StateMachine);
-                match Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE2))
-                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
-                    .state(StateInfo::new("state3", Self::state3))
-                    .build(IDX_STATE1)
-                {
-                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
-                }
+            fn running(&mut self, _e: &Executor<Self, Value>, msg: &Value) -> StateResult {
+                self.received.push(msg.0);
+                handled()
             }
+        }
 
-            #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+        #[no_coverage]
+        fn build(pump_order: PumpOrder) -> Executor<StateMachine, Value> {
+            let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+                .pump_order(pump_order)
+                .state(StateInfo::new("running", StateMachine::running))
+                .build_idx(IDX_RUNNING)
+                .expect("Unexpected error initializing");
+
+            let network = sme.add_channel("network");
+            let ui = sme.add_channel("ui");
+            let timer = sme.add_channel("timer");
+            for v in [1, 2, 3] {
+                network.send(Value(v)).unwrap();
+            }
+            ui.send(Value(10)).unwrap();
+            for v in [20, 21] {
+                timer.send(Value(v)).unwrap();
             }
 
-            #[no_coverage]
-            fn state2(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            sme
+        }
+
+        #[no_coverage]
+        fn pump_all(sme: &mut Executor<StateMachine, Value>) -> Vec<String> {
+            let mut names = Vec::new();
+            while let Some((name, result)) = sme.pump_once() {
+                assert_eq!(result.messages_processed, 1);
+                names.push(name);
             }
+            names
+        }
+
+        // Each channel gets a turn
+        let mut sme = build(PumpOrder::RoundRobin);
+        assert_eq!(
+            pump_all(&mut sme),
+            ["network", "ui", "timer", "network", "timer", "network"]
+        );
+        assert_eq!(sme.get_sm().borrow().received, [1, 10, 20, 2, 21, 3]);
+
+        // The first channel added is drained first
+        let mut sme = build(PumpOrder::Priority);
+        assert_eq!(
+            pump_all(&mut sme),
+            ["network", "network", "network", "ui", "timer", "timer"]
+        );
+        assert_eq!(sme.get_sm().borrow().received, [1, 2, 3, 10, 20, 21]);
+
+        // Adding an existing name returns a sender for the same channel
+        let ui = sme.add_channel("ui");
+        ui.send(Value(11)).unwrap();
+        assert_eq!(
+            sme.pump_once(),
+            Some((
+                "ui".to_owned(),
+                DispatcherOutcome {
+                    transitioned: false,
+                    deferred_remaining: 0,
+                    messages_processed: 1,
+                    truncated: false,
+                }
+            ))
+        );
+        assert!(sme.pump_once().is_none());
+
+        // Without channels there's nothing to pump
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("running", StateMachine::running))
+            .build_idx(IDX_RUNNING)
+            .expect("Unexpected error initializing");
+        assert!(sme.pump_once().is_none());
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_process_pending() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            received: u32,
+        }
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct Increment;
 
+        const IDX_COUNTING: usize = 0;
+
+        impl StateMachine {
             #[no_coverage]
-            fn state3(
+            fn counting(
                 &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
+                _e: &Executor<Self, Increment>,
+                _msg: &Increment,
             ) -> StateResult {
-                (Handled::Yes, None)
+                self.received += 1;
+                handled()
             }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("counting", StateMachine::counting))
+            .build_idx(IDX_COUNTING)
+            .expect("Unexpected error initializing");
 
-        StateMachine::new();
+        // Safe to call with nothing waiting
+        assert_eq!(sme.process_pending(3), 0);
+
+        for _ in 0..10 {
+            sme.send(Increment).unwrap();
+        }
+        assert_eq!(sme.process_pending(3), 3);
+        assert_eq!(sme.process_pending(3), 3);
+        assert_eq!(sme.process_pending(3), 3);
+        assert_eq!(sme.process_pending(3), 1);
+        assert_eq!(sme.process_pending(3), 0);
+        assert_eq!(sme.get_sm().borrow().received, 10);
+        assert_eq!(sme.get_deferred_cnt(), 0);
     }
 
+    #[cfg(feature = "messaging")]
     #[test]
     #[no_coverage]
-    fn test_5s_long_cycle() {
-        // StateMachine with 5 states twi leafs and a long cycle from state1 to state3
-        // this should fail to initialize!
-        //
-        //  --- state1
-        //  |      ^
-        //  |      |
-        //  |   state2
-        //  |      ^
-        //  |      |
-        //  --> state3 <-------
-        //         ^          |
-        //         |          |
-        //      state4     state5
-        //
-
-        #[derive(Debug)]
-        pub struct StateMachine;
+    fn test_defer_policy() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            open: bool,
+            jobs: Vec<u32>,
+        }
 
         // Create a Protocol
-        #[derive(Debug)]
-        pub struct NoMessages;
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Job(u32),
+            Open,
+        }
 
-        const MAX_STATES: usize = 5;
-        const IDX_STATE1: usize = 0;
-        const IDX_STATE2: usize = 1;
-        const IDX_STATE3: usize = 2;
-        const _IDX_STATE4: usize = 3;
-        const _IDX_STATE5: usize = 4;
+        const IDX_GATE: usize = 0;
 
         impl StateMachine {
+            // Jobs are deferred until Open, which doesn't transition
             #[no_coverage]
-            fn new() {
-                let sm = RefCell::new(StateMachine);
-                match Executor::new(sm, MAX_STATES)
-                    .state(StateInfo::new("state1", Self::state1).parent_idx(IDX_STATE3))
-                    .state(StateInfo::new("state2", Self::state2).parent_idx(IDX_STATE1))
-                    .state(StateInfo::new("state3", Self::state3).parent_idx(IDX_STATE2))
-                    .state(StateInfo::new("state4", Self::state4).parent_idx(IDX_STATE3))
-                    .state(StateInfo::new("state5", Self::state5).parent_idx(IDX_STATE3))
-                    .build(IDX_STATE1)
-                {
-                    Ok(_) => panic!("Expected a cycle it wasn't detected"),
-                    Err(e) => assert_eq!(e.to_string(), "Cycle detected"),
+            fn gate(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Job(n) if self.open => self.jobs.push(*n),
+                    Messages::Job(_) => e.defer_current().unwrap(),
+                    Messages::Open => self.open = true,
                 }
+                handled()
             }
+        }
 
-            #[no_coverage]
-            fn state1(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+        #[no_coverage]
+        fn run(defer_policy: DeferPolicy) -> Executor<StateMachine, Messages> {
+            let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+                .defer_policy(defer_policy)
+                .state(StateInfo::new("gate", StateMachine::gate))
+                .build_idx(IDX_GATE)
+                .expect("Unexpected error initializing");
+            for msg in [
+                Messages::Job(1),
+                Messages::Job(2),
+                Messages::Open,
+                Messages::Job(3),
+            ] {
+                assert!(!sme.dispatcher(&msg).truncated);
             }
 
+            sme
+        }
+
+        // Without a transition the deferred jobs wait
+        let mut sme = run(DeferPolicy::OnTransition);
+        assert_eq!(sme.get_sm().borrow().jobs, [3]);
+        assert_eq!(sme.get_deferred_cnt(), 2);
+
+        // They're retried after every message
+        let mut sme_always = run(DeferPolicy::Always);
+        assert_eq!(sme_always.get_sm().borrow().jobs, [1, 2, 3]);
+        assert_eq!(sme_always.get_deferred_cnt(), 0);
+
+        // Or when asked to
+        let mut sme_manual = run(DeferPolicy::Manual);
+        assert_eq!(sme_manual.get_sm().borrow().jobs, [3]);
+        assert_eq!(sme_manual.process_deferred(), 2);
+        assert_eq!(sme_manual.get_sm().borrow().jobs, [3, 1, 2]);
+        assert_eq!(sme_manual.process_deferred(), 0);
+
+        // process_deferred works with any policy
+        assert_eq!(sme.process_deferred(), 2);
+        assert_eq!(sme.get_sm().borrow().jobs, [3, 1, 2]);
+        assert_eq!(sme_always.process_deferred(), 0);
+    }
+
+    #[cfg(feature = "messaging")]
+    #[test]
+    #[no_coverage]
+    fn test_dispatcher_outcome() {
+        #[derive(Debug, Default)]
+        pub struct StateMachine {
+            jobs: Vec<u32>,
+        }
+
+        // Create a Protocol
+        #[derive(Debug, Clone)]
+        pub enum Messages {
+            Job(u32),
+            Open,
+        }
+
+        const IDX_CLOSED: usize = 0;
+        const IDX_OPEN: usize = 1;
+
+        impl StateMachine {
             #[no_coverage]
-            fn state2(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            fn closed(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                match msg {
+                    Messages::Job(_) => {
+                        e.defer_current().unwrap();
+                        handled()
+                    }
+                    Messages::Open => transition(e.state_id(IDX_OPEN).unwrap()),
+                }
             }
 
             #[no_coverage]
-            fn state3(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            fn open(&mut self, _e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+                if let Messages::Job(n) = msg {
+                    self.jobs.push(*n);
+                }
+                handled()
             }
+        }
 
-            #[no_coverage]
-            fn state4(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine::default()))
+            .state(StateInfo::new("closed", StateMachine::closed))
+            .state(StateInfo::new("open", StateMachine::open))
+            .build_idx(IDX_CLOSED)
+            .expect("Unexpected error initializing");
+
+        // The jobs stay deferred across calls
+        assert_eq!(
+            sme.dispatcher(&Messages::Job(1)),
+            DispatcherOutcome {
+                transitioned: false,
+                deferred_remaining: 1,
+                messages_processed: 1,
+                truncated: false,
             }
+        );
+        assert_eq!(
+            sme.dispatcher(&Messages::Job(2)),
+            DispatcherOutcome {
+                transitioned: false,
+                deferred_remaining: 2,
+                messages_processed: 1,
+                truncated: false,
+            }
+        );
+
+        // Until a transition reprocesses them
+        assert_eq!(
+            sme.dispatcher(&Messages::Open),
+            DispatcherOutcome {
+                transitioned: true,
+                deferred_remaining: 0,
+                messages_processed: 3,
+                truncated: false,
+            }
+        );
+        assert_eq!(sme.get_sm().borrow().jobs, [1, 2]);
+    }
+
+    #[test]
+    #[no_coverage]
+    fn test_panic_messages_name_states() {
+        #[derive(Debug)]
+        pub struct StateMachine;
+
+        // Create a Protocol
+        #[derive(Debug)]
+        pub struct NoMessages;
 
+        const IDX_VEHICLE_BASE: usize = 0;
+        const IDX_YELLOW: usize = 2;
+
+        impl StateMachine {
             #[no_coverage]
-            fn state5(
-                &mut self,
-                _e: &Executor<Self, NoMessages>,
-                _msg: &NoMessages,
-            ) -> StateResult {
-                (Handled::Yes, None)
+            fn leaf(&mut self, e: &Executor<Self, NoMessages>, _msg: &NoMessages) -> StateResult {
+                // Invalid transition, vehicle_base isn't a leaf
+                transition(e.state_id(IDX_VEHICLE_BASE).unwrap())
             }
         }
 
-        // For code coverage
-        println!("{:?}", NoMessages);
-        println!("{:?}", StateMachine);
+        #[no_coverage]
+        fn panic_msg(sme: &mut Executor<StateMachine, NoMessages>) -> String {
+            let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                sme.dispatch(&NoMessages);
+            }))
+            .expect_err("Expected dispatch to panic");
+            *payload.downcast::<String>().unwrap()
+        }
 
-        StateMachine::new();
+        let mut builder = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("traffic_light")
+            .state(StateInfo::new("vehicle_base", StateMachine::leaf));
+        for name in ["green", "yellow", "red"] {
+            builder = builder.state(StateInfo::new(name, StateMachine::leaf).parent_idx(0));
+        }
+        let mut sme = builder
+            .build_idx(IDX_YELLOW)
+            .expect("Unexpected error initializing");
+        assert_eq!(
+            panic_msg(&mut sme),
+            "traffic_light: transition from 'yellow' (idx 2) to 'vehicle_base' (idx 0) rejected: target is not a leaf; valid targets: green, yellow, red"
+        );
+
+        // Long lists of targets are truncated
+        let mut builder = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("many")
+            .state(StateInfo::new("vehicle_base", StateMachine::leaf));
+        for n in 0..10 {
+            builder =
+                builder.state(StateInfo::new(format!("leaf{n}"), StateMachine::leaf).parent_idx(0));
+        }
+        let mut sme = builder.build_idx(1).expect("Unexpected error initializing");
+        assert_eq!(
+            panic_msg(&mut sme),
+            "many: transition from 'leaf0' (idx 1) to 'vehicle_base' (idx 0) rejected: target is not a leaf; valid targets: leaf0, leaf1, leaf2, leaf3, leaf4, leaf5, leaf6, leaf7, ... 2 more"
+        );
+
+        // Only the crate can make a StateId that isn't a state, dispatch
+        // still checks it is one
+        #[no_coverage]
+        fn nowhere(
+            _sm: &mut StateMachine,
+            _e: &Executor<StateMachine, NoMessages>,
+            _msg: &NoMessages,
+        ) -> StateResult {
+            transition(StateId(5))
+        }
+
+        let mut sme = ExecutorBuilder::new(RefCell::new(StateMachine))
+            .name("lost")
+            .state(StateInfo::new("only", nowhere))
+            .build_idx(0)
+            .expect("Unexpected error initializing");
+        assert_eq!(
+            panic_msg(&mut sme),
+            "lost: transition from 'only' (idx 0) to idx 5 rejected: there are only 1 states"
+        );
     }
 }
@@ -2,7 +2,7 @@
 use std::{cell::RefCell, rc::Rc};
 
 use custom_logger::env_logger_init;
-use hsm0_with_executor::{Executor, Handled, StateInfo, StateResult};
+use hsm0_with_executor::{transition, Executor, ExecutorBuilder, StateInfo, StateResult};
 
 #[derive(Debug)]
 pub struct StateMachine {
@@ -15,7 +15,6 @@ pub enum Messages {
     Val { val: i32 },
 }
 
-const MAX_STATES: usize = 2;
 const IDX_STATE1: usize = 0;
 const IDX_STATE2: usize = 1;
 
@@ -23,23 +22,23 @@ impl StateMachine {
     fn new() -> Executor<Self, Messages> {
         let sm = RefCell::new(StateMachine { state: 0 });
 
-        let sme = Executor::new(sm, MAX_STATES)
+        let sme = ExecutorBuilder::new(sm)
             .state(StateInfo::new("state1", Self::state1))
             .state(StateInfo::new("state2", Self::state2))
-            .build(IDX_STATE1)
+            .build_idx(IDX_STATE1)
             .expect("Unexpected error initializing");
 
         sme
     }
 
-    fn state1(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
+    fn state1(&mut self, e: &Executor<Self, Messages>, _msg: &Messages) -> StateResult {
         println!("{}:+ &self={self:p}", e.get_state_name(IDX_STATE1));
 
         // Defer messages
-        e.defer_send(msg.clone());
+        e.defer_current().unwrap();
 
         println!("{}:-", e.get_state_name(IDX_STATE1));
-        (Handled::Yes, Some(IDX_STATE2))
+        transition(e.state_id(IDX_STATE2).unwrap())
     }
 
     fn state2(&mut self, e: &Executor<Self, Messages>, msg: &Messages) -> StateResult {
@@ -52,7 +51,7 @@ impl StateMachine {
         }
 
         println!("{}:-", e.get_state_name(IDX_STATE2));
-        (Handled::Yes, Some(IDX_STATE1))
+        transition(e.state_id(IDX_STATE1).unwrap())
     }
 }
 
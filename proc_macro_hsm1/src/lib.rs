@@ -6,29 +6,39 @@ use proc_macro2::TokenStream as TokenStream2;
 
 use proc_macro::{self, TokenStream};
 use quote::quote;
-use syn::parse::{Parse, ParseStream};
+use syn::parse::{Parse, ParseStream, Parser};
+use syn::punctuated::Punctuated;
 use syn::visit_mut::{self, VisitMut};
 use syn::{parse_macro_input, Macro, Result};
 
 #[proc_macro_attribute]
 pub fn hsm1_state(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    //println!("proc_macro_attribute hsm1_state: attr={:#?}", attr);
-    //println!("proc_macro_attribute hsm1_state: item={:#?}", item);
     item
 }
 
 #[proc_macro_attribute]
 pub fn hsm1_initial_state(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    //println!("proc_macro_attribute hsm1_initial_state: attr={:#?}", attr);
-    //println!("proc_macro_attribute hsm1_initial_state: item={:#?}", item);
+    item
+}
+
+#[proc_macro_attribute]
+pub fn hsm1_error_state(_attr: TokenStream, item: TokenStream) -> TokenStream {
     item
 }
 
 #[derive(Debug)]
 struct Hsm1 {
+    // The `prefix = ident;` of the generated field and helper type names
+    hsm_prefix: Option<syn::Ident>,
+    hsm_attrs: Vec<syn::Attribute>,
+    hsm_vis: syn::Visibility,
     hsm_ident: syn::Ident,
+    hsm_generics: syn::Generics,
     hsm_fields: Vec<syn::Field>,
+    // The #[hsm1_default(expr)] of each field
+    hsm_field_defaults: Vec<Option<syn::Expr>>,
     hsm_fns: Vec<syn::ItemFn>,
+    hsm_items: Vec<syn::Item>,
     #[allow(unused)]
     hsm_state_fn_ident_map: HashMap<String, usize>,
     hsm_state_fn_idents: Vec<StateFnIdents>,
@@ -47,50 +57,200 @@ struct StateFnIdents {
     process_fn_ident: syn::Ident,
     process_fn_msg_type: MsgType,
     exit_fn_ident: Option<syn::Ident>,
-    initial_state: bool,
+    guard_fn_ident: Option<syn::Ident>,
+    // The #[hsm1_initial_state] attribute, None for #[hsm1_state]
+    initial_state_attr: Option<syn::Attribute>,
+    // The #[cfg] attributes of the process fn
+    cfg_attrs: Vec<syn::Attribute>,
+    // The #[hsm1_error_state] attribute, None for the other states
+    error_state_attr: Option<syn::Attribute>,
+    // E when the process fn returns Result<StateResult!(), E>
+    process_fn_error_type: Option<syn::Type>,
+    // The `initial` of `#[hsm1_state(parent, initial)]`
+    initial_substate: Option<syn::Ident>,
+}
+
+// The arguments of hsm1_state and hsm1_initial_state, a bare ident is
+// the parent, `#[hsm1_state(base)]`, or they're named,
+// `#[hsm1_state(parent = base, enter = on_open, exit = on_close)]`.
+// A guard, `fn can_accept(&self, msg: MsgType) -> bool`, is called before
+// the state fn and the message is not handled if it returns false.
+// After the parent a bare `initial` makes the state its parent's initial
+// substate, `#[hsm1_state(base, initial)]`, a transition to the parent
+// is a transition to it.
+#[derive(Debug, Default)]
+struct Hsm1Args {
+    parent: Option<syn::Ident>,
+    enter: Option<syn::Ident>,
+    exit: Option<syn::Ident>,
+    guard: Option<syn::Ident>,
+    initial: Option<syn::Ident>,
+}
+
+impl Parse for Hsm1Args {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut args = Hsm1Args::default();
+        while !input.is_empty() {
+            let key = input.parse::<syn::Ident>()?;
+            let (slot, value) = if input.peek(syn::Token![=]) {
+                input.parse::<syn::Token![=]>()?;
+                let slot = match key.to_string().as_str() {
+                    "parent" => &mut args.parent,
+                    "enter" => &mut args.enter,
+                    "exit" => &mut args.exit,
+                    "guard" => &mut args.guard,
+                    _ => {
+                        return Err(syn::Error::new_spanned(
+                            &key,
+                            format!(
+                                "Unknown argument {key}, expected parent, enter, exit or guard"
+                            ),
+                        ));
+                    }
+                };
+                (slot, input.parse::<syn::Ident>()?)
+            } else if key == "initial" && args.parent.is_some() {
+                (&mut args.initial, key.clone())
+            } else {
+                (&mut args.parent, key.clone())
+            };
+            if slot.is_some() {
+                return Err(syn::Error::new_spanned(
+                    &key,
+                    "Each of parent, enter, exit, guard and initial may be given only once",
+                ));
+            }
+            *slot = Some(value);
+
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
 }
 
 impl Parse for Hsm1 {
     fn parse(input: ParseStream) -> Result<Self> {
-        //println!("hsm1::parse:+");
-        //println!("hsm1::parse: input={:#?}", input);
+        let hsm_prefix = if input.peek(syn::Ident) && input.peek2(syn::Token![=]) {
+            let key = input.parse::<syn::Ident>()?;
+            if key != "prefix" {
+                return Err(syn::Error::new_spanned(
+                    &key,
+                    format!("Unknown argument {key}, expected prefix"),
+                ));
+            }
+            input.parse::<syn::Token![=]>()?;
+            let prefix = input.parse::<syn::Ident>()?;
+            input.parse::<syn::Token![;]>()?;
+            Some(prefix)
+        } else {
+            None
+        };
 
         let item_struct = input.parse::<syn::ItemStruct>()?;
-        //println!("hsm1::parse: item_struct={:#?}", item_struct);
 
         // Parse all of the hsm1 data fields
-        let fields: Vec<syn::Field> = match item_struct.fields {
+        let mut fields: Vec<syn::Field> = match item_struct.fields {
             syn::Fields::Named(fields_named) => fields_named.named.iter().cloned().collect(),
             _ => {
                 let err = syn::Error::new_spanned(item_struct, "hsm1::parse: expecting hsm struct");
                 return Err(err);
             }
         };
-        //println!("hsm1::parse: fields={:#?}", fields);
 
         // The only thing that should remain are functions
         #[derive(Debug)]
         struct StateFnInfo {
             hdl: usize,
-            parent_ident: Option<syn::Ident>,
+            args: Hsm1Args,
             msg_type: MsgType,
-            initial_state: bool,
+            initial_state_attr: Option<syn::Attribute>,
+            error_state_attr: Option<syn::Attribute>,
         }
         let mut state_fn_infos = Vec::<StateFnInfo>::new();
         let mut fns = Vec::<syn::ItemFn>::new();
         let mut fn_map = HashMap::<String, usize>::new();
 
-        // TODO: Gracefully handle when input.parse::<syn::ItemFn> returns an Err!
-        while let Ok(a_fn) = input.parse::<syn::ItemFn>() {
-            //println!("hsm1::parse: tol ItemFn a_fn={:#?}", a_fn);
+        // Errors in the state fn signatures are collected so all of
+        // them are reported, not just the first
+        let mut errors: Option<syn::Error> = None;
+
+        // A field's #[hsm1_default(expr)] is its value in new, the
+        // attribute is removed from the field
+        let mut field_defaults = Vec::<Option<syn::Expr>>::new();
+        for field in fields.iter_mut() {
+            let mut default = None;
+            for a in field
+                .attrs
+                .iter()
+                .filter(|a| a.path.is_ident("hsm1_default"))
+            {
+                if default.is_some() {
+                    push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(a, "A field may have only one #[hsm1_default]"),
+                    );
+                    continue;
+                }
+                match a.parse_args::<syn::Expr>() {
+                    Ok(expr) => default = Some(expr),
+                    Err(err) => push_error(&mut errors, err),
+                }
+            }
+            field.attrs.retain(|a| !a.path.is_ident("hsm1_default"));
+            field_defaults.push(default);
+        }
+
+        // Items other than fns, consts, use's, impls etc., are emitted
+        // as they are alongside the generated code
+        let mut items = Vec::<syn::Item>::new();
+
+        // Everything after the struct must parse as an item, anything
+        // else is an error rather than being ignored
+        while !input.is_empty() {
+            let expected = format!("expected a fn or other item in {}", item_struct.ident);
+            if input.peek(syn::Token![;]) {
+                let semi = input.parse::<syn::Token![;]>()?;
+                return Err(syn::Error::new_spanned(
+                    semi,
+                    format!("Unexpected `;`, {expected}"),
+                ));
+            }
+            let item = input
+                .parse::<syn::Item>()
+                .map_err(|err| syn::Error::new(err.span(), format!("{err}, {expected}")))?;
+            let a_fn = match item {
+                syn::Item::Fn(a_fn) => a_fn,
+                syn::Item::Verbatim(tokens) => {
+                    return Err(syn::Error::new_spanned(
+                        tokens,
+                        format!("Unexpected tokens, {expected}"),
+                    ));
+                }
+                item => {
+                    items.push(item);
+                    continue;
+                }
+            };
 
             // Look at the attributes and check for "hsm1_state"
             for a in a_fn.attrs.iter() {
-                //println!("hsm1::parse: function attributes: {:#?}", a);
-
                 if let Some(ident) = a.path.get_ident() {
-                    if ident == "hsm1_state" || ident == "hsm1_initial_state" {
-                        let initial_state = ident == "hsm1_initial_state";
+                    if ident == "hsm1_state"
+                        || ident == "hsm1_initial_state"
+                        || ident == "hsm1_error_state"
+                    {
+                        let initial_state_attr = if ident == "hsm1_initial_state" {
+                            Some(a.clone())
+                        } else {
+                            None
+                        };
+                        let error_state_attr = if ident == "hsm1_error_state" {
+                            Some(a.clone())
+                        } else {
+                            None
+                        };
 
                         // Enable to print the sig.input as pair
                         //for a_pair in a_fn.sig.inputs.pairs() {
@@ -104,130 +264,35 @@ impl Parse for Hsm1 {
                         //    }
                         //}
 
-                        // Now parse the arguments there should be two arguments
-                        //   &mut self,  msg: &mut MsgType
-
-                        let len_input_pairs = a_fn.sig.inputs.pairs().len();
-                        //println!("hsm1::parse: fn {} inputs.pairs.len={}", a_fn.sig.ident, len_input_pairs);
-                        if len_input_pairs != 2 {
-                            // TODO: Improve error handling
-                            panic!("All hsm1_state functions must have two parameters, `fn xxx(&mut self, msg: MsgType)`");
-                        }
-
-                        // Iternate over the "inputs" which are the parameters
-                        let mut sig_iter = a_fn.sig.inputs.pairs(); // why is sig_iter need to be mut?
-
-                        // Verify first argument is "&mut self"
-                        if let Some(pair) = sig_iter.next() {
-                            match pair {
-                                syn::punctuated::Pair::Punctuated(self_arg, _) => {
-                                    //println!("self_arg={self_arg:#?}");
-                                    match self_arg {
-                                        syn::FnArg::Receiver(rcvr) => {
-                                            if !rcvr.attrs.is_empty()
-                                                || rcvr.reference.is_none()
-                                                || rcvr.mutability.is_none()
-                                            {
-                                                panic!(
-                                                    "Expected first parameter to be `&mut self`"
-                                                );
-                                            }
-                                        }
-                                        syn::FnArg::Typed(_) => {
-                                            panic!("Expected first parameter to be `&mut self`");
-                                        }
-                                    }
-                                }
-                                syn::punctuated::Pair::End(_) => {
-                                    panic!("Expected &mut self as first parameter to an state funtion (SHOULD NOT HAPPEN as len_input_pairs == 2)");
-                                }
+                        let msg_type = match state_fn_msg_type(&a_fn.sig) {
+                            Ok(msg_type) => msg_type,
+                            Err(err) => {
+                                push_error(&mut errors, err);
+                                break;
                             }
-                        } else {
-                            panic!("No parameters, expected two parameters; &mut self, msg &MsgType (SHOULD NOT HAPPEN, as len_input_pairs == 2)");
-                        }
-
-                        // Get msg Type in the signature
-                        let msg_type = if let Some(pair) = sig_iter.next() {
-                            match pair {
-                                syn::punctuated::Pair::Punctuated(_, _) => {
-                                    panic!("Too many parameters, expected two parameters; &mut self, msg &MsgType (SHOULD NOT HAPPEN, as len_input_pairs == 2)");
-                                }
-                                syn::punctuated::Pair::End(last_arg) => {
-                                    //println!("last_arg={last_arg:#?}");
-                                    match last_arg {
-                                        syn::FnArg::Typed(pt) => {
-                                            match &*pt.ty {
-                                                syn::Type::Reference(tr) => {
-                                                    //println!("tr={tr:#?}");
-                                                    MsgType::MtTypeReference { tr: tr.clone() }
-                                                }
-                                                syn::Type::Path(tp) => {
-                                                    //println!("tp={tp:#?}");
-                                                    MsgType::MtTypePath { tp: tp.clone() }
-                                                }
-                                                //syn::Type::Array(_) => todo!(),
-                                                //syn::Type::BareFn(_) => todo!(),
-                                                //syn::Type::Group(_) => todo!(),
-                                                //syn::Type::ImplTrait(_) => todo!(),
-                                                //syn::Type::Infer(_) => todo!(),
-                                                //syn::Type::Macro(_) => todo!(),
-                                                //syn::Type::Never(_) => todo!(),
-                                                //syn::Type::Paren(_) => todo!(),
-                                                //syn::Type::Ptr(_) => todo!(),
-                                                //syn::Type::Slice(_) => todo!(),
-                                                //syn::Type::TraitObject(_) => todo!(),
-                                                //syn::Type::Tuple(_) => todo!(),
-                                                //syn::Type::Verbatim(_) => todo!(),
-                                                _ => {
-                                                    panic!("Expected msg type");
-                                                }
-                                            }
-                                        }
-                                        syn::FnArg::Receiver(_) => {
-                                            // TODO Improve error handling
-                                            panic!("Expected `msg: MsgType` as last parameter, a `self` is not allowed");
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            panic!("Expected &mut self as first parameter of an state funtion");
                         };
 
-                        // There zero or one parameter to the hsm1_state and
-                        // we're not interested in other atributes
-                        #[derive(Debug)]
-                        struct Hsm1Args {
-                            #[allow(unused)]
-                            arg_ident: Option<syn::Ident>,
-                        }
-
-                        impl Parse for Hsm1Args {
-                            fn parse(input: ParseStream) -> Result<Self> {
-                                // There should only be one ident
-                                let name = if let Ok(id) = input.parse() {
-                                    Some(id)
-                                } else {
-                                    None
-                                };
-                                Ok(Hsm1Args { arg_ident: name })
-                            }
-                        }
-
-                        let parent_ident = if let Ok(fa) = a.parse_args::<Hsm1Args>() {
-                            fa.arg_ident
+                        // The attribute's arguments are optional
+                        let args = if a.tokens.is_empty() {
+                            Hsm1Args::default()
                         } else {
-                            None
+                            match a.parse_args::<Hsm1Args>() {
+                                Ok(args) => args,
+                                Err(err) => {
+                                    push_error(&mut errors, err);
+                                    break;
+                                }
+                            }
                         };
 
                         // Save the StateFnInfo
                         state_fn_infos.push(StateFnInfo {
                             hdl: fns.len(),
-                            parent_ident,
+                            args,
                             msg_type,
-                            initial_state,
+                            initial_state_attr,
+                            error_state_attr,
                         });
-                        //println!("hsm1::parse: state_fn_info {:#?}", state_fn_infos.last());
 
                         break;
                     }
@@ -239,43 +304,171 @@ impl Parse for Hsm1 {
             fns.push(a_fn.clone());
         }
 
+        let hsm_ident = &item_struct.ident;
         let mut state_fn_idents_map = HashMap::<String, usize>::new();
         let mut state_fn_idents = Vec::<StateFnIdents>::new();
         for state_fn_info in state_fn_infos {
             let item_fn = &fns[state_fn_info.hdl];
             let process_fn_ident = item_fn.sig.ident.clone();
 
-            let enter_fn_ident = new_ident(process_fn_ident.clone(), "_enter");
-            let exit_fn_ident = new_ident(process_fn_ident.clone(), "_exit");
-
-            let enter_fn_ident_opt = if fn_map.get(enter_fn_ident.to_string().as_str()).is_some() {
-                Some(enter_fn_ident)
-            } else {
-                None
-            };
-
-            let exit_fn_ident_opt = if fn_map.get(exit_fn_ident.to_string().as_str()).is_some() {
-                Some(exit_fn_ident)
-            } else {
-                None
+            // An explicit enter or exit fn must be defined and the fn named
+            // with the suffix convention is used if there isn't one
+            let mut enter_exit_fn_ident = |explicit: Option<syn::Ident>, kind: &str| {
+                let suffix_fn_ident = fn_map
+                    .get(&format!("{process_fn_ident}_{kind}"))
+                    .map(|hdl| fns[*hdl].sig.ident.clone());
+                let explicit = match explicit {
+                    Some(explicit) => explicit,
+                    None => return suffix_fn_ident,
+                };
+                if !fn_map.contains_key(&explicit.to_string()) {
+                    push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(
+                            &explicit,
+                            format!("{hsm_ident}::{explicit} is not defined and cannot be the {kind} fn of {process_fn_ident}"),
+                        ),
+                    );
+                } else if let Some(suffix_fn_ident) =
+                    suffix_fn_ident.filter(|ident| *ident != explicit)
+                {
+                    push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(
+                            &explicit,
+                            format!("{process_fn_ident} has the {kind} fn {explicit} and also {suffix_fn_ident}, remove one"),
+                        ),
+                    );
+                    push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(
+                            &suffix_fn_ident,
+                            format!("{suffix_fn_ident} conflicts with the {kind} fn {explicit} of {process_fn_ident}"),
+                        ),
+                    );
+                }
+                Some(explicit)
             };
+            let enter_fn_ident_opt = enter_exit_fn_ident(state_fn_info.args.enter, "enter");
+            let exit_fn_ident_opt = enter_exit_fn_ident(state_fn_info.args.exit, "exit");
+
+            if let Some(guard) = &state_fn_info.args.guard {
+                match fn_map.get(&guard.to_string()) {
+                    Some(hdl) => {
+                        if let Err(err) = guard_fn_check(&fns[*hdl].sig) {
+                            push_error(&mut errors, err);
+                        }
+                    }
+                    None => push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(
+                            guard,
+                            format!("{hsm_ident}::{guard} is not defined and cannot be the guard fn of {process_fn_ident}"),
+                        ),
+                    ),
+                }
+            }
 
             state_fn_idents_map.insert(process_fn_ident.to_string(), state_fn_idents.len());
             state_fn_idents.push(StateFnIdents {
-                parent_fn_ident: state_fn_info.parent_ident,
+                parent_fn_ident: state_fn_info.args.parent,
+                initial_substate: state_fn_info.args.initial,
                 enter_fn_ident: enter_fn_ident_opt,
                 process_fn_ident,
                 process_fn_msg_type: state_fn_info.msg_type,
                 exit_fn_ident: exit_fn_ident_opt,
-                initial_state: state_fn_info.initial_state,
+                guard_fn_ident: state_fn_info.args.guard,
+                initial_state_attr: state_fn_info.initial_state_attr,
+                cfg_attrs: item_fn
+                    .attrs
+                    .iter()
+                    .filter(|a| a.path.is_ident("cfg"))
+                    .cloned()
+                    .collect(),
+                error_state_attr: state_fn_info.error_state_attr,
+                process_fn_error_type: state_fn_error_type(&item_fn.sig),
             });
         }
 
-        //println!("hsm1::parse:-");
+        // The state, enter and exit fns must all have the initial state's
+        // msg type, otherwise the errors are in the generated code. Only
+        // the first mismatch is reported.
+        let expected_sfn = state_fn_idents
+            .iter()
+            .find(|sfn| sfn.initial_state_attr.is_some())
+            .or_else(|| state_fn_idents.first());
+        let msg_ty = |ident: &syn::Ident| {
+            fn_map
+                .get(&ident.to_string())
+                .and_then(|hdl| msg_param_ty(&fns[*hdl].sig))
+        };
+        if let Some(expected_sfn) = expected_sfn {
+            if let Some(expected_ty) = msg_ty(&expected_sfn.process_fn_ident) {
+                let expected = type_string(expected_ty);
+                let mismatch = state_fn_idents
+                    .iter()
+                    .flat_map(|sfn| {
+                        [
+                            Some(&sfn.process_fn_ident),
+                            sfn.enter_fn_ident.as_ref(),
+                            sfn.exit_fn_ident.as_ref(),
+                        ]
+                    })
+                    .flatten()
+                    .filter_map(msg_ty)
+                    .find(|ty| type_string(ty) != expected);
+                if let Some(ty) = mismatch {
+                    let found = type_string(ty);
+                    let initial = &expected_sfn.process_fn_ident;
+                    push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(
+                            ty,
+                            format!(
+                                "expected `{expected}` as used by `{initial}`, found `{found}`"
+                            ),
+                        ),
+                    );
+                }
+            }
+        }
+
+        // The fallible state fns must all have the same error type, the
+        // first one's
+        let mut fallible_sfns = state_fn_idents
+            .iter()
+            .filter_map(|sfn| Some((sfn, sfn.process_fn_error_type.as_ref()?)));
+        if let Some((first_sfn, first_error_ty)) = fallible_sfns.next() {
+            let expected = type_string(first_error_ty);
+            let first = &first_sfn.process_fn_ident;
+            for (_, error_ty) in fallible_sfns {
+                let found = type_string(error_ty);
+                if found != expected {
+                    push_error(
+                        &mut errors,
+                        syn::Error::new_spanned(
+                            error_ty,
+                            format!("expected the error type `{expected}` as used by `{first}`, found `{found}`"),
+                        ),
+                    );
+                }
+            }
+        }
+
+        if let Some(err) = errors {
+            return Err(err);
+        }
+
         Ok(Hsm1 {
+            hsm_attrs: item_struct.attrs.clone(),
+            hsm_prefix,
+            hsm_vis: item_struct.vis.clone(),
             hsm_ident: item_struct.ident.clone(),
+            hsm_generics: item_struct.generics.clone(),
             hsm_fields: fields,
+            hsm_field_defaults: field_defaults,
             hsm_fns: fns,
+            hsm_items: items,
             hsm_state_fn_ident_map: state_fn_idents_map,
             hsm_state_fn_idents: state_fn_idents,
         })
@@ -290,12 +483,15 @@ impl Parse for Hsm1 {
 /// MyHsm is the simplest HSM with two states, initial with base
 /// as its parent.
 ///
-/// ```ignore // Used to supress clippy warnings, there's got to be a better way :(
-/// use proc_macro_hsm1::{handled, hsm1, hsm1_state, not_handled};
+/// State fns return a `state_result::StateResult`, `StateResult!()` is
+/// also accepted. Until it's removed they may also return the deprecated
+/// `state_result::legacy::StateResult` enum.
+///
+/// ```
+/// use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, not_handled};
 ///
-/// // These two use's needed as hsm1 is dependent upon them.
-/// // How can hsm1 proc_macro signify the dependency?
-/// use state_result::*;
+/// // hsm1 is dependent upon state_result
+/// use state_result::StateResult;
 ///
 /// pub enum Messages {
 ///     Add {
@@ -312,7 +508,7 @@ impl Parse for Hsm1 {
 ///     }
 ///
 ///     #[hsm1_initial_state]
-///     fn initial(&mut self, _msg: &mut Messages) -> StateResult!() {
+///     fn initial(&mut self, _msg: &mut Messages) -> StateResult {
 ///         // Mutate the state
 ///         self.initial_counter += 1;
 ///
@@ -328,7 +524,7 @@ impl Parse for Hsm1 {
 ///     }
 ///
 ///     #[hsm1_state]
-///     fn base(&mut self, msg: &mut Messages) -> StateResult!() {
+///     fn base(&mut self, msg: &mut Messages) -> StateResult {
 ///         // Mutate the state
 ///         self.base_counter += 1;
 ///         match msg {
@@ -341,7 +537,7 @@ impl Parse for Hsm1 {
 ///     }
 ///
 ///     #[hsm1_initial_state(base)]
-///     fn initial(&mut self, msg: &mut Messages) -> StateResult!() {
+///     fn initial(&mut self, msg: &mut Messages) -> StateResult {
 ///         // Mutate the state
 ///         self.initial_counter += 1;
 ///
@@ -403,46 +599,336 @@ impl Parse for Hsm1 {
 /// ```
 #[proc_macro]
 pub fn hsm1(input: TokenStream) -> TokenStream {
-    //println!("hsm1:+");
-
-    //println!("hsm1:+ input={:#?}", &input);
     let in_ts = input;
 
     let hsm = parse_macro_input!(in_ts as Hsm1);
-    //println!("hsm1: hsm={:#?}", hsm);
+
+    let hsm_attrs = hsm.hsm_attrs;
+
+    // The state machine info is only Clone when the struct derives Clone,
+    // it clones the deferred msgs so the msg type must also be Clone
+    let smi_derive_clone = if hsm_attrs.iter().any(derives_clone) {
+        quote!(#[derive(Clone)])
+    } else {
+        quote!()
+    };
+
+    // The struct, new and dispatch have the struct's visibility
+    let hsm_vis = hsm.hsm_vis;
 
     let hsm_ident = hsm.hsm_ident;
-    //println!("hsm1: hsm_ident={:#?}", hsm_ident);
+
+    // The struct's generics are also the generics of the generated types,
+    // the type aliases get them without bounds as those aren't enforced
+    let hsm_generics = hsm.hsm_generics;
+    let (impl_generics, ty_generics, where_clause) = hsm_generics.split_for_impl();
+    let alias_generics = without_bounds(&hsm_generics);
 
     let hsm_fields = hsm.hsm_fields;
-    //println!("hsm1: hsm_fields={:#?}", hsm_fields);
+    let hsm_field_defaults = hsm.hsm_field_defaults;
 
     let hsm_fns = hsm.hsm_fns;
-    //println!("hsm1: hsm_fns={:#?}", hsm_fns);
+
+    let hsm_items = hsm.hsm_items;
 
     let hsm_state_fn_ident_map = hsm.hsm_state_fn_ident_map;
-    //println!("hsm1: hsm_state_fn_ident_map={:?}", _hsm_state_fn_ident_map);
 
-    let state_fn = new_ident(hsm_ident.clone(), "StateFn");
-    let state_fn_enter = new_ident(hsm_ident.clone(), "StateFnEnter");
-    let state_fn_exit = new_ident(hsm_ident.clone(), "StateFnExit");
-    let state_info = new_ident(hsm_ident.clone(), "StateInfo");
-    let state_machine_info = new_ident(hsm_ident.clone(), "StateMachineInfo");
+    // The state machine info field and the helper types are named with
+    // a prefix, __hsm1 by default, so they don't collide with the user's
+    // fields and types. The field is __hsm1_smi and the types are
+    // __Hsm1MyFsmStateInfo and so on.
+    let prefix = hsm
+        .hsm_prefix
+        .map(|p| p.to_string())
+        .unwrap_or_else(|| "__hsm1".to_owned());
+    let smi = syn::Ident::new(&format!("{prefix}_smi"), hsm_ident.span());
+    let type_prefix = syn::Ident::new(
+        &format!("{}{hsm_ident}", camel_case(&prefix)),
+        hsm_ident.span(),
+    );
+    let state_fn = new_ident(type_prefix.clone(), "StateFn");
+    let state_fn_enter = new_ident(type_prefix.clone(), "StateFnEnter");
+    let state_fn_exit = new_ident(type_prefix.clone(), "StateFnExit");
+    let state_fn_guard = new_ident(type_prefix.clone(), "StateFnGuard");
+    let state_info = new_ident(type_prefix.clone(), "StateInfo");
+    let state_machine_info = new_ident(type_prefix.clone(), "StateMachineInfo");
+    let deferred = new_ident(type_prefix.clone(), "Deferred");
     let mut state_fn_msg_type_opt: Option<MsgType> = None;
 
     let hsm_state_fn_idents = hsm.hsm_state_fn_idents;
+
+    // Errors are collected and returned as compile errors spanned on
+    // the offending tokens
+    let mut errors: Option<syn::Error> = None;
+
+    // A state with #[cfg] attributes has them on everything generated for
+    // it, its StateInfo, STATE_XXX constant, enum variant and name. So
+    // the handles of the states after it depend on whether it's enabled,
+    // they're the number of enabled states before them.
+    let state_cfgs: Vec<TokenStream2> = hsm_state_fn_idents
+        .iter()
+        .map(|sfn| {
+            let cfg_attrs = &sfn.cfg_attrs;
+            quote!(#(#cfg_attrs)*)
+        })
+        .collect();
+    let state_cfg_predicates: Vec<Option<TokenStream2>> = hsm_state_fn_idents
+        .iter()
+        .map(|sfn| {
+            let mut predicates = Vec::<TokenStream2>::new();
+            for a in sfn.cfg_attrs.iter() {
+                match a.parse_args::<TokenStream2>() {
+                    Ok(predicate) => predicates.push(predicate),
+                    Err(err) => push_error(&mut errors, err),
+                }
+            }
+            match predicates.len() {
+                0 => None,
+                1 => predicates.pop(),
+                _ => Some(quote!(all(#(#predicates),*))),
+            }
+        })
+        .collect();
+    let hdl_expr = |hdl: usize| -> TokenStream2 {
+        let preceding = &state_cfg_predicates[..hdl];
+        let unconditional_cnt = preceding.iter().filter(|p| p.is_none()).count();
+        let predicates: Vec<&TokenStream2> = preceding.iter().flatten().collect();
+        if predicates.is_empty() {
+            quote!(#unconditional_cnt)
+        } else {
+            quote!((#unconditional_cnt #(+ cfg!(#predicates) as usize)*))
+        }
+    };
+    let state_hdl_exprs: Vec<TokenStream2> = (0..hsm_state_fn_idents.len()).map(hdl_expr).collect();
+    let hsm_state_fns_len = hdl_expr(hsm_state_fn_idents.len());
+
+    // A STATE_XXX handle constant for each state and their names for state_handle
+    let state_hdl_const_idents: Vec<syn::Ident> = hsm_state_fn_idents
+        .iter()
+        .map(|sfn| {
+            let process_fn_ident = &sfn.process_fn_ident;
+            syn::Ident::new(
+                &format!("STATE_{}", process_fn_ident.to_string().to_uppercase()),
+                process_fn_ident.span(),
+            )
+        })
+        .collect();
+    let state_hdl_consts: Vec<TokenStream2> = state_hdl_const_idents
+        .iter()
+        .zip(state_cfgs.iter().zip(state_hdl_exprs.iter()))
+        .map(|(const_ident, (cfg, hdl))| {
+            quote!(
+                #cfg
+                #[allow(unused)]
+                #hsm_vis const #const_ident: usize = #hdl;
+            )
+        })
+        .collect();
+    let state_names: Vec<String> = hsm_state_fn_idents
+        .iter()
+        .map(|sfn| sfn.process_fn_ident.to_string())
+        .collect();
+
+    // How a state's handle is referred to by its children and transitions,
+    // a state with #[cfg] is referred to by its constant so referring to
+    // it when it's disabled is an error
+    let state_hdl_refs: Vec<TokenStream2> = hsm_state_fn_idents
+        .iter()
+        .enumerate()
+        .map(|(hdl, sfn)| {
+            if sfn.cfg_attrs.is_empty() {
+                state_hdl_exprs[hdl].clone()
+            } else {
+                let const_ident = &state_hdl_const_idents[hdl];
+                quote!(<#hsm_ident #ty_generics>::#const_ident)
+            }
+        })
+        .collect();
+
+    // The state enum, MyFsmState, has a variant for each state whose
+    // discriminant is its handle
+    let state_enum = new_ident(hsm_ident.clone(), "State");
+    let state_variants: Vec<syn::Ident> = hsm_state_fn_idents
+        .iter()
+        .map(|sfn| {
+            let process_fn_ident = &sfn.process_fn_ident;
+            syn::Ident::new(
+                &upper_camel_case(&process_fn_ident.to_string()),
+                process_fn_ident.span(),
+            )
+        })
+        .collect();
+    let state_from_hdl_arms: Vec<TokenStream2> = state_variants
+        .iter()
+        .zip(state_cfgs.iter().zip(state_hdl_exprs.iter()))
+        .map(|(variant, (cfg, hdl))| quote!(#cfg h if h == #hdl => #state_enum::#variant))
+        .collect();
+
     let mut hsm_state_fns = Vec::<syn::ExprStruct>::new();
     let mut hsm_initial_state_fns_hdl: Option<usize> = None;
 
-    for sfn in &hsm_state_fn_idents {
-        //println!("hsm1: sf={:#?}", sfn);
+    // With more than one initial state each of their attributes is
+    // reported, so it's clear which ones to look at
+    let initial_state_cnt = hsm_state_fn_idents
+        .iter()
+        .filter(|sfn| sfn.initial_state_attr.is_some())
+        .count();
+
+    // A machine with a state fn returning Result<StateResult!(), E> is
+    // fallible, its other state fns are wrapped to return Ok. An error is
+    // returned by dispatch or, if there's an #[hsm1_error_state], the
+    // machine transitions to it and take_error returns the error.
+    let error_type: Option<syn::Type> = hsm_state_fn_idents
+        .iter()
+        .find_map(|sfn| sfn.process_fn_error_type.clone());
+    let error_states: Vec<(usize, &syn::Attribute)> = hsm_state_fn_idents
+        .iter()
+        .enumerate()
+        .filter_map(|(hdl, sfn)| Some((hdl, sfn.error_state_attr.as_ref()?)))
+        .collect();
+    if error_states.len() > 1 {
+        for (hdl, error_state_attr) in error_states.iter() {
+            let process_fn_ident = &hsm_state_fn_idents[*hdl].process_fn_ident;
+            push_error(
+                &mut errors,
+                syn::Error::new_spanned(
+                    error_state_attr,
+                    format!("{process_fn_ident} is one of {} error states in {hsm_ident}, mark at most one state fn with #[hsm1_error_state]", error_states.len()),
+                ),
+            );
+        }
+    }
+    let error_state_hdl: Option<TokenStream2> = error_states
+        .first()
+        .map(|(hdl, _)| state_hdl_refs[*hdl].clone());
+
+    // A composite state, a parent, has at most one initial substate and a
+    // transition to it is a transition to the leaf reached by following
+    // the initial substates. A transition to a composite without an
+    // initial substate is an error.
+    let state_parents: Vec<Option<usize>> = hsm_state_fn_idents
+        .iter()
+        .map(|sfn| {
+            sfn.parent_fn_ident
+                .as_ref()
+                .and_then(|parent| hsm_state_fn_ident_map.get(&parent.to_string()).copied())
+        })
+        .collect();
+
+    // A state can't be its own ancestor, each state in a cycle of
+    // parents has an error naming the cycle
+    for (hdl, sfn) in hsm_state_fn_idents.iter().enumerate() {
+        let mut cycle = vec![hdl];
+        let mut parent_hdl = state_parents[hdl];
+        while let Some(cur_hdl) = parent_hdl {
+            if cur_hdl == hdl {
+                cycle.push(hdl);
+                let names = cycle
+                    .iter()
+                    .map(|h| hsm_state_fn_idents[*h].process_fn_ident.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                let process_fn_ident = &sfn.process_fn_ident;
+                push_error(
+                    &mut errors,
+                    syn::Error::new_spanned(
+                        &sfn.parent_fn_ident,
+                        format!("{process_fn_ident} is its own ancestor, the parents {names} are a cycle"),
+                    ),
+                );
+                break;
+            }
+            if cycle.contains(&cur_hdl) {
+                // A cycle above hdl, it's reported by its states
+                break;
+            }
+            cycle.push(cur_hdl);
+            parent_hdl = state_parents[cur_hdl];
+        }
+    }
+
+    let mut initial_substates: Vec<Option<usize>> = vec![None; hsm_state_fn_idents.len()];
+    for (hdl, sfn) in hsm_state_fn_idents.iter().enumerate() {
+        let (initial, parent_hdl) = match (&sfn.initial_substate, state_parents[hdl]) {
+            (Some(initial), Some(parent_hdl)) => (initial, parent_hdl),
+            _ => continue,
+        };
+        if let Some(first_hdl) = initial_substates[parent_hdl] {
+            let parent = &hsm_state_fn_idents[parent_hdl].process_fn_ident;
+            let first = &hsm_state_fn_idents[first_hdl].process_fn_ident;
+            let process_fn_ident = &sfn.process_fn_ident;
+            push_error(
+                &mut errors,
+                syn::Error::new_spanned(
+                    initial,
+                    format!("{first} is the initial substate of {parent}, {process_fn_ident} can't be too"),
+                ),
+            );
+        } else {
+            initial_substates[parent_hdl] = Some(hdl);
+        }
+    }
+    let transition_target = |hdl: usize| -> std::result::Result<TokenStream2, String> {
+        let mut leaf_hdl = hdl;
+        // A cycle of initial substates can't be followed, it's limited
+        // to the number of states
+        for _ in 0..hsm_state_fn_idents.len() {
+            let children: Vec<&syn::Ident> = state_parents
+                .iter()
+                .enumerate()
+                .filter(|(_, parent)| **parent == Some(leaf_hdl))
+                .map(|(child, _)| &hsm_state_fn_idents[child].process_fn_ident)
+                .collect();
+            if children.is_empty() {
+                return Ok(state_hdl_refs[leaf_hdl].clone());
+            }
+            match initial_substates[leaf_hdl] {
+                Some(initial_hdl) => leaf_hdl = initial_hdl,
+                None => {
+                    let composite = &hsm_state_fn_idents[leaf_hdl].process_fn_ident;
+                    let children = children
+                        .iter()
+                        .map(|child| child.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    return Err(format!(
+                        "{composite} is the parent of {children} but has no initial substate to transition to, mark one with `#[hsm1_state({composite}, initial)]`"
+                    ));
+                }
+            }
+        }
+        Ok(state_hdl_refs[leaf_hdl].clone())
+    };
+    let transition_targets: Vec<std::result::Result<TokenStream2, String>> = (0
+        ..hsm_state_fn_idents.len())
+        .map(transition_target)
+        .collect();
 
+    for sfn in &hsm_state_fn_idents {
         let process_fn_ident = sfn.process_fn_ident.clone();
-        //println!("hsm1: process_fn_ident={}", process_fn_ident);
-        if sfn.initial_state {
-            assert_eq!(hsm_initial_state_fns_hdl, None);
-            hsm_initial_state_fns_hdl = Some(hsm_state_fns.len());
-            state_fn_msg_type_opt = Some(sfn.process_fn_msg_type.clone());
+        if let Some(initial_state_attr) = &sfn.initial_state_attr {
+            if initial_state_cnt > 1 {
+                push_error(
+                    &mut errors,
+                    syn::Error::new_spanned(
+                        initial_state_attr,
+                        format!("{process_fn_ident} is one of {initial_state_cnt} initial states in {hsm_ident}, mark exactly one state fn with #[hsm1_initial_state]"),
+                    ),
+                );
+            }
+            if let Some(cfg_attr) = sfn.cfg_attrs.first() {
+                push_error(
+                    &mut errors,
+                    syn::Error::new_spanned(
+                        cfg_attr,
+                        format!("The initial state {process_fn_ident} can't have #[cfg], {hsm_ident} must always have it"),
+                    ),
+                );
+            }
+            if hsm_initial_state_fns_hdl.is_none() {
+                hsm_initial_state_fns_hdl = Some(hsm_state_fns.len());
+                state_fn_msg_type_opt = Some(sfn.process_fn_msg_type.clone());
+            }
         }
 
         let opt_fn_ident = |ident: Option<syn::Ident>| match ident {
@@ -452,30 +938,49 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
         let parent_hdl: TokenStream2 = if let Some(parent_ident) = &sfn.parent_fn_ident {
             let parent = parent_ident.to_string();
             if let Some(hdl) = hsm_state_fn_ident_map.get(&parent) {
-                quote!(Some(#hdl))
+                let parent_hdl = &state_hdl_refs[*hdl];
+                quote!(Some(#parent_hdl))
             } else {
-                // TODO: Improve error handling
-                panic!(
-                    "{hsm_ident}::{parent} is not defined and cannot be parent of {process_fn_ident}"
-                );
+                let msg = if hsm_fns.iter().any(|a_fn| a_fn.sig.ident == *parent_ident) {
+                    format!("{hsm_ident}::{parent} is not a state fn and cannot be parent of {process_fn_ident}, mark it with #[hsm1_state]")
+                } else {
+                    format!("{hsm_ident}::{parent} is not defined and cannot be parent of {process_fn_ident}")
+                };
+                push_error(&mut errors, syn::Error::new_spanned(parent_ident, msg));
+                quote!(None)
             }
         } else {
             quote!(None)
         };
-        //println!("hsm1: parent_fn={}", parent_fn);
         let enter_fn = opt_fn_ident(sfn.enter_fn_ident.clone());
-        //println!("hsm1: enter_fn={}", enter_fn);
         let exit_fn = opt_fn_ident(sfn.exit_fn_ident.clone());
-        //println!("hsm1: exit_fn={}", exit_fn);
+        let guard_fn = opt_fn_ident(sfn.guard_fn_ident.clone());
+        // The result is converted with Into so state fns can still return
+        // the deprecated state_result::legacy::StateResult enum
+        let process_fn = match (&error_type, &sfn.process_fn_error_type) {
+            (None, _) => quote!(
+                |sm, msg| ::core::convert::Into::into(#hsm_ident::#process_fn_ident(sm, msg))
+            ),
+            (Some(_), None) => quote!(
+                |sm, msg| Ok(::core::convert::Into::into(#hsm_ident::#process_fn_ident(sm, msg)))
+            ),
+            (Some(_), Some(_)) => quote!(
+                |sm, msg| #hsm_ident::#process_fn_ident(sm, msg).map(::core::convert::Into::into)
+            ),
+        };
 
         let ts: TokenStream2 = quote!(
             #state_info {
                 name: stringify!(#process_fn_ident).to_owned(),
                 parent: #parent_hdl,
                 enter: #enter_fn,
-                process: #hsm_ident::#process_fn_ident,
+                process: #process_fn,
                 exit: #exit_fn,
+                guard: #guard_fn,
                 active: false,
+                enter_cnt: 0,
+                process_cnt: 0,
+                exit_cnt: 0,
             }
         );
         let sf_es = syn::parse2::<syn::ExprStruct>(ts);
@@ -483,64 +988,275 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
             hsm_state_fns.push(es);
         }
     }
-    //println!("hsm1: hsm_state_fns:\n{:#?}", hsm_state_fns);
 
-    let hsm_state_fns_len = hsm_state_fns.len();
+    // A composite initial state starts in the leaf its initial substates
+    // lead to, like a transition to it
     let initial_state_hdl = if let Some(hdl) = hsm_initial_state_fns_hdl {
-        hdl
+        match &transition_targets[hdl] {
+            Ok(target) => target.clone(),
+            Err(msg) => {
+                let sfn = &hsm_state_fn_idents[hdl];
+                let process_fn_ident = &sfn.process_fn_ident;
+                let msg = format!("{hsm_ident} can't start in {process_fn_ident}, {msg}");
+                push_error(
+                    &mut errors,
+                    syn::Error::new_spanned(&sfn.initial_state_attr, msg),
+                );
+                quote!(0)
+            }
+        }
     } else {
-        // TODO: Better error handling
-        panic!("No initial state");
+        push_error(
+            &mut errors,
+            syn::Error::new_spanned(
+                &hsm_ident,
+                format!(
+                    "No initial state in {hsm_ident}, mark exactly one state fn with #[hsm1_initial_state]"
+                ),
+            ),
+        );
+        quote!(0)
     };
-    //println!("hsm1: hsm_state_fns_len: {} initial_state_hdl={}", hsm_state_fns_len, initial_state_hdl);
 
     let mut visitor = Visitor {
         hsm_ident: hsm_ident.clone(),
-        hsm_state_fn_ident_map,
+        hsm_state_hdl_map: state_names
+            .iter()
+            .cloned()
+            .zip(transition_targets.iter().cloned())
+            .collect(),
+        hsm_fn_names: hsm_fns.iter().map(|f| f.sig.ident.to_string()).collect(),
+        hsm_state_enum: state_enum.clone(),
+        hsm_state_variant_map: state_variants
+            .iter()
+            .map(|variant| variant.to_string())
+            .zip(transition_targets.iter().cloned())
+            .collect(),
+        state_fn_target: None,
+        errors: None,
+    };
+
+    // A user fn new is the constructor, then the struct isn't Default so
+    // it may have fields like a Sender that aren't Default. Either way
+    // with_fields constructs it from all of the fields.
+    let user_new = hsm_fns.iter().any(|a_fn| a_fn.sig.ident == "new");
+    let has_field_defaults = hsm_field_defaults.iter().any(|d| d.is_some());
+    let (derive_default, new_fn) = if user_new {
+        (quote!(), quote!())
+    } else if has_field_defaults {
+        // Only the fields without an #[hsm1_default] need to be Default
+        let field_inits: Vec<TokenStream2> = hsm_field_defaults
+            .iter()
+            .map(|default| match default {
+                Some(expr) => quote!(#expr),
+                None => quote!(Default::default()),
+            })
+            .collect();
+        (
+            quote!(),
+            quote!(
+                #[allow(clippy::new_without_default)]
+                #hsm_vis fn new() -> Self {
+                    Self::with_fields(#(#field_inits),*)
+                }
+            ),
+        )
+    } else {
+        (
+            quote!(#[derive(Default)]),
+            quote!(
+                #hsm_vis fn new() -> Self
+                where
+                    Self: Default,
+                {
+                    let mut sm: Self = Default::default();
+
+                    sm.initial_enter_fns_hdls();
+
+                    sm
+                }
+            ),
+        )
     };
+    let field_idents: Vec<&Option<syn::Ident>> = hsm_fields.iter().map(|f| &f.ident).collect();
+    let field_tys: Vec<&syn::Type> = hsm_fields.iter().map(|f| &f.ty).collect();
 
     let mut converted_fns = Vec::<syn::ItemFn>::new();
     for a_fn in hsm_fns.iter() {
-        //println!("hsm1: visiting a_fn={:?}", a_fn.sig.ident);
         let mut mut_a_fn = a_fn.clone();
+        visitor.state_fn_target = hsm_state_fn_ident_map
+            .get(&a_fn.sig.ident.to_string())
+            .map(|hdl| transition_targets[*hdl].clone());
         visitor.visit_item_fn_mut(&mut mut_a_fn);
         converted_fns.push(mut_a_fn);
     }
-    //println!("hsm1: converted_fns={:#?}", converted_fns);
+    if let Some(err) = visitor.errors {
+        push_error(&mut errors, err);
+    }
+
+    if let Some(err) = errors {
+        return err.to_compile_error().into();
+    }
 
-    let state_fn_msg_type: TokenStream2 = if let Some(msg_type) = state_fn_msg_type_opt {
-        //println!("msg_type={msg_type:?}");
-        match msg_type {
-            MsgType::MtTypePath { tp } => quote!(#tp),
-            MsgType::MtTypeReference { tr } => quote!(#tr),
+    // The msg type is the initial state's, without one an error was returned above
+    let state_fn_msg_type: TokenStream2 = match &state_fn_msg_type_opt {
+        Some(MsgType::MtTypePath { tp }) => quote!(#tp),
+        Some(MsgType::MtTypeReference { tr }) => quote!(#tr),
+        None => unreachable!("No msg type"),
+    };
+
+    // Deferred msgs are owned, a deferred msg is dispatched the same way
+    // as the msgs passed to dispatcher
+    let (owned_msg_type, deferred_msg) = match &state_fn_msg_type_opt {
+        Some(MsgType::MtTypeReference { tr }) => {
+            let elem = &tr.elem;
+            if tr.mutability.is_some() {
+                (quote!(#elem), quote!(&mut msg))
+            } else {
+                (quote!(#elem), quote!(&msg))
+            }
+        }
+        _ => (state_fn_msg_type.clone(), quote!(msg)),
+    };
+
+    // A msg passed by value is passed by reference within dispatch, as
+    // the parent may need it, and each state fn is passed a clone.
+    let (dispatch_msg_type, dispatch_msg, state_fn_msg, msg_where_clause) =
+        match &state_fn_msg_type_opt {
+            Some(MsgType::MtTypePath { tp }) => (
+                quote!(&#tp),
+                quote!(&msg),
+                quote!(msg.clone()),
+                quote!(where #tp: Clone),
+            ),
+            _ => (
+                state_fn_msg_type.clone(),
+                quote!(msg),
+                quote!(msg),
+                quote!(),
+            ),
+        };
+
+    // The result of a fallible process fn is kept in the smi field's error, with an
+    // error state it's a transition to it
+    let pending_error = new_ident(type_prefix.clone(), "PendingError");
+    let process_call = quote!((self.#smi.state_fns[hdl].process)(self, #state_fn_msg));
+    let (process_result_type, process_sr) = match &error_type {
+        None => (quote!(state_result::StateResult), process_call),
+        Some(error_type) => {
+            let on_error = match &error_state_hdl {
+                Some(error_state_hdl) => {
+                    quote!((state_result::Handled::Yes, Some(#error_state_hdl)))
+                }
+                None => quote!((state_result::Handled::Yes, None)),
+            };
+            (
+                quote!(std::result::Result<state_result::StateResult, #error_type>),
+                quote!(
+                    match #process_call {
+                        Ok(sr) => sr,
+                        Err(err) => {
+                            self.#smi.error.error = Some(err);
+                            #on_error
+                        }
+                    }
+                ),
+            )
         }
-    } else {
-        panic!("No msg type");
     };
-    //println!("state_fn_msg_type_path={state_fn_msg_type_path:?}");
-    //println!("hsm_ident={hsm_ident:?}");
+
+    let not_handled_result = match &error_type {
+        Some(_) => quote!(Ok((state_result::Handled::No, None))),
+        None => quote!((state_result::Handled::No, None)),
+    };
+
+    // Without an error state dispatch and dispatcher return the error.
+    // When dispatching a deferred msg fails the msgs not yet dispatched
+    // are put back in front of those deferred since, so they keep their
+    // order.
+    let (dispatch_result_type, dispatch_result, dispatch_try, dispatch_deferred, dispatcher_result) =
+        match (&error_type, &error_state_hdl) {
+            (Some(error_type), None) => (
+                quote!(std::result::Result<bool, #error_type>),
+                quote!(match self.#smi.error.error.take() {
+                    Some(err) => Err(err),
+                    None => Ok(transitioned),
+                }),
+                quote!(?),
+                quote!(match self.dispatch(#deferred_msg) {
+                    Ok(transitioned) => transitioned,
+                    Err(err) => {
+                        let current = self.#smi.deferred.current;
+                        let queues = &mut self.#smi.deferred.queues;
+                        let mut remaining = std::mem::take(&mut queues[previous]);
+                        remaining.append(&mut queues[current]);
+                        queues[current] = remaining;
+                        return Err(err);
+                    }
+                }),
+                quote!(Ok(any_transitioned)),
+            ),
+            _ => (
+                quote!(bool),
+                quote!(transitioned),
+                quote!(),
+                quote!(self.dispatch(#deferred_msg)),
+                quote!(any_transitioned),
+            ),
+        };
+    let (error_field, error_field_init, error_items) = match &error_type {
+        None => (quote!(), quote!(), quote!()),
+        Some(error_type) => (
+            quote!(error: #pending_error<#error_type>,),
+            quote!(error: Default::default(),),
+            quote!(
+                // The error of a state fn that hasn't been taken yet, it's
+                // not cloned as the error may not be Clone
+                struct #pending_error<E> {
+                    error: Option<E>,
+                }
+
+                impl<E> Default for #pending_error<E> {
+                    fn default() -> Self {
+                        Self { error: None }
+                    }
+                }
+
+                impl<E> Clone for #pending_error<E> {
+                    fn clone(&self) -> Self {
+                        Self::default()
+                    }
+                }
+
+                impl<E> std::fmt::Debug for #pending_error<E> {
+                    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                        f.debug_struct(stringify!(#pending_error))
+                            .field("is_some", &self.error.is_some())
+                            .finish()
+                    }
+                }
+            ),
+        ),
+    };
+    let take_error_fn = error_type.as_ref().map(|error_type| {
+        quote!(
+            // The error of the state fn that transitioned to the error
+            // state, None once it's taken
+            #[allow(unused)]
+            #hsm_vis fn take_error(&mut self) -> Option<#error_type> {
+                self.#smi.error.error.take()
+            }
+        )
+    });
 
     let output = quote!(
 
-        // error: implementation of `Debug` is not general enough
-        //   --> proc-macro-hsm1/src/main.rs:8:1
-        //    |
-        // 8  | / hsm1!(
-        // 9  | |     #[derive(Debug)]
-        // 10 | |     struct MyFsm {
-        // 11 | |         initial_counter: u64,
-        // ...  |
-        // 21 | |     }
-        // 22 | | );
-        //    | |_^ implementation of `Debug` is not general enough
-        //    |
-        //    = note: `Debug` would have to be implemented for the type `for<'r> fn(&'r mut MyFsm)`
-        //    = note: ...but `Debug` is actually implemented for the type `fn(&'0 mut MyFsm)`, for some specific lifetime `'0`
-        //    = note: this error originates in the derive macro `Debug` (in Nightly builds, run with -Z macro-backtrace for more info)
-        //#[derive(Debug)]
-        #[derive(Default)]
-        struct #hsm_ident {
-            smi: #state_machine_info,
+        // The user's attributes and derives, StateMachineInfo implements
+        // Debug and Clone so the smi field doesn't prevent deriving them
+        #(#hsm_attrs)*
+        #derive_default
+        #hsm_vis struct #hsm_ident #hsm_generics #where_clause {
+            #smi: #state_machine_info #ty_generics,
 
             #(
                 #[allow(unused)]
@@ -548,13 +1264,81 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
             ),*
         }
 
-        impl #hsm_ident {
-            pub fn new() -> Self {
-                let mut smi: #hsm_ident = Default::default();
+        #(#hsm_items)*
+
+        #[allow(unused)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(usize)]
+        #hsm_vis enum #state_enum {
+            #(
+                #state_cfgs
+                #state_variants = #state_hdl_exprs,
+            )*
+        }
+
+        impl From<usize> for #state_enum {
+            fn from(hdl: usize) -> Self {
+                match hdl {
+                    #(
+                        #state_from_hdl_arms,
+                    )*
+                    _ => panic!("{} is not a {} state handle", hdl, stringify!(#hsm_ident)),
+                }
+            }
+        }
 
-                smi.initial_enter_fns_hdls();
+        impl From<#state_enum> for usize {
+            fn from(state: #state_enum) -> Self {
+                state as usize
+            }
+        }
 
-                smi
+        impl #impl_generics #hsm_ident #ty_generics #where_clause {
+            #(#state_hdl_consts)*
+
+            // The handle of the state named name
+            #[allow(unused)]
+            #hsm_vis fn state_handle(name: &str) -> Option<usize> {
+                match name {
+                    #(
+                        #state_cfgs
+                        #state_names => Some(#state_hdl_exprs),
+                    )*
+                    _ => None,
+                }
+            }
+
+            #[allow(unused)]
+            #hsm_vis fn current_state(&self) -> #state_enum {
+                self.#smi.current_state_fns_hdl.into()
+            }
+
+            #[allow(unused)]
+            #hsm_vis fn previous_state(&self) -> #state_enum {
+                self.#smi.previous_state_fns_hdl.into()
+            }
+
+            // True between a transition and the dispatch that enters
+            // the new state
+            #[allow(unused)]
+            fn current_state_changed(&self) -> bool {
+                self.#smi.current_state_changed
+            }
+
+            #new_fn
+
+            // Construct the state machine from its fields, in the order
+            // they're declared
+            #[allow(unused, clippy::too_many_arguments)]
+            #hsm_vis fn with_fields(#(#field_idents: #field_tys),*) -> Self {
+                let mut sm = Self {
+                    #smi: #state_machine_info::new(),
+                    #(#field_idents),*
+                };
+
+                sm.initial_enter_fns_hdls();
+
+                sm
             }
 
             #(
@@ -565,11 +1349,10 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
             // When the state machine starts there will be no fn's to
             // exit so we initialize only the enter_fns_hdls.
             fn initial_enter_fns_hdls(&mut self) {
-                let mut enter_hdl = self.smi.current_state_fns_hdl;
+                let mut enter_hdl = self.#smi.current_state_fns_hdl;
                 loop {
-                    //println!("initial_enter_fns_hdls: push(enter_hdl={})", enter_hdl);
-                    self.smi.enter_fns_hdls.push(enter_hdl);
-                    enter_hdl = if let Some(hdl) = self.smi.state_fns[enter_hdl].parent {
+                    self.#smi.enter_fns_hdls.push(enter_hdl);
+                    enter_hdl = if let Some(hdl) = self.#smi.state_fns[enter_hdl].parent {
                         hdl
                     } else {
                         break;
@@ -586,172 +1369,360 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
                 // ulitimate parent.
                 let mut cur_hdl = dest_state_hdl;
                 let exit_sentinel = loop {
-                    //println!("setup_exit_enter_fns_hdls: push(cur_hdl={})", cur_hdl);
-                    self.smi.enter_fns_hdls.push(cur_hdl);
+                    self.#smi.enter_fns_hdls.push(cur_hdl);
 
-                    cur_hdl = if let Some(hdl) = self.smi.state_fns[cur_hdl].parent {
-                        //println!("setup_exit_enter_fns_hdls: cur_hdl={}", cur_hdl);
+                    cur_hdl = if let Some(hdl) = self.#smi.state_fns[cur_hdl].parent {
                         hdl
                     } else {
                         // Exit state_fns[self.current_state_fns_hdl] and all its parents
-                        //println!("setup_exit_enter_fns_hdls: No more parents");
                         break None;
                     };
 
-                    if self.smi.state_fns[cur_hdl].active {
+                    if self.#smi.state_fns[cur_hdl].active {
                         // Exit state_fns[self.current_state_fns_hdl] and
                         // parents upto but excluding state_fns[cur_hdl]
-                        //println!("setup_exit_enter_fns_hdls: set exit_sentinel={}", cur_hdl);
                         break Some(cur_hdl);
                     }
                 };
 
-                // Starting at self.smi.current_state_fns_hdl generate the
+                // Starting at self.#smi.current_state_fns_hdl generate the
                 // list of StateFns that we're going to exit. If exit_sentinel is None
                 // then exit from current_state_fns_hdl and all of its parents.
                 // If exit_sentinel is Some then exit from the current state_fns_hdl
                 // up to but not including the exit_sentinel.
-                let mut exit_hdl = self.smi.current_state_fns_hdl;
+                let mut exit_hdl = self.#smi.current_state_fns_hdl;
 
                 // Always exit the first state, this handles the special case
                 // where Some(exit_hdl) == exit_sentinel.
 
-                //println!("setup_exit_enter_fns_hdls: push_back(current_stsate_fns_hdl={}) ", exit_hdl);
-                self.smi.exit_fns_hdls.push_back(exit_hdl);
+                self.#smi.exit_fns_hdls.push_back(exit_hdl);
 
                 loop {
-                    exit_hdl = if let Some(hdl) = self.smi.state_fns[exit_hdl].parent {
+                    exit_hdl = if let Some(hdl) = self.#smi.state_fns[exit_hdl].parent {
                         hdl
                     } else {
-                        //println!("setup_exit_enter_fns_hdls: No more parents, done");
                         return;
                     };
 
                     if Some(exit_hdl) == exit_sentinel {
-                        //println!("setup_exit_enter_fns_hdls: reached exit_sentinel, done");
                         return;
                     }
 
-                    //println!("setup_exit_enter_fns_hdls: push_back(exit_hdl={})", exit_hdl);
-                    self.smi.exit_fns_hdls.push_back(exit_hdl);
+                    self.#smi.exit_fns_hdls.push_back(exit_hdl);
                 }
             }
 
-            // TODO: Not sure this is worth it, if it is consider adding hsm_name()
-            fn state_name(&self) -> &str {
-                &self.smi.state_fns[self.smi.current_state_fns_hdl].name
+            // TODO: Consider adding hsm_name()
+            #[allow(unused)]
+            #hsm_vis fn current_state_name(&self) -> &str {
+                &self.#smi.state_fns[self.#smi.current_state_fns_hdl].name
+            }
+
+            #[allow(unused)]
+            #hsm_vis fn previous_state_name(&self) -> &str {
+                &self.#smi.state_fns[self.#smi.previous_state_fns_hdl].name
+            }
+
+            // The number of times the enter, process and exit fns of the
+            // state with handle hdl were called
+            #[allow(unused)]
+            #hsm_vis fn get_state_enter_cnt(&self, hdl: usize) -> usize {
+                self.#smi.state_fns[hdl].enter_cnt
+            }
+
+            #[allow(unused)]
+            #hsm_vis fn get_state_process_cnt(&self, hdl: usize) -> usize {
+                self.#smi.state_fns[hdl].process_cnt
+            }
+
+            #[allow(unused)]
+            #hsm_vis fn get_state_exit_cnt(&self, hdl: usize) -> usize {
+                self.#smi.state_fns[hdl].exit_cnt
+            }
+
+            // The name of the state with handle hdl
+            #[allow(unused)]
+            #hsm_vis fn state_name(hdl: usize) -> Option<&'static str> {
+                const STATE_NAMES: [&str; #hsm_state_fns_len] = [#(#state_cfgs #state_names),*];
+                STATE_NAMES.get(hdl).copied()
             }
 
-            fn dispatch_hdl(&mut self, msg: #state_fn_msg_type, hdl: usize) {
-                //println!("dispatch_hdl {}:+", hdl);
-                if self.smi.current_state_changed && !self.smi.enter_fns_hdls.is_empty() {
+            // Returns true if there was a transition, its enter fns are
+            // called at the start of the next dispatch
+            fn dispatch_hdl(&mut self, msg: #dispatch_msg_type, hdl: usize) -> bool
+            #msg_where_clause
+            {
+                if self.#smi.current_state_changed && !self.#smi.enter_fns_hdls.is_empty() {
                     // Execute the enter functions
-                    while let Some(enter_hdl) = self.smi.enter_fns_hdls.pop() {
-                        if let Some(state_enter) = self.smi.state_fns[enter_hdl].enter {
-                            //println!("dispatch_hdl {}: call enter_hdl={}", hdl, enter_hdl);
-                            (state_enter)(self, msg);
-                            self.smi.state_fns[enter_hdl].active = true;
-                            //println!("dispatch_hdl {}: retf enter_hdl={}", hdl, enter_hdl);
-                        } else {
-                            //println!("dispatch_hdl {}: no enter_hdl", hdl);
+                    while let Some(enter_hdl) = self.#smi.enter_fns_hdls.pop() {
+                        // A state is active once entered, with or without an
+                        // enter fn, so a transition between its children
+                        // doesn't exit it
+                        if let Some(state_enter) = self.#smi.state_fns[enter_hdl].enter {
+                            (state_enter)(self, #state_fn_msg);
+                            self.#smi.state_fns[enter_hdl].enter_cnt += 1;
                         }
+                        self.#smi.state_fns[enter_hdl].active = true;
                     }
 
-                    self.smi.current_state_changed = false;
+                    self.#smi.current_state_changed = false;
                 }
 
                 let mut transition_dest_hdl = None;
+                let mut parent_transitioned = false;
+
+                // A state whose guard returns false doesn't handle the msg
+                let guarded = match self.#smi.state_fns[hdl].guard {
+                    Some(guard) => !(guard)(self, #state_fn_msg),
+                    None => false,
+                };
 
-                //println!("dispatch_hdl {}: call process", hdl);
-                match (self.smi.state_fns[hdl].process)(self, msg) {
-                    state_result::StateResult::NotHandled => {
+                let sr = if guarded {
+                    (state_result::Handled::No, None)
+                } else {
+                    self.#smi.state_fns[hdl].process_cnt += 1;
+                    self.#smi.processing_state_fns_hdl = hdl;
+                    #process_sr
+                };
+                match sr {
+                    (state_result::Handled::No, None) => {
                         // This handles the special case where we're transitioning to ourself
-                        if let Some(parent_hdl) = self.smi.state_fns[hdl].parent {
-                            //println!("dispatch_hdl {}: retf process, NotHandled, call dispatch_hdl({})", hdl, parent_hdl);
-                            self.dispatch_hdl(msg, parent_hdl);
-                            //println!("dispatch_hdl {}: retf process, NotHandled, retf dispatch_hdl({})", hdl, parent_hdl);
+                        if let Some(parent_hdl) = self.#smi.state_fns[hdl].parent {
+                            parent_transitioned = self.dispatch_hdl(msg, parent_hdl);
                         } else {
                             // TODO: Consider calling a "default_handler" when NotHandled and no parent
-                            //println!("dispatch_hdl {}: retf process, NotHandled no parent", hdl);
                         }
                     }
-                    state_result::StateResult::Handled => {
+                    (state_result::Handled::Yes, None) => {
                         // Nothing to do
-                        //println!("dispatch_hdl {}: retf process, Handled", hdl);
                     }
-                    state_result::StateResult::TransitionTo(dest_hdl) => {
-                        //println!("dispatch_hdl {}: retf process, TransitionTo({})", hdl, dest_hdl);
+                    // A transition with a msg that isn't handled happens
+                    // after the parents process it, unless one of them
+                    // transitions
+                    (state_result::Handled::No, Some(dest_hdl)) => {
+                        if let Some(parent_hdl) = self.#smi.state_fns[hdl].parent {
+                            parent_transitioned = self.dispatch_hdl(msg, parent_hdl);
+                        }
+                        if !parent_transitioned {
+                            self.setup_exit_enter_fns_hdls(dest_hdl);
+                            self.#smi.current_state_changed = true;
+                            transition_dest_hdl = Some(dest_hdl);
+                        }
+                    }
+                    (state_result::Handled::Yes, Some(dest_hdl)) => {
                         self.setup_exit_enter_fns_hdls(dest_hdl);
-                        self.smi.current_state_changed = true;
+                        self.#smi.current_state_changed = true;
                         transition_dest_hdl = Some(dest_hdl);
                     }
                 }
 
-                if self.smi.current_state_changed && !self.smi.exit_fns_hdls.is_empty() {
-                    while let Some(exit_hdl) = self.smi.exit_fns_hdls.pop_front() {
-                        if let Some(state_exit) = self.smi.state_fns[exit_hdl].exit {
-                            //println!("dispatch_hdl {}: call exit_hdl {}", hdl, exit_hdl);
-                            (state_exit)(self, msg);
-                            self.smi.state_fns[exit_hdl].active = false;
-                            //println!("dispatch_hdl {}: retf exit_hdl {}", hdl, exit_hdl);
-                        } else {
-                            //println!("dispatch_hdl {}: no exit_hdl", hdl);
+                if self.#smi.current_state_changed && !self.#smi.exit_fns_hdls.is_empty() {
+                    while let Some(exit_hdl) = self.#smi.exit_fns_hdls.pop_front() {
+                        if let Some(state_exit) = self.#smi.state_fns[exit_hdl].exit {
+                            (state_exit)(self, #state_fn_msg);
+                            self.#smi.state_fns[exit_hdl].exit_cnt += 1;
                         }
+                        self.#smi.state_fns[exit_hdl].active = false;
                     }
                 }
 
                 if let Some(dest_hdl) = transition_dest_hdl {
                     // Change the previous and current state_fns_hdl after we've
                     // preformed the exit routines so state_name is correct.
-                    self.smi.previous_state_fns_hdl = self.smi.current_state_fns_hdl;
-                    self.smi.current_state_fns_hdl = dest_hdl;
-                    //println!("dispatch_hdl {}: transitioned, updated previous {} and current {} state hdls", hdl, self.smi.previous_state_fns_hdl, self.smi.current_state_fns_hdl);
+                    self.#smi.previous_state_fns_hdl = self.#smi.current_state_fns_hdl;
+                    self.#smi.current_state_fns_hdl = dest_hdl;
                 }
 
-                //println!("dispatch_hdl {}:-", hdl);
+                parent_transitioned || transition_dest_hdl.is_some()
             }
 
-            pub fn dispatch(&mut self, msg: #state_fn_msg_type) {
-                self.dispatch_hdl(msg, self.smi.current_state_fns_hdl);
+            // Dispatch msg to the current state, returns true if it
+            // transitioned to a new state
+            #hsm_vis fn dispatch(&mut self, msg: #state_fn_msg_type) -> #dispatch_result_type
+            #msg_where_clause
+            {
+                let transitioned = self.dispatch_hdl(#dispatch_msg, self.#smi.current_state_fns_hdl);
+                #dispatch_result
+            }
+
+            #take_error_fn
+
+            // Called by a state fn to call its parent's process fn, so a
+            // state can do what its parent does and then more. The parent's
+            // guard is checked first and if it returns false, or there's no
+            // parent, the msg isn't handled. The parent's result is returned
+            // without acting on it:
+            //  - A transition the parent returns only happens if the state
+            //    fn returns it, it may return its own instead.
+            //  - A msg the parent doesn't handle isn't passed on to its
+            //    parent, if the state fn returns not handled dispatch passes
+            //    it to the parent again.
+            // The parent's process fn can call handle_in_parent too, which
+            // calls the grandparent's.
+            #[allow(unused)]
+            fn handle_in_parent(&mut self, msg: #state_fn_msg_type) -> #process_result_type
+            #msg_where_clause
+            {
+                let hdl = self.#smi.processing_state_fns_hdl;
+                match self.#smi.state_fns[hdl].parent {
+                    Some(parent_hdl) => {
+                        if let Some(guard) = self.#smi.state_fns[parent_hdl].guard {
+                            if !(guard)(self, #state_fn_msg) {
+                                return #not_handled_result;
+                            }
+                        }
+
+                        self.#smi.state_fns[parent_hdl].process_cnt += 1;
+                        self.#smi.processing_state_fns_hdl = parent_hdl;
+                        let sr = (self.#smi.state_fns[parent_hdl].process)(self, msg);
+                        self.#smi.processing_state_fns_hdl = hdl;
+                        sr
+                    }
+                    None => #not_handled_result,
+                }
+            }
+
+            // Defer msg, it's dispatched again by dispatcher after the
+            // next transition
+            #[allow(unused)]
+            #hsm_vis fn defer(&mut self, msg: #owned_msg_type) {
+                let current = self.#smi.deferred.current;
+                self.#smi.deferred.queues[current].push_back(msg);
+            }
+
+            // The number of deferred messages waiting to be dispatched
+            #[allow(unused)]
+            #hsm_vis fn get_deferred_cnt(&self) -> usize {
+                self.#smi.deferred.queues.iter().map(|q| q.len()).sum()
+            }
+
+            // Dispatch msg and after each transition dispatch the deferred
+            // msgs again. Msgs deferred while they're dispatched go to the
+            // other queue, so previously deferred msgs are always dispatched
+            // before newly deferred ones. Returns true if there was a
+            // transition. An error returned by dispatch is returned
+            // leaving the msgs not yet dispatched deferred, ahead of
+            // those deferred while dispatching them.
+            #[allow(unused, unused_mut)]
+            #hsm_vis fn dispatcher(&mut self, msg: #state_fn_msg_type) -> #dispatch_result_type
+            #msg_where_clause
+            {
+                let mut transitioned = self.dispatch(msg)#dispatch_try;
+                let mut any_transitioned = transitioned;
+                while transitioned {
+                    transitioned = false;
+
+                    // Switch to the other queue and dispatch all of this one
+                    let previous = self.#smi.deferred.current;
+                    self.#smi.deferred.current = (previous + 1) % 2;
+                    while let Some(mut msg) = self.#smi.deferred.queues[previous].pop_front() {
+                        transitioned |= #dispatch_deferred;
+                    }
+                    any_transitioned |= transitioned;
+                }
+
+                #dispatcher_result
+            }
+        }
+
+        // The two queues of deferred msgs, the current one is added to
+        // while the other is dispatched
+        struct #deferred<M> {
+            queues: [std::collections::VecDeque<M>; 2],
+            current: usize,
+        }
+
+        impl<M> Default for #deferred<M> {
+            fn default() -> Self {
+                Self {
+                    queues: Default::default(),
+                    current: 0,
+                }
             }
         }
 
-        type #state_fn = fn(&mut #hsm_ident, #state_fn_msg_type) -> state_result::StateResult;
-        type #state_fn_enter = fn(&mut #hsm_ident, #state_fn_msg_type);
-        type #state_fn_exit = fn(&mut #hsm_ident, #state_fn_msg_type);
+        impl<M: Clone> Clone for #deferred<M> {
+            fn clone(&self) -> Self {
+                Self {
+                    queues: self.queues.clone(),
+                    current: self.current,
+                }
+            }
+        }
 
-        //#[derive(Debug)]
-        struct #state_info {
+        impl<M> std::fmt::Debug for #deferred<M> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!(#deferred))
+                    .field("len", &(self.queues[0].len() + self.queues[1].len()))
+                    .finish()
+            }
+        }
+
+        type #state_fn #alias_generics = fn(&mut #hsm_ident #ty_generics, #state_fn_msg_type) -> #process_result_type;
+        type #state_fn_enter #alias_generics = fn(&mut #hsm_ident #ty_generics, #state_fn_msg_type);
+        type #state_fn_exit #alias_generics = fn(&mut #hsm_ident #ty_generics, #state_fn_msg_type);
+        type #state_fn_guard #alias_generics = fn(&#hsm_ident #ty_generics, #state_fn_msg_type) -> bool;
+
+        #[derive(Clone)]
+        struct #state_info #hsm_generics #where_clause {
             name: String, // TODO: Remove or add StateMachineInfo::name?
             parent: Option<state_result::StateFnsHdl>,
-            enter: Option<#state_fn_enter>,
-            process: #state_fn,
-            exit: Option<#state_fn_exit>,
+            enter: Option<#state_fn_enter #ty_generics>,
+            process: #state_fn #ty_generics,
+            exit: Option<#state_fn_exit #ty_generics>,
+            guard: Option<#state_fn_guard #ty_generics>,
             active: bool,
+            // The number of times the enter, process and exit fns were called
+            enter_cnt: usize,
+            process_cnt: usize,
+            exit_cnt: usize,
+        }
+
+        // The fn pointers don't implement Debug as their lifetimes are
+        // higher-ranked, so they're skipped.
+        impl #impl_generics std::fmt::Debug for #state_info #ty_generics #where_clause {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!(#state_info))
+                    .field("name", &self.name)
+                    .field("parent", &self.parent)
+                    .field("active", &self.active)
+                    .field("enter_cnt", &self.enter_cnt)
+                    .field("process_cnt", &self.process_cnt)
+                    .field("exit_cnt", &self.exit_cnt)
+                    .finish_non_exhaustive()
+            }
         }
 
-        //#[derive(Debug)]
-        struct #state_machine_info {
+        #[derive(Debug)]
+        #smi_derive_clone
+        struct #state_machine_info #hsm_generics #where_clause {
             //name: String, // TODO: add StateMachineInfo::name
-            state_fns: [#state_info; #hsm_state_fns_len],
+            state_fns: [#state_info #ty_generics; #hsm_state_fns_len],
             enter_fns_hdls: Vec<state_result::StateFnsHdl>,
             exit_fns_hdls: std::collections::VecDeque<state_result::StateFnsHdl>,
             current_state_fns_hdl: state_result::StateFnsHdl,
             previous_state_fns_hdl: state_result::StateFnsHdl,
             current_state_changed: bool,
+            // The state whose process fn is being called, for handle_in_parent
+            processing_state_fns_hdl: state_result::StateFnsHdl,
+            deferred: #deferred<#owned_msg_type>,
+            #error_field
         }
 
-        impl Default for #state_machine_info {
+        #error_items
+
+        impl #impl_generics Default for #state_machine_info #ty_generics #where_clause {
             fn default() -> Self {
                 Self::new()
             }
         }
 
-        impl #state_machine_info {
+        impl #impl_generics #state_machine_info #ty_generics #where_clause {
             fn new() -> Self {
                 Self {
                     state_fns: [
                         #(
+                            #state_cfgs
                             #hsm_state_fns
                         ),*
                     ],
@@ -760,43 +1731,275 @@ pub fn hsm1(input: TokenStream) -> TokenStream {
                     current_state_fns_hdl: #initial_state_hdl,
                     previous_state_fns_hdl: #initial_state_hdl,
                     current_state_changed: true,
+                    processing_state_fns_hdl: #initial_state_hdl,
+                    deferred: Default::default(),
+                    #error_field_init
                 }
             }
         }
     );
-    //println!("hsm1: output={:#?}", output);
 
-    //println!("hsm1:-");
     output.into()
 }
 
 #[proc_macro]
 pub fn transition_to(item: TokenStream) -> TokenStream {
     let item_ts2: TokenStream2 = item.into();
-    //println!("proc_macro transition_to!: item_ts2={:?}", item_ts2);
 
-    quote!(state_result::StateResult::TransitionTo(#item_ts2)).into()
+    quote!((state_result::Handled::Yes, Some(#item_ts2))).into()
 }
 
+// handled!(state) is handled and a transition to state, like
+// transition_to!(state)
 #[proc_macro]
-pub fn handled(_item: TokenStream) -> TokenStream {
-    //println!("proc_macro handled!: item={:?}", item);
-    quote!(state_result::StateResult::Handled).into()
+pub fn handled(item: TokenStream) -> TokenStream {
+    if item.is_empty() {
+        quote!((state_result::Handled::Yes, None)).into()
+    } else {
+        transition_to(item)
+    }
 }
 
+// not_handled!(state) is a transition to state if the msg isn't handled
+// by a parent that transitions
 #[proc_macro]
-pub fn not_handled(_item: TokenStream) -> TokenStream {
-    //println!("proc_macro not_handled!: item={:?}", item);
-    quote!(state_result::StateResult::NotHandled).into()
+pub fn not_handled(item: TokenStream) -> TokenStream {
+    if item.is_empty() {
+        quote!((state_result::Handled::No, None)).into()
+    } else {
+        let item_ts2: TokenStream2 = item.into();
+        quote!((state_result::Handled::No, Some(#item_ts2))).into()
+    }
 }
 
+// StateResult!() is state_result::StateResult, it's kept so existing
+// state fns compile but they can return the type itself. A proc-macro
+// crate can only export macros so the type is used from state_result.
 #[allow(non_snake_case)]
 #[proc_macro]
 pub fn StateResult(_item: TokenStream) -> TokenStream {
-    //println!("proc_macro not_handled!: item={:?}", item);
     quote!(state_result::StateResult).into()
 }
 
+// Check a state fn has the signature `fn xxx(&mut self, msg: MsgType)`
+// and return its MsgType.
+fn state_fn_msg_type(sig: &syn::Signature) -> Result<MsgType> {
+    if sig.inputs.len() != 2 {
+        return Err(syn::Error::new(
+            sig.paren_token.span,
+            "All hsm1_state functions must have two parameters, `fn xxx(&mut self, msg: MsgType)`",
+        ));
+    }
+
+    // Verify first argument is "&mut self"
+    let self_arg = &sig.inputs[0];
+    match self_arg {
+        syn::FnArg::Receiver(rcvr)
+            if rcvr.attrs.is_empty() && rcvr.reference.is_some() && rcvr.mutability.is_some() => {}
+        _ => {
+            return Err(syn::Error::new_spanned(
+                self_arg,
+                "Expected first parameter to be `&mut self`",
+            ));
+        }
+    }
+
+    // Get msg Type in the signature
+    match &sig.inputs[1] {
+        syn::FnArg::Typed(pt) => match &*pt.ty {
+            syn::Type::Reference(tr) => Ok(MsgType::MtTypeReference { tr: tr.clone() }),
+            syn::Type::Path(tp) => Ok(MsgType::MtTypePath { tp: tp.clone() }),
+            ty => Err(syn::Error::new_spanned(
+                ty,
+                "Expected the msg parameter to be `msg: &Msg`, `msg: &mut Msg` or `msg: Msg` where Msg: Clone",
+            )),
+        },
+        last_arg @ syn::FnArg::Receiver(_) => Err(syn::Error::new_spanned(
+            last_arg,
+            "Expected `msg: MsgType` as last parameter, a `self` is not allowed",
+        )),
+    }
+}
+
+// E when a state fn returns `Result<StateResult!(), E>`
+fn state_fn_error_type(sig: &syn::Signature) -> Option<syn::Type> {
+    let ty = match &sig.output {
+        syn::ReturnType::Type(_, ty) => ty,
+        syn::ReturnType::Default => return None,
+    };
+    let segment = match &**ty {
+        syn::Type::Path(tp) => tp.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != "Result" {
+        return None;
+    }
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 2 => match &args.args[1] {
+            syn::GenericArgument::Type(error_ty) => Some(error_ty.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The type of the msg parameter, the second one
+fn msg_param_ty(sig: &syn::Signature) -> Option<&syn::Type> {
+    match sig.inputs.iter().nth(1) {
+        Some(syn::FnArg::Typed(pt)) => Some(&*pt.ty),
+        _ => None,
+    }
+}
+
+// A type as it's written, quote separates every token with a space
+fn type_string(ty: &syn::Type) -> String {
+    quote!(#ty)
+        .to_string()
+        .replace("& ", "&")
+        .replace(" <", "<")
+        .replace("< ", "<")
+        .replace(" >", ">")
+        .replace(" ,", ",")
+        .replace(" :: ", "::")
+}
+
+// Check a guard fn has the signature `fn xxx(&self, msg: MsgType) -> bool`
+fn guard_fn_check(sig: &syn::Signature) -> Result<()> {
+    let is_ref_self = matches!(
+        sig.inputs.first(),
+        Some(syn::FnArg::Receiver(rcvr)) if rcvr.reference.is_some() && rcvr.mutability.is_none()
+    );
+    let returns_bool = matches!(
+        &sig.output,
+        syn::ReturnType::Type(_, ty) if matches!(&**ty, syn::Type::Path(tp) if tp.path.is_ident("bool"))
+    );
+    if sig.inputs.len() != 2 || !is_ref_self || !returns_bool {
+        let ident = &sig.ident;
+        return Err(syn::Error::new_spanned(
+            sig,
+            format!("Guard fns must have the signature `fn {ident}(&self, msg: MsgType) -> bool`"),
+        ));
+    }
+    Ok(())
+}
+
+// True if attr is a derive that includes Clone
+fn derives_clone(attr: &syn::Attribute) -> bool {
+    match attr.parse_meta() {
+        Ok(syn::Meta::List(list)) if list.path.is_ident("derive") => {
+            list.nested.iter().any(|nested| match nested {
+                syn::NestedMeta::Meta(meta) => meta
+                    .path()
+                    .segments
+                    .last()
+                    .is_some_and(|segment| segment.ident == "Clone"),
+                syn::NestedMeta::Lit(_) => false,
+            })
+        }
+        _ => false,
+    }
+}
+
+// The generics with the bounds and where clause removed, for type aliases
+fn without_bounds(generics: &syn::Generics) -> syn::Generics {
+    let mut generics = generics.clone();
+    for param in generics.params.iter_mut() {
+        match param {
+            syn::GenericParam::Type(tp) => {
+                tp.colon_token = None;
+                tp.bounds.clear();
+                tp.eq_token = None;
+                tp.default = None;
+            }
+            syn::GenericParam::Lifetime(lp) => {
+                lp.colon_token = None;
+                lp.bounds.clear();
+            }
+            syn::GenericParam::Const(cp) => {
+                cp.eq_token = None;
+                cp.default = None;
+            }
+        }
+    }
+    generics.where_clause = None;
+    generics
+}
+
+// The ident of the first transition_to!, or handled! or not_handled!
+// with a state, in tokens including in its groups
+fn nested_transition_to(tokens: &TokenStream2) -> Option<proc_macro2::Ident> {
+    use proc_macro2::TokenTree;
+
+    let tts: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    for (i, tt) in tts.iter().enumerate() {
+        match tt {
+            TokenTree::Ident(ident) => {
+                let bang =
+                    matches!(tts.get(i + 1), Some(TokenTree::Punct(p)) if p.as_char() == '!');
+                let has_args =
+                    matches!(tts.get(i + 2), Some(TokenTree::Group(g)) if !g.stream().is_empty());
+                if bang
+                    && (ident == "transition_to"
+                        || ((ident == "handled" || ident == "not_handled") && has_args))
+                {
+                    return Some(ident.clone());
+                }
+            }
+            TokenTree::Group(group) => {
+                if let Some(ident) = nested_transition_to(&group.stream()) {
+                    return Some(ident);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// An expr, a comma and the remaining tokens, the comma is kept with them
+fn leading_expr(input: ParseStream) -> Result<(syn::Expr, TokenStream2)> {
+    let expr = input.parse::<syn::Expr>()?;
+    if !input.peek(syn::Token![,]) {
+        return Err(input.error("expected `,`"));
+    }
+    Ok((expr, input.parse::<TokenStream2>()?))
+}
+
+// Combine err with the errors already found so they're all reported
+fn push_error(errors: &mut Option<syn::Error>, err: syn::Error) {
+    match errors {
+        Some(errors) => errors.combine(err),
+        None => *errors = Some(err),
+    }
+}
+
+// A snake case fn name as an UpperCamelCase variant name, do_work is DoWork
+fn upper_camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+// my_fsm and __hsm1 become MyFsm and __Hsm1, leading underscores are kept
+fn camel_case(s: &str) -> String {
+    let trimmed = s.trim_start_matches('_');
+    let mut camel = s[..s.len() - trimmed.len()].to_owned();
+    for word in trimmed.split('_').filter(|w| !w.is_empty()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
 fn new_ident(ident: syn::Ident, suffix: &str) -> syn::Ident {
     syn::Ident::new(
         (ident.to_string() + suffix.to_owned().as_str()).as_str(),
@@ -806,7 +2009,102 @@ fn new_ident(ident: syn::Ident, suffix: &str) -> syn::Ident {
 
 struct Visitor {
     hsm_ident: syn::Ident,
-    hsm_state_fn_ident_map: HashMap<String, usize>,
+    // The handle a transition to a state is to, or the error if it's a
+    // composite without an initial substate
+    hsm_state_hdl_map: HashMap<String, std::result::Result<TokenStream2, String>>,
+    hsm_fn_names: Vec<String>,
+    hsm_state_enum: syn::Ident,
+    hsm_state_variant_map: HashMap<String, std::result::Result<TokenStream2, String>>,
+    // The target of transition_to!(self) in the state fn being visited,
+    // None when it's not a state fn
+    state_fn_target: Option<std::result::Result<TokenStream2, String>>,
+    errors: Option<syn::Error>,
+}
+
+impl Visitor {
+    // The handle of a transition_to! target that's a state enum variant,
+    // `transition_to!(MyFsmState::Done)`.
+    fn state_variant_hdl(&self, tokens: &TokenStream2) -> Result<TokenStream2> {
+        let state_enum = &self.hsm_state_enum;
+        let path = syn::parse2::<syn::Path>(tokens.clone()).map_err(|_| {
+            syn::Error::new_spanned(
+                tokens,
+                format!("transition_to! may have only one parameter, the name of the state or a {state_enum} variant"),
+            )
+        })?;
+        if path.segments.len() != 2 || path.segments[0].ident != *state_enum {
+            return Err(syn::Error::new_spanned(
+                &path,
+                format!("Expected the name of a state or a {state_enum} variant"),
+            ));
+        }
+        let variant = &path.segments[1].ident;
+        match self.hsm_state_variant_map.get(&variant.to_string()) {
+            Some(Ok(hdl)) => Ok(hdl.clone()),
+            Some(Err(msg)) => Err(syn::Error::new_spanned(&path, msg)),
+            None => Err(syn::Error::new_spanned(
+                variant,
+                format!("No variant {variant} in {state_enum}"),
+            )),
+        }
+    }
+
+    // The error for a transition_to!, or handled! or not_handled!, in
+    // the tokens of a macro that can't be parsed as exprs.
+    fn push_nested_error(&mut self, node: &Macro, nested: &proc_macro2::Ident) {
+        let mac = node
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident.to_string())
+            .unwrap_or_default();
+        push_error(
+            &mut self.errors,
+            syn::Error::new(
+                nested.span(),
+                format!("{nested}! can't be used inside {mac}!, bind it to a variable first"),
+            ),
+        );
+    }
+
+    // The error for a transition_to! target that isn't a state, it
+    // suggests the closest state name if there's one near enough.
+    fn unknown_target_msg(&self, target: &str) -> String {
+        let hsm_ident = &self.hsm_ident;
+        if self.hsm_fn_names.iter().any(|name| name == target) {
+            return format!(
+                "{target} is a fn in {hsm_ident} but not a state, only #[hsm1_state] and #[hsm1_initial_state] fns can be transitioned to"
+            );
+        }
+
+        let closest = self
+            .hsm_state_hdl_map
+            .keys()
+            .map(|name| (edit_distance(target, name), name))
+            .min();
+        match closest {
+            Some((distance, name)) if distance <= (target.len() / 3).max(1) => {
+                format!("No state named {target} in {hsm_ident}, did you mean `{name}`?")
+            }
+            _ => format!("No state named {target} in {hsm_ident}"),
+        }
+    }
+}
+
+// The Levenshtein distance, the number of single char inserts, deletes
+// and substitutions to change a into b.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = substitute.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
 }
 
 impl VisitMut for Visitor {
@@ -815,35 +2113,92 @@ impl VisitMut for Visitor {
     // transtion_to!(state_fn_name) to transition_to!(state_fn_index).
     fn visit_macro_mut(&mut self, node: &mut Macro) {
         if let Some(ident_segment) = node.path.segments.last() {
-            // The last segment is the name of the macro
-            if ident_segment.ident == "transition_to" {
-                // Found our macro, transition_to
+            // The last segment is the name of the macro, handled! and
+            // not_handled! with a state are transitions too
+            let name = ident_segment.ident.to_string();
+            if name == "transition_to"
+                || ((name == "handled" || name == "not_handled") && !node.tokens.is_empty())
+            {
+                // Found one of our macros
 
                 // Get the first token; aka: parameter to the function
                 let mut iter = node.tokens.clone().into_iter();
                 if let Some(token) = iter.next() {
                     if iter.next().is_some() {
-                        // TODO: improve error handling
-                        panic!("transition_to! may have only one parameter, the name of the state")
+                        match self.state_variant_hdl(&node.tokens) {
+                            Ok(hdl) => node.tokens = quote!(#hdl),
+                            Err(err) => push_error(&mut self.errors, err),
+                        }
+                        return;
                     }
                     let parameter = token.to_string();
-                    if let Some(hdl) = self.hsm_state_fn_ident_map.get(&parameter) {
-                        //println!("Visitor::visit_macro_mut: Found {} in {} with index {}", parameter, self.hsm_ident, hdl);
-                        node.tokens = quote!(#hdl);
-                        return;
+                    let target = if parameter == "self" {
+                        match &self.state_fn_target {
+                            Some(target) => Some(target),
+                            None => {
+                                push_error(
+                                    &mut self.errors,
+                                    syn::Error::new(
+                                        token.span(),
+                                        format!("{name}!(self) may only be used in a state fn"),
+                                    ),
+                                );
+                                return;
+                            }
+                        }
+                    } else {
+                        self.hsm_state_hdl_map.get(&parameter)
+                    };
+                    if let Some(target) = target {
+                        match target {
+                            Ok(hdl) => node.tokens = quote!(#hdl),
+                            Err(msg) => {
+                                let err = syn::Error::new(token.span(), msg);
+                                push_error(&mut self.errors, err);
+                            }
+                        }
                     } else {
-                        panic!("No state named {} in {}", parameter, self.hsm_ident);
+                        let msg = self.unknown_target_msg(&parameter);
+                        push_error(&mut self.errors, syn::Error::new(token.span(), msg));
                     }
                 } else {
-                    // TODO: improve error handling
-                    panic!("transition_to! must have one parameter, the name of the state")
+                    push_error(
+                        &mut self.errors,
+                        syn::Error::new_spanned(
+                            &*node,
+                            "transition_to! must have one parameter, the name of the state",
+                        ),
+                    );
                 }
+                return;
             }
         }
 
+        // The tokens of other macros aren't visited, so if they have a
+        // transition_to!, or handled! or not_handled!, they're parsed as the comma separated exprs of
+        // assert_eq!, vec! and the like, or an expr followed by anything
+        // as in matches!, and the exprs are visited
+        if let Some(nested) = nested_transition_to(&node.tokens) {
+            let parser = Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated;
+            if let Ok(mut exprs) = parser.parse2(node.tokens.clone()) {
+                for expr in exprs.iter_mut() {
+                    self.visit_expr_mut(expr);
+                }
+                node.tokens = quote!(#exprs);
+            } else if let Ok((mut expr, rest)) = leading_expr.parse2(node.tokens.clone()) {
+                if let Some(nested) = nested_transition_to(&rest) {
+                    self.push_nested_error(node, &nested);
+                } else {
+                    self.visit_expr_mut(&mut expr);
+                    node.tokens = quote!(#expr #rest);
+                }
+            } else {
+                self.push_nested_error(node, &nested);
+            }
+            return;
+        }
+
         // Delegate to the default impl to visit any nested macros.
         visit_mut::visit_macro_mut(self, node);
-
-        //println!("Visitor::visit_macro_mut:- hsm_ident={} node={:?}",hsm_ident, node);
     }
 }
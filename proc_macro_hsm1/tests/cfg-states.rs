@@ -0,0 +1,101 @@
+use proc_macro_hsm1::{
+    handled, hsm1, hsm1_initial_state, hsm1_state, not_handled, transition_to, StateResult,
+};
+
+enum Msg {
+    Debug,
+    Work,
+    Done,
+}
+
+hsm1!(
+    // tracing is first so the handles of all the states after it change
+    // with the debug-states feature
+    struct Worker {
+        traced_cnt: usize,
+        work_cnt: usize,
+    }
+
+    #[cfg(feature = "debug-states")]
+    #[hsm1_state(base)]
+    fn tracing(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Debug => {
+                self.traced_cnt += 1;
+                transition_to!(working)
+            }
+            _ => not_handled!(),
+        }
+    }
+
+    #[hsm1_state]
+    fn base(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Done => transition_to!(done),
+            _ => handled!(),
+        }
+    }
+
+    #[hsm1_initial_state(base)]
+    fn working(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Work => {
+                self.work_cnt += 1;
+                handled!()
+            }
+            #[cfg(feature = "debug-states")]
+            Msg::Debug => transition_to!(tracing),
+            _ => not_handled!(),
+        }
+    }
+
+    #[hsm1_state]
+    fn done(&mut self, _msg: &Msg) -> StateResult!() {
+        handled!()
+    }
+);
+
+#[test]
+fn test_cfg_states() {
+    let mut worker = Worker::new();
+    assert_eq!(worker.current_state(), WorkerState::Working);
+    assert_eq!(Worker::state_name(Worker::STATE_WORKING), Some("working"));
+    assert_eq!(Worker::state_handle("done"), Some(Worker::STATE_DONE));
+    assert_eq!(usize::from(WorkerState::Done), Worker::STATE_DONE);
+
+    worker.dispatch(&Msg::Work);
+    assert_eq!(worker.work_cnt, 1);
+
+    worker.dispatch(&Msg::Done);
+    assert_eq!(worker.current_state(), WorkerState::Done);
+    assert_eq!(worker.current_state_name(), "done");
+}
+
+#[cfg(feature = "debug-states")]
+#[test]
+fn test_cfg_states_enabled() {
+    assert_eq!(Worker::STATE_TRACING, 0);
+    assert_eq!(Worker::STATE_WORKING, 2);
+    assert_eq!(Worker::state_handle("tracing"), Some(0));
+
+    let mut worker = Worker::new();
+    assert!(worker.dispatch(&Msg::Debug));
+    assert_eq!(worker.current_state(), WorkerState::Tracing);
+    assert!(worker.dispatch(&Msg::Debug));
+    assert_eq!(worker.traced_cnt, 1);
+    assert_eq!(worker.current_state(), WorkerState::Working);
+}
+
+#[cfg(not(feature = "debug-states"))]
+#[test]
+fn test_cfg_states_disabled() {
+    assert_eq!(Worker::STATE_BASE, 0);
+    assert_eq!(Worker::STATE_WORKING, 1);
+    assert_eq!(Worker::state_handle("tracing"), None);
+    assert_eq!(Worker::state_name(3), None);
+
+    // Debug isn't handled by any state
+    let mut worker = Worker::new();
+    assert!(!worker.dispatch(&Msg::Debug));
+    assert_eq!(worker.current_state(), WorkerState::Working);
+}
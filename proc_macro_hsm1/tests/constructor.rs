@@ -0,0 +1,81 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, transition_to, StateResult};
+
+#[derive(Debug, PartialEq)]
+enum Msg {
+    Ping(usize),
+    Pong(usize),
+}
+
+hsm1!(
+    // Sender isn't Default, so new is defined here and the fields are
+    // passed to with_fields
+    struct Pinger {
+        tx: Sender<Msg>,
+        pings: usize,
+    }
+
+    fn new(tx: Sender<Msg>) -> Self {
+        Self::with_fields(tx, 0)
+    }
+
+    fn pinging_enter(&mut self, _msg: &Msg) {
+        self.tx.send(Msg::Ping(self.pings)).unwrap();
+    }
+
+    #[hsm1_initial_state]
+    fn pinging(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Pong(n) if *n == self.pings => {
+                self.pings += 1;
+                transition_to!(pinging)
+            }
+            _ => handled!(),
+        }
+    }
+);
+
+#[test]
+fn test_user_new_with_a_sender_field() {
+    let (tx, rx): (Sender<Msg>, Receiver<Msg>) = channel();
+    let mut pinger = Pinger::new(tx);
+    assert_eq!(pinger.pings, 0);
+
+    // The enter fn runs at the start of the next dispatch
+    assert!(rx.try_recv().is_err());
+    assert!(pinger.dispatch(&Msg::Pong(0)));
+    assert_eq!(pinger.pings, 1);
+    assert_eq!(rx.try_recv(), Ok(Msg::Ping(0)));
+
+    // A stale pong is ignored, after pinging is entered again
+    assert!(!pinger.dispatch(&Msg::Pong(0)));
+    assert_eq!(rx.try_recv(), Ok(Msg::Ping(1)));
+    assert!(!pinger.dispatch(&Msg::Pong(0)));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_with_fields_and_new() {
+    hsm1!(
+        struct Counter {
+            cnt: usize,
+            step: usize,
+        }
+
+        #[hsm1_initial_state]
+        fn counting(&mut self, _msg: &()) -> StateResult!() {
+            self.cnt += self.step;
+            handled!()
+        }
+    );
+
+    let mut counter = Counter::with_fields(10, 2);
+    counter.dispatch(&());
+    assert_eq!(counter.cnt, 12);
+
+    let mut counter = Counter::new();
+    counter.dispatch(&());
+    assert_eq!(counter.cnt, 0);
+    assert_eq!(counter.step, 0);
+}
@@ -0,0 +1,113 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Msg {
+    Start,
+    Stop,
+    Work(usize),
+}
+
+hsm1!(
+    #[derive(Clone)]
+    struct Worker {
+        done: Vec<usize>,
+    }
+
+    // Work is deferred until started
+    #[hsm1_initial_state]
+    fn idle(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Start => transition_to!(working),
+            Msg::Stop => handled!(),
+            Msg::Work(_) => {
+                self.defer(msg.clone());
+                handled!()
+            }
+        }
+    }
+
+    // Work 0 stops, deferring the work after it until started again
+    #[hsm1_state]
+    fn working(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Start => handled!(),
+            Msg::Stop => transition_to!(idle),
+            Msg::Work(0) => {
+                self.done.push(0);
+                transition_to!(idle)
+            }
+            Msg::Work(n) => {
+                self.done.push(*n);
+                handled!()
+            }
+        }
+    }
+);
+
+#[test]
+fn test_deferred_msgs_are_dispatched_in_order_after_a_transition() {
+    let mut worker = Worker::new();
+
+    assert!(!worker.dispatcher(&Msg::Work(1)));
+    assert!(!worker.dispatcher(&Msg::Work(2)));
+    assert!(!worker.dispatcher(&Msg::Work(3)));
+    assert_eq!(worker.get_deferred_cnt(), 3);
+    assert!(worker.done.is_empty());
+
+    assert!(worker.dispatcher(&Msg::Start));
+    assert_eq!(worker.get_deferred_cnt(), 0);
+    assert_eq!(worker.done, vec![1, 2, 3]);
+    assert_eq!(worker.current_state(), WorkerState::Working);
+}
+
+#[test]
+fn test_msgs_deferred_while_dispatching_deferred_msgs_keep_their_order() {
+    let mut worker = Worker::new();
+
+    // 0 stops working so 4 and 5 are deferred again, behind 6 which is
+    // deferred before Start is dispatched
+    worker.dispatcher(&Msg::Work(3));
+    worker.dispatcher(&Msg::Work(0));
+    worker.dispatcher(&Msg::Work(4));
+    worker.dispatcher(&Msg::Work(5));
+    assert!(worker.dispatcher(&Msg::Start));
+    assert_eq!(worker.done, vec![3, 0]);
+    assert_eq!(worker.current_state(), WorkerState::Idle);
+    assert_eq!(worker.get_deferred_cnt(), 2);
+
+    worker.dispatcher(&Msg::Work(6));
+    assert!(worker.dispatcher(&Msg::Start));
+    assert_eq!(worker.done, vec![3, 0, 4, 5, 6]);
+    assert_eq!(worker.get_deferred_cnt(), 0);
+}
+
+#[test]
+fn test_dispatch_does_not_dispatch_deferred_msgs() {
+    let mut worker = Worker::new();
+
+    worker.dispatch(&Msg::Work(1));
+    assert!(worker.dispatch(&Msg::Start));
+    assert!(worker.done.is_empty());
+    assert_eq!(worker.get_deferred_cnt(), 1);
+
+    // The next dispatcher transition dispatches it
+    assert!(worker.dispatcher(&Msg::Stop));
+    assert!(worker.dispatcher(&Msg::Start));
+    assert_eq!(worker.done, vec![1]);
+}
+
+#[test]
+fn test_clone_keeps_the_deferred_msgs() {
+    let mut worker = Worker::new();
+    worker.dispatcher(&Msg::Work(1));
+    worker.dispatcher(&Msg::Work(2));
+
+    let mut clone = worker.clone();
+    assert_eq!(clone.get_deferred_cnt(), 2);
+    assert!(clone.dispatcher(&Msg::Start));
+    assert_eq!(clone.done, vec![1, 2]);
+
+    // The original still has its own
+    assert_eq!(worker.get_deferred_cnt(), 2);
+    assert!(worker.done.is_empty());
+}
@@ -0,0 +1,31 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, StateResult};
+
+// Cloning a machine clones its deferred msgs so they must be Clone
+#[derive(Clone)]
+struct NoMessages;
+
+hsm1!(
+    #[derive(Debug, Clone)]
+    struct MyFsm {
+        initial_counter: u64,
+    }
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        self.initial_counter += 1;
+        handled!()
+    }
+);
+
+#[test]
+fn test_derive_debug_and_clone() {
+    let mut fsm = MyFsm::new();
+    fsm.dispatch(&NoMessages);
+
+    let clone = fsm.clone();
+    assert_eq!(clone.initial_counter, 1);
+
+    let debug = format!("{fsm:?}");
+    assert!(debug.contains("initial_counter: 1"));
+    assert!(debug.contains("name: \"initial\""));
+}
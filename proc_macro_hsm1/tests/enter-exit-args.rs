@@ -0,0 +1,70 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+
+struct NoMessages;
+
+#[test]
+fn test_explicit_enter_exit_and_parent() {
+    hsm1!(
+        struct Test {
+            base_cnt: usize,
+            on_open_cnt: usize,
+            on_close_cnt: usize,
+        }
+
+        #[hsm1_state]
+        fn base(&mut self, _msg: &NoMessages) -> StateResult!() {
+            self.base_cnt += 1;
+            handled!()
+        }
+
+        fn on_open(&mut self, _msg: &NoMessages) {
+            self.on_open_cnt += 1;
+        }
+
+        fn on_close(&mut self, _msg: &NoMessages) {
+            self.on_close_cnt += 1;
+        }
+
+        #[hsm1_initial_state(parent = base, enter = on_open, exit = on_close)]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+            transition_to!(initial)
+        }
+    );
+
+    let mut fsm = Test::new();
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.on_open_cnt, 1);
+    assert_eq!(fsm.on_close_cnt, 1);
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.on_open_cnt, 2);
+    assert_eq!(fsm.on_close_cnt, 2);
+    assert_eq!(fsm.base_cnt, 0);
+}
+
+#[test]
+fn test_suffix_enter_exit() {
+    hsm1!(
+        struct Test {
+            initial_enter_cnt: usize,
+            initial_exit_cnt: usize,
+        }
+
+        fn initial_enter(&mut self, _msg: &NoMessages) {
+            self.initial_enter_cnt += 1;
+        }
+
+        fn initial_exit(&mut self, _msg: &NoMessages) {
+            self.initial_exit_cnt += 1;
+        }
+
+        #[hsm1_initial_state]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+            transition_to!(initial)
+        }
+    );
+
+    let mut fsm = Test::new();
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.initial_enter_cnt, 1);
+    assert_eq!(fsm.initial_exit_cnt, 1);
+}
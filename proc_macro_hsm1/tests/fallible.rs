@@ -0,0 +1,158 @@
+use std::io;
+
+use proc_macro_hsm1::{
+    handled, hsm1, hsm1_error_state, hsm1_initial_state, hsm1_state, transition_to, StateResult,
+};
+
+enum Msg {
+    Read(Result<usize, io::ErrorKind>),
+    Close,
+    Reset,
+}
+
+hsm1!(
+    struct Reader {
+        read_cnt: usize,
+        failed_enter_cnt: usize,
+    }
+
+    #[hsm1_initial_state]
+    fn reading(&mut self, msg: &Msg) -> Result<StateResult!(), io::Error> {
+        match msg {
+            Msg::Read(Ok(n)) => {
+                self.read_cnt += n;
+                Ok(handled!())
+            }
+            Msg::Read(Err(kind)) => Err(io::Error::from(*kind)),
+            Msg::Close => Ok(transition_to!(closed)),
+            Msg::Reset => Ok(handled!()),
+        }
+    }
+
+    // Not fallible, it's wrapped in Ok
+    #[hsm1_state]
+    fn closed(&mut self, _msg: &Msg) -> StateResult!() {
+        handled!()
+    }
+
+    fn failed_enter(&mut self, _msg: &Msg) {
+        self.failed_enter_cnt += 1;
+    }
+
+    #[hsm1_error_state]
+    fn failed(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Reset => transition_to!(reading),
+            _ => handled!(),
+        }
+    }
+);
+
+#[test]
+fn test_error_transitions_to_the_error_state() {
+    let mut reader = Reader::new();
+    assert!(!reader.dispatch(&Msg::Read(Ok(2))));
+    assert_eq!(reader.read_cnt, 2);
+    assert!(reader.take_error().is_none());
+
+    assert!(reader.dispatch(&Msg::Read(Err(io::ErrorKind::UnexpectedEof))));
+    assert_eq!(reader.current_state(), ReaderState::Failed);
+    assert_eq!(
+        reader.take_error().map(|err| err.kind()),
+        Some(io::ErrorKind::UnexpectedEof)
+    );
+    assert!(reader.take_error().is_none());
+
+    // The error state's enter fn runs at the next dispatch
+    assert!(reader.dispatch(&Msg::Reset));
+    assert_eq!(reader.failed_enter_cnt, 1);
+    assert_eq!(reader.current_state(), ReaderState::Reading);
+    assert!(reader.dispatch(&Msg::Close));
+    assert_eq!(reader.current_state(), ReaderState::Closed);
+}
+
+#[test]
+fn test_error_returned_by_dispatch_without_an_error_state() {
+    hsm1!(
+        struct Writer {
+            written: usize,
+        }
+
+        #[hsm1_initial_state]
+        fn writing(&mut self, msg: &usize) -> Result<StateResult!(), io::Error> {
+            if *msg == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            self.written += msg;
+            Ok(handled!())
+        }
+    );
+
+    let mut writer = Writer::new();
+    assert!(!writer.dispatch(&3).unwrap());
+    assert_eq!(writer.written, 3);
+
+    let err = writer.dispatch(&0).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    assert_eq!(writer.current_state(), WriterState::Writing);
+
+    assert!(!writer.dispatch(&1).unwrap());
+    assert_eq!(writer.written, 4);
+
+    // dispatcher returns it too
+    assert!(writer.dispatcher(&0).is_err());
+}
+
+#[test]
+fn test_deferred_msgs_keep_their_order_after_an_error() {
+    hsm1!(
+        struct Sink {
+            written: Vec<usize>,
+            deferred: Vec<usize>,
+        }
+
+        // Msgs are deferred until 100 starts writing
+        #[hsm1_initial_state]
+        fn waiting(&mut self, msg: &usize) -> Result<StateResult!(), io::Error> {
+            if *msg == 100 {
+                return Ok(transition_to!(writing));
+            }
+            self.deferred.push(*msg);
+            self.defer(*msg);
+            Ok(handled!())
+        }
+
+        // 0 fails, 7 is deferred again and 200 waits again
+        #[hsm1_state]
+        fn writing(&mut self, msg: &usize) -> Result<StateResult!(), io::Error> {
+            match *msg {
+                0 => Err(io::Error::from(io::ErrorKind::WriteZero)),
+                7 => {
+                    self.defer(7);
+                    Ok(handled!())
+                }
+                200 => Ok(transition_to!(waiting)),
+                n => {
+                    self.written.push(n);
+                    Ok(handled!())
+                }
+            }
+        }
+    );
+
+    let mut sink = Sink::new();
+    for msg in [1, 7, 0, 2] {
+        sink.dispatcher(&msg).unwrap();
+    }
+    assert_eq!(sink.get_deferred_cnt(), 4);
+
+    // 2 wasn't dispatched when 0 failed, it stays ahead of 7 which was
+    // deferred again before it
+    assert!(sink.dispatcher(&100).is_err());
+    assert_eq!(sink.written, vec![1]);
+    assert_eq!(sink.get_deferred_cnt(), 2);
+
+    sink.deferred.clear();
+    assert!(sink.dispatcher(&200).unwrap());
+    assert_eq!(sink.deferred, vec![2, 7]);
+}
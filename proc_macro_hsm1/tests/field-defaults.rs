@@ -0,0 +1,58 @@
+use std::time::Instant;
+
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+
+const MAX_ATTEMPTS: u32 = 3;
+
+enum Msg {
+    Fail,
+    Succeed,
+}
+
+hsm1!(
+    // started is an Instant which isn't Default
+    struct Retry {
+        #[hsm1_default(MAX_ATTEMPTS)]
+        max_attempts: u32,
+        attempts: u32,
+        #[hsm1_default(Instant::now())]
+        started: Instant,
+    }
+
+    #[hsm1_initial_state]
+    fn trying(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Fail => {
+                self.attempts += 1;
+                if self.attempts >= self.max_attempts {
+                    transition_to!(gave_up)
+                } else {
+                    handled!()
+                }
+            }
+            Msg::Succeed => handled!(),
+        }
+    }
+
+    #[hsm1_state]
+    fn gave_up(&mut self, _msg: &Msg) -> StateResult!() {
+        handled!()
+    }
+);
+
+#[test]
+fn test_field_defaults() {
+    let before = Instant::now();
+    let mut retry = Retry::new();
+    assert_eq!(retry.max_attempts, MAX_ATTEMPTS);
+    assert_eq!(retry.attempts, 0);
+    assert!(retry.started >= before);
+
+    retry.dispatch(&Msg::Succeed);
+    retry.dispatch(&Msg::Fail);
+    retry.dispatch(&Msg::Fail);
+    assert_eq!(retry.current_state(), RetryState::Trying);
+    assert!(retry.dispatch(&Msg::Fail));
+    assert_eq!(retry.current_state(), RetryState::GaveUp);
+    assert_eq!(retry.attempts, MAX_ATTEMPTS);
+}
@@ -0,0 +1,48 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, StateResult};
+
+struct NoMessages;
+
+hsm1!(
+    struct Tracker<T: Default> {
+        items: Vec<T>,
+    }
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        self.items.push(T::default());
+        handled!()
+    }
+);
+
+hsm1!(
+    struct Borrower<'a> {
+        name: &'a str,
+        lens: Vec<usize>,
+    }
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        self.lens.push(self.name.len());
+        handled!()
+    }
+);
+
+#[test]
+fn test_generic_type_parameter() {
+    let mut numbers = Tracker::<u32>::new();
+    numbers.dispatch(&NoMessages);
+    numbers.dispatch(&NoMessages);
+    assert_eq!(numbers.items, vec![0, 0]);
+
+    let mut strings = Tracker::<String>::new();
+    strings.dispatch(&NoMessages);
+    assert_eq!(strings.items, vec![String::new()]);
+}
+
+#[test]
+fn test_lifetime_parameter() {
+    let mut borrower = Borrower::new();
+    borrower.name = "hello";
+    borrower.dispatch(&NoMessages);
+    assert_eq!(borrower.lens, vec![5]);
+}
@@ -0,0 +1,48 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, StateResult};
+
+struct Msg {
+    value: u32,
+}
+
+#[test]
+fn test_guarded_child_falls_through_to_parent() {
+    hsm1!(
+        struct Test {
+            limit: u32,
+            base_cnt: usize,
+            child_cnt: usize,
+        }
+
+        #[hsm1_state]
+        fn base(&mut self, _msg: &Msg) -> StateResult!() {
+            self.base_cnt += 1;
+            handled!()
+        }
+
+        fn can_accept(&self, msg: &Msg) -> bool {
+            msg.value <= self.limit
+        }
+
+        #[hsm1_initial_state(base, guard = can_accept)]
+        fn child(&mut self, _msg: &Msg) -> StateResult!() {
+            self.child_cnt += 1;
+            handled!()
+        }
+    );
+
+    let mut fsm = Test::new();
+    fsm.limit = 10;
+
+    fsm.dispatch(&Msg { value: 5 });
+    assert_eq!(fsm.child_cnt, 1);
+    assert_eq!(fsm.base_cnt, 0);
+
+    // The guard returns false so child isn't called and base handles it
+    fsm.dispatch(&Msg { value: 11 });
+    assert_eq!(fsm.child_cnt, 1);
+    assert_eq!(fsm.base_cnt, 1);
+
+    fsm.dispatch(&Msg { value: 10 });
+    assert_eq!(fsm.child_cnt, 2);
+    assert_eq!(fsm.base_cnt, 1);
+}
@@ -0,0 +1,79 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, not_handled, transition_to};
+
+use state_result::StateResult;
+
+enum Msg {
+    Fail,
+    Retry,
+    Skip,
+}
+
+hsm1!(
+    struct Job {
+        parent_cnt: usize,
+    }
+
+    // Declines everything but Retry
+    #[hsm1_state]
+    fn parent(&mut self, msg: &Msg) -> StateResult {
+        self.parent_cnt += 1;
+        match msg {
+            Msg::Retry => transition_to!(retrying),
+            _ => not_handled!(),
+        }
+    }
+
+    // Falls back unless parent transitions
+    #[hsm1_initial_state(parent)]
+    fn working(&mut self, msg: &Msg) -> StateResult {
+        match msg {
+            Msg::Fail | Msg::Retry => not_handled!(fallback),
+            Msg::Skip => handled!(done),
+        }
+    }
+
+    #[hsm1_state]
+    fn fallback(&mut self, msg: &Msg) -> StateResult {
+        match msg {
+            Msg::Retry => handled!(working),
+            _ => handled!(),
+        }
+    }
+
+    #[hsm1_state]
+    fn retrying(&mut self, _msg: &Msg) -> StateResult {
+        handled!()
+    }
+
+    #[hsm1_state]
+    fn done(&mut self, _msg: &Msg) -> StateResult {
+        handled!()
+    }
+);
+
+#[test]
+fn test_not_handled_transition_when_the_parent_declines() {
+    let mut job = Job::new();
+    assert!(job.dispatch(&Msg::Fail));
+    assert_eq!(job.parent_cnt, 1);
+    assert_eq!(job.current_state(), JobState::Fallback);
+
+    assert!(job.dispatch(&Msg::Retry));
+    assert_eq!(job.current_state(), JobState::Working);
+}
+
+#[test]
+fn test_not_handled_transition_when_the_parent_transitions() {
+    let mut job = Job::new();
+    assert!(job.dispatch(&Msg::Retry));
+    assert_eq!(job.parent_cnt, 1);
+    assert_eq!(job.current_state(), JobState::Retrying);
+}
+
+#[test]
+fn test_handled_transition() {
+    let mut job = Job::new();
+    assert!(job.dispatch(&Msg::Skip));
+    assert_eq!(job.parent_cnt, 0);
+    assert_eq!(job.current_state(), JobState::Done);
+}
@@ -0,0 +1,110 @@
+use proc_macro_hsm1::{
+    handled, hsm1, hsm1_initial_state, hsm1_state, not_handled, transition_to, StateResult,
+};
+
+enum Msg {
+    On,
+    Up,
+    Reset,
+}
+
+hsm1!(
+    // root has the initial substate off, on has low
+    struct Lamp {
+        low_enter_cnt: usize,
+    }
+
+    #[hsm1_state]
+    fn root(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Reset => transition_to!(root),
+            _ => handled!(),
+        }
+    }
+
+    #[hsm1_initial_state(root, initial)]
+    fn off(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::On => transition_to!(on),
+            _ => not_handled!(),
+        }
+    }
+
+    #[hsm1_state(root)]
+    fn on(&mut self, _msg: &Msg) -> StateResult!() {
+        not_handled!()
+    }
+
+    fn low_enter(&mut self, _msg: &Msg) {
+        self.low_enter_cnt += 1;
+    }
+
+    #[hsm1_state(parent = on, initial)]
+    fn low(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Up => transition_to!(high),
+            _ => not_handled!(),
+        }
+    }
+
+    #[hsm1_state(on)]
+    fn high(&mut self, _msg: &Msg) -> StateResult!() {
+        not_handled!()
+    }
+);
+
+#[test]
+fn test_transition_to_a_composite_enters_its_initial_substate() {
+    let mut lamp = Lamp::new();
+    assert_eq!(lamp.current_state(), LampState::Off);
+
+    assert!(lamp.dispatch(&Msg::On));
+    assert_eq!(lamp.current_state(), LampState::Low);
+
+    assert!(lamp.dispatch(&Msg::Up));
+    assert_eq!(lamp.current_state(), LampState::High);
+    assert_eq!(lamp.low_enter_cnt, 1);
+
+    // root's initial substate is off, the leaf
+    assert!(lamp.dispatch(&Msg::Reset));
+    assert_eq!(lamp.current_state(), LampState::Off);
+
+    assert!(lamp.dispatch(&Msg::On));
+    assert_eq!(lamp.current_state(), LampState::Low);
+    lamp.dispatch(&Msg::Up);
+    assert_eq!(lamp.low_enter_cnt, 2);
+}
+
+#[test]
+fn test_a_composite_initial_state_starts_in_its_initial_substate() {
+    hsm1!(
+        struct Fan {
+            slow_cnt: usize,
+        }
+
+        #[hsm1_initial_state]
+        fn running(&mut self, _msg: &Msg) -> StateResult!() {
+            handled!()
+        }
+
+        #[hsm1_state(running, initial)]
+        fn slow(&mut self, msg: &Msg) -> StateResult!() {
+            self.slow_cnt += 1;
+            match msg {
+                Msg::Up => transition_to!(fast),
+                _ => not_handled!(),
+            }
+        }
+
+        #[hsm1_state(running)]
+        fn fast(&mut self, _msg: &Msg) -> StateResult!() {
+            not_handled!()
+        }
+    );
+
+    let mut fan = Fan::new();
+    assert_eq!(fan.current_state(), FanState::Slow);
+    assert!(fan.dispatch(&Msg::Up));
+    assert_eq!(fan.slow_cnt, 1);
+    assert_eq!(fan.current_state(), FanState::Fast);
+}
@@ -0,0 +1,43 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+
+struct NoMessages;
+
+hsm1!(
+    struct Counter {
+        cnt: u32,
+    }
+
+    #[hsm1_initial_state]
+    fn counting(&mut self, _msg: &NoMessages) -> StateResult!() {
+        self.cnt += 1;
+        if self.cnt >= LIMIT {
+            transition_to!(done)
+        } else {
+            handled!()
+        }
+    }
+
+    const LIMIT: u32 = 2;
+
+    impl std::fmt::Display for Counter {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Counter({})", self.cnt)
+        }
+    }
+
+    // A state fn after the non-fn items
+    #[hsm1_state]
+    fn done(&mut self, _msg: &NoMessages) -> StateResult!() {
+        handled!()
+    }
+);
+
+#[test]
+fn test_items_between_state_fns() {
+    let mut counter = Counter::new();
+    counter.dispatch(&NoMessages);
+    counter.dispatch(&NoMessages);
+    counter.dispatch(&NoMessages);
+    assert_eq!(counter.cnt, 2);
+    assert_eq!(counter.to_string(), "Counter(2)");
+}
@@ -0,0 +1,59 @@
+// State fns returning the deprecated enum still work through the
+// deprecation window
+#![allow(deprecated)]
+
+use proc_macro_hsm1::{hsm1, hsm1_initial_state, hsm1_state, transition_to};
+
+use state_result::legacy::StateResult;
+
+enum Msg {
+    Ignore,
+    Count,
+    Stop,
+}
+
+hsm1!(
+    struct Legacy {
+        base_cnt: usize,
+        counting_cnt: usize,
+    }
+
+    #[hsm1_state]
+    fn base(&mut self, _msg: &Msg) -> StateResult {
+        self.base_cnt += 1;
+        StateResult::Handled
+    }
+
+    #[hsm1_initial_state(base)]
+    fn counting(&mut self, msg: &Msg) -> StateResult {
+        match msg {
+            Msg::Ignore => StateResult::NotHandled,
+            Msg::Count => {
+                self.counting_cnt += 1;
+                StateResult::Handled
+            }
+            Msg::Stop => transition_to!(stopped).into(),
+        }
+    }
+
+    #[hsm1_state]
+    fn stopped(&mut self, _msg: &Msg) -> StateResult {
+        StateResult::Handled
+    }
+);
+
+#[test]
+fn test_legacy_state_result() {
+    let mut legacy = Legacy::new();
+
+    legacy.dispatch(&Msg::Count);
+    assert_eq!(legacy.counting_cnt, 1);
+    assert_eq!(legacy.base_cnt, 0);
+
+    legacy.dispatch(&Msg::Ignore);
+    assert_eq!(legacy.counting_cnt, 1);
+    assert_eq!(legacy.base_cnt, 1);
+
+    legacy.dispatch(&Msg::Stop);
+    assert_eq!(legacy.current_state(), LegacyState::Stopped);
+}
@@ -17,9 +17,9 @@ fn test_initialization() {
     );
 
     let fsm = Test::new();
-    assert_eq!(fsm.smi.current_state_fns_hdl as usize, 0);
-    assert_eq!(fsm.smi.previous_state_fns_hdl as usize, 0);
-    assert!(fsm.smi.current_state_changed);
+    assert_eq!(fsm.current_state_name(), "initial");
+    assert_eq!(fsm.previous_state_name(), "initial");
+    assert!(fsm.current_state_changed());
 }
 
 #[test]
@@ -41,24 +41,21 @@ fn test_dispatch() {
     );
 
     let mut fsm = TestDispatch::new();
-    assert_eq!(fsm.smi.current_state_fns_hdl as usize, 0);
-    assert_eq!(fsm.smi.previous_state_fns_hdl as usize, 0);
-    assert!(fsm.smi.current_state_changed);
+    assert_eq!(fsm.current_state(), TestDispatchState::Initial);
+    assert_eq!(fsm.previous_state(), TestDispatchState::Initial);
+    assert!(fsm.current_state_changed());
 
-    fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.smi.current_state_fns_hdl as usize, 1);
-    assert_eq!(fsm.smi.previous_state_fns_hdl as usize, 0);
-    assert!(fsm.smi.current_state_changed);
+    assert!(fsm.dispatch(&NoMessages));
+    assert_eq!(fsm.current_state(), TestDispatchState::Done);
+    assert_eq!(fsm.previous_state(), TestDispatchState::Initial);
 
-    fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.smi.current_state_fns_hdl as usize, 1);
-    assert_eq!(fsm.smi.previous_state_fns_hdl as usize, 0);
-    assert!(!fsm.smi.current_state_changed);
+    assert!(!fsm.dispatch(&NoMessages));
+    assert_eq!(fsm.current_state(), TestDispatchState::Done);
+    assert_eq!(fsm.previous_state(), TestDispatchState::Initial);
 
-    fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.smi.current_state_fns_hdl as usize, 1);
-    assert_eq!(fsm.smi.previous_state_fns_hdl as usize, 0);
-    assert!(!fsm.smi.current_state_changed);
+    assert!(!fsm.dispatch(&NoMessages));
+    assert_eq!(fsm.current_state(), TestDispatchState::Done);
+    assert_eq!(fsm.previous_state(), TestDispatchState::Initial);
 }
 
 #[test]
@@ -80,14 +77,13 @@ fn test_transition_to() {
     );
 
     let mut fsm = Test::new();
-    assert_eq!(fsm.smi.current_state_fns_hdl, 0);
-    assert_eq!(fsm.smi.previous_state_fns_hdl, 0);
-    assert!(fsm.smi.current_state_changed);
+    assert_eq!(fsm.current_state_name(), "initial");
+    assert_eq!(fsm.previous_state_name(), "initial");
+    assert!(fsm.current_state_changed());
 
-    fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.smi.current_state_fns_hdl, 1);
-    assert_eq!(fsm.smi.previous_state_fns_hdl, 0);
-    assert!(fsm.smi.current_state_changed);
+    assert!(fsm.dispatch(&NoMessages));
+    assert_eq!(fsm.current_state_name(), "done");
+    assert_eq!(fsm.previous_state_name(), "initial");
 }
 
 #[test]
@@ -270,145 +266,121 @@ fn test_initial_and_done_both_with_exit() {
 
 #[test]
 fn test_initial_and_do_work_and_done_all_with_enter_exit() {
+    // Uses the built-in counters, the enter and exit fns only need to exist
     hsm1!(
-        struct Test {
-            initial_enter_cnt: usize,
-            initial_cnt: usize,
-            initial_exit_cnt: usize,
-            do_work_enter_cnt: usize,
-            do_work_cnt: usize,
-            do_work_exit_cnt: usize,
-            done_enter_cnt: usize,
-            done_cnt: usize,
-            done_exit_cnt: usize,
-        }
+        struct Test {}
 
-        fn initial_enter(&mut self, _msg: &NoMessages) {
-            self.initial_enter_cnt += 1;
-        }
+        fn initial_enter(&mut self, _msg: &NoMessages) {}
 
         #[hsm1_initial_state]
         // This state has hdl 0
         fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
-            self.initial_cnt += 1;
             transition_to!(do_work)
         }
 
-        fn initial_exit(&mut self, _msg: &NoMessages) {
-            self.initial_exit_cnt += 1;
-        }
+        fn initial_exit(&mut self, _msg: &NoMessages) {}
 
-        fn do_work_exit(&mut self, _msg: &NoMessages) {
-            self.do_work_exit_cnt += 1;
-        }
+        fn do_work_exit(&mut self, _msg: &NoMessages) {}
 
         #[hsm1_state]
         // This state has hdl 1
         fn do_work(&mut self, _msg: &NoMessages) -> StateResult!() {
-            self.do_work_cnt += 1;
-            if self.do_work_cnt < 3 {
+            if self.get_state_process_cnt(Self::STATE_DO_WORK) < 3 {
                 handled!()
             } else {
                 transition_to!(done)
             }
         }
 
-        fn do_work_enter(&mut self, _msg: &NoMessages) {
-            self.do_work_enter_cnt += 1;
-        }
+        fn do_work_enter(&mut self, _msg: &NoMessages) {}
 
-        fn done_exit(&mut self, _msg: &NoMessages) {
-            self.done_exit_cnt += 1;
-        }
+        fn done_exit(&mut self, _msg: &NoMessages) {}
 
         #[hsm1_state]
         // This state has hdl 2
         fn done(&mut self, _msg: &NoMessages) -> StateResult!() {
-            self.done_cnt += 1;
             handled!()
         }
 
-        fn done_enter(&mut self, _msg: &NoMessages) {
-            self.done_enter_cnt += 1;
-        }
+        fn done_enter(&mut self, _msg: &NoMessages) {}
     );
 
     let mut fsm = Test::new();
-    assert_eq!(fsm.initial_enter_cnt, 0);
-    assert_eq!(fsm.initial_cnt, 0);
-    assert_eq!(fsm.initial_exit_cnt, 0);
-    assert_eq!(fsm.do_work_enter_cnt, 0);
-    assert_eq!(fsm.do_work_cnt, 0);
-    assert_eq!(fsm.do_work_exit_cnt, 0);
-    assert_eq!(fsm.done_enter_cnt, 0);
-    assert_eq!(fsm.done_cnt, 0);
-    assert_eq!(fsm.done_exit_cnt, 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_INITIAL), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_INITIAL), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_INITIAL), 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DONE), 0);
 
     fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.initial_enter_cnt, 1);
-    assert_eq!(fsm.initial_cnt, 1);
-    assert_eq!(fsm.initial_exit_cnt, 1);
-    assert_eq!(fsm.do_work_enter_cnt, 0);
-    assert_eq!(fsm.do_work_cnt, 0);
-    assert_eq!(fsm.do_work_exit_cnt, 0);
-    assert_eq!(fsm.done_enter_cnt, 0);
-    assert_eq!(fsm.done_cnt, 0);
-    assert_eq!(fsm.done_exit_cnt, 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DONE), 0);
 
     fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.initial_enter_cnt, 1);
-    assert_eq!(fsm.initial_cnt, 1);
-    assert_eq!(fsm.initial_exit_cnt, 1);
-    assert_eq!(fsm.do_work_enter_cnt, 1);
-    assert_eq!(fsm.do_work_cnt, 1);
-    assert_eq!(fsm.do_work_exit_cnt, 0);
-    assert_eq!(fsm.done_enter_cnt, 0);
-    assert_eq!(fsm.done_cnt, 0);
-    assert_eq!(fsm.done_exit_cnt, 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DONE), 0);
 
     fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.initial_enter_cnt, 1);
-    assert_eq!(fsm.initial_cnt, 1);
-    assert_eq!(fsm.initial_exit_cnt, 1);
-    assert_eq!(fsm.do_work_enter_cnt, 1);
-    assert_eq!(fsm.do_work_cnt, 2);
-    assert_eq!(fsm.do_work_exit_cnt, 0);
-    assert_eq!(fsm.done_enter_cnt, 0);
-    assert_eq!(fsm.done_cnt, 0);
-    assert_eq!(fsm.done_exit_cnt, 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DO_WORK), 2);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DO_WORK), 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DONE), 0);
 
     fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.initial_enter_cnt, 1);
-    assert_eq!(fsm.initial_cnt, 1);
-    assert_eq!(fsm.initial_exit_cnt, 1);
-    assert_eq!(fsm.do_work_enter_cnt, 1);
-    assert_eq!(fsm.do_work_cnt, 3);
-    assert_eq!(fsm.do_work_exit_cnt, 1);
-    assert_eq!(fsm.done_enter_cnt, 0);
-    assert_eq!(fsm.done_cnt, 0);
-    assert_eq!(fsm.done_exit_cnt, 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DO_WORK), 3);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DONE), 0);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DONE), 0);
 
     fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.initial_enter_cnt, 1);
-    assert_eq!(fsm.initial_cnt, 1);
-    assert_eq!(fsm.initial_exit_cnt, 1);
-    assert_eq!(fsm.do_work_enter_cnt, 1);
-    assert_eq!(fsm.do_work_cnt, 3);
-    assert_eq!(fsm.do_work_exit_cnt, 1);
-    assert_eq!(fsm.done_enter_cnt, 1);
-    assert_eq!(fsm.done_cnt, 1);
-    assert_eq!(fsm.done_exit_cnt, 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DO_WORK), 3);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DONE), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DONE), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DONE), 0);
 
     fsm.dispatch(&NoMessages);
-    assert_eq!(fsm.initial_enter_cnt, 1);
-    assert_eq!(fsm.initial_cnt, 1);
-    assert_eq!(fsm.initial_exit_cnt, 1);
-    assert_eq!(fsm.do_work_enter_cnt, 1);
-    assert_eq!(fsm.do_work_cnt, 3);
-    assert_eq!(fsm.do_work_exit_cnt, 1);
-    assert_eq!(fsm.done_enter_cnt, 1);
-    assert_eq!(fsm.done_cnt, 2);
-    assert_eq!(fsm.done_exit_cnt, 0);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_INITIAL), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DO_WORK), 3);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DO_WORK), 1);
+    assert_eq!(fsm.get_state_enter_cnt(Test::STATE_DONE), 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_DONE), 2);
+    assert_eq!(fsm.get_state_exit_cnt(Test::STATE_DONE), 0);
 }
 
 #[test]
@@ -543,7 +515,7 @@ fn test_tree_parent_with_children_initial_do_work_done_all_with_enter_exit() {
             self.initial_enter_cnt += 1;
         }
 
-        #[hsm1_initial_state(parent)]
+        #[hsm1_initial_state(parent, initial)]
         fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
             self.initial_cnt += 1;
             match self.initial_cnt {
@@ -694,26 +666,12 @@ fn test_tree_parent_with_children_initial_do_work_done_all_with_enter_exit() {
     assert_eq!(hsm.done_cnt, 0);
     assert_eq!(hsm.done_exit_cnt, 0);
 
-    // Into done always returns transition_to!(parent)
+    // Into done always returns transition_to!(parent), that's initial
+    // as it's the initial substate of parent
     hsm.dispatch(&NoMessages);
     assert_eq!(hsm.parent_enter_cnt, 1);
     assert_eq!(hsm.parent_cnt, 2);
-    assert_eq!(hsm.parent_exit_cnt, 1);
-    assert_eq!(hsm.initial_enter_cnt, 1);
-    assert_eq!(hsm.initial_cnt, 3);
-    assert_eq!(hsm.initial_exit_cnt, 1);
-    assert_eq!(hsm.do_work_enter_cnt, 1);
-    assert_eq!(hsm.do_work_cnt, 3);
-    assert_eq!(hsm.do_work_exit_cnt, 1);
-    assert_eq!(hsm.done_enter_cnt, 1);
-    assert_eq!(hsm.done_cnt, 1);
-    assert_eq!(hsm.done_exit_cnt, 1);
-
-    // Into parent always returns handled!()
-    hsm.dispatch(&NoMessages);
-    assert_eq!(hsm.parent_enter_cnt, 2);
-    assert_eq!(hsm.parent_cnt, 3);
-    assert_eq!(hsm.parent_exit_cnt, 1);
+    assert_eq!(hsm.parent_exit_cnt, 0);
     assert_eq!(hsm.initial_enter_cnt, 1);
     assert_eq!(hsm.initial_cnt, 3);
     assert_eq!(hsm.initial_exit_cnt, 1);
@@ -723,15 +681,16 @@ fn test_tree_parent_with_children_initial_do_work_done_all_with_enter_exit() {
     assert_eq!(hsm.done_enter_cnt, 1);
     assert_eq!(hsm.done_cnt, 1);
     assert_eq!(hsm.done_exit_cnt, 1);
+    assert_eq!(hsm.current_state(), TestState::Initial);
 
-    // Into parent always returns handled!()
+    // Into initial which returned transition_to!(do_work)
     hsm.dispatch(&NoMessages);
-    assert_eq!(hsm.parent_enter_cnt, 2);
-    assert_eq!(hsm.parent_cnt, 4);
-    assert_eq!(hsm.parent_exit_cnt, 1);
-    assert_eq!(hsm.initial_enter_cnt, 1);
-    assert_eq!(hsm.initial_cnt, 3);
-    assert_eq!(hsm.initial_exit_cnt, 1);
+    assert_eq!(hsm.parent_enter_cnt, 1);
+    assert_eq!(hsm.parent_cnt, 2);
+    assert_eq!(hsm.parent_exit_cnt, 0);
+    assert_eq!(hsm.initial_enter_cnt, 2);
+    assert_eq!(hsm.initial_cnt, 4);
+    assert_eq!(hsm.initial_exit_cnt, 2);
     assert_eq!(hsm.do_work_enter_cnt, 1);
     assert_eq!(hsm.do_work_cnt, 3);
     assert_eq!(hsm.do_work_exit_cnt, 1);
@@ -1143,3 +1102,64 @@ fn test_initial_then_tree_of_parent_and_do_work_then_done() {
     assert_eq!(hsm.done_cnt, 2);
     assert_eq!(hsm.done_exit_cnt, 0);
 }
+
+#[test]
+fn test_state_handles() {
+    hsm1!(
+        struct Test {}
+
+        #[hsm1_state]
+        fn base(&mut self, _msg: &NoMessages) -> StateResult!() {
+            handled!()
+        }
+
+        #[hsm1_initial_state(base)]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+            transition_to!(done)
+        }
+
+        #[hsm1_state(base)]
+        fn done(&mut self, _msg: &NoMessages) -> StateResult!() {
+            handled!()
+        }
+    );
+
+    assert_eq!(Test::STATE_BASE, 0);
+    assert_eq!(Test::STATE_INITIAL, 1);
+    assert_eq!(Test::STATE_DONE, 2);
+    assert_eq!(Test::state_handle("initial"), Some(Test::STATE_INITIAL));
+    assert_eq!(Test::state_handle("done"), Some(Test::STATE_DONE));
+    assert_eq!(Test::state_handle("nowhere"), None);
+    assert_eq!(Test::state_name(Test::STATE_INITIAL), Some("initial"));
+    assert_eq!(Test::state_name(3), None);
+
+    let mut fsm = Test::new();
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.current_state_name(), "done");
+}
+
+#[test]
+fn test_state_enum() {
+    hsm1!(
+        struct Test {}
+
+        #[hsm1_initial_state]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+            transition_to!(TestState::DoWork)
+        }
+
+        #[hsm1_state]
+        fn do_work(&mut self, _msg: &NoMessages) -> StateResult!() {
+            handled!()
+        }
+    );
+
+    assert_eq!(TestState::from(Test::STATE_DO_WORK), TestState::DoWork);
+    assert_eq!(usize::from(TestState::Initial), Test::STATE_INITIAL);
+
+    let mut fsm = Test::new();
+    assert_eq!(fsm.current_state(), TestState::Initial);
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.current_state(), TestState::DoWork);
+    assert_eq!(fsm.previous_state(), TestState::Initial);
+}
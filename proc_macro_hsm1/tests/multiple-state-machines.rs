@@ -59,29 +59,37 @@ hsm1!(
 
     #[hsm1_state]
     fn initial_parent(&mut self, _msg: &NoMessages) -> StateResult!() {
-        println!("{}: never executed", self.state_name());
+        println!("{}: never executed", self.current_state_name());
         handled!()
     }
 
     fn initial_enter(&mut self, _msg: &NoMessages) {
-        println!("{}: enter self.a_i32={}", self.state_name(), self.a_i32);
+        println!(
+            "{}: enter self.a_i32={}",
+            self.current_state_name(),
+            self.a_i32
+        );
     }
 
     #[hsm1_initial_state]
     fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
         self.non_state_fn();
-        println!("{}: self.a_i32={}", self.state_name(), self.a_i32);
+        println!("{}: self.a_i32={}", self.current_state_name(), self.a_i32);
         transition_to!(do_work)
     }
 
     fn initial_exit(&mut self, _msg: &NoMessages) {
-        println!("{}: exit  self.a_i32={}", self.state_name(), self.a_i32);
+        println!(
+            "{}: exit  self.a_i32={}",
+            self.current_state_name(),
+            self.a_i32
+        );
     }
 
     #[hsm1_state]
     fn do_work(&mut self, _msg: &NoMessages) -> StateResult!() {
         self.a_i32 += 1;
-        println!("{}: self.a_i32={}", self.state_name(), self.a_i32);
+        println!("{}: self.a_i32={}", self.current_state_name(), self.a_i32);
 
         transition_to!(done)
     }
@@ -89,7 +97,7 @@ hsm1!(
     #[hsm1_state]
     fn done(&mut self, _msg: &NoMessages) -> StateResult!() {
         self.a_i32 += 1;
-        println!("{}: self.a_i32={}", self.state_name(), self.a_i32);
+        println!("{}: self.a_i32={}", self.current_state_name(), self.a_i32);
 
         handled!()
     }
@@ -97,7 +105,7 @@ hsm1!(
     #[hsm1_state]
     fn do_nothing_ret_not_handled(&mut self, _msg: &NoMessages) -> StateResult!() {
         self.a_i32 += 1;
-        println!("{}: self.a_i32={}", self.state_name(), self.a_i32);
+        println!("{}: self.a_i32={}", self.current_state_name(), self.a_i32);
 
         not_handled!()
     }
@@ -126,38 +134,34 @@ fn multiple_state_machines() {
     );
 
     let mut my_hsm = MyHsm::new();
-    assert_eq!(my_hsm.smi.current_state_fns_hdl as usize, 1); //MyHsm::initial as usize);
-    assert_eq!(my_hsm.smi.previous_state_fns_hdl as usize, 1); //MyHsm::initial as usize);
-    assert!(my_hsm.smi.current_state_changed);
+    assert_eq!(my_hsm.current_state(), MyHsmState::Initial);
+    assert_eq!(my_hsm.previous_state(), MyHsmState::Initial);
+    assert!(my_hsm.current_state_changed());
 
     my_hsm.a_i32 = 123;
     println!("main: my_hsm.a_i32={}", my_hsm.a_i32);
 
     // Invoke initial
-    my_hsm.dispatch(&msg);
+    assert!(my_hsm.dispatch(&msg));
     println!("main: my_hsm.a_i32={}", my_hsm.a_i32);
-    assert_eq!(my_hsm.smi.current_state_fns_hdl as usize, 2); //MyHsm::do_work as usize);
-    assert_eq!(my_hsm.smi.previous_state_fns_hdl as usize, 1); //MyHsm::initial as usize);
-    assert!(my_hsm.smi.current_state_changed);
+    assert_eq!(my_hsm.current_state(), MyHsmState::DoWork);
+    assert_eq!(my_hsm.previous_state(), MyHsmState::Initial);
 
     // Invoke do_work
-    my_hsm.dispatch(&msg);
+    assert!(my_hsm.dispatch(&msg));
     println!("main: my_hsm.a_i32={}", my_hsm.a_i32);
-    assert_eq!(my_hsm.smi.current_state_fns_hdl as usize, 3); //MyHsm::done as usize);
-    assert_eq!(my_hsm.smi.previous_state_fns_hdl as usize, 2); //MyHsm::do_work as usize);
-    assert!(my_hsm.smi.current_state_changed);
+    assert_eq!(my_hsm.current_state(), MyHsmState::Done);
+    assert_eq!(my_hsm.previous_state(), MyHsmState::DoWork);
 
     // Invoke done
-    my_hsm.dispatch(&msg);
+    assert!(!my_hsm.dispatch(&msg));
     println!("main: my_hsm.a_i32={}", my_hsm.a_i32);
-    assert_eq!(my_hsm.smi.current_state_fns_hdl as usize, 3); //MyHsm::done as usize);
-    assert_eq!(my_hsm.smi.previous_state_fns_hdl as usize, 2); //MyHsm::do_work as usize);
-    assert!(!my_hsm.smi.current_state_changed);
+    assert_eq!(my_hsm.current_state(), MyHsmState::Done);
+    assert_eq!(my_hsm.previous_state(), MyHsmState::DoWork);
 
     // Invoke done again
-    my_hsm.dispatch(&msg);
+    assert!(!my_hsm.dispatch(&msg));
     println!("main: my_hsm.a_i32={}", my_hsm.a_i32);
-    assert_eq!(my_hsm.smi.current_state_fns_hdl as usize, 3); //MyHsm::done as usize);
-    assert_eq!(my_hsm.smi.previous_state_fns_hdl as usize, 2); //MyHsm::do_work as usize);
-    assert!(!my_hsm.smi.current_state_changed);
+    assert_eq!(my_hsm.current_state(), MyHsmState::Done);
+    assert_eq!(my_hsm.previous_state(), MyHsmState::DoWork);
 }
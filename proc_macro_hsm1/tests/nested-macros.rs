@@ -0,0 +1,37 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+
+use state_result::Handled;
+
+hsm1!(
+    struct Nested {
+        matched: bool,
+    }
+
+    // transition_to! inside other macros is rewritten too
+    #[hsm1_initial_state]
+    fn initial(&mut self, msg: &usize) -> StateResult!() {
+        self.matched =
+            matches!(transition_to!(done), (Handled::Yes, Some(hdl)) if hdl == Self::STATE_DONE);
+        assert_eq!(transition_to!(done), (Handled::Yes, Some(Self::STATE_DONE)));
+        // vec! rather than an array as it's a macro
+        #[allow(clippy::useless_vec)]
+        let results = vec![handled!(), transition_to!(done)];
+        results[*msg]
+    }
+
+    #[hsm1_state]
+    fn done(&mut self, _msg: &usize) -> StateResult!() {
+        handled!()
+    }
+);
+
+#[test]
+fn test_transition_to_inside_other_macros() {
+    let mut sm = Nested::new();
+    assert!(!sm.dispatch(&0));
+    assert!(sm.matched);
+    assert_eq!(sm.current_state(), NestedState::Initial);
+
+    assert!(sm.dispatch(&1));
+    assert_eq!(sm.current_state(), NestedState::Done);
+}
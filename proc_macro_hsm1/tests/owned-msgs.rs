@@ -0,0 +1,51 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, not_handled, StateResult};
+
+#[derive(Clone)]
+enum Msg {
+    Push(String),
+    Clear,
+}
+
+hsm1!(
+    struct Collector {
+        items: Vec<String>,
+        cleared_cnt: usize,
+    }
+
+    #[hsm1_state]
+    fn base(&mut self, msg: Msg) -> StateResult!() {
+        match msg {
+            Msg::Clear => {
+                self.items.clear();
+                self.cleared_cnt += 1;
+                handled!()
+            }
+            Msg::Push(_) => handled!(),
+        }
+    }
+
+    #[hsm1_initial_state(base)]
+    fn collecting(&mut self, msg: Msg) -> StateResult!() {
+        match msg {
+            Msg::Push(item) => {
+                // The String is moved into the machine
+                self.items.push(item);
+                handled!()
+            }
+            Msg::Clear => not_handled!(),
+        }
+    }
+);
+
+#[test]
+fn test_msg_passed_by_value() {
+    let mut collector = Collector::new();
+    collector.dispatch(Msg::Push("one".to_owned()));
+    collector.dispatch(Msg::Push("two".to_owned()));
+    assert_eq!(collector.items, vec!["one".to_owned(), "two".to_owned()]);
+
+    // Not handled by collecting so it's passed to base
+    collector.dispatch(Msg::Clear);
+    assert!(collector.items.is_empty());
+    assert_eq!(collector.cleared_cnt, 1);
+}
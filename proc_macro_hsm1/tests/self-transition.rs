@@ -0,0 +1,78 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+
+enum Msg {
+    Again,
+    Stay,
+}
+
+hsm1!(
+    struct Test {
+        parent_enter_cnt: usize,
+        parent_exit_cnt: usize,
+        child_enter_cnt: usize,
+        child_exit_cnt: usize,
+    }
+
+    fn parent_enter(&mut self, _msg: &Msg) {
+        self.parent_enter_cnt += 1;
+    }
+
+    #[hsm1_state]
+    fn parent(&mut self, _msg: &Msg) -> StateResult!() {
+        handled!()
+    }
+
+    fn parent_exit(&mut self, _msg: &Msg) {
+        self.parent_exit_cnt += 1;
+    }
+
+    fn child_enter(&mut self, _msg: &Msg) {
+        self.child_enter_cnt += 1;
+    }
+
+    // transition_to!(self) re-enters child
+    #[hsm1_initial_state(parent, initial)]
+    fn child(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Again => transition_to!(self),
+            Msg::Stay => handled!(),
+        }
+    }
+
+    fn child_exit(&mut self, _msg: &Msg) {
+        self.child_exit_cnt += 1;
+    }
+);
+
+#[test]
+fn test_transition_to_self_exits_and_enters_only_the_current_state() {
+    let mut sm = Test::new();
+
+    // The first dispatch enters parent and child, the transition to
+    // self exits child and the next dispatch enters it again
+    assert!(sm.dispatch(&Msg::Again));
+    assert_eq!(sm.parent_enter_cnt, 1);
+    assert_eq!(sm.child_enter_cnt, 1);
+    assert_eq!(sm.child_exit_cnt, 1);
+    assert_eq!(sm.current_state(), TestState::Child);
+
+    assert!(!sm.dispatch(&Msg::Stay));
+    assert_eq!(sm.child_enter_cnt, 2);
+    assert_eq!(sm.child_exit_cnt, 1);
+    assert_eq!(sm.get_state_enter_cnt(1), 2);
+    assert_eq!(sm.get_state_exit_cnt(1), 1);
+    assert_eq!(sm.get_state_process_cnt(1), 2);
+
+    assert!(sm.dispatch(&Msg::Again));
+    assert!(!sm.dispatch(&Msg::Stay));
+    assert_eq!(sm.child_enter_cnt, 3);
+    assert_eq!(sm.child_exit_cnt, 2);
+    assert_eq!(sm.get_state_process_cnt(1), 4);
+
+    // The parent is untouched
+    assert_eq!(sm.parent_enter_cnt, 1);
+    assert_eq!(sm.parent_exit_cnt, 0);
+    assert_eq!(sm.get_state_enter_cnt(0), 1);
+    assert_eq!(sm.get_state_exit_cnt(0), 0);
+    assert_eq!(sm.get_state_process_cnt(0), 0);
+}
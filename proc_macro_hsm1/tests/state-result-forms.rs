@@ -0,0 +1,33 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, not_handled, StateResult};
+
+// The type, the StateResult!() macro is imported from proc_macro_hsm1
+use state_result::StateResult;
+
+struct NoMessages;
+
+hsm1!(
+    struct Test {
+        base_cnt: usize,
+        initial_cnt: usize,
+    }
+
+    #[hsm1_state]
+    fn base(&mut self, _msg: &NoMessages) -> StateResult {
+        self.base_cnt += 1;
+        handled!()
+    }
+
+    #[hsm1_initial_state(base)]
+    fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        self.initial_cnt += 1;
+        not_handled!()
+    }
+);
+
+#[test]
+fn test_state_result_type_and_macro() {
+    let mut fsm = Test::new();
+    fsm.dispatch(&NoMessages);
+    assert_eq!(fsm.initial_cnt, 1);
+    assert_eq!(fsm.base_cnt, 1);
+}
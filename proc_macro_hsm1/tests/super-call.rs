@@ -0,0 +1,165 @@
+use proc_macro_hsm1::{
+    handled, hsm1, hsm1_initial_state, hsm1_state, not_handled, transition_to, StateResult,
+};
+
+use state_result::Handled;
+
+enum Msg {
+    Add(u32),
+    Overflow,
+}
+
+hsm1!(
+    struct Accumulator {
+        sum: u32,
+        child_add_cnt: usize,
+        overflow_cnt: usize,
+        full_add_cnt: usize,
+    }
+
+    // Adds to sum and transitions to full when sum is over 10
+    #[hsm1_state]
+    fn base(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Add(n) => {
+                self.sum += n;
+                if self.sum > 10 {
+                    transition_to!(full)
+                } else {
+                    handled!()
+                }
+            }
+            Msg::Overflow => not_handled!(),
+        }
+    }
+
+    // Does what base does and also counts the adds, and ignores base's
+    // transition to full
+    #[hsm1_initial_state(base)]
+    fn counting(&mut self, msg: &Msg) -> StateResult!() {
+        match msg {
+            Msg::Add(_) => {
+                let (handled, transition) = self.handle_in_parent(msg);
+                assert_eq!(handled, Handled::Yes);
+                self.child_add_cnt += 1;
+                if transition.is_some() {
+                    self.overflow_cnt += 1;
+                }
+                handled!()
+            }
+            Msg::Overflow => {
+                // base doesn't handle it and it's not passed further up
+                let sr = self.handle_in_parent(msg);
+                assert_eq!(sr, (Handled::No, None));
+                transition_to!(full)
+            }
+        }
+    }
+
+    // full has no parent
+    #[hsm1_state]
+    fn full(&mut self, msg: &Msg) -> StateResult!() {
+        assert_eq!(self.handle_in_parent(msg), (Handled::No, None));
+        self.full_add_cnt += 1;
+        handled!()
+    }
+);
+
+#[test]
+fn test_handle_in_parent() {
+    let mut acc = Accumulator::new();
+    assert!(!acc.dispatch(&Msg::Add(4)));
+    assert!(!acc.dispatch(&Msg::Add(5)));
+    assert_eq!(acc.sum, 9);
+    assert_eq!(acc.child_add_cnt, 2);
+
+    // base returns a transition which counting ignores
+    assert!(!acc.dispatch(&Msg::Add(5)));
+    assert_eq!(acc.sum, 14);
+    assert_eq!(acc.overflow_cnt, 1);
+    assert_eq!(acc.current_state(), AccumulatorState::Counting);
+    assert_eq!(acc.get_state_process_cnt(Accumulator::STATE_BASE), 3);
+
+    assert!(acc.dispatch(&Msg::Overflow));
+    assert_eq!(acc.current_state(), AccumulatorState::Full);
+
+    assert!(!acc.dispatch(&Msg::Add(1)));
+    assert_eq!(acc.full_add_cnt, 1);
+    assert_eq!(acc.sum, 14);
+}
+
+#[test]
+fn test_handle_in_parent_checks_the_guard_and_chains() {
+    hsm1!(
+        struct Test {
+            locked: bool,
+            top_cnt: usize,
+            base_cnt: usize,
+        }
+
+        #[hsm1_state]
+        fn top(&mut self, _msg: &u32) -> StateResult!() {
+            self.top_cnt += 1;
+            transition_to!(child)
+        }
+
+        fn unlocked(&self, _msg: &u32) -> bool {
+            !self.locked
+        }
+
+        // Does what top does
+        #[hsm1_state(top, guard = unlocked)]
+        fn base(&mut self, msg: &u32) -> StateResult!() {
+            self.base_cnt += 1;
+            self.handle_in_parent(msg)
+        }
+
+        // Returns what base and top return, so their transition happens
+        #[hsm1_initial_state(base)]
+        fn child(&mut self, msg: &u32) -> StateResult!() {
+            self.handle_in_parent(msg)
+        }
+    );
+
+    let mut fsm = Test::new();
+    assert!(fsm.dispatch(&1));
+    assert_eq!(fsm.base_cnt, 1);
+    assert_eq!(fsm.top_cnt, 1);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_TOP), 1);
+
+    // base's guard returns false so neither base nor top are called by
+    // handle_in_parent, child returns not handled and dispatch passes
+    // the msg to base, whose guard returns false again, and then top
+    fsm.locked = true;
+    assert!(fsm.dispatch(&2));
+    assert_eq!(fsm.base_cnt, 1);
+    assert_eq!(fsm.top_cnt, 2);
+    assert_eq!(fsm.get_state_process_cnt(Test::STATE_BASE), 1);
+}
+
+#[test]
+fn test_handle_in_parent_with_msgs_passed_by_value() {
+    hsm1!(
+        struct Test {
+            items: Vec<String>,
+        }
+
+        #[hsm1_state]
+        fn base(&mut self, msg: String) -> StateResult!() {
+            self.items.push(msg);
+            handled!()
+        }
+
+        #[hsm1_initial_state(base)]
+        fn child(&mut self, msg: String) -> StateResult!() {
+            let upper = msg.to_uppercase();
+            let sr = self.handle_in_parent(msg);
+            self.items.push(upper);
+            sr
+        }
+    );
+
+    let mut fsm = Test::new();
+    fsm.dispatch("a".to_owned());
+    assert_eq!(fsm.items, vec!["a".to_owned(), "A".to_owned()]);
+}
@@ -1,5 +1,7 @@
 //use hsm1::{handled, hsm1, hsm1_state, not_handled, transition_to, StateResult};
-use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to, StateResult};
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, hsm1_state, transition_to};
+
+use state_result::StateResult;
 
 struct NoMessages;
 
@@ -18,7 +20,7 @@ fn test_transitions_with_one_state() {
 
         #[hsm1_initial_state]
         // This state has hdl 0
-        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult {
             self.initial_cnt += 1;
             transition_to!(initial)
         }
@@ -67,7 +69,7 @@ fn test_transitions_between_two_unrelated_states() {
 
         #[hsm1_initial_state]
         // This state has hdl 0
-        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult {
             self.initial_cnt += 1;
             transition_to!(other)
         }
@@ -82,7 +84,7 @@ fn test_transitions_between_two_unrelated_states() {
 
         #[hsm1_state]
         // This state has hdl 0
-        fn other(&mut self, _msg: &NoMessages) -> StateResult!() {
+        fn other(&mut self, _msg: &NoMessages) -> StateResult {
             self.other_cnt += 1;
             transition_to!(initial)
         }
@@ -165,7 +167,7 @@ fn test_transitions_between_leafs_of_trees() {
 
         #[hsm1_state]
         // This state has hdl 0
-        fn initial_base(&mut self, _msg: &NoMessages) -> StateResult!() {
+        fn initial_base(&mut self, _msg: &NoMessages) -> StateResult {
             self.initial_base_cnt += 1;
             handled!()
         }
@@ -180,7 +182,7 @@ fn test_transitions_between_leafs_of_trees() {
 
         #[hsm1_initial_state(initial_base)]
         // This state has hdl 0
-        fn initial(&mut self, _msg: &NoMessages) -> StateResult!() {
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult {
             self.initial_cnt += 1;
             transition_to!(other)
         }
@@ -195,7 +197,7 @@ fn test_transitions_between_leafs_of_trees() {
 
         #[hsm1_state]
         // This state has hdl 0
-        fn other_base(&mut self, _msg: &NoMessages) -> StateResult!() {
+        fn other_base(&mut self, _msg: &NoMessages) -> StateResult {
             self.other_base_cnt += 1;
             handled!()
         }
@@ -210,7 +212,7 @@ fn test_transitions_between_leafs_of_trees() {
 
         #[hsm1_state(other_base)]
         // This state has hdl 0
-        fn other(&mut self, _msg: &NoMessages) -> StateResult!() {
+        fn other(&mut self, _msg: &NoMessages) -> StateResult {
             self.other_cnt += 1;
             transition_to!(initial)
         }
@@ -304,3 +306,49 @@ fn test_transitions_between_leafs_of_trees() {
     assert_eq!(sm.other_cnt, 2);
     assert_eq!(sm.other_exit_cnt, 2);
 }
+
+#[test]
+fn test_transitions_between_children_of_a_parent_without_an_enter_fn() {
+    hsm1!(
+        struct Test {
+            base_exit_cnt: usize,
+            initial_cnt: usize,
+            other_cnt: usize,
+        }
+
+        // base has no enter fn, it's still active while a child is
+        fn base_exit(&mut self, _msg: &NoMessages) {
+            self.base_exit_cnt += 1;
+        }
+
+        #[hsm1_state]
+        fn base(&mut self, _msg: &NoMessages) -> StateResult {
+            handled!()
+        }
+
+        #[hsm1_initial_state(base)]
+        fn initial(&mut self, _msg: &NoMessages) -> StateResult {
+            self.initial_cnt += 1;
+            transition_to!(other)
+        }
+
+        #[hsm1_state(base)]
+        fn other(&mut self, _msg: &NoMessages) -> StateResult {
+            self.other_cnt += 1;
+            transition_to!(initial)
+        }
+    );
+
+    let mut sm = Test::new();
+    sm.dispatch(&NoMessages);
+    assert_eq!(sm.initial_cnt, 1);
+    assert_eq!(sm.base_exit_cnt, 0);
+
+    sm.dispatch(&NoMessages);
+    assert_eq!(sm.other_cnt, 1);
+    assert_eq!(sm.base_exit_cnt, 0);
+
+    sm.dispatch(&NoMessages);
+    assert_eq!(sm.initial_cnt, 2);
+    assert_eq!(sm.base_exit_cnt, 0);
+}
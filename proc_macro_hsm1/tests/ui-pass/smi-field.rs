@@ -0,0 +1,43 @@
+use proc_macro_hsm1::{handled, hsm1, hsm1_initial_state, transition_to, StateResult};
+
+// Named like the helper types used to be
+#[allow(unused)]
+struct SmiFieldStateInfo;
+
+hsm1!(
+    struct SmiField {
+        smi: usize,
+    }
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &()) -> StateResult!() {
+        self.smi += 1;
+        handled!()
+    }
+);
+
+hsm1!(
+    prefix = my_fsm;
+    struct Prefixed {
+        __hsm1_smi: usize,
+    }
+
+    fn initial_exit(&mut self, _msg: &()) {}
+
+    #[hsm1_initial_state(exit = initial_exit)]
+    fn initial(&mut self, _msg: &()) -> StateResult!() {
+        self.__hsm1_smi += 1;
+        transition_to!(initial)
+    }
+);
+
+fn main() {
+    let mut sm = SmiField::new();
+    sm.dispatch(&());
+    assert_eq!(sm.smi, 1);
+
+    let mut sm = Prefixed::new();
+    sm.dispatch(&());
+    assert_eq!(sm.__hsm1_smi, 1);
+    assert_eq!(sm.get_state_exit_cnt(0), 1);
+}
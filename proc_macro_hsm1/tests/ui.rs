@@ -0,0 +1,10 @@
+// The hsm1! compile errors, regenerate the .stderr files with
+// TRYBUILD=overwrite cargo test --test ui
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+
+    // Machines that must compile, named to collide with what's generated
+    t.pass("tests/ui-pass/*.rs");
+}
@@ -0,0 +1,15 @@
+proc_macro_hsm1::hsm1!(
+    struct CompositeInitialState {}
+
+    #[hsm1_initial_state]
+    fn on(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+
+    #[hsm1_state(on)]
+    fn low(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,20 @@
+proc_macro_hsm1::hsm1!(
+    struct CompositeWithoutInitialSubstate {}
+
+    #[hsm1_state]
+    fn on(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+
+    #[hsm1_initial_state(on)]
+    fn low(&mut self, _msg: &Messages) -> StateResult!() {
+        transition_to!(on)
+    }
+
+    #[hsm1_state(on)]
+    fn high(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
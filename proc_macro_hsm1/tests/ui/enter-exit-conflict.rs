@@ -0,0 +1,14 @@
+proc_macro_hsm1::hsm1!(
+    struct EnterConflict {}
+
+    fn on_open(&mut self, _msg: &Messages) {}
+
+    fn open_enter(&mut self, _msg: &Messages) {}
+
+    #[hsm1_initial_state(enter = on_open)]
+    fn open(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
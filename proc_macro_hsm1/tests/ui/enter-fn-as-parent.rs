@@ -0,0 +1,12 @@
+proc_macro_hsm1::hsm1!(
+    struct EnterFnAsParent {}
+
+    fn initial_enter(&mut self, _msg: &Messages) {}
+
+    #[hsm1_initial_state(initial_enter)]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,15 @@
+proc_macro_hsm1::hsm1!(
+    struct GarbageBetweenStateFns {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        transition_to!(other)
+    };
+
+    #[hsm1_state]
+    fn other(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,14 @@
+proc_macro_hsm1::hsm1!(
+    struct GuardSignature {}
+
+    fn can_accept(&mut self, _msg: &Messages) -> bool {
+        true
+    }
+
+    #[hsm1_initial_state(guard = can_accept)]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,15 @@
+proc_macro_hsm1::hsm1!(
+    struct MismatchedErrorType {}
+
+    #[hsm1_initial_state]
+    fn reading(&mut self, _msg: &Messages) -> Result<StateResult!(), std::io::Error> {
+        Ok(handled!())
+    }
+
+    #[hsm1_state]
+    fn parsing(&mut self, _msg: &Messages) -> Result<StateResult!(), String> {
+        Ok(handled!())
+    }
+);
+
+fn main() {}
@@ -0,0 +1,15 @@
+proc_macro_hsm1::hsm1!(
+    struct MismatchedMsgType {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+
+    #[hsm1_state]
+    fn other(&mut self, _msg: &mut Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,10 @@
+proc_macro_hsm1::hsm1!(
+    struct MissingMutSelf {}
+
+    #[hsm1_initial_state]
+    fn initial(&self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
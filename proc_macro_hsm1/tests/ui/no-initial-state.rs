@@ -0,0 +1,10 @@
+proc_macro_hsm1::hsm1!(
+    struct NoInitialState {}
+
+    #[hsm1_state]
+    fn base(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,12 @@
+proc_macro_hsm1::hsm1!(
+    struct NonStateTarget {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        transition_to!(done_enter)
+    }
+
+    fn done_enter(&mut self, _msg: &Messages) {}
+);
+
+fn main() {}
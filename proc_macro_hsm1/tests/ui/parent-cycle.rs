@@ -0,0 +1,20 @@
+proc_macro_hsm1::hsm1!(
+    struct ParentCycle {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+
+    #[hsm1_state(b)]
+    fn a(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+
+    #[hsm1_state(a)]
+    fn b(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,14 @@
+proc_macro_hsm1::hsm1!(
+    struct SelfTransitionOutsideState {}
+
+    fn helper(&mut self) -> StateResult!() {
+        transition_to!(self)
+    }
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        self.helper()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,11 @@
+proc_macro_hsm1::hsm1!(
+    struct TransitionToInUnparsableMacro {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        let results = vec![transition_to!(initial); 2];
+        results[0]
+    }
+);
+
+fn main() {}
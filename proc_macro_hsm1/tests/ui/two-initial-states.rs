@@ -0,0 +1,15 @@
+proc_macro_hsm1::hsm1!(
+    struct TwoInitialStates {}
+
+    #[hsm1_initial_state]
+    fn first(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+
+    #[hsm1_initial_state]
+    fn second(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
@@ -0,0 +1,15 @@
+proc_macro_hsm1::hsm1!(
+    struct TypoTarget {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        transition_to!(don)
+    }
+
+    #[hsm1_state]
+    fn done(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
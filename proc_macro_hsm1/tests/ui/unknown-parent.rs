@@ -0,0 +1,10 @@
+proc_macro_hsm1::hsm1!(
+    struct UnknownParent {}
+
+    #[hsm1_initial_state(nowhere)]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
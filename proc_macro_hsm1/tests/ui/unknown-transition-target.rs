@@ -0,0 +1,10 @@
+proc_macro_hsm1::hsm1!(
+    struct UnknownTarget {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self, _msg: &Messages) -> StateResult!() {
+        transition_to!(nowhere)
+    }
+);
+
+fn main() {}
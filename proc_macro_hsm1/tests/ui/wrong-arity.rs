@@ -0,0 +1,10 @@
+proc_macro_hsm1::hsm1!(
+    struct WrongArity {}
+
+    #[hsm1_initial_state]
+    fn initial(&mut self) -> StateResult!() {
+        handled!()
+    }
+);
+
+fn main() {}
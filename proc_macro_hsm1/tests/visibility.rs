@@ -0,0 +1,48 @@
+// The machine is defined in a module and driven from outside it
+mod door {
+    use proc_macro_hsm1::{
+        handled, hsm1, hsm1_initial_state, hsm1_state, not_handled, transition_to, StateResult,
+    };
+
+    pub enum DoorMsg {
+        Open,
+        Close,
+    }
+
+    hsm1!(
+        pub struct Door {
+            pub opened_cnt: u32,
+        }
+
+        #[hsm1_initial_state]
+        fn closed(&mut self, msg: &DoorMsg) -> StateResult!() {
+            match msg {
+                DoorMsg::Open => {
+                    self.opened_cnt += 1;
+                    transition_to!(open)
+                }
+                DoorMsg::Close => handled!(),
+            }
+        }
+
+        #[hsm1_state]
+        fn open(&mut self, msg: &DoorMsg) -> StateResult!() {
+            match msg {
+                DoorMsg::Close => transition_to!(closed),
+                DoorMsg::Open => not_handled!(),
+            }
+        }
+    );
+}
+
+use door::{Door, DoorMsg};
+
+#[test]
+fn test_machine_exported_from_module() {
+    let mut door = Door::new();
+    door.dispatch(&DoorMsg::Open);
+    door.dispatch(&DoorMsg::Open);
+    door.dispatch(&DoorMsg::Close);
+    door.dispatch(&DoorMsg::Open);
+    assert_eq!(door.opened_cnt, 2);
+}
@@ -1,7 +1,91 @@
+#![no_std]
+
 pub type StateFnsHdl = usize;
 
-pub enum StateResult {
+// Whether a state fn handled the message, a message that isn't handled
+// is passed to the parent state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handled {
+    Yes,
+    No,
+}
+
+// What a state fn returns, whether it handled the message and the state
+// to transition to if any. The hsm1 proc-macro states transition to a
+// StateFnsHdl and hsm0_with_executor states to a StateId.
+pub type StateResult<T = StateFnsHdl> = (Handled, Option<T>);
+
+// The enum hsm1 states returned before StateResult was shared with
+// hsm0_with_executor, use into() to convert it until it's removed.
+#[deprecated(note = "use StateResult, a (Handled, Option<StateFnsHdl>)")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyStateResult {
     NotHandled,
     Handled,
     TransitionTo(StateFnsHdl),
 }
+
+// Keeps the old name working through the deprecation window, code that
+// matched on or returned StateResult::Handled etc. only has to change
+// `use state_result::StateResult` to `use state_result::legacy::StateResult`.
+pub mod legacy {
+    #[allow(deprecated)]
+    pub use super::LegacyStateResult as StateResult;
+}
+
+#[allow(deprecated)]
+impl From<LegacyStateResult> for StateResult {
+    fn from(sr: LegacyStateResult) -> Self {
+        match sr {
+            LegacyStateResult::NotHandled => (Handled::No, None),
+            LegacyStateResult::Handled => (Handled::Yes, None),
+            LegacyStateResult::TransitionTo(hdl) => (Handled::Yes, Some(hdl)),
+        }
+    }
+}
+
+// The enum can't express a transition that didn't handle the message,
+// so (Handled::No, Some(hdl)) becomes TransitionTo(hdl).
+#[allow(deprecated)]
+impl From<StateResult> for LegacyStateResult {
+    fn from(sr: StateResult) -> Self {
+        match sr {
+            (Handled::No, None) => LegacyStateResult::NotHandled,
+            (Handled::Yes, None) => LegacyStateResult::Handled,
+            (_, Some(hdl)) => LegacyStateResult::TransitionTo(hdl),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_legacy_conversions() {
+        let sr: StateResult = LegacyStateResult::NotHandled.into();
+        assert_eq!(sr, (Handled::No, None));
+        let sr: StateResult = LegacyStateResult::Handled.into();
+        assert_eq!(sr, (Handled::Yes, None));
+        let sr: StateResult = LegacyStateResult::TransitionTo(2).into();
+        assert_eq!(sr, (Handled::Yes, Some(2)));
+
+        let legacy: LegacyStateResult = (Handled::No, None).into();
+        assert_eq!(legacy, LegacyStateResult::NotHandled);
+        let legacy: LegacyStateResult = (Handled::Yes, None).into();
+        assert_eq!(legacy, LegacyStateResult::Handled);
+        let legacy: LegacyStateResult = (Handled::No, Some(3)).into();
+        assert_eq!(legacy, LegacyStateResult::TransitionTo(3));
+    }
+
+    #[test]
+    fn test_legacy_name() {
+        let sr: StateResult = legacy::StateResult::Handled.into();
+        assert_eq!(sr, (Handled::Yes, None));
+        assert_eq!(
+            legacy::StateResult::from((Handled::Yes, Some(1))),
+            legacy::StateResult::TransitionTo(1)
+        );
+    }
+}
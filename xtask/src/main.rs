@@ -16,6 +16,7 @@ fn main() -> Result<(), DynError> {
     match task.as_deref() {
         Some("pre-commit") => pre_commit(&remaining_args)?,
         Some("gen-cov") => gen_cov(&get_current_dir())?,
+        Some("no-std-check") => no_std_check(&remaining_args)?,
         Some("fmt") => cargo_cmd(&get_current_dir(), "fmt", &remaining_args)?,
         Some("test") => cargo_cmd(&get_current_dir(), "test", &remaining_args)?,
         Some("clippy") => cargo_cmd(&get_current_dir(), "clippy", &remaining_args)?,
@@ -34,9 +35,14 @@ fn main() -> Result<(), DynError> {
 fn print_help() {
     eprintln!(
         r#"Tasks:
-pre-commit:    Runs `cargo fmt`, `cargo clippy` and `cargo test`
+pre-commit:    Runs `cargo fmt`, `cargo clippy` and `cargo test` plus `cargo test`
+               of hsm0-with-executor without default features, with only
+               the counters feature and with all features and of
+               proc_macro_hsm1 with the debug-states feature
 gen-cov:       Removes <current-dir>/coverage/ then generates coverage data in <current-dir>/coverage/
                using gen-profraw, gen-html gen-lcov and gen-covdir.
+no-std-check:  Builds hsm0-with-executor without default features for
+               thumbv7m-none-eabi, needs `rustup target add thumbv7m-none-eabi`
 
 tasks for testing gen-phl:
     clippy:           Runs `cargo clippy`
@@ -58,6 +64,64 @@ fn pre_commit(remaining_args: &Vec<String>) -> Result<(), DynError> {
     cargo_cmd(&project_root(), "clippy", remaining_args)?;
     cargo_cmd(&project_root(), "test", remaining_args)?;
 
+    // Prove nothing depends on the executor's optional counters
+    let mut no_counters_args = vec![
+        "-p".to_owned(),
+        "hsm0-with-executor".to_owned(),
+        "--no-default-features".to_owned(),
+    ];
+    no_counters_args.extend(remaining_args.iter().cloned());
+    cargo_cmd(&project_root(), "test", &no_counters_args)?;
+
+    // And that the core executor works without the messaging machinery
+    let mut no_messaging_args = vec![
+        "-p".to_owned(),
+        "hsm0-with-executor".to_owned(),
+        "--no-default-features".to_owned(),
+        "--features".to_owned(),
+        "counters".to_owned(),
+    ];
+    no_messaging_args.extend(remaining_args.iter().cloned());
+    cargo_cmd(&project_root(), "test", &no_messaging_args)?;
+
+    // And the optional features such as timing
+    let mut all_features_args = vec![
+        "-p".to_owned(),
+        "hsm0-with-executor".to_owned(),
+        "--all-features".to_owned(),
+    ];
+    all_features_args.extend(remaining_args.iter().cloned());
+    cargo_cmd(&project_root(), "test", &all_features_args)?;
+
+    // And the states of tests/cfg-states.rs that are only there with
+    // the macro's debug-states feature
+    let mut debug_states_args = vec![
+        "-p".to_owned(),
+        "proc_macro_hsm1".to_owned(),
+        "--features".to_owned(),
+        "debug-states".to_owned(),
+    ];
+    debug_states_args.extend(remaining_args.iter().cloned());
+    cargo_cmd(&project_root(), "test", &debug_states_args)?;
+
+    Ok(())
+}
+
+// Prove the executor builds as no_std on a target without std
+fn no_std_check(remaining_args: &[String]) -> Result<(), DynError> {
+    let mut no_std_args = vec![
+        "-p".to_owned(),
+        "hsm0-with-executor".to_owned(),
+        "--lib".to_owned(),
+        "--no-default-features".to_owned(),
+        "--features".to_owned(),
+        "counters".to_owned(),
+        "--target".to_owned(),
+        "thumbv7m-none-eabi".to_owned(),
+    ];
+    no_std_args.extend(remaining_args.iter().cloned());
+    cargo_cmd(&project_root(), "build", &no_std_args)?;
+
     Ok(())
 }
 